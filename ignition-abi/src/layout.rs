@@ -0,0 +1,112 @@
+//! How the guest's types look to the host.
+
+use core::convert::TryInto;
+
+/// A type that crosses between host and guest, as a parameter, result or struct field.
+pub trait WasmAbi {
+    /// How the host sees it.
+    type Wasm: WasmValue;
+
+    /// Its size, and alignment, in a wasm32 guest.
+    const SIZE: u32 = <Self::Wasm as WasmValue>::SIZE;
+}
+
+impl WasmAbi for u32 {
+    type Wasm = u32;
+}
+
+impl WasmAbi for u64 {
+    type Wasm = u64;
+}
+
+impl WasmAbi for usize {
+    type Wasm = u32;
+}
+
+impl<T> WasmAbi for *const T {
+    type Wasm = u32;
+}
+
+impl<T> WasmAbi for *mut T {
+    type Wasm = u32;
+}
+
+/// Gives the host's result type for an import that returns `R`, written as `fn() -> R` so that
+/// imports that return nothing, or never return, can be described too.
+pub trait WasmReturn {
+    type Wasm;
+}
+
+impl WasmReturn for fn() {
+    type Wasm = ();
+}
+
+impl WasmReturn for fn() -> ! {
+    type Wasm = ();
+}
+
+impl<T: WasmAbi> WasmReturn for fn() -> T {
+    type Wasm = T::Wasm;
+}
+
+/// A Wasm value type, stored little-endian in guest memory.
+pub trait WasmValue: Copy {
+    const SIZE: u32;
+
+    /// Reads the value from the start of `data`.
+    fn read(data: &[u8]) -> Self;
+
+    /// Writes the value to the start of `data`.
+    fn write(self, data: &mut [u8]);
+}
+
+macro_rules! wasm_values {
+    ($($ty:ty),*) => {
+        $(
+            impl WasmValue for $ty {
+                const SIZE: u32 = core::mem::size_of::<$ty>() as u32;
+
+                fn read(data: &[u8]) -> Self {
+                    Self::from_le_bytes(data[..core::mem::size_of::<$ty>()].try_into().unwrap())
+                }
+
+                fn write(self, data: &mut [u8]) {
+                    data[..core::mem::size_of::<$ty>()].copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+wasm_values!(u32, u64);
+
+/// Places the fields of a `#[repr(C)]` struct as wasm32 does, where every field type is aligned to
+/// its size.
+pub(crate) struct Layout {
+    size: u32,
+    align: u32,
+}
+
+impl Layout {
+    pub(crate) const fn new() -> Self {
+        Self { size: 0, align: 1 }
+    }
+
+    /// Places the next field, and returns its offset.
+    pub(crate) const fn field(&mut self, size: u32) -> u32 {
+        let offset = align_up(self.size, size);
+        self.size = offset + size;
+        if size > self.align {
+            self.align = size;
+        }
+        offset
+    }
+
+    pub(crate) const fn size(&self) -> u32 {
+        align_up(self.size, self.align)
+    }
+}
+
+const fn align_up(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
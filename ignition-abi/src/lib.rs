@@ -0,0 +1,366 @@
+//! The interface between ignition-host and its guests, defined once for both sides.
+//!
+//! [`imports!`] lists every function a guest can import from the `ignition` module. `ignition_guest`
+//! declares its `extern` block from the list, and the host builds its linker from it, checking each
+//! host function against the signature given here. An import that changes on one side then fails
+//! to build on the other. The structs that imports take by pointer are declared here too, along
+//! with the layout they have in guest memory; see [`wasm`].
+//!
+//! Guests also carry a custom section declaring the ABI version and optional features they were
+//! built for, which the host checks before linking them; see [`section`].
+
+#![no_std]
+
+mod layout;
+pub mod section;
+
+pub use layout::{WasmAbi, WasmReturn, WasmValue};
+pub use section::{FEATURES, HEAP_STATS, MIN_ABI_VERSION};
+
+use layout::Layout;
+
+/// Bumped whenever an import or struct changes in a way that older guests or hosts can't handle.
+pub const ABI_VERSION: u32 = 1;
+
+macro_rules! handles {
+    ($($(#[$meta:meta])* pub struct $name:ident(pub u32);)*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Clone, Copy)]
+            #[repr(transparent)]
+            pub struct $name(pub u32);
+
+            impl WasmAbi for $name {
+                type Wasm = u32;
+            }
+        )*
+    };
+}
+
+handles! {
+    /// Identifies what a call to the guest's `wake` export is for.
+    pub struct TaskId(pub u32);
+
+    pub struct IoHandle(pub u32);
+
+    pub struct RpcServerHandle(pub u32);
+
+    pub struct RpcClientHandle(pub u32);
+
+    /// Zero if a request was returned, or one if the task will be woken once there is one.
+    #[must_use]
+    pub struct RpcServerGetRequestResult(pub u32);
+}
+
+/// Declares structs passed through guest memory, as the guest sees them, and generates their
+/// [`wasm`] counterparts for the host.
+macro_rules! structs {
+    ($($(#[$meta:meta])* pub struct $name:ident { $(pub $field:ident: $ty:ty),* $(,)? })*) => {
+        $(
+            $(#[$meta])*
+            #[repr(C)]
+            pub struct $name {
+                $(pub $field: $ty),*
+            }
+
+            // The guest's own layout has to be the one the host reads.
+            #[cfg(target_arch = "wasm32")]
+            const _: () = {
+                let mut layout = Layout::new();
+                $(
+                    assert!(
+                        core::mem::offset_of!($name, $field)
+                            == layout.field(<$ty as WasmAbi>::SIZE) as usize
+                    );
+                )*
+                assert!(core::mem::size_of::<$name>() == layout.size() as usize);
+            };
+        )*
+
+        /// The structs guests pass through their memory, as the host sees them: each field in its
+        /// Wasm representation, at the offset a wasm32 guest puts it.
+        pub mod wasm {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $(
+                pub struct $name {
+                    $(pub $field: <$ty as WasmAbi>::Wasm),*
+                }
+
+                impl $name {
+                    pub const SIZE: u32 = {
+                        let mut layout = Layout::new();
+                        $(layout.field(<$ty as WasmAbi>::SIZE);)*
+                        layout.size()
+                    };
+
+                    /// Reads the struct from the start of `data` and advances past it. Panics if
+                    /// `data` is shorter than [`SIZE`](Self::SIZE).
+                    pub fn read(data: &mut &[u8]) -> Self {
+                        let mut layout = Layout::new();
+                        let value = Self {
+                            $(
+                                $field: WasmValue::read(
+                                    &data[layout.field(<$ty as WasmAbi>::SIZE) as usize..],
+                                ),
+                            )*
+                        };
+                        *data = &data[Self::SIZE as usize..];
+                        value
+                    }
+
+                    /// Writes the struct to the start of `data` and advances past it. Panics if
+                    /// `data` is shorter than [`SIZE`](Self::SIZE).
+                    pub fn write(&self, data: &mut &mut [u8]) {
+                        let (bytes, rest) =
+                            core::mem::take(data).split_at_mut(Self::SIZE as usize);
+                        let mut layout = Layout::new();
+                        $(
+                            let offset = layout.field(<$ty as WasmAbi>::SIZE) as usize;
+                            WasmValue::write(self.$field, &mut bytes[offset..]);
+                        )*
+                        *data = rest;
+                    }
+                }
+            )*
+        }
+    };
+}
+
+structs! {
+    pub struct RpcServerParams {
+        pub service_name_ptr: *const u8,
+        pub service_name_len: usize,
+        pub methods_ptr: *const RpcServerMethod,
+        pub methods_len: usize,
+    }
+
+    pub struct RpcServerMethod {
+        pub method_name_ptr: *const u8,
+        pub method_name_len: usize,
+    }
+
+    pub struct RpcMethodMetadata {
+        pub index: usize,
+        pub request_io: IoHandle,
+        pub response_io: IoHandle,
+    }
+}
+
+/// Passes the definition of every import to `$callback`, a macro that generates one side of the
+/// interface from it. The definition is a list of groups like this, with the guest's types:
+///
+/// ```text
+/// mod group {
+///     /// Documentation.
+///     fn name(param: Type, ...) -> Type;
+/// }
+/// ```
+///
+/// Each group is implemented by the host module of the same name. A group marked
+/// `#[feature = "name"]` belongs to that optional [feature](FEATURES), and the host only offers its
+/// imports to guests that declare the feature.
+#[macro_export]
+macro_rules! imports {
+    ($callback:ident) => {
+        $callback! {
+            mod core {
+                /// Requests that this instance be destroyed after the current wake() invocation
+                /// returns.
+                fn shutdown();
+
+                /// Immediately ends execution and destroys this instance.
+                fn abort() -> !;
+
+                /// Reports a panic and where it happened, for the host's crash report. The guest is
+                /// expected to abort next. An empty file means the location is unknown.
+                fn panic(
+                    message_ptr: *const u8,
+                    message_len: usize,
+                    file_ptr: *const u8,
+                    file_len: usize,
+                    line: u32,
+                    column: u32,
+                );
+
+                /// Requests that wake() be called precisely once with the given task_id when the
+                /// host begins shutting down, or straight away if it already has. The instance is
+                /// destroyed once the host's grace period runs out, whether or not it has called
+                /// shutdown().
+                fn host_shutdown_requested(task_id: $crate::TaskId);
+
+                // Debug, test, and diagnostic functions.
+
+                /// Emits a debug log message.
+                fn log(ptr: *const ::core::ffi::c_void, len: usize);
+
+                /// Requests that wake() be called precisely once with the given task_id.
+                fn impulse(task_id: $crate::TaskId);
+            }
+
+            #[feature = "heap-stats"]
+            mod core {
+                /// Reports heap usage. Byte counts are current; allocation counts are totals since
+                /// the instance started.
+                fn heap_stats(
+                    live_bytes: usize,
+                    peak_bytes: usize,
+                    allocations: u64,
+                    deallocations: u64,
+                );
+            }
+
+            mod time {
+                /// Asynchronously starts a timer. After it elapses, wake() will be called precisely
+                /// once with the given task_id.
+                fn sleep(task_id: $crate::TaskId, usec: u32);
+
+                /// Gets the current time in microseconds according to a monotonic clock with
+                /// unspecified epoch.
+                fn monotonic_time() -> u64;
+            }
+
+            mod io {
+                fn io_read(
+                    task_id: $crate::TaskId,
+                    io: $crate::IoHandle,
+                    ptr: *mut u8,
+                    len: usize,
+                    n_ptr: *mut usize,
+                ) -> u32;
+
+                fn io_write(
+                    task_id: $crate::TaskId,
+                    io: $crate::IoHandle,
+                    ptr: *const u8,
+                    len: usize,
+                    n_ptr: *mut usize,
+                ) -> u32;
+
+                fn io_close(io: $crate::IoHandle);
+            }
+
+            // Each call that takes a path allocates an IO handle immediately and writes it to
+            // io_ptr. wake() is later called precisely once with the given task_id and a status
+            // code, where zero indicates success. The handle must be closed either way.
+            mod fs {
+                /// Opens a file for reading. On success, the handle yields the file's content.
+                fn fs_open(
+                    task_id: $crate::TaskId,
+                    path_ptr: *const u8,
+                    path_len: usize,
+                    io_ptr: *mut $crate::IoHandle,
+                );
+
+                /// Creates or truncates a file. On success, content written to the handle is stored
+                /// in the file.
+                fn fs_create(
+                    task_id: $crate::TaskId,
+                    path_ptr: *const u8,
+                    path_len: usize,
+                    io_ptr: *mut $crate::IoHandle,
+                );
+
+                /// Describes a file. On success, the handle yields one 9p2000 stat structure.
+                fn fs_stat(
+                    task_id: $crate::TaskId,
+                    path_ptr: *const u8,
+                    path_len: usize,
+                    io_ptr: *mut $crate::IoHandle,
+                );
+
+                /// Lists a directory. On success, the handle yields a 9p2000 stat structure for
+                /// each entry.
+                fn fs_readdir(
+                    task_id: $crate::TaskId,
+                    path_ptr: *const u8,
+                    path_len: usize,
+                    io_ptr: *mut $crate::IoHandle,
+                );
+
+                /// Closes a handle from fs_create. Once the file is complete, wake() is called
+                /// precisely once with the given task_id and a status code, which is zero only if
+                /// everything written reached the file.
+                fn fs_close(task_id: $crate::TaskId, io: $crate::IoHandle);
+            }
+
+            mod rpc_client {
+                fn rpc_client_create(
+                    service_name_ptr: *const u8,
+                    service_name_len: usize,
+                ) -> $crate::RpcClientHandle;
+
+                fn rpc_client_wait_healthy(
+                    task_id: $crate::TaskId,
+                    rpc_client: $crate::RpcClientHandle,
+                ) -> u32;
+
+                fn rpc_client_request(
+                    rpc_client: $crate::RpcClientHandle,
+                    method_name_ptr: *const u8,
+                    method_name_len: usize,
+                    request_io_ptr: *mut $crate::IoHandle,
+                    response_io_ptr: *mut $crate::IoHandle,
+                ) -> u32;
+            }
+
+            mod rpc_server {
+                fn rpc_server_create(
+                    params: *const $crate::RpcServerParams,
+                ) -> $crate::RpcServerHandle;
+
+                fn rpc_server_get_request(
+                    task_id: $crate::TaskId,
+                    rpc_server: $crate::RpcServerHandle,
+                    metadata: *mut $crate::RpcMethodMetadata,
+                ) -> $crate::RpcServerGetRequestResult;
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::section::{section, section_len, Declaration};
+    use super::wasm;
+
+    #[test]
+    fn lays_structs_out_like_wasm32() {
+        assert_eq!(wasm::RpcServerParams::SIZE, 16);
+        assert_eq!(wasm::RpcServerMethod::SIZE, 8);
+        assert_eq!(wasm::RpcMethodMetadata::SIZE, 12);
+
+        let mut buffer = [0; 13];
+        wasm::RpcMethodMetadata {
+            index: 1,
+            request_io: 2,
+            response_io: 0x0403,
+        }
+        .write(&mut &mut buffer[..]);
+        assert_eq!(buffer, [1, 0, 0, 0, 2, 0, 0, 0, 3, 4, 0, 0, 0]);
+
+        let mut data = &buffer[..];
+        let metadata = wasm::RpcMethodMetadata::read(&mut data);
+        assert_eq!(
+            (metadata.index, metadata.request_io, metadata.response_io),
+            (1, 2, 0x0403)
+        );
+        assert_eq!(data, [0]);
+    }
+
+    #[test]
+    fn encodes_declarations() {
+        const FEATURES: &[&str] = &["heap-stats", "x"];
+        const SECTION: [u8; section_len(FEATURES)] = section(2, FEATURES);
+        assert_eq!(SECTION, *b"\x02\0\0\0\x0aheap-stats\x01x");
+
+        let declaration = Declaration::parse(&SECTION).unwrap();
+        assert_eq!(declaration.version, 2);
+        assert!(declaration.features().eq(FEATURES.iter().copied()));
+
+        assert!(Declaration::parse(&SECTION[..3]).is_none());
+        assert!(Declaration::parse(&SECTION[..SECTION.len() - 1]).is_none());
+        assert!(Declaration::parse(b"\x01\0\0\0\x01\xff").is_none());
+    }
+}
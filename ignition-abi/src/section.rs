@@ -0,0 +1,100 @@
+//! The custom section in which a guest declares what it was built against.
+//!
+//! The section holds the guest's [`ABI_VERSION`](crate::ABI_VERSION) as a little-endian `u32`,
+//! followed by the name of each feature it needs, each prefixed by its length in one byte. The host
+//! reads it before linking the guest, so that a guest it can't run is refused with a reason instead
+//! of failing to link or misbehaving.
+
+use core::str;
+
+/// The name of the section. The `#[ignition_guest::main]` macro has to spell it out, since
+/// `#[link_section]` only takes a literal.
+pub const SECTION_NAME: &str = "ignition_abi";
+
+/// The oldest version a host built from this crate still runs guests of.
+pub const MIN_ABI_VERSION: u32 = 1;
+
+/// Lets a guest call `heap_stats`.
+pub const HEAP_STATS: &str = "heap-stats";
+
+/// Every optional part of the interface. Imports that belong to one, marked with `#[feature]` in
+/// [`imports!`](crate::imports), are only offered to guests that declare it.
+pub const FEATURES: &[&str] = &[HEAP_STATS];
+
+/// The length of the section declaring `features`.
+pub const fn section_len(features: &[&str]) -> usize {
+    let mut len = 4;
+    let mut i = 0;
+    while i < features.len() {
+        len += 1 + features[i].len();
+        i += 1;
+    }
+    len
+}
+
+/// Encodes the section, where `N` is [`section_len(features)`](section_len).
+pub const fn section<const N: usize>(version: u32, features: &[&str]) -> [u8; N] {
+    let mut section = [0; N];
+    let version = version.to_le_bytes();
+    let mut pos = 0;
+    while pos < version.len() {
+        section[pos] = version[pos];
+        pos += 1;
+    }
+    let mut i = 0;
+    while i < features.len() {
+        let name = features[i].as_bytes();
+        assert!(name.len() <= u8::MAX as usize, "feature name too long");
+        section[pos] = name.len() as u8;
+        pos += 1;
+        let mut j = 0;
+        while j < name.len() {
+            section[pos] = name[j];
+            pos += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    assert!(pos == N, "wrong section length");
+    section
+}
+
+/// What a guest declared in its section.
+#[derive(Clone, Copy, Debug)]
+pub struct Declaration<'a> {
+    pub version: u32,
+    features: &'a [u8],
+}
+
+impl<'a> Declaration<'a> {
+    /// Decodes a section, or returns `None` if it's malformed.
+    pub fn parse(section: &'a [u8]) -> Option<Self> {
+        if section.len() < 4 {
+            return None;
+        }
+        let (version, features) = section.split_at(4);
+        let declaration = Self {
+            version: u32::from_le_bytes([version[0], version[1], version[2], version[3]]),
+            features,
+        };
+        // Check every name now, so that iterating over them can't fail.
+        let mut rest = features;
+        while let Some((&len, tail)) = rest.split_first() {
+            let name = tail.get(..len as usize)?;
+            str::from_utf8(name).ok()?;
+            rest = &tail[len as usize..];
+        }
+        Some(declaration)
+    }
+
+    /// The features the guest needs, in the order it listed them.
+    pub fn features(&self) -> impl Iterator<Item = &'a str> + 'a {
+        let mut rest = self.features;
+        core::iter::from_fn(move || {
+            let (&len, tail) = rest.split_first()?;
+            let (name, tail) = tail.split_at(len as usize);
+            rest = tail;
+            Some(str::from_utf8(name).unwrap())
+        })
+    }
+}
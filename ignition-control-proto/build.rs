@@ -0,0 +1,6 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    tonic_build::compile_protos("./control.proto")?;
+    Ok(())
+}
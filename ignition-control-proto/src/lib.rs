@@ -0,0 +1,3 @@
+pub mod control_pb {
+    tonic::include_proto!("ignition.control");
+}
@@ -0,0 +1,252 @@
+//! Checks that a module was built for an ABI this host provides, before it's linked.
+//!
+//! Guests declare the ABI version and optional features they need in a custom section; see
+//! [`ignition_abi::section`]. Refusing a module here gives a reason that names what's missing,
+//! instead of a link error, or a guest that misreads the structs it shares with the host.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use ignition_abi::section::{Declaration, SECTION_NAME};
+use ignition_abi::{ABI_VERSION, FEATURES, MIN_ABI_VERSION};
+use wasmtime::Module;
+
+use crate::api::IMPORTS;
+
+/// The ABI version and optional features a module needs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbiRequirements {
+    pub version: u32,
+    pub features: BTreeSet<String>,
+}
+
+impl AbiRequirements {
+    /// Reads the requirements from a module's section. Modules without one, such as those written
+    /// by hand in the text format, need the first version and no features.
+    pub fn read(module: &[u8]) -> Result<Self> {
+        match custom_section(module, SECTION_NAME)? {
+            Some(section) => Self::decode(section),
+            None => Ok(Self {
+                version: 1,
+                features: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// Decodes the content of a module's section.
+    pub fn decode(section: &[u8]) -> Result<Self> {
+        match Declaration::parse(section) {
+            Some(declaration) => Ok(Self {
+                version: declaration.version,
+                features: declaration.features().map(str::to_owned).collect(),
+            }),
+            None => bail!("malformed {} section", SECTION_NAME),
+        }
+    }
+
+    /// Encodes the requirements as the content of a section.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut section = self.version.to_le_bytes().to_vec();
+        for feature in &self.features {
+            section.push(feature.len() as u8);
+            section.extend_from_slice(feature.as_bytes());
+        }
+        debug_assert!(Declaration::parse(&section).is_some());
+        section
+    }
+
+    /// Refuses `module` unless this host supports its version and features, and it only imports
+    /// what they provide.
+    pub fn check(&self, module: &Module) -> Result<()> {
+        if !(MIN_ABI_VERSION..=ABI_VERSION).contains(&self.version) {
+            if MIN_ABI_VERSION == ABI_VERSION {
+                bail!(
+                    "needs ABI version {}, but this host only supports version {}",
+                    self.version,
+                    ABI_VERSION
+                );
+            }
+            bail!(
+                "needs ABI version {}, but this host supports versions {} to {}",
+                self.version,
+                MIN_ABI_VERSION,
+                ABI_VERSION
+            );
+        }
+        if let Some(feature) = self
+            .features
+            .iter()
+            .find(|&feature| !FEATURES.contains(&feature.as_str()))
+        {
+            bail!(
+                "needs the {} feature, which this host doesn't support",
+                feature
+            );
+        }
+
+        for import in module.imports() {
+            let name = import.name().unwrap_or("");
+            let group = match import.module() {
+                "ignition" => IMPORTS.iter().find(|group| group.names.contains(&name)),
+                _ => None,
+            };
+            match group {
+                None => bail!(
+                    "imports {}::{}, which this host doesn't provide",
+                    import.module(),
+                    name
+                ),
+                Some(group) => {
+                    if let Some(feature) = group.feature {
+                        if !self.features.contains(feature) {
+                            bail!(
+                                "imports ignition::{}, which needs the {} feature it didn't ask for",
+                                name,
+                                feature
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the content of the custom section called `name` in a binary module, if there is one.
+/// Anything else, like the text format, has no sections.
+fn custom_section<'a>(module: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    let mut rest = match module.strip_prefix(b"\0asm") {
+        Some(rest) if rest.len() >= 4 => &rest[4..],
+        _ => return Ok(None),
+    };
+    while let Some((&id, tail)) = rest.split_first() {
+        rest = tail;
+        let size = read_u32(&mut rest)? as usize;
+        if size > rest.len() {
+            bail!("malformed module: section runs past the end");
+        }
+        let (mut section, tail) = rest.split_at(size);
+        rest = tail;
+        if id != 0 {
+            continue;
+        }
+        let name_len = read_u32(&mut section)? as usize;
+        if name_len > section.len() {
+            bail!("malformed module: section name runs past the end");
+        }
+        if &section[..name_len] == name.as_bytes() {
+            return Ok(Some(&section[name_len..]));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads an unsigned LEB128 number and advances past it.
+fn read_u32(data: &mut &[u8]) -> Result<u32> {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = match data.split_first() {
+            Some(split) => split,
+            None => bail!("malformed module: truncated number"),
+        };
+        *data = rest;
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("malformed module: number too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ignition_abi::section::section;
+    use ignition_abi::HEAP_STATS;
+    use wasmtime::{Engine, Module};
+
+    use super::AbiRequirements;
+
+    /// A module that imports `imports`, with a section declaring `version` and `features`.
+    fn module(version: u32, features: &[&str], imports: &str) -> Vec<u8> {
+        let requirements = AbiRequirements {
+            version,
+            features: features.iter().map(|&f| f.to_owned()).collect(),
+        };
+        let content = requirements.encode();
+        let mut custom = vec![12];
+        custom.extend_from_slice(b"ignition_abi");
+        custom.extend_from_slice(&content);
+
+        let mut bytes = wat::parse_str(format!("(module {})", imports)).unwrap();
+        bytes.push(0);
+        bytes.push(custom.len() as u8);
+        bytes.extend_from_slice(&custom);
+        bytes
+    }
+
+    fn check(bytes: &[u8]) -> Result<(), String> {
+        let module = Module::new(&Engine::default(), bytes).unwrap();
+        AbiRequirements::read(bytes)
+            .and_then(|requirements| requirements.check(&module))
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn reads_declared_requirements() {
+        const SECTION: [u8; 15] = section(1, &[HEAP_STATS]);
+        let bytes = module(1, &[HEAP_STATS], "");
+        assert_eq!(
+            AbiRequirements::read(&bytes).unwrap(),
+            AbiRequirements::decode(&SECTION).unwrap()
+        );
+
+        // Modules from before the section are the first version.
+        assert_eq!(
+            AbiRequirements::read(b"(module)").unwrap(),
+            AbiRequirements {
+                version: 1,
+                features: BTreeSet::new(),
+            }
+        );
+
+        assert_eq!(
+            AbiRequirements::read(&bytes[..bytes.len() - 1])
+                .unwrap_err()
+                .to_string(),
+            "malformed module: section runs past the end"
+        );
+    }
+
+    #[test]
+    fn refuses_incompatible_modules() {
+        let heap_stats = r#"(import "ignition" "heap_stats" (func (param i32 i32 i64 i64)))"#;
+        assert_eq!(check(&module(1, &[HEAP_STATS], heap_stats)), Ok(()));
+        assert_eq!(
+            check(&module(2, &[], "")),
+            Err("needs ABI version 2, but this host only supports version 1".to_owned())
+        );
+        assert_eq!(
+            check(&module(1, &["threads"], "")),
+            Err("needs the threads feature, which this host doesn't support".to_owned())
+        );
+        assert_eq!(
+            check(&module(1, &[], heap_stats)),
+            Err(
+                "imports ignition::heap_stats, which needs the heap-stats feature it didn't ask \
+                 for"
+                .to_owned()
+            )
+        );
+        assert_eq!(
+            check(&module(1, &[], r#"(import "ignition" "fork" (func))"#)),
+            Err("imports ignition::fork, which this host doesn't provide".to_owned())
+        );
+        assert_eq!(
+            check(&module(1, &[], r#"(import "env" "memcpy" (func))"#)),
+            Err("imports env::memcpy, which this host doesn't provide".to_owned())
+        );
+    }
+}
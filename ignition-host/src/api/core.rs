@@ -1,9 +1,10 @@
 use std::sync::Arc;
 
-use chrono::{SecondsFormat, Utc};
+use log::{info, Level};
 use wasmtime::{AsContext, Caller, Trap};
 
-use crate::process::process::Process;
+use crate::crash::GuestPanic;
+use crate::process::process::{HeapStats, Process};
 use crate::util::{get_memory, get_str};
 use crate::{TaskId, WakeParams};
 
@@ -11,24 +12,65 @@ pub fn shutdown(caller: Caller<'_, Arc<Process>>) {
     caller.data().shutdown();
 }
 
-pub fn abort() -> Result<(), Trap> {
+pub fn host_shutdown_requested(caller: Caller<'_, Arc<Process>>, task_id: u32) {
+    caller.data().wake_on_host_shutdown(TaskId(task_id));
+}
+
+pub fn abort(_caller: Caller<'_, Arc<Process>>) -> Result<(), Trap> {
     Err(Trap::new("aborted"))
 }
 
+pub fn panic(
+    mut caller: Caller<'_, Arc<Process>>,
+    message_ptr: u32,
+    message_len: u32,
+    file_ptr: u32,
+    file_len: u32,
+    line: u32,
+    column: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let message = get_str(caller.as_context(), memory, message_ptr, message_len)?.to_owned();
+    let file = get_str(caller.as_context(), memory, file_ptr, file_len)?.to_owned();
+
+    caller.data().set_guest_panic(GuestPanic {
+        message,
+        file,
+        line,
+        column,
+    });
+
+    Ok(())
+}
+
 pub fn log(mut caller: Caller<'_, Arc<Process>>, ptr: u32, len: u32) -> Result<(), Trap> {
     let memory = get_memory(&mut caller)?;
     let message = get_str(caller.as_context(), memory, ptr, len)?;
 
-    println!(
-        "[{}] {} {}",
-        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
-        caller.data().pid(),
-        message,
-    );
+    // Guests have no levels of their own, so everything they log is info.
+    let process = caller.data();
+    if process.log_level() >= Level::Info {
+        info!(target: "guest", "pid {}: {}", process.pid(), message);
+    }
 
     Ok(())
 }
 
+pub fn heap_stats(
+    caller: Caller<'_, Arc<Process>>,
+    live_bytes: u32,
+    peak_bytes: u32,
+    allocations: u64,
+    deallocations: u64,
+) {
+    caller.data().set_heap_stats(HeapStats {
+        live_bytes,
+        peak_bytes,
+        allocations,
+        deallocations,
+    });
+}
+
 pub fn impulse(caller: Caller<'_, Arc<Process>>, task_id: u32) {
     let task_id = TaskId(task_id);
 
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use wasmtime::{AsContext, AsContextMut, Caller, Trap};
+
+use crate::process::process::Process;
+use crate::util::{get_memory, get_slice_mut, get_str};
+use crate::TaskId;
+
+pub fn fs_open(
+    caller: Caller<'_, Arc<Process>>,
+    task_id: u32,
+    path_ptr: u32,
+    path_len: u32,
+    io_ptr: u32,
+) -> Result<(), Trap> {
+    fs_call(caller, path_ptr, path_len, io_ptr, |process, path| {
+        process.fs_open(TaskId(task_id), path)
+    })
+}
+
+pub fn fs_create(
+    caller: Caller<'_, Arc<Process>>,
+    task_id: u32,
+    path_ptr: u32,
+    path_len: u32,
+    io_ptr: u32,
+) -> Result<(), Trap> {
+    fs_call(caller, path_ptr, path_len, io_ptr, |process, path| {
+        process.fs_create(TaskId(task_id), path)
+    })
+}
+
+pub fn fs_stat(
+    caller: Caller<'_, Arc<Process>>,
+    task_id: u32,
+    path_ptr: u32,
+    path_len: u32,
+    io_ptr: u32,
+) -> Result<(), Trap> {
+    fs_call(caller, path_ptr, path_len, io_ptr, |process, path| {
+        process.fs_stat(TaskId(task_id), path)
+    })
+}
+
+pub fn fs_readdir(
+    caller: Caller<'_, Arc<Process>>,
+    task_id: u32,
+    path_ptr: u32,
+    path_len: u32,
+    io_ptr: u32,
+) -> Result<(), Trap> {
+    fs_call(caller, path_ptr, path_len, io_ptr, |process, path| {
+        process.fs_readdir(TaskId(task_id), path)
+    })
+}
+
+pub fn fs_close(caller: Caller<'_, Arc<Process>>, task_id: u32, io: u32) -> Result<(), Trap> {
+    caller.data().fs_close(TaskId(task_id), io)
+}
+
+/// Reads the path argument, starts the operation, and writes the resulting IO handle back.
+fn fs_call(
+    mut caller: Caller<'_, Arc<Process>>,
+    path_ptr: u32,
+    path_len: u32,
+    io_ptr: u32,
+    f: impl FnOnce(&Process, String) -> u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let path = get_str(caller.as_context(), memory, path_ptr, path_len)?.to_owned();
+
+    let io = f(caller.data(), path);
+
+    let mut io_data = get_slice_mut(caller.as_context_mut(), memory, io_ptr, 4)?;
+    io_data.write_u32::<LittleEndian>(io).unwrap();
+    Ok(())
+}
@@ -1,5 +1,148 @@
+//! The host's side of the functions guests import from `ignition`, as defined by
+//! [`ignition_abi::imports`].
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use ignition_abi::{WasmAbi, WasmReturn};
+use wasmtime::{Caller, Linker, Trap};
+
+use crate::process::process::Process;
+
 pub mod core;
+pub mod fs;
 pub mod io;
 pub mod rpc_client;
 pub mod rpc_server;
 pub mod time;
+
+/// A group of imports guests can ask for.
+pub struct ImportGroup {
+    /// The optional feature the group belongs to, if any.
+    pub feature: Option<&'static str>,
+    pub names: &'static [&'static str],
+}
+
+/// Turns a group's optional `#[feature]` into an `Option`.
+macro_rules! feature {
+    () => {
+        None
+    };
+    ($feature:literal) => {
+        Some($feature)
+    };
+}
+
+macro_rules! list_imports {
+    ($($(#[feature = $feature:literal])? mod $group:ident {
+        $($(#[$meta:meta])* fn $name:ident($($param:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*
+    })*) => {
+        &[$(
+            ImportGroup {
+                feature: feature!($($feature)?),
+                names: &[$(stringify!($name)),*],
+            },
+        )*]
+    };
+}
+
+/// Every import in the `ignition` module.
+pub const IMPORTS: &[ImportGroup] = ignition_abi::imports!(list_imports);
+
+/// Defines the imports in `linker` that belong to no feature or to one of `features`.
+pub fn add_to_linker(linker: &mut Linker<Arc<Process>>, features: &BTreeSet<String>) -> Result<()> {
+    // Each import is wrapped in a closure with the signature the definition gives it, so that a
+    // function here that doesn't match fails to build.
+    macro_rules! define_imports {
+        ($($(#[feature = $feature:literal])? mod $group:ident {
+            $($(#[$meta:meta])* fn $name:ident($($param:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*
+        })*) => {
+            $(
+                let feature: Option<&str> = feature!($($feature)?);
+                if feature.map_or(true, |feature| features.contains(feature)) {
+                    $(
+                        linker.func_wrap(
+                            "ignition",
+                            stringify!($name),
+                            |caller: Caller<'_, Arc<Process>>,
+                             $($param: <$ty as WasmAbi>::Wasm),*|
+                             -> Result<<fn() $(-> $ret)? as WasmReturn>::Wasm, Trap> {
+                                IntoResult::into_result(self::$group::$name(caller, $($param),*))
+                            },
+                        )?;
+                    )*
+                }
+            )*
+        };
+    }
+
+    ignition_abi::imports!(define_imports);
+    Ok(())
+}
+
+/// Lets host functions that can't trap leave out the `Result`.
+trait IntoResult<T> {
+    fn into_result(self) -> Result<T, Trap>;
+}
+
+macro_rules! into_result {
+    ($($ty:ty),*) => {
+        $(
+            impl IntoResult<$ty> for $ty {
+                fn into_result(self) -> Result<$ty, Trap> {
+                    Ok(self)
+                }
+            }
+
+            impl IntoResult<$ty> for Result<$ty, Trap> {
+                fn into_result(self) -> Result<$ty, Trap> {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+into_result!((), u32, u64);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use wasmtime::{Engine, Linker, Module, Store};
+
+    use super::add_to_linker;
+    use crate::process::process::Process;
+
+    #[test]
+    fn links_every_import() {
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        let features = ignition_abi::FEATURES
+            .iter()
+            .map(|&f| f.to_owned())
+            .collect();
+        add_to_linker(&mut linker, &features).unwrap();
+
+        // Signatures as a wasm32 guest declares them.
+        let module = Module::new(
+            &engine,
+            r#"(module
+                (import "ignition" "abort" (func))
+                (import "ignition" "heap_stats" (func (param i32 i32 i64 i64)))
+                (import "ignition" "monotonic_time" (func (result i64)))
+                (import "ignition" "rpc_server_get_request" (func (param i32 i32 i32) (result i32)))
+            )"#,
+        )
+        .unwrap();
+        let (process, _) = Process::new(0, "test".to_owned(), None);
+        let mut store = Store::new(&engine, std::sync::Arc::new(process));
+        linker.instantiate(&mut store, &module).unwrap();
+
+        // Optional imports are left out unless their feature is asked for.
+        let mut linker = Linker::new(&engine);
+        add_to_linker(&mut linker, &BTreeSet::new()).unwrap();
+        assert!(linker.instantiate(&mut store, &module).is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//! Serves `ControlService` on a Unix socket, so that tools like `ignitionctl` can inspect and manage
+//! a running host.
+
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Result};
+use ignition_control_proto::control_pb::control_service_server::{
+    ControlService, ControlServiceServer,
+};
+use ignition_control_proto::control_pb::{self, LogLevel, ProcessState};
+use log::{error, LevelFilter};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::StreamExt;
+use tonic::transport::server::Connected;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::process::process::HeapStats;
+use crate::process::service_registry::SERVICE_REGISTRY;
+use crate::snapshot::Snapshot;
+use crate::supervisor::{ProcessEntry, Supervisor};
+
+/// Starts serving on a socket at `path`, replacing any socket left there by an earlier host. Refuses
+/// to replace anything else.
+pub fn serve(path: &Path, supervisor: Arc<Supervisor>) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and isn't a socket", path.display()),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        Err(_) => {}
+    }
+    let incoming = UnixListenerStream::new(UnixListener::bind(path)?).map(|r| r.map(Connection));
+    let server = Server::builder()
+        .add_service(ControlServiceServer::new(ControlServiceImpl { supervisor }))
+        .serve_with_incoming(incoming);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Control socket failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+struct ControlServiceImpl {
+    supervisor: Arc<Supervisor>,
+}
+
+#[tonic::async_trait]
+impl ControlService for ControlServiceImpl {
+    async fn list_processes(
+        &self,
+        _request: Request<control_pb::ListProcessesRequest>,
+    ) -> Result<Response<control_pb::ListProcessesResponse>, Status> {
+        let processes = self
+            .supervisor
+            .processes()
+            .into_iter()
+            .map(|(pid, entry)| process_info(pid, &entry))
+            .collect();
+        Ok(Response::new(control_pb::ListProcessesResponse {
+            processes,
+        }))
+    }
+
+    async fn start_process(
+        &self,
+        request: Request<control_pb::StartProcessRequest>,
+    ) -> Result<Response<control_pb::StartProcessResponse>, Status> {
+        let request = request.get_ref();
+        let principal = Some(&request.principal[..]).filter(|principal| !principal.is_empty());
+        let pid = self
+            .supervisor
+            .start(&request.module_ref, principal)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        Ok(Response::new(control_pb::StartProcessResponse {
+            pid: pid as u64,
+        }))
+    }
+
+    async fn kill_process(
+        &self,
+        request: Request<control_pb::KillProcessRequest>,
+    ) -> Result<Response<control_pb::KillProcessResponse>, Status> {
+        self.supervisor
+            .kill(request.get_ref().pid as usize)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        Ok(Response::new(control_pb::KillProcessResponse {}))
+    }
+
+    async fn upgrade_process(
+        &self,
+        request: Request<control_pb::UpgradeProcessRequest>,
+    ) -> Result<Response<control_pb::UpgradeProcessResponse>, Status> {
+        let request = request.get_ref();
+        let new_pid = self
+            .supervisor
+            .upgrade(request.pid as usize, &request.module_ref)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        Ok(Response::new(control_pb::UpgradeProcessResponse {
+            new_pid: new_pid as u64,
+        }))
+    }
+
+    async fn snapshot_process(
+        &self,
+        request: Request<control_pb::SnapshotProcessRequest>,
+    ) -> Result<Response<control_pb::SnapshotProcessResponse>, Status> {
+        let request = request.get_ref();
+        self.supervisor
+            .snapshot(request.pid as usize, Path::new(&request.path), request.stop)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        Ok(Response::new(control_pb::SnapshotProcessResponse {}))
+    }
+
+    async fn restore_process(
+        &self,
+        request: Request<control_pb::RestoreProcessRequest>,
+    ) -> Result<Response<control_pb::RestoreProcessResponse>, Status> {
+        let path = &request.get_ref().path;
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| Status::not_found(format!("Failed to read {}: {}", path, e)))?;
+        let snapshot = Snapshot::decode(&data)
+            .map_err(|e| Status::invalid_argument(format!("{}: {:#}", path, e)))?;
+        let pid = self
+            .supervisor
+            .restore(&snapshot)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        Ok(Response::new(control_pb::RestoreProcessResponse {
+            pid: pid as u64,
+        }))
+    }
+
+    async fn dump_registry(
+        &self,
+        _request: Request<control_pb::DumpRegistryRequest>,
+    ) -> Result<Response<control_pb::DumpRegistryResponse>, Status> {
+        let entries = SERVICE_REGISTRY
+            .dump()
+            .into_iter()
+            .map(|entry| control_pb::RegistryEntry {
+                service_name: entry.service_name,
+                servers: entry
+                    .servers
+                    .into_iter()
+                    .map(|server| control_pb::RpcServer {
+                        server: Some(match server {
+                            Some((pid, rpc_server)) => {
+                                control_pb::rpc_server::Server::Guest(control_pb::GuestRpcServer {
+                                    pid: pid as u64,
+                                    rpc_server,
+                                })
+                            }
+                            None => control_pb::rpc_server::Server::Native(
+                                control_pb::NativeRpcServer {},
+                            ),
+                        }),
+                    })
+                    .collect(),
+                waiting_pids: entry
+                    .waiting_pids
+                    .into_iter()
+                    .map(|pid| pid as u64)
+                    .collect(),
+            })
+            .collect();
+        Ok(Response::new(control_pb::DumpRegistryResponse { entries }))
+    }
+
+    async fn set_log_level(
+        &self,
+        request: Request<control_pb::SetLogLevelRequest>,
+    ) -> Result<Response<control_pb::SetLogLevelResponse>, Status> {
+        let request = request.get_ref();
+        let level = LogLevel::from_i32(request.level)
+            .map(level_filter)
+            .ok_or_else(|| Status::invalid_argument("unknown log level"))?;
+        match request.target {
+            Some(control_pb::set_log_level_request::Target::Pid(pid)) => {
+                match self.supervisor.processes().get(&(pid as usize)) {
+                    Some(entry) => entry.process.set_log_level(level),
+                    None => return Err(Status::not_found(format!("no process with pid {}", pid))),
+                }
+            }
+            None => log::set_max_level(level),
+        }
+        Ok(Response::new(control_pb::SetLogLevelResponse {}))
+    }
+
+    async fn get_heap_stats(
+        &self,
+        request: Request<control_pb::GetHeapStatsRequest>,
+    ) -> Result<Response<control_pb::GetHeapStatsResponse>, Status> {
+        let mut processes = self.supervisor.processes();
+        if let Some(control_pb::get_heap_stats_request::Target::Pid(pid)) = request.get_ref().target
+        {
+            let entry = processes
+                .remove(&(pid as usize))
+                .ok_or_else(|| Status::not_found(format!("no process with pid {}", pid)))?;
+            processes = std::iter::once((pid as usize, entry)).collect();
+        }
+        let processes = processes
+            .into_iter()
+            .filter_map(|(pid, entry)| {
+                let stats = entry.process.heap_stats()?;
+                Some(control_pb::ProcessHeapStats {
+                    pid: pid as u64,
+                    module_ref: entry.module_ref,
+                    heap_stats: Some(heap_stats(stats)),
+                })
+            })
+            .collect();
+        Ok(Response::new(control_pb::GetHeapStatsResponse {
+            processes,
+        }))
+    }
+}
+
+fn process_info(pid: usize, entry: &ProcessEntry) -> control_pb::ProcessInfo {
+    let process = &entry.process;
+    let state = if process.is_shutdown() {
+        ProcessState::Stopping
+    } else if entry.draining {
+        ProcessState::Draining
+    } else {
+        ProcessState::Running
+    };
+    let handles = process.handles();
+    control_pb::ProcessInfo {
+        pid: pid as u64,
+        module_ref: entry.module_ref.clone(),
+        principal: process.principal().to_owned(),
+        state: state as i32,
+        uptime_ms: process.start_time().elapsed().as_millis() as u64,
+        spawn_time_us: entry.spawn_time.as_micros() as u64,
+        services: SERVICE_REGISTRY
+            .services_served_by(process)
+            .into_iter()
+            .collect(),
+        handles: Some(control_pb::ProcessHandles {
+            rpc_clients: handles.rpc_clients,
+            rpc_servers: handles.rpc_servers as u32,
+            io_objects: handles.io_objects as u32,
+            pending_requests: handles.pending_requests as u32,
+        }),
+        heap_stats: process.heap_stats().map(heap_stats),
+        log_level: log_level(process.log_level()) as i32,
+    }
+}
+
+fn heap_stats(stats: HeapStats) -> control_pb::HeapStats {
+    control_pb::HeapStats {
+        live_bytes: stats.live_bytes,
+        peak_bytes: stats.peak_bytes,
+        allocations: stats.allocations,
+        deallocations: stats.deallocations,
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn log_level(level: LevelFilter) -> LogLevel {
+    match level {
+        LevelFilter::Off => LogLevel::Off,
+        LevelFilter::Error => LogLevel::Error,
+        LevelFilter::Warn => LogLevel::Warn,
+        LevelFilter::Info => LogLevel::Info,
+        LevelFilter::Debug => LogLevel::Debug,
+        LevelFilter::Trace => LogLevel::Trace,
+    }
+}
+
+/// A control socket connection. Tonic only accepts connections that can describe their peer, which
+/// a Unix socket peer has no use for.
+struct Connection(UnixStream);
+
+impl Connected for Connection {}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use ignition_control_proto::control_pb::control_service_client::ControlServiceClient;
+    use ignition_control_proto::control_pb::{
+        KillProcessRequest, ListProcessesRequest, ProcessState, StartProcessRequest,
+    };
+    use testable_file_system::RealFileSystem;
+    use tokio::net::UnixStream;
+    use tokio::time::sleep;
+    use tonic::transport::{Channel, Endpoint, Uri};
+    use tower::service_fn;
+
+    use super::serve;
+    use crate::engine::EngineOptions;
+    use crate::module_loader::ModuleLoader;
+    use crate::supervisor::Supervisor;
+
+    const MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "wake") (param i32 i32)))
+    "#;
+
+    async fn connect(socket: PathBuf) -> ControlServiceClient<Channel> {
+        let channel = Endpoint::try_from("http://[::]:0")
+            .unwrap()
+            .connect_with_connector(service_fn(move |_: Uri| {
+                UnixStream::connect(socket.clone())
+            }))
+            .await
+            .unwrap();
+        ControlServiceClient::new(channel)
+    }
+
+    fn supervisor() -> Arc<Supervisor> {
+        let engine = EngineOptions::default().build().unwrap();
+        let loader = ModuleLoader::<Channel, RealFileSystem>::new(engine, None, None);
+        Supervisor::new(loader, None).unwrap().0
+    }
+
+    #[tokio::test]
+    async fn starts_lists_and_kills_processes_over_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let module_path = dir.path().join("idle.wasm");
+        std::fs::write(&module_path, wat::parse_str(MODULE).unwrap()).unwrap();
+        let socket = dir.path().join("control.sock");
+        let supervisor = supervisor();
+        serve(&socket, supervisor.clone()).unwrap();
+        // A socket left behind by an earlier host is replaced.
+        serve(&socket, supervisor).unwrap();
+
+        let mut client = connect(socket).await;
+        let pid = client
+            .start_process(StartProcessRequest {
+                module_ref: module_path.to_str().unwrap().to_owned(),
+                principal: "idler".to_owned(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .pid;
+
+        let processes = client
+            .list_processes(ListProcessesRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .processes;
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].pid, pid);
+        assert_eq!(processes[0].state, ProcessState::Running as i32);
+
+        client
+            .kill_process(KillProcessRequest { pid })
+            .await
+            .unwrap();
+        while !client
+            .list_processes(ListProcessesRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .processes
+            .is_empty()
+        {
+            sleep(Duration::from_millis(1)).await;
+        }
+        let status = client
+            .kill_process(KillProcessRequest { pid })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_replace_anything_but_a_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        std::fs::write(&path, "precious").unwrap();
+        let error = serve(&path, supervisor()).unwrap_err();
+        assert!(error.to_string().contains("isn't a socket"), "{}", error);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "precious");
+    }
+}
@@ -0,0 +1,199 @@
+//! Crash reports for processes that trap.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use wasmtime::{FrameInfo, Trap};
+
+/// A panic reported by the guest just before it aborted.
+#[derive(Debug)]
+pub struct GuestPanic {
+    pub message: String,
+    /// Empty if the guest didn't know where it panicked.
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Describes why a process died, with its Wasm backtrace symbolicated as far as the module's name
+/// section and DWARF debug info allow.
+#[derive(Debug)]
+pub struct CrashReport {
+    pid: usize,
+    path: String,
+    panic: Option<GuestPanic>,
+    reason: String,
+    frames: Vec<Frame>,
+}
+
+#[derive(Debug)]
+struct Frame {
+    func_index: u32,
+    module_offset: usize,
+    /// From the name section.
+    func_name: Option<String>,
+    /// From DWARF. Inlined calls produce several symbols for one frame, innermost first.
+    symbols: Vec<Symbol>,
+}
+
+#[derive(Debug)]
+struct Symbol {
+    name: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+}
+
+impl CrashReport {
+    pub fn new(pid: usize, path: &str, panic: Option<GuestPanic>, trap: &Trap) -> Self {
+        Self {
+            pid,
+            path: path.to_owned(),
+            panic,
+            reason: trap.display_reason().to_string(),
+            frames: trap.trace().iter().map(Frame::new).collect(),
+        }
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    /// Writes the report to `pid-<pid>.crash` in `dir`, returning the file's path.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("pid-{}.crash", self.pid));
+        std::fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl Frame {
+    fn new(frame: &FrameInfo) -> Self {
+        Self {
+            func_index: frame.func_index(),
+            module_offset: frame.module_offset(),
+            func_name: frame.func_name().map(demangle),
+            symbols: frame
+                .symbols()
+                .iter()
+                .map(|symbol| Symbol {
+                    name: symbol.name().map(demangle),
+                    file: symbol.file().map(str::to_owned),
+                    line: symbol.line(),
+                    column: symbol.column(),
+                })
+                .collect(),
+        }
+    }
+
+    fn fmt_name(&self, f: &mut Formatter, name: Option<&str>) -> fmt::Result {
+        match name.or(self.func_name.as_deref()) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "<wasm function {}>", self.func_index),
+        }
+    }
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "pid {} crashed running {}", self.pid, self.path)?;
+        if let Some(panic) = &self.panic {
+            if panic.file.is_empty() {
+                writeln!(f, "panicked: {}", panic.message)?;
+            } else {
+                writeln!(
+                    f,
+                    "panicked at {}:{}:{}: {}",
+                    panic.file, panic.line, panic.column, panic.message,
+                )?;
+            }
+        }
+        writeln!(f, "trap: {}", self.reason)?;
+        writeln!(f, "backtrace:")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "  {:>3}: {:#8x} - ", i, frame.module_offset)?;
+            if frame.symbols.is_empty() {
+                frame.fmt_name(f, None)?;
+                writeln!(f)?;
+                continue;
+            }
+            for (j, symbol) in frame.symbols.iter().enumerate() {
+                if j > 0 {
+                    write!(f, "{:17}", "")?;
+                }
+                frame.fmt_name(f, symbol.name.as_deref())?;
+                writeln!(f)?;
+                if let Some(file) = &symbol.file {
+                    write!(f, "{:19}at {}", "", file)?;
+                    if let Some(line) = symbol.line {
+                        write!(f, ":{}", line)?;
+                        if let Some(column) = symbol.column {
+                            write!(f, ":{}", column)?;
+                        }
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CrashReport {}
+
+/// Demangles a Rust symbol, dropping its hash. Other names pass through unchanged.
+fn demangle(name: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CrashReport, Frame, GuestPanic, Symbol};
+
+    #[test]
+    fn report_includes_panic_and_symbolicated_frames() {
+        let report = CrashReport {
+            pid: 3,
+            path: "guest.wasm".to_owned(),
+            panic: Some(GuestPanic {
+                message: "oh no".to_owned(),
+                file: "src/lib.rs".to_owned(),
+                line: 12,
+                column: 5,
+            }),
+            reason: "aborted".to_owned(),
+            frames: vec![
+                Frame {
+                    func_index: 7,
+                    module_offset: 0x1234,
+                    func_name: None,
+                    symbols: Vec::new(),
+                },
+                Frame {
+                    func_index: 8,
+                    module_offset: 0x5678,
+                    func_name: Some("guest::main".to_owned()),
+                    symbols: vec![Symbol {
+                        name: Some(super::demangle("_ZN5guest4main17h0123456789abcdefE")),
+                        file: Some("src/lib.rs".to_owned()),
+                        line: Some(12),
+                        column: None,
+                    }],
+                },
+            ],
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "pid 3 crashed running guest.wasm\n\
+             panicked at src/lib.rs:12:5: oh no\n\
+             trap: aborted\n\
+             backtrace:\n\
+             \x20   0:   0x1234 - <wasm function 7>\n\
+             \x20   1:   0x5678 - guest::main\n\
+             \x20                  at src/lib.rs:12\n",
+        );
+    }
+}
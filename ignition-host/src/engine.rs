@@ -0,0 +1,288 @@
+//! Configures the Wasm engine that compiles and runs every module.
+//!
+//! The defaults suit development: Cranelift optimizing for speed, instances allocated on demand.
+//! A deployment can trade compile time for throughput, or pre-reserve instances, through the
+//! `[engine]` table of the host manifest or the command line, which takes precedence.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, InstanceLimits, ModuleLimits, OptLevel,
+    PoolingAllocationStrategy, Strategy, WasmBacktraceDetails,
+};
+
+/// The most pages a 32-bit linear memory can have.
+const MAX_MEMORY_PAGES: u64 = 0x10000;
+
+/// Every option is optional, so that options from the command line can override just the ones
+/// they set. Features left unset keep wasmtime's defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EngineOptions {
+    pub strategy: Option<CompileStrategy>,
+    pub opt_level: Option<OptimizationLevel>,
+    pub simd: Option<bool>,
+    pub threads: Option<bool>,
+    pub bulk_memory: Option<bool>,
+    pub reference_types: Option<bool>,
+    /// Emits native DWARF for compiled code, so that debuggers and profilers can see into guests.
+    pub debug_info: Option<bool>,
+    /// Reserves memory for a fixed number of instances up front, which makes starting a process
+    /// much cheaper.
+    pub pooling: Option<bool>,
+    /// How many instances the pool holds (default: 1000).
+    pub pooling_instances: Option<u32>,
+    /// The most Wasm pages each pooled instance's memory can grow to (default: 160, i.e. 10 MiB).
+    pub pooling_memory_pages: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompileStrategy {
+    Auto,
+    Cranelift,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptimizationLevel {
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+impl CompileStrategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::Auto),
+            "cranelift" => Some(Self::Cranelift),
+            _ => None,
+        }
+    }
+}
+
+impl OptimizationLevel {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Self::None),
+            "speed" => Some(Self::Speed),
+            "speed-and-size" => Some(Self::SpeedAndSize),
+            _ => None,
+        }
+    }
+}
+
+impl EngineOptions {
+    /// Returns these options with every option that `overrides` sets replaced.
+    pub fn merge(self, overrides: EngineOptions) -> EngineOptions {
+        EngineOptions {
+            strategy: overrides.strategy.or(self.strategy),
+            opt_level: overrides.opt_level.or(self.opt_level),
+            simd: overrides.simd.or(self.simd),
+            threads: overrides.threads.or(self.threads),
+            bulk_memory: overrides.bulk_memory.or(self.bulk_memory),
+            reference_types: overrides.reference_types.or(self.reference_types),
+            debug_info: overrides.debug_info.or(self.debug_info),
+            pooling: overrides.pooling.or(self.pooling),
+            pooling_instances: overrides.pooling_instances.or(self.pooling_instances),
+            pooling_memory_pages: overrides.pooling_memory_pages.or(self.pooling_memory_pages),
+        }
+    }
+
+    /// Sets a Wasm feature by the name used on the command line and in the manifest.
+    pub fn set_feature(&mut self, name: &str, enable: bool) -> Result<()> {
+        let feature = match name {
+            "simd" => &mut self.simd,
+            "threads" => &mut self.threads,
+            "bulk-memory" => &mut self.bulk_memory,
+            "reference-types" => &mut self.reference_types,
+            _ => bail!("unknown Wasm feature {:?}", name),
+        };
+        if *feature == Some(!enable) {
+            bail!("the {} feature is both enabled and disabled", name);
+        }
+        *feature = Some(enable);
+        Ok(())
+    }
+
+    /// Rejects combinations that wasmtime would quietly adjust or fail on later, with errors that
+    /// name the options involved.
+    pub fn validate(&self) -> Result<()> {
+        // Wasmtime turns bulk memory back on for these rather than refusing.
+        if self.bulk_memory == Some(false) {
+            if self.threads == Some(true) {
+                bail!("the threads feature requires bulk-memory, which is disabled");
+            }
+            if self.reference_types == Some(true) {
+                bail!("the reference-types feature requires bulk-memory, which is disabled");
+            }
+        }
+        if self.pooling != Some(true) {
+            if self.pooling_instances.is_some() {
+                bail!("pooling-instances is set, but pooling is disabled");
+            }
+            if self.pooling_memory_pages.is_some() {
+                bail!("pooling-memory-pages is set, but pooling is disabled");
+            }
+        }
+        if self.pooling_instances == Some(0) {
+            bail!("pooling-instances must be at least 1");
+        }
+        match self.pooling_memory_pages {
+            Some(pages) if pages == 0 || pages > MAX_MEMORY_PAGES => bail!(
+                "pooling-memory-pages must be between 1 and {}, got {}",
+                MAX_MEMORY_PAGES,
+                pages
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Result<Engine> {
+        self.validate()?;
+        let mut config = Config::new();
+        // Symbolicating backtraces with DWARF costs nothing until a trap actually happens.
+        // Interruption lets a guest that's stuck in a wake be killed.
+        config
+            .wasm_backtrace_details(WasmBacktraceDetails::Enable)
+            .interruptable(true);
+        if let Some(strategy) = self.strategy {
+            config.strategy(match strategy {
+                CompileStrategy::Auto => Strategy::Auto,
+                CompileStrategy::Cranelift => Strategy::Cranelift,
+            })?;
+        }
+        // Setting the strategy resets the optimization level, so this has to come after it.
+        if let Some(opt_level) = self.opt_level {
+            config.cranelift_opt_level(match opt_level {
+                OptimizationLevel::None => OptLevel::None,
+                OptimizationLevel::Speed => OptLevel::Speed,
+                OptimizationLevel::SpeedAndSize => OptLevel::SpeedAndSize,
+            });
+        }
+        // Bulk memory goes first, since the features that depend on it turn it on.
+        if let Some(enable) = self.bulk_memory {
+            config.wasm_bulk_memory(enable);
+        }
+        if let Some(enable) = self.simd {
+            config.wasm_simd(enable);
+        }
+        if let Some(enable) = self.threads {
+            config.wasm_threads(enable);
+        }
+        if let Some(enable) = self.reference_types {
+            config.wasm_reference_types(enable);
+        }
+        if let Some(enable) = self.debug_info {
+            config.debug_info(enable);
+        }
+        if self.pooling == Some(true) {
+            let mut module_limits = ModuleLimits::default();
+            if let Some(pages) = self.pooling_memory_pages {
+                module_limits.memory_pages = pages;
+            }
+            let mut instance_limits = InstanceLimits::default();
+            if let Some(count) = self.pooling_instances {
+                instance_limits.count = count;
+            }
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling {
+                strategy: PoolingAllocationStrategy::NextAvailable,
+                module_limits,
+                instance_limits,
+            });
+        }
+        Engine::new(&config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::Module;
+
+    use super::{CompileStrategy, EngineOptions, OptimizationLevel};
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        let error = |options: EngineOptions| options.build().err().unwrap().to_string();
+
+        let mut options = EngineOptions::default();
+        options.set_feature("bulk-memory", false).unwrap();
+        options.set_feature("threads", true).unwrap();
+        assert_eq!(
+            error(options),
+            "the threads feature requires bulk-memory, which is disabled"
+        );
+
+        let options = EngineOptions {
+            pooling_instances: Some(10),
+            ..EngineOptions::default()
+        };
+        assert_eq!(
+            error(options),
+            "pooling-instances is set, but pooling is disabled"
+        );
+
+        let options = EngineOptions {
+            pooling: Some(true),
+            pooling_memory_pages: Some(0x10001),
+            ..EngineOptions::default()
+        };
+        assert_eq!(
+            error(options),
+            "pooling-memory-pages must be between 1 and 65536, got 65537"
+        );
+
+        let mut options = EngineOptions::default();
+        options.set_feature("simd", true).unwrap();
+        assert_eq!(
+            options.set_feature("simd", false).unwrap_err().to_string(),
+            "the simd feature is both enabled and disabled"
+        );
+    }
+
+    #[test]
+    fn builds_engines_that_honor_their_options() {
+        // A SIMD instruction only compiles once the feature is on.
+        let module = r#"(module (func (result v128) (v128.const i64x2 0 0)))"#;
+        let options = EngineOptions {
+            simd: Some(false),
+            ..EngineOptions::default()
+        };
+        assert!(Module::new(&options.build().unwrap(), module).is_err());
+
+        let options = EngineOptions {
+            strategy: Some(CompileStrategy::Cranelift),
+            opt_level: Some(OptimizationLevel::None),
+            simd: Some(true),
+            pooling: Some(true),
+            pooling_instances: Some(2),
+            pooling_memory_pages: Some(1),
+            ..EngineOptions::default()
+        };
+        assert!(Module::new(&options.build().unwrap(), module).is_ok());
+    }
+
+    #[test]
+    fn merges_overrides() {
+        let manifest = EngineOptions {
+            opt_level: Some(OptimizationLevel::SpeedAndSize),
+            simd: Some(true),
+            ..EngineOptions::default()
+        };
+        let command_line = EngineOptions {
+            simd: Some(false),
+            pooling: Some(true),
+            ..EngineOptions::default()
+        };
+        assert_eq!(
+            manifest.merge(command_line),
+            EngineOptions {
+                opt_level: Some(OptimizationLevel::SpeedAndSize),
+                simd: Some(false),
+                pooling: Some(true),
+                ..EngineOptions::default()
+            }
+        );
+    }
+}
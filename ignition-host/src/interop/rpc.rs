@@ -1,6 +1,6 @@
 use std::iter::repeat_with;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ignition_abi::wasm;
 use wasmtime::{AsContext, Memory, StoreContext, Trap};
 
 use crate::interop::{FromWasm, ToWasm, Wasm};
@@ -12,7 +12,7 @@ pub struct RpcServerParams {
 }
 
 impl Wasm for RpcServerParams {
-    const SIZE: u32 = 16;
+    const SIZE: u32 = wasm::RpcServerParams::SIZE;
 }
 
 impl FromWasm for RpcServerParams {
@@ -21,22 +21,20 @@ impl FromWasm for RpcServerParams {
         memory: Memory,
         data: &mut &[u8],
     ) -> Result<Self, Trap> {
-        let service_name_ptr = data.read_u32::<LittleEndian>().unwrap();
-        let service_name_len = data.read_u32::<LittleEndian>().unwrap();
-        let methods_ptr = data.read_u32::<LittleEndian>().unwrap();
-        let methods_len = data.read_u32::<LittleEndian>().unwrap();
+        let params = wasm::RpcServerParams::read(data);
 
         let service_name = get_str(
             context.as_context(),
             memory,
-            service_name_ptr,
-            service_name_len,
+            params.service_name_ptr,
+            params.service_name_len,
         )?
         .to_owned();
+        let methods_len = params.methods_len;
         let mut methods_data = get_slice(
             context.as_context(),
             memory,
-            methods_ptr,
+            params.methods_ptr,
             RpcServerMethodParams::SIZE * methods_len,
         )?;
         let methods = repeat_with(|| {
@@ -57,7 +55,7 @@ pub struct RpcServerMethodParams {
 }
 
 impl Wasm for RpcServerMethodParams {
-    const SIZE: u32 = 8;
+    const SIZE: u32 = wasm::RpcServerMethod::SIZE;
 }
 
 impl FromWasm for RpcServerMethodParams {
@@ -66,10 +64,15 @@ impl FromWasm for RpcServerMethodParams {
         memory: Memory,
         data: &mut &[u8],
     ) -> Result<Self, Trap> {
-        let method_name_ptr = data.read_u32::<LittleEndian>().unwrap();
-        let method_name_len = data.read_u32::<LittleEndian>().unwrap();
+        let method = wasm::RpcServerMethod::read(data);
 
-        let method_name = get_str(context, memory, method_name_ptr, method_name_len)?.to_owned();
+        let method_name = get_str(
+            context,
+            memory,
+            method.method_name_ptr,
+            method.method_name_len,
+        )?
+        .to_owned();
 
         Ok(Self { method_name })
     }
@@ -82,7 +85,7 @@ pub struct RpcMetadata {
 }
 
 impl Wasm for RpcMetadata {
-    const SIZE: u32 = 12;
+    const SIZE: u32 = wasm::RpcMethodMetadata::SIZE;
 }
 
 impl FromWasm for RpcMetadata {
@@ -91,23 +94,24 @@ impl FromWasm for RpcMetadata {
         _memory: Memory,
         data: &mut &[u8],
     ) -> Result<Self, Trap> {
-        let index = data.read_u32::<LittleEndian>().unwrap();
-        let request_io = data.read_u32::<LittleEndian>().unwrap();
-        let response_io = data.read_u32::<LittleEndian>().unwrap();
+        let metadata = wasm::RpcMethodMetadata::read(data);
 
         Ok(Self {
-            method_index: index,
-            request_io,
-            response_io,
+            method_index: metadata.index,
+            request_io: metadata.request_io,
+            response_io: metadata.response_io,
         })
     }
 }
 
 impl ToWasm for RpcMetadata {
     fn to_wasm(&self, data: &mut &mut [u8]) -> Result<(), Trap> {
-        data.write_u32::<LittleEndian>(self.method_index).unwrap();
-        data.write_u32::<LittleEndian>(self.request_io).unwrap();
-        data.write_u32::<LittleEndian>(self.response_io).unwrap();
+        wasm::RpcMethodMetadata {
+            index: self.method_index,
+            request_io: self.request_io,
+            response_io: self.response_io,
+        }
+        .write(data);
         Ok(())
     }
 }
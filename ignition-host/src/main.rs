@@ -1,20 +1,53 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use std::env::args;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
+use clap::{clap_app, ArgMatches};
 use futures::stream::FuturesUnordered;
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
+use ignition_blob_proto::blob_pb::blob_service_client::BlobServiceClient;
+use log::{error, info, warn, LevelFilter};
+use simple_logger::SimpleLogger;
+use testable_file_system::{real_file_system, RealFileSystem};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::spawn;
-use wasmtime::{Engine, Linker, Module, Store};
+use tonic::transport::{Channel, Endpoint};
 
+use crate::crash::CrashReport;
+use crate::engine::{CompileStrategy, EngineOptions, OptimizationLevel};
+use crate::manifest::Manifest;
+use crate::module_loader::ModuleLoader;
+use crate::namespace::{LocalNamespace, Namespace, RemoteNamespace};
 use crate::process::process::Process;
+use crate::service::{
+    register_native_service, BlobService, ConfigService, DiscoveryService, KvService, KvStore,
+    TopicService,
+};
+use crate::supervisor::Supervisor;
 
+mod abi;
 mod api;
+mod control;
+mod crash;
+mod engine;
 mod interop;
+mod manifest;
+mod module_loader;
+mod namespace;
 mod process;
+mod service;
+mod snapshot;
+mod supervisor;
 mod util;
+mod wake;
+
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+const WASM_FEATURES: [&str; 4] = ["simd", "threads", "bulk-memory", "reference-types"];
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(pub u32);
@@ -40,81 +73,344 @@ impl From<WakeParams> for (u32, u32) {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let engine = Engine::default();
-    let args = args().skip(1);
-    if args.len() == 0 {
-        return Err(anyhow!("expected one or more paths to Wasm modules"));
+    let matches = clap_app!(("ignition-host") =>
+        (about: "Runs Ignition Wasm modules")
+        (@arg mount_dir: --("mount-dir") [DIR] conflicts_with[mount_9p]
+            "Serves guest filesystem access from a local directory")
+        (@arg mount_9p: --("mount-9p") [ADDR]
+            "Serves guest filesystem access from a 9p2000 server at this TCP address")
+        (@arg crash_dir: --("crash-dir") [DIR]
+            "Also writes a crash report for each process that traps to this directory")
+        (@arg config: --config [ENTRY]... number_of_values(1)
+            "Sets a KEY=VALUE entry served to guests by ConfigService")
+        (@arg blob_server: --("blob-server") [URL]
+            "Serves BlobService to guests from the ignition-blob server at this URL")
+        (@arg kv_dir: --("kv-dir") [DIR]
+            "Serves KvService to guests, storing its data in this directory")
+        (@arg module_cache: --("module-cache") [DIR]
+            "Saves modules loaded by blob ID in this directory once compiled")
+        (@arg control_socket: --("control-socket") [PATH]
+            "Serves the control interface used by ignitionctl on a Unix socket at this path")
+        (@arg log_level: --("log-level") [LEVEL]
+            possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+            "Logs messages at this level and above (default: info)")
+        (@arg shutdown_grace: --("shutdown-grace") [SECONDS]
+            "How long processes have to exit after SIGINT or SIGTERM before they're killed \
+            (default: 30)")
+        (@arg manifest: --manifest [FILE]
+            "Reads deployment settings from a TOML manifest, which command-line flags override")
+        (@arg strategy: --strategy [STRATEGY] possible_values(&["auto", "cranelift"])
+            "Compiles modules with this code generator (default: auto)")
+        (@arg opt_level: --("opt-level") [LEVEL]
+            possible_values(&["none", "speed", "speed-and-size"])
+            "Optimizes compiled modules this much; none starts processes fastest (default: speed)")
+        (@arg enable: --enable [FEATURE]... number_of_values(1) possible_values(&WASM_FEATURES)
+            "Enables a Wasm feature")
+        (@arg disable: --disable [FEATURE]... number_of_values(1) possible_values(&WASM_FEATURES)
+            "Disables a Wasm feature")
+        (@arg debug_info: --("debug-info")
+            "Emits native debug info for compiled modules, for debuggers and profilers")
+        (@arg pooling: --pooling
+            "Reserves memory for instances up front, so that processes start faster")
+        (@arg pooling_instances: --("pooling-instances") [COUNT]
+            "How many instances --pooling reserves (default: 1000)")
+        (@arg pooling_memory_pages: --("pooling-memory-pages") [PAGES]
+            "How many 64 KiB pages each pooled instance's memory can have (default: 160)")
+        (@arg modules: <MODULE>...
+            "Wasm modules to run, each a path or a blob ID like blake3:<hex>, optionally as \
+            PRINCIPAL=MODULE to name who it acts as and keeps durable state under")
+    )
+    .get_matches();
+
+    // The host's own messages are filtered only by the max level, which the control socket can
+    // change later. Dependencies like Cranelift are much too chatty below warnings.
+    SimpleLogger::new()
+        .with_level(LevelFilter::Warn)
+        .with_module_level("ignition_host", LevelFilter::Trace)
+        .with_module_level("guest", LevelFilter::Trace)
+        .init()?;
+    log::set_max_level(
+        matches
+            .value_of("log_level")
+            .unwrap_or("info")
+            .parse()
+            .unwrap(),
+    );
+
+    let manifest = match matches.value_of("manifest") {
+        Some(path) => Manifest::load(Path::new(path))?,
+        None => Manifest::default(),
+    };
+
+    let namespace: Option<Arc<dyn Namespace>> = if let Some(dir) = matches.value_of("mount_dir") {
+        Some(Arc::new(LocalNamespace::new(PathBuf::from(dir))))
+    } else if let Some(addr) = matches.value_of("mount_9p") {
+        Some(Arc::new(RemoteNamespace::connect(addr).await?))
+    } else {
+        None
+    };
+
+    let crash_dir = matches.value_of("crash_dir").map(PathBuf::from);
+    let shutdown_grace = match matches.value_of("shutdown_grace") {
+        Some(seconds) => parse_shutdown_grace(seconds)?,
+        None => DEFAULT_SHUTDOWN_GRACE,
+    };
+
+    let mut config = BTreeMap::new();
+    for entry in matches.values_of("config").into_iter().flatten() {
+        match entry.split_once('=') {
+            Some((key, value)) => config.insert(key.to_owned(), value.to_owned()),
+            None => bail!("--config expects KEY=VALUE, got {:?}", entry),
+        };
+    }
+    register_native_service(
+        ConfigService::SERVICE_NAME,
+        Arc::new(ConfigService::new(config)),
+    );
+    register_native_service(
+        DiscoveryService::SERVICE_NAME,
+        Arc::new(DiscoveryService::new()),
+    );
+    register_native_service(TopicService::SERVICE_NAME, Arc::new(TopicService::new()));
+    let blob_client = match matches.value_of("blob_server") {
+        // Connects on first use, so the server needn't be up before the host starts.
+        Some(url) => Some(BlobServiceClient::new(
+            Endpoint::from_shared(url.to_owned())?.connect_lazy()?,
+        )),
+        None => None,
+    };
+    if let Some(client) = &blob_client {
+        register_native_service(
+            BlobService::<Channel>::SERVICE_NAME,
+            Arc::new(BlobService::new(client.clone())),
+        );
     }
+    if let Some(dir) = matches.value_of("kv_dir") {
+        let store = KvStore::new(real_file_system(), PathBuf::from(dir));
+        register_native_service(
+            KvService::<RealFileSystem>::SERVICE_NAME,
+            Arc::new(KvService::new(store)),
+        );
+    }
+
+    let engine = engine_options(&manifest, &matches)?
+        .build()
+        .context("Invalid engine configuration")?;
+    let module_cache = matches
+        .value_of("module_cache")
+        .map(|dir| (real_file_system(), PathBuf::from(dir)));
+    let loader = ModuleLoader::new(engine, blob_client, module_cache);
+    let (supervisor, mut exits) = Supervisor::new(loader, namespace)?;
+
+    if let Some(path) = matches.value_of("control_socket") {
+        control::serve(Path::new(path), Arc::clone(&supervisor))?;
+    }
+
+    let mut failures = 0;
+    let mut report_failure = |e: anyhow::Error| {
+        // One process failing doesn't stop the others.
+        failures += 1;
+        match e.downcast::<CrashReport>() {
+            Ok(report) => print_crash_report(&report, crash_dir.as_deref()),
+            Err(e) => error!("{:#}", e),
+        }
+    };
 
-    let mut modules: FuturesUnordered<_> = args
-        .enumerate()
-        .map(|(pid, path)| {
-            let engine = engine.clone();
-            spawn(async move { start_module(&engine, &path, pid).await })
+    // Replacing a module's file and sending SIGHUP upgrades the processes running it.
+    let mut hangups = signal(SignalKind::hangup())?;
+    let reloader = Arc::clone(&supervisor);
+    spawn(async move {
+        while hangups.recv().await.is_some() {
+            reloader.reload().await;
+        }
+    });
+
+    // The first SIGINT or SIGTERM lets processes finish what they're doing before the host exits.
+    // A second one exits right away.
+    let mut interrupts = signal(SignalKind::interrupt())?;
+    let mut terminations = signal(SignalKind::terminate())?;
+    let stopper = Arc::clone(&supervisor);
+    spawn(async move {
+        tokio::select! {
+            _ = interrupts.recv() => {}
+            _ = terminations.recv() => {}
+        }
+        info!("Shutting down");
+        tokio::select! {
+            () = stopper.shutdown(shutdown_grace) => return,
+            _ = interrupts.recv() => {}
+            _ = terminations.recv() => {}
+        }
+        warn!("Exiting without waiting for processes");
+        std::process::exit(1);
+    });
+
+    let start_time = Instant::now();
+    let mut starts: FuturesUnordered<_> = matches
+        .values_of("modules")
+        .unwrap()
+        .map(parse_module_arg)
+        .map(|(principal, module_ref)| {
+            // Pids follow the order modules are given in, whichever loads first.
+            let pid = supervisor.new_pid();
+            let supervisor = Arc::clone(&supervisor);
+            spawn(async move {
+                supervisor
+                    .start_as(pid, &module_ref, principal.as_deref())
+                    .await
+            })
         })
         .collect();
-    while let Some(result) = modules.next().await {
-        result??;
+    let mut spawn_times = Vec::new();
+    while let Some(result) = starts.next().await {
+        match result? {
+            Ok(spawn_time) => spawn_times.push(spawn_time),
+            Err(e) => report_failure(e),
+        }
+    }
+    // Starting many copies of a module, like ignition-spawn-bench, measures spawn throughput.
+    if spawn_times.len() > 1 {
+        log_spawn_times(&mut spawn_times, start_time.elapsed());
     }
 
+    loop {
+        let result = if supervisor.is_empty() {
+            // Every process has exited, though not every exit may have been received yet.
+            match exits.recv().now_or_never() {
+                Some(Some(result)) => result,
+                _ => break,
+            }
+        } else {
+            exits.recv().await.unwrap()
+        };
+        if let Err(e) = result {
+            report_failure(e);
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} process(es) failed", failures);
+    }
     Ok(())
 }
 
-async fn start_module(engine: &Engine, path: &str, pid: usize) -> Result<()> {
-    println!("pid {}: Loading {}", pid, path);
-
-    let module = Module::from_file(engine, path)?;
-
-    let mut linker = Linker::new(engine);
-    linker.func_wrap("ignition", "shutdown", api::core::shutdown)?;
-    linker.func_wrap("ignition", "abort", api::core::abort)?;
-    linker.func_wrap("ignition", "log", api::core::log)?;
-    linker.func_wrap("ignition", "impulse", api::core::impulse)?;
-    linker.func_wrap("ignition", "sleep", api::time::sleep)?;
-    linker.func_wrap("ignition", "monotonic_time", api::time::monotonic_time)?;
-    linker.func_wrap("ignition", "io_read", api::io::io_read)?;
-    linker.func_wrap("ignition", "io_write", api::io::io_write)?;
-    linker.func_wrap("ignition", "io_close", api::io::io_close)?;
-    linker.func_wrap(
-        "ignition",
-        "rpc_client_create",
-        api::rpc_client::rpc_client_create,
-    )?;
-    linker.func_wrap(
-        "ignition",
-        "rpc_client_wait_healthy",
-        api::rpc_client::rpc_client_wait_healthy,
-    )?;
-    linker.func_wrap(
-        "ignition",
-        "rpc_client_request",
-        api::rpc_client::rpc_client_request,
-    )?;
-    linker.func_wrap(
-        "ignition",
-        "rpc_server_create",
-        api::rpc_server::rpc_server_create,
-    )?;
-    linker.func_wrap(
-        "ignition",
-        "rpc_server_get_request",
-        api::rpc_server::rpc_server_get_request,
-    )?;
-
-    let (state, mut wake_queue_receiver) = Process::new(pid);
-    state.wake_queue_sender().send(WakeParams::INIT).unwrap();
-    let mut store = Store::new(engine, Arc::new(state));
-
-    let instance = linker.instantiate(&mut store, &module)?;
-    let wake = instance.get_typed_func::<(u32, u32), (), _>(&mut store, "wake")?;
-
-    // Dispatch wake events.
-    while !store.data().is_shutdown() {
-        let params = wake_queue_receiver.recv().await.unwrap();
-        wake.call(&mut store, params.into())?;
-    }
-
-    println!("pid {}: Quit", pid);
+/// Combines the manifest's engine options with those on the command line, which win.
+fn engine_options(manifest: &Manifest, matches: &ArgMatches) -> Result<EngineOptions> {
+    let mut options = EngineOptions {
+        strategy: matches
+            .value_of("strategy")
+            .and_then(CompileStrategy::parse),
+        opt_level: matches
+            .value_of("opt_level")
+            .and_then(OptimizationLevel::parse),
+        ..EngineOptions::default()
+    };
+    for feature in matches.values_of("enable").into_iter().flatten() {
+        options.set_feature(feature, true)?;
+    }
+    for feature in matches.values_of("disable").into_iter().flatten() {
+        options.set_feature(feature, false)?;
+    }
+    if matches.is_present("debug_info") {
+        options.debug_info = Some(true);
+    }
+    if matches.is_present("pooling") {
+        options.pooling = Some(true);
+    }
+    if let Some(count) = matches.value_of("pooling_instances") {
+        match count.parse() {
+            Ok(count) => options.pooling_instances = Some(count),
+            Err(_) => bail!("--pooling-instances expects a count, got {:?}", count),
+        }
+    }
+    if let Some(pages) = matches.value_of("pooling_memory_pages") {
+        match pages.parse() {
+            Ok(pages) => options.pooling_memory_pages = Some(pages),
+            Err(_) => bail!(
+                "--pooling-memory-pages expects a page count, got {:?}",
+                pages
+            ),
+        }
+    }
+    Ok(manifest.engine.clone().merge(options))
+}
 
-    Ok(())
+fn parse_shutdown_grace(seconds: &str) -> Result<Duration> {
+    match seconds.parse().map(Duration::try_from_secs_f64) {
+        Ok(Ok(grace)) => Ok(grace),
+        _ => bail!(
+            "--shutdown-grace expects a non-negative number of seconds, got {:?}",
+            seconds
+        ),
+    }
+}
+
+/// Splits a module argument of the form `[PRINCIPAL=]MODULE`. A module path containing `=` needs
+/// a principal in front of it.
+fn parse_module_arg(arg: &str) -> (Option<String>, String) {
+    match arg.split_once('=') {
+        Some((principal, module_ref)) => (Some(principal.to_owned()), module_ref.to_owned()),
+        None => (None, arg.to_owned()),
+    }
+}
+
+fn log_spawn_times(spawn_times: &mut [Duration], elapsed: Duration) {
+    spawn_times.sort();
+    let percentile = |p: usize| spawn_times[(spawn_times.len() - 1) * p / 100];
+    info!(
+        "Started {} processes in {:.3} s ({:.0} per second); spawn time p50 {:?}, p99 {:?}, max {:?}",
+        spawn_times.len(),
+        elapsed.as_secs_f64(),
+        spawn_times.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+fn print_crash_report(report: &CrashReport, crash_dir: Option<&Path>) {
+    print!("{}", report);
+    if let Some(dir) = crash_dir {
+        match report.save(dir) {
+            Ok(path) => info!(
+                "pid {}: Crash report saved to {}",
+                report.pid(),
+                path.display()
+            ),
+            Err(e) => error!("pid {}: Failed to save crash report: {}", report.pid(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_grace_is_a_finite_number_of_seconds() {
+        assert_eq!(
+            parse_shutdown_grace("1.5").unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(parse_shutdown_grace("0").unwrap(), Duration::ZERO);
+        for invalid in &["-1", "NaN", "inf", "1e30", "soon"] {
+            assert!(parse_shutdown_grace(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn module_args_name_an_optional_principal() {
+        assert_eq!(
+            parse_module_arg("echo.wasm"),
+            (None, "echo.wasm".to_owned())
+        );
+        assert_eq!(
+            parse_module_arg("echo=blake3:00ff"),
+            (Some("echo".to_owned()), "blake3:00ff".to_owned())
+        );
+        assert_eq!(
+            parse_module_arg("echo=a=b.wasm"),
+            (Some("echo".to_owned()), "a=b.wasm".to_owned())
+        );
+    }
 }
@@ -0,0 +1,70 @@
+//! The host manifest: a TOML file of settings for a deployment, given with `--manifest`.
+//!
+//! ```toml
+//! [engine]
+//! opt-level = "speed-and-size"
+//! simd = true
+//! pooling = true
+//! pooling-instances = 200
+//! ```
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::engine::EngineOptions;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    pub engine: EngineOptions,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid manifest {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Manifest;
+    use crate::engine::{EngineOptions, OptimizationLevel};
+
+    #[test]
+    fn parses_engine_options() {
+        let manifest = Manifest::parse(
+            r#"
+            [engine]
+            opt-level = "none"
+            bulk-memory = false
+            pooling-instances = 8
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.engine,
+            EngineOptions {
+                opt_level: Some(OptimizationLevel::None),
+                bulk_memory: Some(false),
+                pooling_instances: Some(8),
+                ..EngineOptions::default()
+            }
+        );
+
+        assert_eq!(
+            Manifest::parse("").unwrap().engine,
+            EngineOptions::default()
+        );
+        assert!(Manifest::parse("[engine]\nstrategy = \"lightbeam\"").is_err());
+        assert!(Manifest::parse("[engine]\nsimd = \"yes\"").is_err());
+        assert!(Manifest::parse("[engine]\nsmid = true").is_err());
+    }
+}
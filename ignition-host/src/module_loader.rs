@@ -0,0 +1,306 @@
+//! Finds and compiles the Wasm modules that processes run.
+//!
+//! A module reference is either a local path or a blob ID like `blake3:<hex>`, which names an
+//! immutable module version in an `ignition-blob` store. Modules loaded by ID are checked against
+//! the hash, and their compiled form is cached by the same ID, in memory and optionally on disk.
+//! Each module comes with the ABI requirements it declares, which are cached alongside it.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ignition_blob_proto::blob_pb::blob_service_client::BlobServiceClient;
+use ignition_blob_proto::blob_pb::{BlobId, GetRequest};
+use log::warn;
+use testable_file_system::FileSystem;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex as AsyncMutex;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, HttpBody, StdError};
+use wasmtime::{Engine, Module};
+
+use crate::abi::AbiRequirements;
+use crate::service::{format_blob_id, parse_blob_id};
+use crate::util::temporary_path;
+
+/// Starts every file in the cache, followed by the length of the module's encoded requirements,
+/// the requirements, and the serialized module.
+const CACHE_MAGIC: &[u8] = b"ignition-module\0";
+
+#[derive(Clone)]
+pub struct LoadedModule {
+    pub module: Module,
+    pub abi: AbiRequirements,
+    /// The BLAKE3 hash of the module's bytes, which for a blob is its ID.
+    pub hash: [u8; 32],
+}
+
+pub struct ModuleLoader<T, F> {
+    engine: Engine,
+    blob_client: Option<BlobServiceClient<T>>,
+    /// Where compiled modules are saved, so that a restarted host needn't compile them again.
+    ///
+    /// Loading from here skips every check that compilation would make, so it must be as trusted as
+    /// the host binary itself.
+    cache: Option<(F, PathBuf)>,
+    // Ensures each module is fetched and compiled only once, even when several processes start it
+    // at the same time.
+    compiled: Mutex<HashMap<String, Arc<AsyncMutex<Option<LoadedModule>>>>>,
+}
+
+impl<T, F> ModuleLoader<T, F>
+where
+    T: GrpcService<BoxBody> + Clone + Send + Sync + 'static,
+    T::Future: Send,
+    T::ResponseBody: Body + HttpBody + Send + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as HttpBody>::Error: Into<StdError> + Send,
+    F: FileSystem,
+{
+    /// `blob_client` serves modules referred to by ID. Without it, only paths and modules already in
+    /// the cache can be loaded.
+    pub fn new(
+        engine: Engine,
+        blob_client: Option<BlobServiceClient<T>>,
+        cache: Option<(F, PathBuf)>,
+    ) -> Self {
+        Self {
+            engine,
+            blob_client,
+            cache,
+            compiled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub async fn load(&self, module_ref: &str) -> Result<LoadedModule> {
+        match parse_blob_id(module_ref) {
+            Some(id) => self.load_blob(&id).await,
+            None if module_ref.starts_with("blake3:") => bail!("invalid blob ID"),
+            None => {
+                let bytes = tokio::fs::read(module_ref)
+                    .await
+                    .with_context(|| format!("Failed to read {}", module_ref))?;
+                self.compile(&bytes)
+            }
+        }
+    }
+
+    fn compile(&self, bytes: &[u8]) -> Result<LoadedModule> {
+        Ok(LoadedModule {
+            abi: AbiRequirements::read(bytes)?,
+            module: Module::new(&self.engine, bytes)?,
+            hash: *blake3::hash(bytes).as_bytes(),
+        })
+    }
+
+    async fn load_blob(&self, id: &BlobId) -> Result<LoadedModule> {
+        let key = format_blob_id(id);
+        let slot = Arc::clone(
+            self.compiled
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_default(),
+        );
+        let mut slot = slot.lock().await;
+        if let Some(module) = &*slot {
+            return Ok(module.clone());
+        }
+
+        let hash = id
+            .hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("invalid blob ID"))?;
+        let module = match self.load_cached(&key, hash).await {
+            Some(module) => module,
+            None => {
+                let bytes = self.fetch(id).await?;
+                let module = self.compile(&bytes)?;
+                if let Err(e) = self.save_cached(&key, &module).await {
+                    warn!("Failed to cache compiled module {}: {:#}", key, e);
+                }
+                module
+            }
+        };
+        *slot = Some(module.clone());
+        Ok(module)
+    }
+
+    async fn fetch(&self, id: &BlobId) -> Result<Vec<u8>> {
+        let mut client = self
+            .blob_client
+            .clone()
+            .ok_or_else(|| anyhow!("no blob server to fetch it from"))?;
+        let mut stream = client
+            .get(GetRequest {
+                id: Some(id.clone()),
+                max_chunk_size: 0,
+            })
+            .await?
+            .into_inner();
+        let mut bytes = Vec::new();
+        while let Some(message) = stream.message().await? {
+            bytes.extend_from_slice(&message.data);
+        }
+
+        if blake3::hash(&bytes).as_bytes()[..] != id.hash[..] {
+            bail!("content doesn't match its hash");
+        }
+        Ok(bytes)
+    }
+
+    fn cache_path(&self, key: &str) -> Option<(&F, PathBuf)> {
+        let (file_system, dir) = self.cache.as_ref()?;
+        // IDs contain a colon, which not every filesystem allows.
+        Some((file_system, dir.join(key.replace(':', "-"))))
+    }
+
+    /// Returns None if the module isn't cached or can't be used, e.g. because it was compiled by a
+    /// different version of the host.
+    async fn load_cached(&self, key: &str, hash: [u8; 32]) -> Option<LoadedModule> {
+        let (file_system, path) = self.cache_path(key)?;
+        let result = async {
+            let mut file = file_system.open(&path).await?;
+            file.seek(SeekFrom::Start(0)).await?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).await?;
+            Ok::<_, io::Error>(bytes)
+        }
+        .await;
+        let bytes = match result {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read cached module {}: {}", key, e);
+                return None;
+            }
+        };
+        match self.deserialize(&bytes, hash) {
+            Ok(module) => Some(module),
+            Err(e) => {
+                warn!("Ignoring cached module {}: {:#}", key, e);
+                None
+            }
+        }
+    }
+
+    fn deserialize(&self, bytes: &[u8], hash: [u8; 32]) -> Result<LoadedModule> {
+        let bytes = match bytes.strip_prefix(CACHE_MAGIC) {
+            Some(bytes) if bytes.len() >= 4 => bytes,
+            // Hosts from before requirements were cached saved just the module.
+            _ => bail!("not a cached module"),
+        };
+        let (len, bytes) = bytes.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if len > bytes.len() {
+            bail!("truncated");
+        }
+        let (abi, bytes) = bytes.split_at(len);
+        Ok(LoadedModule {
+            abi: AbiRequirements::decode(abi)?,
+            // SAFETY: The cache only holds modules this host serialized; see `cache`.
+            module: unsafe { Module::deserialize(&self.engine, bytes)? },
+            hash,
+        })
+    }
+
+    async fn save_cached(&self, key: &str, module: &LoadedModule) -> Result<()> {
+        let (file_system, path) = match self.cache_path(key) {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let abi = module.abi.encode();
+        let mut bytes = CACHE_MAGIC.to_vec();
+        bytes.extend_from_slice(&(abi.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&abi);
+        bytes.extend_from_slice(&module.module.serialize()?);
+        let dir = path.parent().unwrap();
+        file_system.create_dir_all(dir).await?;
+        // Write to a temporary file first, so that a partly written module is never loaded.
+        let temporary_path = temporary_path(&path);
+        let mut file = file_system
+            .create(&temporary_path)
+            .await
+            .with_context(|| format!("Failed to create {}", temporary_path.display()))?;
+        file.write_all(&bytes).await?;
+        file.flush().await?;
+        drop(file);
+        file_system.rename(&temporary_path, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use ignition_blob::BlobServiceImpl;
+    use ignition_blob_proto::blob_pb::blob_service_client::BlobServiceClient;
+    use ignition_blob_proto::blob_pb::blob_service_server::BlobServiceServer;
+    use ignition_blob_proto::blob_pb::PutRequest;
+    use testable_file_system::InMemoryFileSystem;
+    use wasmtime::Engine;
+
+    use super::ModuleLoader;
+    use crate::service::format_blob_id;
+
+    const MODULE: &str = r#"(module (func (export "wake") (param i32 i32)))"#;
+
+    #[tokio::test]
+    async fn loads_modules_by_verified_id() {
+        let blob_file_system = InMemoryFileSystem::new();
+        let mut blob_client = BlobServiceClient::new(BlobServiceServer::new(BlobServiceImpl::new(
+            blob_file_system.clone(),
+        )));
+        let id = blob_client
+            .put(tokio_stream::iter(vec![PutRequest {
+                data: MODULE.as_bytes().to_vec(),
+            }]))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+        let id = format_blob_id(&id);
+
+        let cache_file_system = InMemoryFileSystem::new();
+        let cache = || Some((cache_file_system.clone(), PathBuf::from("cache")));
+        let engine = Engine::default();
+        let loader = ModuleLoader::new(engine.clone(), Some(blob_client.clone()), cache());
+        let module = loader.load(&id).await.unwrap();
+        assert!(module.module.get_export("wake").is_some());
+        assert_eq!(module.abi.version, 1);
+        assert_eq!(module.hash, *blake3::hash(MODULE.as_bytes()).as_bytes());
+
+        // A new loader finds the compiled module in the cache, without the blob store.
+        let loader = ModuleLoader::<BlobServiceServer<BlobServiceImpl<InMemoryFileSystem>>, _>::new(
+            engine.clone(),
+            None,
+            cache(),
+        );
+        let cached = loader.load(&id).await.unwrap();
+        assert_eq!(cached.abi, module.abi);
+        assert_eq!(cached.hash, module.hash);
+
+        // Content that doesn't match its ID is refused.
+        let forged = format!("blake3:{}", "ab".repeat(32));
+        blob_file_system.write(
+            format!("data/blake3/ab/{}", "ab".repeat(31)),
+            MODULE.as_bytes().to_vec(),
+        );
+        let loader = ModuleLoader::new(engine, Some(blob_client), cache());
+        match loader.load(&forged).await {
+            Ok(_) => panic!("loaded a module that doesn't match its ID"),
+            Err(e) => assert_eq!(e.to_string(), "content doesn't match its hash"),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use ignition_9p::{FileType, Qid, Stat, StatMode, UnixTriplet};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::namespace::{split_path, FsError, Namespace, NamespaceFile};
+
+/// User name set for all owner, group, and last-modifier fields.
+const USER_NAME: &str = "ignition";
+
+/// A namespace backed by a directory on the host's filesystem.
+pub struct LocalNamespace {
+    root: PathBuf,
+}
+
+impl LocalNamespace {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Maps a guest path to the file it refers to, following symlinks. Returns the file's real
+    /// path, which is inside the root, along with the guest path's components.
+    async fn resolve(&self, path: &str) -> Result<(PathBuf, Vec<String>), FsError> {
+        let names: Vec<String> = split_path(path)?.into_iter().map(str::to_owned).collect();
+        let root = tokio::fs::canonicalize(&self.root).await?;
+        let mut resolved = root.clone();
+        resolved.extend(&names);
+
+        // A symlink inside the root can point anywhere, so check where the path really leads. A
+        // file that doesn't exist yet is found through its directory.
+        let real = match tokio::fs::canonicalize(&resolved).await {
+            Ok(real) => real,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Creating a file through a dangling symlink would create the link's target.
+                if tokio::fs::symlink_metadata(&resolved).await.is_ok() {
+                    return Err(FsError::InvalidPath);
+                }
+                match (resolved.parent(), names.last()) {
+                    (Some(parent), Some(name)) => tokio::fs::canonicalize(parent).await?.join(name),
+                    _ => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !real.starts_with(&root) {
+            return Err(FsError::InvalidPath);
+        }
+        Ok((real, names))
+    }
+}
+
+#[async_trait]
+impl Namespace for LocalNamespace {
+    async fn stat(&self, path: &str) -> Result<Stat, FsError> {
+        let (resolved, names) = self.resolve(path).await?;
+        let metadata = tokio::fs::metadata(&resolved).await?;
+        Ok(stat_from_metadata(&names, &metadata))
+    }
+
+    async fn read_dir(&self, path: &str) -> Result<Vec<Stat>, FsError> {
+        let (resolved, mut names) = self.resolve(path).await?;
+        if !tokio::fs::metadata(&resolved).await?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = tokio::fs::read_dir(&resolved).await?;
+        let mut stats = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                // Names that can't be represented in 9p are skipped.
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata().await?;
+            names.push(name);
+            stats.push(stat_from_metadata(&names, &metadata));
+            names.pop();
+        }
+        Ok(stats)
+    }
+
+    async fn open(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError> {
+        let (resolved, _) = self.resolve(path).await?;
+        let file = File::open(&resolved).await?;
+        if file.metadata().await?.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(Box::new(LocalFile(file)))
+    }
+
+    async fn create(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError> {
+        let (resolved, names) = self.resolve(path).await?;
+        if names.is_empty() {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(Box::new(LocalFile(File::create(&resolved).await?)))
+    }
+}
+
+struct LocalFile(File);
+
+#[async_trait]
+impl NamespaceFile for LocalFile {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(self.0.read(buf).await?)
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(self.0.write(buf).await?)
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<(), FsError> {
+        // A successful call to flush() ensures the file will be closed immediately when it's
+        // dropped.
+        Ok(self.0.flush().await?)
+    }
+}
+
+fn stat_from_metadata(names: &[String], metadata: &Metadata) -> Stat {
+    // Paths within the namespace are stable identifiers for as long as the file exists, which is
+    // close enough to the qid contract for a local directory.
+    let mut hasher = DefaultHasher::new();
+    names.hash(&mut hasher);
+
+    let file_type = FileType::default().with_dir(metadata.is_dir());
+    let user = if metadata.permissions().readonly() {
+        UnixTriplet::R
+    } else {
+        UnixTriplet::RW
+    }
+    .with_execute(metadata.is_dir());
+    let mtime = metadata.modified().map(unix_seconds).unwrap_or(0);
+    let atime = metadata.accessed().map(unix_seconds).unwrap_or(mtime);
+
+    Stat {
+        kernel_type: 0,
+        kernel_dev: 0,
+        qid: Qid {
+            file_type,
+            version: 0,
+            path: hasher.finish(),
+        },
+        mode: StatMode::default()
+            .with_file_type(file_type)
+            .with_user(user)
+            .with_group(UnixTriplet::R.with_execute(metadata.is_dir()))
+            .with_other(UnixTriplet::R.with_execute(metadata.is_dir())),
+        atime,
+        mtime,
+        length: if metadata.is_dir() { 0 } else { metadata.len() },
+        name: names.last().map_or("/", String::as_str).to_owned(),
+        uid: USER_NAME.to_owned(),
+        gid: USER_NAME.to_owned(),
+        muid: USER_NAME.to_owned(),
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| {
+        duration.as_secs().try_into().unwrap_or(u32::MAX)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::LocalNamespace;
+    use crate::namespace::{FsError, Namespace};
+
+    #[tokio::test]
+    async fn reads_and_writes_files() {
+        let root = tempfile::tempdir().unwrap();
+        let namespace = LocalNamespace::new(root.path().to_owned());
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+
+        let mut file = namespace.create("/dir/a.txt").await.unwrap();
+        assert_eq!(file.write(b"hello").await.unwrap(), 5);
+        file.close().await.unwrap();
+
+        let mut file = namespace.open("dir//a.txt").await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(file.read(&mut buf).await.unwrap(), 0);
+        file.close().await.unwrap();
+
+        let stat = namespace.stat("dir/a.txt").await.unwrap();
+        assert_eq!((stat.name.as_str(), stat.length), ("a.txt", 5));
+        let names: Vec<_> = namespace
+            .read_dir("/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|stat| stat.name)
+            .collect();
+        assert_eq!(names, ["dir"]);
+
+        assert!(matches!(
+            namespace.open("dir").await,
+            Err(FsError::IsADirectory)
+        ));
+        assert!(matches!(
+            namespace.open("missing").await,
+            Err(FsError::NotFound)
+        ));
+        assert!(matches!(
+            namespace.read_dir("dir/a.txt").await,
+            Err(FsError::NotADirectory)
+        ));
+    }
+
+    #[tokio::test]
+    async fn keeps_symlinks_inside_the_root() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::fs::write(root.path().join("dir/a.txt"), "a").unwrap();
+        symlink(outside.path(), root.path().join("out")).unwrap();
+        symlink(outside.path().join("new"), root.path().join("dangling")).unwrap();
+        symlink(root.path().join("dir"), root.path().join("in")).unwrap();
+        let namespace = LocalNamespace::new(root.path().to_owned());
+
+        // Links that stay inside the root are followed.
+        assert_eq!(namespace.stat("in/a.txt").await.unwrap().length, 1);
+
+        for path in ["out/secret", "out"] {
+            assert!(matches!(
+                namespace.stat(path).await,
+                Err(FsError::InvalidPath)
+            ));
+        }
+        assert!(matches!(
+            namespace.open("out/secret").await,
+            Err(FsError::InvalidPath)
+        ));
+        assert!(matches!(
+            namespace.create("out/new").await,
+            Err(FsError::InvalidPath)
+        ));
+        assert!(matches!(
+            namespace.create("dangling").await,
+            Err(FsError::InvalidPath)
+        ));
+        assert!(!outside.path().join("new").exists());
+    }
+}
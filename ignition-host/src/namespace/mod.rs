@@ -0,0 +1,229 @@
+//! Filesystem namespaces that serve the `fs_*` imports.
+//!
+//! Paths are always `/`-separated and relative to the root of the namespace, whether or not they
+//! begin with a `/`. Entries are described with 9p2000 [`Stat`] structures regardless of the backing
+//! store, and that is also the encoding guests receive.
+
+use std::io;
+
+use async_trait::async_trait;
+use ignition_9p::wire::WriteTo;
+use ignition_9p::Stat;
+use log::warn;
+use thiserror::Error;
+
+use crate::process::host_pipe::{HostPipeReader, HostPipeWriter};
+
+mod local;
+mod remote;
+
+pub use self::local::LocalNamespace;
+pub use self::remote::RemoteNamespace;
+
+/// Size of the buffer used when copying between files and pipes.
+const COPY_BUFFER_SIZE: usize = 8192;
+
+#[async_trait]
+pub trait Namespace: Send + Sync {
+    async fn stat(&self, path: &str) -> Result<Stat, FsError>;
+    async fn read_dir(&self, path: &str) -> Result<Vec<Stat>, FsError>;
+
+    /// Opens an existing file for reading.
+    async fn open(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError>;
+
+    /// Creates a file for writing, truncating it if it already exists.
+    async fn create(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError>;
+}
+
+#[async_trait]
+pub trait NamespaceFile: Send {
+    /// Reads from the current offset. Returns zero at end of file.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes some prefix of `buf` at the current offset.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FsError>;
+
+    /// Releases the file, making any writes durable.
+    async fn close(self: Box<Self>) -> Result<(), FsError>;
+}
+
+#[derive(Debug, Error)]
+pub enum FsError {
+    #[error("not found")]
+    NotFound,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("already exists")]
+    AlreadyExists,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("invalid path")]
+    InvalidPath,
+    #[error("no filesystem is mounted")]
+    Unavailable,
+    #[error("I/O error: {0}")]
+    Io(String),
+}
+
+impl FsError {
+    /// The status code reported to guests as a wake param. Zero is reserved for success.
+    pub fn status(&self) -> u32 {
+        match self {
+            FsError::NotFound => 1,
+            FsError::PermissionDenied => 2,
+            FsError::AlreadyExists => 3,
+            FsError::NotADirectory => 4,
+            FsError::IsADirectory => 5,
+            FsError::InvalidPath => 6,
+            FsError::Unavailable => 7,
+            FsError::Io(_) => 8,
+        }
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => FsError::NotFound,
+            io::ErrorKind::PermissionDenied => FsError::PermissionDenied,
+            io::ErrorKind::AlreadyExists => FsError::AlreadyExists,
+            _ => FsError::Io(e.to_string()),
+        }
+    }
+}
+
+/// Splits a guest path into its components, rejecting any attempt to climb above the root.
+pub fn split_path(path: &str) -> Result<Vec<&str>, FsError> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(|name| {
+            if name == ".." {
+                Err(FsError::InvalidPath)
+            } else {
+                Ok(name)
+            }
+        })
+        .collect()
+}
+
+/// Streams `file` into `writer` until either end of file or the reader goes away.
+pub async fn copy_file_to_pipe(mut file: Box<dyn NamespaceFile>, mut writer: HostPipeWriter) {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    loop {
+        let n = match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                warn!("fs: read failed: {}", e);
+                break;
+            }
+        };
+        if !writer.write_all(&buf[..n]).await {
+            break;
+        }
+    }
+    if let Err(e) = file.close().await {
+        warn!("fs: close failed: {}", e);
+    }
+}
+
+/// Streams `reader` into `file` until the writer closes the pipe, and returns the first error in
+/// writing or closing the file.
+///
+/// The pipe is drained even after a write fails, so that the guest sees its writes succeed and
+/// learns of the failure from the file's status instead.
+pub async fn copy_pipe_to_file(
+    mut reader: HostPipeReader,
+    mut file: Box<dyn NamespaceFile>,
+) -> Result<(), FsError> {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut result = Ok(());
+    loop {
+        let n = reader.read(&mut buf).await;
+        if n == 0 {
+            break;
+        }
+        let mut data = &buf[..n];
+        while result.is_ok() && !data.is_empty() {
+            match file.write(data).await {
+                Ok(n) => data = &data[n..],
+                Err(e) => result = Err(e),
+            }
+        }
+    }
+    let closed = file.close().await;
+    result.and(closed)
+}
+
+/// Writes each entry's 9p2000 encoding to `writer`.
+pub async fn write_stats_to_pipe(stats: &[Stat], mut writer: HostPipeWriter) {
+    let mut data = Vec::new();
+    for stat in stats {
+        stat.write_to(&mut data).unwrap();
+    }
+    writer.write_all(&data).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::{copy_pipe_to_file, split_path, FsError, NamespaceFile};
+    use crate::process::host_pipe::{HostPipeReader, HostPipeWriter};
+    use crate::process::pipe::pipe;
+
+    #[test]
+    fn split_path_ignores_empty_components() {
+        assert_eq!(split_path("/a//b/./c/").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(split_path("a/b").unwrap(), vec!["a", "b"]);
+        assert!(split_path("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_path_rejects_parent_components() {
+        assert!(matches!(split_path("a/../b"), Err(FsError::InvalidPath)));
+    }
+
+    /// Accepts `capacity` bytes, then fails.
+    struct FullFile {
+        capacity: usize,
+    }
+
+    #[async_trait]
+    impl NamespaceFile for FullFile {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, FsError> {
+            Ok(0)
+        }
+
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+            if self.capacity == 0 {
+                return Err(FsError::Io("no space left".to_owned()));
+            }
+            let n = buf.len().min(self.capacity);
+            self.capacity -= n;
+            Ok(n)
+        }
+
+        async fn close(self: Box<Self>) -> Result<(), FsError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_pipe_to_file_reports_write_errors() {
+        let (reader, writer) = pipe();
+        let mut writer = HostPipeWriter::new(writer);
+        let copy = tokio::spawn(copy_pipe_to_file(
+            HostPipeReader::new(reader),
+            Box::new(FullFile { capacity: 4 }),
+        ));
+
+        // The writer sees every write succeed, even past the failure.
+        assert!(writer.write_all(b"hello").await);
+        assert!(writer.write_all(b"world").await);
+        drop(writer);
+        assert!(matches!(copy.await.unwrap(), Err(FsError::Io(_))));
+    }
+}
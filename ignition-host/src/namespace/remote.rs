@@ -0,0 +1,432 @@
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
+use ignition_9p::message::{
+    Message, MessageBody, RError, RRead, RStat, RVersion, RWalk, RWrite, TAttach, TClunk, TCreate,
+    TOpen, TRead, TStat, TVersion, TWalk, TWrite,
+};
+use ignition_9p::wire::{ReadFrom, WriteTo};
+use ignition_9p::{Fid, OpenAccess, OpenMode, Stat, Tag};
+use log::warn;
+use slab::Slab;
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::namespace::{split_path, FsError, Namespace, NamespaceFile};
+
+const VERSION: &str = "9P2000";
+
+/// Maximum message size requested from the server, including the length prefix on the wire.
+const MAX_MSIZE: u32 = 1048576;
+
+/// Overhead of the header on Rread and Twrite messages, per the Plan 9 manual's IOHDRSZ.
+const IO_HEADER_SIZE: u32 = 24;
+
+/// Maximum number of names in a single Twalk.
+const MAX_WALK_ELEMENTS: usize = 16;
+
+/// Permissions for files created through the namespace.
+const CREATE_PERM: u32 = 0o644;
+
+/// The fid attached to the root of the remote tree.
+const ROOT_FID: Fid = Fid(0);
+
+type FramedSink = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
+
+/// A namespace served by a remote 9p2000 server.
+pub struct RemoteNamespace {
+    client: Arc<Client>,
+}
+
+struct Client {
+    sink: tokio::sync::Mutex<FramedSink>,
+    inner: Mutex<InnerClient>,
+    iounit: u32,
+}
+
+struct InnerClient {
+    /// Keyed by tag.
+    pending: Slab<oneshot::Sender<MessageBody>>,
+    /// Keyed by fid.
+    fids: Slab<()>,
+}
+
+impl RemoteNamespace {
+    /// Connects to a 9p2000 server and attaches to the root of its tree.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut framed = LengthDelimitedCodec::builder()
+            .little_endian()
+            .length_field_length(4)
+            .length_adjustment(-4)
+            .max_frame_length(MAX_MSIZE as usize)
+            .new_framed(stream);
+
+        framed
+            .send(encode(Message {
+                tag: Tag::NOTAG,
+                body: MessageBody::TVersion(TVersion {
+                    msize: MAX_MSIZE,
+                    version: VERSION.to_owned(),
+                }),
+            }))
+            .await?;
+        let frame = framed
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("9p server at {} closed the connection", addr))??;
+        let msize = match Message::read_from(&mut &*frame)?.body {
+            MessageBody::RVersion(RVersion { msize, version }) if version == VERSION => msize,
+            body => return Err(anyhow!("unexpected 9p version response: {:?}", body)),
+        };
+
+        let (sink, stream) = framed.split();
+        let mut fids = Slab::new();
+        assert_eq!(fids.insert(()), ROOT_FID.0 as usize);
+        let client = Arc::new(Client {
+            sink: tokio::sync::Mutex::new(sink),
+            inner: Mutex::new(InnerClient {
+                pending: Slab::new(),
+                fids,
+            }),
+            iounit: msize
+                .checked_sub(IO_HEADER_SIZE)
+                .ok_or_else(|| anyhow!("negotiated msize {} is too small", msize))?,
+        });
+
+        // Route responses to whichever request is waiting on their tag.
+        let reader_client = Arc::clone(&client);
+        tokio::spawn(async move {
+            let mut stream = stream;
+            while let Some(Ok(frame)) = stream.next().await {
+                let message = match Message::read_from(&mut &*frame) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("fs: dropping malformed 9p message: {}", e);
+                        continue;
+                    }
+                };
+                let sender = reader_client
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .pending
+                    .try_remove(message.tag.0 as usize);
+                if let Some(sender) = sender {
+                    let _ = sender.send(message.body);
+                }
+            }
+
+            // Fail every request still in flight.
+            reader_client.inner.lock().unwrap().pending.clear();
+        });
+
+        client
+            .rpc(MessageBody::TAttach(TAttach {
+                fid: ROOT_FID,
+                afid: Fid::NOFID,
+                uname: "ignition".to_owned(),
+                aname: String::new(),
+            }))
+            .await?;
+
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl Namespace for RemoteNamespace {
+    async fn stat(&self, path: &str) -> Result<Stat, FsError> {
+        let fid = self.client.walk(&split_path(path)?).await?;
+        let result = self.client.stat(fid).await;
+        self.client.clunk(fid).await;
+        result
+    }
+
+    async fn read_dir(&self, path: &str) -> Result<Vec<Stat>, FsError> {
+        let fid = self.client.walk(&split_path(path)?).await?;
+        let result = self.client.read_dir(fid).await;
+        self.client.clunk(fid).await;
+        result
+    }
+
+    async fn open(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError> {
+        let fid = self.client.walk(&split_path(path)?).await?;
+        match self
+            .client
+            .rpc(MessageBody::TOpen(TOpen {
+                fid,
+                mode: OpenMode::default().with_access(OpenAccess::READ),
+            }))
+            .await
+        {
+            Ok(_) => Ok(Box::new(RemoteFile {
+                client: Arc::clone(&self.client),
+                fid,
+                offset: 0,
+            })),
+            Err(e) => {
+                self.client.clunk(fid).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn create(&self, path: &str) -> Result<Box<dyn NamespaceFile>, FsError> {
+        let names = split_path(path)?;
+        let (name, parent_names) = names.split_last().ok_or(FsError::IsADirectory)?;
+
+        // Prefer truncating an existing file, since Tcreate fails if the name is taken.
+        let fid = self.client.walk(&names).await;
+        if let Ok(fid) = fid {
+            return match self
+                .client
+                .rpc(MessageBody::TOpen(TOpen {
+                    fid,
+                    mode: OpenMode::default()
+                        .with_access(OpenAccess::WRITE)
+                        .with_trunc(true),
+                }))
+                .await
+            {
+                Ok(_) => Ok(Box::new(RemoteFile {
+                    client: Arc::clone(&self.client),
+                    fid,
+                    offset: 0,
+                })),
+                Err(e) => {
+                    self.client.clunk(fid).await;
+                    Err(e)
+                }
+            };
+        }
+
+        // On success, Tcreate leaves the parent's fid referring to the new file.
+        let fid = self.client.walk(parent_names).await?;
+        match self
+            .client
+            .rpc(MessageBody::TCreate(TCreate {
+                fid,
+                name: (*name).to_owned(),
+                perm: CREATE_PERM,
+                mode: OpenMode::default().with_access(OpenAccess::WRITE),
+            }))
+            .await
+        {
+            Ok(_) => Ok(Box::new(RemoteFile {
+                client: Arc::clone(&self.client),
+                fid,
+                offset: 0,
+            })),
+            Err(e) => {
+                self.client.clunk(fid).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Client {
+    async fn rpc(&self, body: MessageBody) -> Result<MessageBody, FsError> {
+        let (sender, receiver) = oneshot::channel();
+        let tag = self.inner.lock().unwrap().pending.insert(sender);
+        let message = encode(Message {
+            tag: Tag(tag.try_into().unwrap()),
+            body,
+        });
+
+        if let Err(e) = self.sink.lock().await.send(message).await {
+            self.inner.lock().unwrap().pending.try_remove(tag);
+            return Err(FsError::Io(e.to_string()));
+        }
+
+        match receiver.await.map_err(|_| FsError::Unavailable)? {
+            MessageBody::RError(RError { ename }) => Err(error_from_ename(ename)),
+            body => Ok(body),
+        }
+    }
+
+    fn allocate_fid(&self) -> Fid {
+        Fid(self
+            .inner
+            .lock()
+            .unwrap()
+            .fids
+            .insert(())
+            .try_into()
+            .unwrap())
+    }
+
+    /// Walks from the root to a new fid. The caller is responsible for clunking it.
+    async fn walk(&self, names: &[&str]) -> Result<Fid, FsError> {
+        let fid = self.allocate_fid();
+
+        // An empty walk just clones the root fid.
+        let mut chunks: Vec<&[&str]> = names.chunks(MAX_WALK_ELEMENTS).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let result = self
+                .rpc(MessageBody::TWalk(TWalk {
+                    fid: if i == 0 { ROOT_FID } else { fid },
+                    newfid: fid,
+                    names: chunk.iter().map(|&name| name.to_owned()).collect(),
+                }))
+                .await;
+            match result {
+                // A short walk means some name along the way was missing.
+                Ok(MessageBody::RWalk(RWalk { qids })) if qids.len() == chunk.len() => (),
+                Ok(_) => {
+                    if i > 0 {
+                        self.clunk(fid).await;
+                    } else {
+                        self.free_fid(fid);
+                    }
+                    return Err(FsError::NotFound);
+                }
+                Err(e) => {
+                    if i > 0 {
+                        self.clunk(fid).await;
+                    } else {
+                        self.free_fid(fid);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(fid)
+    }
+
+    async fn clunk(&self, fid: Fid) {
+        // The fid is released even if the server reports an error.
+        let _ = self.rpc(MessageBody::TClunk(TClunk { fid })).await;
+        self.free_fid(fid);
+    }
+
+    fn free_fid(&self, fid: Fid) {
+        self.inner.lock().unwrap().fids.remove(fid.0 as usize);
+    }
+
+    async fn stat(&self, fid: Fid) -> Result<Stat, FsError> {
+        match self.rpc(MessageBody::TStat(TStat { fid })).await? {
+            MessageBody::RStat(RStat { stat }) => Ok(stat),
+            body => Err(unexpected_response(body)),
+        }
+    }
+
+    async fn read(&self, fid: Fid, offset: u64, count: u32) -> Result<Vec<u8>, FsError> {
+        match self
+            .rpc(MessageBody::TRead(TRead {
+                fid,
+                offset,
+                count: count.min(self.iounit),
+            }))
+            .await?
+        {
+            MessageBody::RRead(RRead { data }) => Ok(data),
+            body => Err(unexpected_response(body)),
+        }
+    }
+
+    async fn read_dir(&self, fid: Fid) -> Result<Vec<Stat>, FsError> {
+        self.rpc(MessageBody::TOpen(TOpen {
+            fid,
+            mode: OpenMode::default().with_access(OpenAccess::READ),
+        }))
+        .await?;
+
+        // Directory reads return whole stat entries, so each response can be decoded on its own.
+        let mut stats = Vec::new();
+        let mut offset = 0;
+        loop {
+            let data = self.read(fid, offset, self.iounit).await?;
+            if data.is_empty() {
+                return Ok(stats);
+            }
+            offset += data.len() as u64;
+
+            let mut r = &*data;
+            while !r.is_empty() {
+                stats.push(Stat::read_from(&mut r).map_err(|e| FsError::Io(e.to_string()))?);
+            }
+        }
+    }
+}
+
+struct RemoteFile {
+    client: Arc<Client>,
+    fid: Fid,
+    offset: u64,
+}
+
+#[async_trait]
+impl NamespaceFile for RemoteFile {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let count = buf.len().try_into().unwrap_or(u32::MAX);
+        let data = self.client.read(self.fid, self.offset, count).await?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.offset += data.len() as u64;
+        Ok(data.len())
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        let len = buf.len().min(self.client.iounit as usize);
+        match self
+            .client
+            .rpc(MessageBody::TWrite(TWrite {
+                fid: self.fid,
+                offset: self.offset,
+                data: buf[..len].to_vec(),
+            }))
+            .await?
+        {
+            MessageBody::RWrite(RWrite { count }) => {
+                self.offset += u64::from(count);
+                Ok(count as usize)
+            }
+            body => Err(unexpected_response(body)),
+        }
+    }
+
+    async fn close(self: Box<Self>) -> Result<(), FsError> {
+        self.client.clunk(self.fid).await;
+        Ok(())
+    }
+}
+
+fn encode(message: Message) -> Bytes {
+    let mut buf = BytesMut::new().writer();
+    message.write_to(&mut buf).unwrap();
+    buf.into_inner().freeze()
+}
+
+fn unexpected_response(body: MessageBody) -> FsError {
+    FsError::Io(format!("unexpected 9p response: {:?}", body.message_type()))
+}
+
+/// Maps a server's error string to the closest error kind. 9p2000 only carries text, so this is
+/// necessarily a guess.
+fn error_from_ename(ename: String) -> FsError {
+    let lower = ename.to_lowercase();
+    if lower.contains("not found") || lower.contains("no such file") {
+        FsError::NotFound
+    } else if lower.contains("permission denied") {
+        FsError::PermissionDenied
+    } else if lower.contains("exists") {
+        FsError::AlreadyExists
+    } else if lower.contains("not a directory") {
+        FsError::NotADirectory
+    } else if lower.contains("is a directory") {
+        FsError::IsADirectory
+    } else {
+        FsError::Io(ename)
+    }
+}
@@ -0,0 +1,145 @@
+use std::convert::TryInto;
+use std::task::Poll;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::process::pipe::{PipeReader, PipeWriter};
+use crate::{TaskId, WakeParams};
+
+/// Host tasks have a private wake queue per pipe end, so any task ID will do.
+const HOST_TASK_ID: TaskId = TaskId(0);
+
+/// How much `read_to_end` asks for at a time.
+const READ_TO_END_CHUNK_SIZE: usize = 8192;
+
+/// The reading end of a pipe, driven by a host task rather than a guest.
+///
+/// Reads land in a buffer owned by this struct so that a pending read never refers to memory owned
+/// by a dropped future. Dropping the reader closes the pipe before that buffer is freed.
+pub struct HostPipeReader {
+    reader: PipeReader,
+    buf: Vec<u8>,
+    wake_queue_sender: UnboundedSender<WakeParams>,
+    wake_queue_receiver: UnboundedReceiver<WakeParams>,
+}
+
+impl HostPipeReader {
+    pub fn new(reader: PipeReader) -> Self {
+        let (wake_queue_sender, wake_queue_receiver) = unbounded_channel();
+        Self {
+            reader,
+            buf: Vec::new(),
+            wake_queue_sender,
+            wake_queue_receiver,
+        }
+    }
+
+    /// Reads up to `dst.len()` bytes. Returns zero once the writer has closed the pipe.
+    ///
+    /// Not cancel-safe: a read abandoned while pending leaves the pipe waiting on this reader.
+    pub async fn read(&mut self, dst: &mut [u8]) -> usize {
+        if self.buf.len() < dst.len() {
+            self.buf.resize(dst.len(), 0);
+        }
+
+        // SAFETY: `self.buf` is not touched again until the read completes, and it outlives the
+        // pipe's reference to it because `drop()` closes the pipe first.
+        let result = unsafe {
+            self.reader.read(
+                &self.wake_queue_sender,
+                HOST_TASK_ID,
+                self.buf.as_mut_ptr(),
+                dst.len().try_into().unwrap(),
+            )
+        };
+        let n = match result {
+            Poll::Ready(n) => n,
+            Poll::Pending => self.wake_queue_receiver.recv().await.unwrap().param,
+        } as usize;
+
+        dst[..n].copy_from_slice(&self.buf[..n]);
+        n
+    }
+
+    /// Reads until the writer closes the pipe.
+    pub async fn read_to_end(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut chunk = vec![0; READ_TO_END_CHUNK_SIZE];
+        loop {
+            let n = self.read(&mut chunk).await;
+            if n == 0 {
+                return data;
+            }
+            data.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl Drop for HostPipeReader {
+    fn drop(&mut self) {
+        self.reader.close();
+    }
+}
+
+/// The writing end of a pipe, driven by a host task rather than a guest.
+///
+/// Writes are staged in a buffer owned by this struct for the same reason as [`HostPipeReader`].
+pub struct HostPipeWriter {
+    writer: PipeWriter,
+    buf: Vec<u8>,
+    wake_queue_sender: UnboundedSender<WakeParams>,
+    wake_queue_receiver: UnboundedReceiver<WakeParams>,
+}
+
+impl HostPipeWriter {
+    pub fn new(writer: PipeWriter) -> Self {
+        let (wake_queue_sender, wake_queue_receiver) = unbounded_channel();
+        Self {
+            writer,
+            buf: Vec::new(),
+            wake_queue_sender,
+            wake_queue_receiver,
+        }
+    }
+
+    /// Writes some prefix of `src`. Returns zero once the reader has closed the pipe.
+    ///
+    /// Not cancel-safe: a write abandoned while pending leaves the pipe waiting on this writer.
+    pub async fn write(&mut self, src: &[u8]) -> usize {
+        self.buf.clear();
+        self.buf.extend_from_slice(src);
+
+        // SAFETY: `self.buf` is not touched again until the write completes, and it outlives the
+        // pipe's reference to it because `drop()` closes the pipe first.
+        let result = unsafe {
+            self.writer.write(
+                &self.wake_queue_sender,
+                HOST_TASK_ID,
+                self.buf.as_ptr(),
+                src.len().try_into().unwrap(),
+            )
+        };
+        match result {
+            Poll::Ready(n) => n as usize,
+            Poll::Pending => self.wake_queue_receiver.recv().await.unwrap().param as usize,
+        }
+    }
+
+    /// Writes all of `src`. Returns false if the reader closed the pipe first.
+    pub async fn write_all(&mut self, mut src: &[u8]) -> bool {
+        while !src.is_empty() {
+            let n = self.write(src).await;
+            if n == 0 {
+                return false;
+            }
+            src = &src[n..];
+        }
+        true
+    }
+}
+
+impl Drop for HostPipeWriter {
+    fn drop(&mut self) {
+        self.writer.close();
+    }
+}
@@ -1,6 +1,7 @@
 use std::task::Poll;
 
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
 
 use crate::process::pipe::{PipeReader, PipeWriter};
 use crate::{TaskId, WakeParams};
@@ -8,6 +9,8 @@ use crate::{TaskId, WakeParams};
 pub struct IoObject {
     reader: Option<PipeReader>,
     writer: Option<PipeWriter>,
+    /// For a writer, receives the status of whatever consumes the other end once it has finished.
+    status: Option<oneshot::Receiver<u32>>,
 }
 
 impl IoObject {
@@ -15,6 +18,7 @@ impl IoObject {
         Self {
             reader: Some(reader),
             writer: None,
+            status: None,
         }
     }
 
@@ -22,9 +26,26 @@ impl IoObject {
         Self {
             reader: None,
             writer: Some(writer),
+            status: None,
         }
     }
 
+    pub fn with_status(self, status: oneshot::Receiver<u32>) -> Self {
+        Self {
+            status: Some(status),
+            ..self
+        }
+    }
+
+    pub fn has_status(&self) -> bool {
+        self.status.is_some()
+    }
+
+    /// Takes the receiver given to [`with_status`](Self::with_status).
+    pub fn take_status(&mut self) -> Option<oneshot::Receiver<u32>> {
+        self.status.take()
+    }
+
     pub unsafe fn read(
         &mut self,
         wake_queue_sender: &UnboundedSender<WakeParams>,
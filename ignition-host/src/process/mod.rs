@@ -1,3 +1,4 @@
+pub mod host_pipe;
 pub mod io_object;
 pub mod pipe;
 pub mod process;
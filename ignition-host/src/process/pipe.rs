@@ -73,8 +73,19 @@ impl InnerPipe {
                     .unwrap();
                 PipeState::Closed
             }
-            PipeState::PendingWrite { .. } => {
-                todo!("write to a closed pipe")
+            PipeState::PendingWrite {
+                write_wake_queue_sender,
+                write_task_id,
+                ..
+            } => {
+                // The pending write can never complete, so report it as having written nothing.
+                write_wake_queue_sender
+                    .send(WakeParams {
+                        task_id: write_task_id,
+                        param: 0,
+                    })
+                    .unwrap();
+                PipeState::Closed
             }
             PipeState::Closed => PipeState::Closed,
         });
@@ -175,7 +186,7 @@ impl PipeWriter {
                 (Poll::Ready(len), PipeState::Idle)
             }
             PipeState::PendingWrite { .. } => todo!("write with a write already pending"),
-            PipeState::Closed => todo!("write to a closed pipe"),
+            PipeState::Closed => (Poll::Ready(0), PipeState::Closed),
         })
     }
 
@@ -1,49 +1,170 @@
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::Poll;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use anyhow::{bail, Result};
+use log::LevelFilter;
 use slab::Slab;
+use tokio::sync::futures::Notified;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use wasmtime::Trap;
+use tokio::sync::{oneshot, Notify};
+use wasmtime::{InterruptHandle, Trap};
 
+use crate::crash::GuestPanic;
 use crate::interop::rpc::{RpcMetadata, RpcServerParams};
+use crate::namespace::{
+    copy_file_to_pipe, copy_pipe_to_file, write_stats_to_pipe, FsError, Namespace,
+};
+use crate::process::host_pipe::{HostPipeReader, HostPipeWriter};
 use crate::process::io_object::IoObject;
 use crate::process::pipe::pipe;
 use crate::process::rpc_client::RpcClient;
 use crate::process::rpc_server::RpcServer;
 use crate::process::service_registry::{RpcServerRef, SERVICE_REGISTRY};
+use crate::service::NativeRequest;
 use crate::util::pointer_identity_arc::PointerIdentityArc;
 use crate::{TaskId, WakeParams};
 
 pub struct Process {
     pid: usize,
+    principal: String,
     start_time: Instant,
     is_shutdown: AtomicBool,
+    is_killed: AtomicBool,
+    killed: Notify,
+    /// Stops the guest mid-wake when it's killed. Set once the process has a store.
+    interrupt_handle: Mutex<Option<InterruptHandle>>,
+    /// Tasks to wake when the host begins shutting down, or None once it has.
+    host_shutdown_tasks: Mutex<Option<Vec<TaskId>>>,
+    /// The deadline of each sleep that hasn't finished yet.
+    timers: Mutex<HashMap<TaskId, Instant>>,
     wake_queue_sender: UnboundedSender<WakeParams>,
+    namespace: Option<Arc<dyn Namespace>>,
+    guest_panic: Mutex<Option<GuestPanic>>,
+    heap_stats: Mutex<Option<HeapStats>>,
+    log_level: Mutex<LevelFilter>,
+    /// Requests the registry has routed to this process's servers that aren't queued yet.
+    requests_in_flight: AtomicUsize,
+    /// Notified when the process might have stopped owing responses, and when it shuts down.
+    drained: Notify,
     inner: Mutex<InnerProcess>,
 }
 
+/// A request on its way to one of a process's servers, from when the registry picks the server
+/// until the guard is dropped once the request is queued. Until then, the process counts as having
+/// pending requests.
+pub struct InFlightRequest {
+    process: Arc<Process>,
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        if self
+            .process
+            .requests_in_flight
+            .fetch_sub(1, Ordering::SeqCst)
+            == 1
+        {
+            self.process.drained.notify_waiters();
+        }
+    }
+}
+
+/// Heap usage as last reported by a guest built with the `ignition_guest` allocator.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub live_bytes: u32,
+    pub peak_bytes: u32,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes live, {} bytes peak, {} allocations, {} deallocations",
+            self.live_bytes, self.peak_bytes, self.allocations, self.deallocations,
+        )
+    }
+}
+
+/// A summary of the handles a process holds, for inspecting it from outside.
+#[derive(Clone, Debug)]
+pub struct ProcessHandles {
+    /// The service name of each RPC client.
+    pub rpc_clients: Vec<String>,
+    pub rpc_servers: usize,
+    pub io_objects: usize,
+    pub pending_requests: usize,
+}
+
+/// The state of a process outside its instance, as saved in a snapshot. Handles keep their values,
+/// since the guest's memory refers to them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedProcess {
+    /// Each RPC client's handle and service name.
+    pub rpc_clients: Vec<(u32, String)>,
+    pub rpc_servers: Vec<SavedRpcServer>,
+    /// Tasks waiting for a service to get its first server.
+    pub tasks_waiting_for_servers: Vec<(String, TaskId)>,
+    pub host_shutdown_tasks: Vec<TaskId>,
+    /// Each unfinished sleep's task and how much of it was left.
+    pub timers: Vec<(TaskId, Duration)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedRpcServer {
+    pub handle: u32,
+    pub service_name: String,
+    pub method_names: Vec<String>,
+    pub waiting_task_ids: Vec<TaskId>,
+}
+
 struct InnerProcess {
     rpc_clients: Slab<RpcClient>,
     rpc_servers: Slab<RpcServer>,
     io_objects: Slab<IoObject>,
+    /// The response IO handles of RPC requests given to this process's servers that it hasn't
+    /// closed yet, whether or not it has picked the requests up.
+    pending_responses: HashSet<u32>,
 }
 
 impl Process {
-    pub fn new(pid: usize) -> (Self, UnboundedReceiver<WakeParams>) {
+    pub fn new(
+        pid: usize,
+        principal: String,
+        namespace: Option<Arc<dyn Namespace>>,
+    ) -> (Self, UnboundedReceiver<WakeParams>) {
         let (wake_queue_sender, wake_queue_receiver) = unbounded_channel();
         let state = Process {
             pid,
+            principal,
             start_time: Instant::now(),
             is_shutdown: AtomicBool::new(false),
+            is_killed: AtomicBool::new(false),
+            killed: Notify::new(),
+            interrupt_handle: Mutex::new(None),
+            host_shutdown_tasks: Mutex::new(Some(Vec::new())),
+            timers: Mutex::new(HashMap::new()),
             wake_queue_sender,
+            namespace,
+            guest_panic: Mutex::new(None),
+            heap_stats: Mutex::new(None),
+            log_level: Mutex::new(LevelFilter::Trace),
+            requests_in_flight: AtomicUsize::new(0),
+            drained: Notify::new(),
             inner: Mutex::new(InnerProcess {
                 rpc_clients: Slab::new(),
                 rpc_servers: Slab::new(),
                 io_objects: Slab::new(),
+                pending_responses: HashSet::new(),
             }),
         };
         (state, wake_queue_receiver)
@@ -53,10 +174,23 @@ impl Process {
         self.pid
     }
 
+    /// The identity that host services attribute this process's requests to. Unlike the pid, it
+    /// outlives the process: it's given at launch, carried over by upgrades and snapshots, and
+    /// otherwise names the module.
+    pub fn principal(&self) -> &str {
+        &self.principal
+    }
+
     pub fn start_time(&self) -> Instant {
         self.start_time
     }
 
+    /// Sets the guest's monotonic clock, so that a restored process doesn't see time go backwards.
+    pub fn set_monotonic_time(&mut self, elapsed: Duration) {
+        let now = Instant::now();
+        self.start_time = now.checked_sub(elapsed).unwrap_or(now);
+    }
+
     pub fn is_shutdown(&self) -> bool {
         // TODO: Relax ordering?
         self.is_shutdown.load(Ordering::SeqCst)
@@ -65,6 +199,255 @@ impl Process {
     pub fn shutdown(&self) {
         // TODO: Relax ordering?
         self.is_shutdown.store(true, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    /// Shuts the process down without waiting for the guest to do anything more, interrupting it if
+    /// it's in the middle of a wake.
+    pub fn kill(&self) {
+        self.shutdown();
+        self.is_killed.store(true, Ordering::SeqCst);
+        self.killed.notify_one();
+        if let Some(handle) = &*self.interrupt_handle.lock().unwrap() {
+            handle.interrupt();
+        }
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.is_killed.load(Ordering::SeqCst)
+    }
+
+    pub fn set_interrupt_handle(&self, handle: InterruptHandle) {
+        *self.interrupt_handle.lock().unwrap() = Some(handle);
+    }
+
+    /// Completes once the process has been killed.
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+
+    /// Wakes `task_id` when the host begins shutting down, or right away if it already has.
+    pub fn wake_on_host_shutdown(&self, task_id: TaskId) {
+        match &mut *self.host_shutdown_tasks.lock().unwrap() {
+            Some(task_ids) => task_ids.push(task_id),
+            None => self
+                .wake_queue_sender
+                .send(WakeParams { task_id, param: 0 })
+                .unwrap(),
+        }
+    }
+
+    /// Tells the guest that the host is shutting down. Only the first call has any effect.
+    pub fn request_host_shutdown(&self) {
+        let task_ids = self.host_shutdown_tasks.lock().unwrap().take();
+        for task_id in task_ids.into_iter().flatten() {
+            // The process may already have exited.
+            let _ = self
+                .wake_queue_sender
+                .send(WakeParams { task_id, param: 0 });
+        }
+    }
+
+    /// Returns true once [`request_host_shutdown`](Self::request_host_shutdown) has been called.
+    pub fn is_host_shutting_down(&self) -> bool {
+        self.host_shutdown_tasks.lock().unwrap().is_none()
+    }
+
+    /// Wakes `task_id` after `duration`.
+    pub fn sleep(arc_self: &Arc<Self>, task_id: TaskId, duration: Duration) {
+        arc_self
+            .timers
+            .lock()
+            .unwrap()
+            .insert(task_id, Instant::now() + duration);
+        let process = Arc::downgrade(arc_self);
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            // There's no one to wake if the process has exited.
+            if let Some(process) = process.upgrade() {
+                process.timers.lock().unwrap().remove(&task_id);
+                let _ = process
+                    .wake_queue_sender
+                    .send(WakeParams { task_id, param: 0 });
+            }
+        });
+    }
+
+    /// Saves everything about the process that lives outside its instance. Fails unless the
+    /// process is idle, meaning that it holds no IO handles, since those are connected to things
+    /// that can't be saved.
+    pub fn save(arc_self: &Arc<Self>) -> Result<SavedProcess> {
+        let inner = arc_self.inner.lock().unwrap();
+        if !inner.io_objects.is_empty() {
+            bail!(
+                "pid {} isn't idle: it has {} open IO handle(s)",
+                arc_self.pid,
+                inner.io_objects.len()
+            );
+        }
+
+        let now = Instant::now();
+        let mut timers: Vec<_> = arc_self
+            .timers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&task_id, &deadline)| (task_id, deadline.saturating_duration_since(now)))
+            .collect();
+        timers.sort_unstable();
+        Ok(SavedProcess {
+            rpc_clients: inner
+                .rpc_clients
+                .iter()
+                .map(|(handle, client)| (handle as u32, client.service_name().to_owned()))
+                .collect(),
+            rpc_servers: inner
+                .rpc_servers
+                .iter()
+                .map(|(handle, server)| SavedRpcServer {
+                    handle: handle as u32,
+                    service_name: server.service_name().to_owned(),
+                    method_names: server.method_names(),
+                    waiting_task_ids: server.waiting_task_ids(),
+                })
+                .collect(),
+            tasks_waiting_for_servers: SERVICE_REGISTRY.tasks_waiting_in(arc_self),
+            host_shutdown_tasks: arc_self
+                .host_shutdown_tasks
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_default(),
+            timers,
+        })
+    }
+
+    /// Brings back the state saved by [`save`](Self::save) in a new process, registering its
+    /// servers and restarting its sleeps.
+    pub fn restore(arc_self: &Arc<Self>, saved: &SavedProcess) {
+        {
+            let mut inner = arc_self.inner.lock().unwrap();
+            inner.rpc_clients = saved
+                .rpc_clients
+                .iter()
+                .map(|(handle, service_name)| {
+                    (*handle as usize, RpcClient::new(service_name.clone()))
+                })
+                .collect();
+            inner.rpc_servers = saved
+                .rpc_servers
+                .iter()
+                .map(|server| {
+                    let mut rpc_server = RpcServer::new(
+                        server.service_name.clone(),
+                        server.method_names.clone(),
+                        arc_self.wake_queue_sender.clone(),
+                    );
+                    for &task_id in &server.waiting_task_ids {
+                        assert!(rpc_server.get_request(task_id).is_pending());
+                    }
+                    (server.handle as usize, rpc_server)
+                })
+                .collect();
+        }
+
+        for server in &saved.rpc_servers {
+            SERVICE_REGISTRY.register(
+                server.service_name.clone(),
+                RpcServerRef::Guest {
+                    process: PointerIdentityArc::new(Arc::clone(arc_self)),
+                    rpc_server: server.handle,
+                },
+            );
+        }
+        for (service_name, task_id) in &saved.tasks_waiting_for_servers {
+            let service_name = Cow::Borrowed(service_name.as_str());
+            if SERVICE_REGISTRY
+                .wait_for_server(arc_self, *task_id, service_name)
+                .is_ready()
+            {
+                // The service got a server while the process was saved.
+                arc_self
+                    .wake_queue_sender
+                    .send(WakeParams {
+                        task_id: *task_id,
+                        param: 0,
+                    })
+                    .unwrap();
+            }
+        }
+        for &task_id in &saved.host_shutdown_tasks {
+            arc_self.wake_on_host_shutdown(task_id);
+        }
+        for &(task_id, remaining) in &saved.timers {
+            Self::sleep(arc_self, task_id, remaining);
+        }
+    }
+
+    /// Returns true if the process owes a response to any RPC request it has been given, or that
+    /// the registry has routed to it.
+    pub fn has_pending_requests(&self) -> bool {
+        // A request is queued before its InFlightRequest is dropped, so checking in this order
+        // can't miss one in between.
+        self.requests_in_flight.load(Ordering::SeqCst) > 0
+            || !self.inner.lock().unwrap().pending_responses.is_empty()
+    }
+
+    /// Completes the next time, after the call, that the process might have finished its pending
+    /// requests or has shut down. Check [`has_pending_requests`](Self::has_pending_requests) again
+    /// before waiting.
+    pub fn drained(&self) -> Notified<'_> {
+        self.drained.notified()
+    }
+
+    /// Counts a request as routed to one of this process's servers until the guard is dropped.
+    pub fn begin_request(self: &Arc<Self>) -> InFlightRequest {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest {
+            process: Arc::clone(self),
+        }
+    }
+
+    pub fn handles(&self) -> ProcessHandles {
+        let inner = self.inner.lock().unwrap();
+        ProcessHandles {
+            rpc_clients: inner
+                .rpc_clients
+                .iter()
+                .map(|(_, client)| client.service_name().to_owned())
+                .collect(),
+            rpc_servers: inner.rpc_servers.len(),
+            io_objects: inner.io_objects.len(),
+            pending_requests: inner.pending_responses.len(),
+        }
+    }
+
+    /// Records a panic for the crash report. Only the first panic is kept, since any later ones
+    /// come from the panic hook itself failing.
+    pub fn set_guest_panic(&self, panic: GuestPanic) {
+        self.guest_panic.lock().unwrap().get_or_insert(panic);
+    }
+
+    pub fn take_guest_panic(&self) -> Option<GuestPanic> {
+        self.guest_panic.lock().unwrap().take()
+    }
+
+    pub fn set_heap_stats(&self, stats: HeapStats) {
+        *self.heap_stats.lock().unwrap() = Some(stats);
+    }
+
+    /// Returns None unless the guest reports its heap usage.
+    pub fn heap_stats(&self) -> Option<HeapStats> {
+        *self.heap_stats.lock().unwrap()
+    }
+
+    /// Limits what the guest logs, on top of the host's own level.
+    pub fn set_log_level(&self, level: LevelFilter) {
+        *self.log_level.lock().unwrap() = level;
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        *self.log_level.lock().unwrap()
     }
 
     pub fn wake_queue_sender(&self) -> &UnboundedSender<WakeParams> {
@@ -102,14 +485,15 @@ impl Process {
     }
 
     pub fn io_close(&self, io: u32) -> Result<(), Trap> {
-        let io = self
-            .inner
-            .lock()
-            .unwrap()
+        let mut inner = self.inner.lock().unwrap();
+        let io_object = inner
             .io_objects
             .try_remove(io as _)
             .ok_or_else(|| Trap::new("bad IO handle"))?;
-        io.close();
+        if inner.pending_responses.remove(&io) && inner.pending_responses.is_empty() {
+            self.drained.notify_waiters();
+        }
+        io_object.close();
         Ok(())
     }
 
@@ -170,21 +554,49 @@ impl Process {
         // TODO: Introduce some kind of RPC channel abstraction and pick a healthy channel instead
         // of making this arbitrary pick all the way to the registry.
 
-        let server_ref = SERVICE_REGISTRY.pick_server_or_die(rpc_client.service_name());
-        let mut server_process_inner = server_ref.process.inner.lock().unwrap();
-        let server_process_inner = &mut *server_process_inner;
-        let server = &mut server_process_inner.rpc_servers[server_ref.rpc_server as _];
-        let server_request_io = server_process_inner
-            .io_objects
-            .insert(IoObject::new_reader(request_reader))
-            .try_into()
-            .unwrap();
-        let server_response_io = server_process_inner
-            .io_objects
-            .insert(IoObject::new_writer(response_writer))
-            .try_into()
-            .unwrap();
-        server.queue_request(method_name, server_request_io, server_response_io);
+        let (server, _in_flight) = match SERVICE_REGISTRY.pick_server(rpc_client.service_name()) {
+            Some(picked) => picked,
+            // The service's last server has exited. Dropping the server's ends refuses the request.
+            None => return Ok((client_request_io, client_response_io)),
+        };
+        match server {
+            // Guests only finish the requests they already have once the host is shutting down.
+            // Dropping the server's ends tells the client that this one was refused. Native
+            // services keep serving, so that guests can still save their state.
+            RpcServerRef::Guest { process, .. } if process.is_host_shutting_down() => {}
+            RpcServerRef::Guest {
+                process,
+                rpc_server,
+            } => {
+                let mut server_process_inner = process.inner.lock().unwrap();
+                let server_process_inner = &mut *server_process_inner;
+                let server = &mut server_process_inner.rpc_servers[rpc_server as _];
+                let server_request_io = server_process_inner
+                    .io_objects
+                    .insert(IoObject::new_reader(request_reader))
+                    .try_into()
+                    .unwrap();
+                let server_response_io = server_process_inner
+                    .io_objects
+                    .insert(IoObject::new_writer(response_writer))
+                    .try_into()
+                    .unwrap();
+                server_process_inner
+                    .pending_responses
+                    .insert(server_response_io);
+                server.queue_request(method_name, server_request_io, server_response_io);
+            }
+            RpcServerRef::Native(service) => {
+                let request = NativeRequest {
+                    caller_pid: arc_self.pid,
+                    caller_principal: arc_self.principal().to_owned(),
+                    method_name: method_name.to_owned(),
+                    request: HostPipeReader::new(request_reader),
+                    response: HostPipeWriter::new(response_writer),
+                };
+                tokio::spawn(async move { service.handle(request).await });
+            }
+        }
 
         Ok((client_request_io, client_response_io))
     }
@@ -200,6 +612,7 @@ impl Process {
         let id = inner
             .rpc_servers
             .insert(RpcServer::new(
+                params.service_name.clone(),
                 method_names,
                 arc_self.wake_queue_sender.clone(),
             ))
@@ -207,7 +620,7 @@ impl Process {
             .unwrap();
         SERVICE_REGISTRY.register(
             params.service_name.clone(),
-            RpcServerRef {
+            RpcServerRef::Guest {
                 process: PointerIdentityArc::new(Arc::clone(arc_self)),
                 rpc_server: id,
             },
@@ -227,4 +640,137 @@ impl Process {
             .ok_or_else(|| Trap::new("bad RPC server handle"))?;
         Ok(rpc_server.get_request(task_id))
     }
+
+    pub fn fs_open(&self, task_id: TaskId, path: String) -> u32 {
+        let (reader, writer) = pipe();
+        let io = self.insert_io_object(IoObject::new_reader(reader));
+        self.spawn_fs_task(task_id, move |namespace, wake| async move {
+            let writer = HostPipeWriter::new(writer);
+            match namespace.open(&path).await {
+                Ok(file) => {
+                    wake(Ok(()));
+                    copy_file_to_pipe(file, writer).await;
+                }
+                Err(e) => wake(Err(e)),
+            }
+        });
+        io
+    }
+
+    pub fn fs_create(&self, task_id: TaskId, path: String) -> u32 {
+        let (reader, writer) = pipe();
+        let (status_sender, status_receiver) = oneshot::channel();
+        let io = self.insert_io_object(IoObject::new_writer(writer).with_status(status_receiver));
+        self.spawn_fs_task(task_id, move |namespace, wake| async move {
+            let reader = HostPipeReader::new(reader);
+            match namespace.create(&path).await {
+                Ok(file) => {
+                    wake(Ok(()));
+                    let status = match copy_pipe_to_file(reader, file).await {
+                        Ok(()) => 0,
+                        Err(e) => e.status(),
+                    };
+                    // The guest only asks for the status if it calls fs_close().
+                    let _ = status_sender.send(status);
+                }
+                Err(e) => wake(Err(e)),
+            }
+        });
+        io
+    }
+
+    /// Closes a handle from [`fs_create`](Self::fs_create), and wakes `task_id` with the status of
+    /// the file once everything written to it has been stored.
+    pub fn fs_close(&self, task_id: TaskId, io: u32) -> Result<(), Trap> {
+        let mut io_object = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner
+                .io_objects
+                .get(io as _)
+                .is_some_and(IoObject::has_status)
+            {
+                return Err(Trap::new("bad file handle"));
+            }
+            inner.io_objects.remove(io as _)
+        };
+        let status = io_object.take_status().unwrap();
+        io_object.close();
+        let wake_queue_sender = self.wake_queue_sender.clone();
+        tokio::spawn(async move {
+            // The file was never created if the sender is gone, and the guest was told so then.
+            let param = status
+                .await
+                .unwrap_or_else(|_| FsError::Unavailable.status());
+            // The process may have exited in the meantime.
+            let _ = wake_queue_sender.send(WakeParams { task_id, param });
+        });
+        Ok(())
+    }
+
+    pub fn fs_stat(&self, task_id: TaskId, path: String) -> u32 {
+        let (reader, writer) = pipe();
+        let io = self.insert_io_object(IoObject::new_reader(reader));
+        self.spawn_fs_task(task_id, move |namespace, wake| async move {
+            let writer = HostPipeWriter::new(writer);
+            match namespace.stat(&path).await {
+                Ok(stat) => {
+                    wake(Ok(()));
+                    write_stats_to_pipe(&[stat], writer).await;
+                }
+                Err(e) => wake(Err(e)),
+            }
+        });
+        io
+    }
+
+    pub fn fs_readdir(&self, task_id: TaskId, path: String) -> u32 {
+        let (reader, writer) = pipe();
+        let io = self.insert_io_object(IoObject::new_reader(reader));
+        self.spawn_fs_task(task_id, move |namespace, wake| async move {
+            let writer = HostPipeWriter::new(writer);
+            match namespace.read_dir(&path).await {
+                Ok(stats) => {
+                    wake(Ok(()));
+                    write_stats_to_pipe(&stats, writer).await;
+                }
+                Err(e) => wake(Err(e)),
+            }
+        });
+        io
+    }
+
+    fn insert_io_object(&self, io_object: IoObject) -> u32 {
+        self.inner
+            .lock()
+            .unwrap()
+            .io_objects
+            .insert(io_object)
+            .try_into()
+            .unwrap()
+    }
+
+    /// Runs a filesystem operation on the host. The operation reports its outcome through the
+    /// provided callback, which wakes `task_id` with a status of zero or an [`FsError`] code.
+    fn spawn_fs_task<F, Fut>(&self, task_id: TaskId, f: F)
+    where
+        F: FnOnce(Arc<dyn Namespace>, Box<dyn FnOnce(Result<(), FsError>) + Send>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let wake_queue_sender = self.wake_queue_sender.clone();
+        let wake = Box::new(move |result: Result<(), FsError>| {
+            let param = match result {
+                Ok(()) => 0,
+                Err(e) => e.status(),
+            };
+            // The process may have exited while the operation ran.
+            let _ = wake_queue_sender.send(WakeParams { task_id, param });
+        });
+
+        match self.namespace.as_ref() {
+            Some(namespace) => {
+                tokio::spawn(f(Arc::clone(namespace), wake));
+            }
+            None => wake(Err(FsError::Unavailable)),
+        }
+    }
 }
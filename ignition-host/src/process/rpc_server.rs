@@ -8,6 +8,7 @@ use crate::interop::rpc::RpcMetadata;
 use crate::{TaskId, WakeParams};
 
 pub struct RpcServer {
+    service_name: String,
     waiting_task_ids: HashSet<TaskId>,
     method_index_by_name: HashMap<String, u32>,
     wake_queue_sender: UnboundedSender<WakeParams>,
@@ -15,8 +16,13 @@ pub struct RpcServer {
 }
 
 impl RpcServer {
-    pub fn new(method_names: Vec<String>, wake_queue_sender: UnboundedSender<WakeParams>) -> Self {
+    pub fn new(
+        service_name: String,
+        method_names: Vec<String>,
+        wake_queue_sender: UnboundedSender<WakeParams>,
+    ) -> Self {
         Self {
+            service_name,
             waiting_task_ids: HashSet::new(),
             method_index_by_name: method_names
                 .into_iter()
@@ -28,6 +34,28 @@ impl RpcServer {
         }
     }
 
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    /// Returns the method names in the order the guest gave them, which is the order their indices
+    /// refer to.
+    pub fn method_names(&self) -> Vec<String> {
+        let mut method_names: Vec<_> = self.method_index_by_name.iter().collect();
+        method_names.sort_unstable_by_key(|(_, &index)| index);
+        method_names
+            .into_iter()
+            .map(|(method_name, _)| method_name.clone())
+            .collect()
+    }
+
+    /// The tasks waiting for a request, if none are queued.
+    pub fn waiting_task_ids(&self) -> Vec<TaskId> {
+        let mut task_ids: Vec<_> = self.waiting_task_ids.iter().copied().collect();
+        task_ids.sort_unstable();
+        task_ids
+    }
+
     pub fn get_request(&mut self, task_id: TaskId) -> Poll<RpcMetadata> {
         if let Some(request) = self.request_queue.pop() {
             Poll::Ready(request)
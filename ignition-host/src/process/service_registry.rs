@@ -1,23 +1,116 @@
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::Poll;
 
+use futures::FutureExt;
 use lazy_static::lazy_static;
+use tokio::sync::futures::Notified;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Notify;
 
-use crate::process::process::Process;
+use crate::process::process::{InFlightRequest, Process};
+use crate::service::NativeService;
 use crate::util::pointer_identity_arc::PointerIdentityArc;
 use crate::{TaskId, WakeParams};
 
 #[derive(Default)]
 pub struct ServiceRegistry {
     inner: Mutex<InnerServiceRegistry>,
+    /// Notified whenever servers register or unregister.
+    changed: Notify,
 }
 
 #[derive(Default)]
 struct InnerServiceRegistry {
     servers_by_service_name: HashMap<String, HashSet<RpcServerRef>>,
     tasks_waiting_by_service_name: HashMap<String, HashSet<ProcessTask>>,
+    watchers: Vec<Watcher>,
+}
+
+/// How many events a watcher can fall behind by before it misses some and has to resync.
+const WATCH_QUEUE_CAPACITY: usize = 256;
+
+struct Watcher {
+    events: Sender<RegistryEvent>,
+    /// Set when an event didn't fit in the queue. Until the watcher resyncs, it's sent no more.
+    lagged: Arc<AtomicBool>,
+}
+
+/// A server joining or leaving a service, as seen by [`ServiceRegistry::watch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistryEvent {
+    pub service_name: String,
+    pub change: RegistryChange,
+    /// How many servers the service has after the change.
+    pub instances: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryChange {
+    Joined,
+    Left,
+}
+
+impl InnerServiceRegistry {
+    fn publish(&mut self, service_name: &str, change: RegistryChange, instances: usize) {
+        let event = RegistryEvent {
+            service_name: service_name.to_owned(),
+            change,
+            instances,
+        };
+        self.watchers.retain(|watcher| {
+            if watcher.lagged.load(Ordering::SeqCst) {
+                return !watcher.events.is_closed();
+            }
+            match watcher.events.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    watcher.lagged.store(true, Ordering::SeqCst);
+                    true
+                }
+                // A watcher that has gone away is forgotten.
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+/// Follows the registry's changes, as returned by [`ServiceRegistry::watch`].
+pub struct RegistryWatch {
+    registry: Arc<ServiceRegistry>,
+    events: Receiver<RegistryEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// What a [`RegistryWatch`] sees next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    Changed(RegistryEvent),
+    /// The watcher fell behind and missed changes. These are the services that have servers now,
+    /// as [`ServiceRegistry::list`] returns them, and changes follow from here.
+    Resync(Vec<(String, usize)>),
+}
+
+impl RegistryWatch {
+    /// Waits for the next change. A watcher that has missed changes gets them all at once as a
+    /// [`WatchEvent::Resync`], after the ones it didn't miss.
+    pub async fn recv(&mut self) -> WatchEvent {
+        if let Some(Some(event)) = self.events.recv().now_or_never() {
+            return WatchEvent::Changed(event);
+        }
+        {
+            // Under the lock, so that no change is both missed and left out of the list.
+            let inner = self.registry.inner.lock().unwrap();
+            if self.lagged.swap(false, Ordering::SeqCst) {
+                return WatchEvent::Resync(list(&inner));
+            }
+        }
+        // The registry holds the sender for as long as the watch exists.
+        WatchEvent::Changed(self.events.recv().await.unwrap())
+    }
 }
 
 impl ServiceRegistry {
@@ -42,11 +135,16 @@ impl ServiceRegistry {
         }
 
         // Add this server to the registry.
-        inner
+        let is_new = inner
             .servers_by_service_name
-            .entry(service_name)
+            .entry(service_name.clone())
             .or_default()
             .insert(rpc_server_ref);
+        if is_new {
+            let instances = inner.servers_by_service_name[&service_name].len();
+            inner.publish(&service_name, RegistryChange::Joined, instances);
+        }
+        self.changed.notify_waiters();
     }
 
     pub fn wait_for_server(
@@ -72,18 +170,174 @@ impl ServiceRegistry {
         }
     }
 
-    pub fn pick_server_or_die(&self, service_name: &str) -> RpcServerRef {
+    /// Picks a server for a request to `service_name`, or returns None if it has none, as it won't
+    /// once its last server has exited. A guest server's process counts the request as pending
+    /// until the returned guard is dropped, so that one picked just before
+    /// [`unregister_servers`](Self::unregister_servers) isn't lost while it's queued.
+    pub fn pick_server(
+        &self,
+        service_name: &str,
+    ) -> Option<(RpcServerRef, Option<InFlightRequest>)> {
+        let inner = self.inner.lock().unwrap();
+        let server = inner
+            .servers_by_service_name
+            .get(service_name)
+            .and_then(|set| set.iter().next())?
+            .clone();
+        let in_flight = match &server {
+            RpcServerRef::Guest { process, .. } => Some(process.begin_request()),
+            RpcServerRef::Native(_) => None,
+        };
+        Some((server, in_flight))
+    }
+
+    /// Returns every service that has servers, with how many it has, ordered by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        list(&self.inner.lock().unwrap())
+    }
+
+    /// Returns the services that have servers, as [`list`](Self::list) does, along with a watch
+    /// for every change after that. Each watch queues a bounded number of changes, and one that
+    /// falls further behind resyncs instead of holding on to more.
+    pub fn watch(self: &Arc<Self>) -> (Vec<(String, usize)>, RegistryWatch) {
+        let mut inner = self.inner.lock().unwrap();
+        let (sender, receiver) = channel(WATCH_QUEUE_CAPACITY);
+        let lagged = Arc::new(AtomicBool::new(false));
+        inner.watchers.push(Watcher {
+            events: sender,
+            lagged: Arc::clone(&lagged),
+        });
+        let watch = RegistryWatch {
+            registry: Arc::clone(self),
+            events: receiver,
+            lagged,
+        };
+        (list(&inner), watch)
+    }
+
+    /// Completes the next time, after the call, that servers register or unregister.
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// Returns the names of the services that `process` has servers for.
+    pub fn services_served_by(&self, process: &Arc<Process>) -> BTreeSet<String> {
         self.inner
             .lock()
             .unwrap()
             .servers_by_service_name
-            .get(service_name)
-            .and_then(|set| set.iter().next())
-            .unwrap_or_else(|| panic!("RPC service {:?} has no servers", service_name))
-            .clone()
+            .iter()
+            .filter(|(_, servers)| servers.iter().any(|server| server.is_in(process)))
+            .map(|(service_name, _)| service_name.clone())
+            .collect()
+    }
+
+    /// Returns the tasks of `process` that are waiting for a service to get its first server.
+    pub fn tasks_waiting_in(&self, process: &Arc<Process>) -> Vec<(String, TaskId)> {
+        let inner = self.inner.lock().unwrap();
+        let mut tasks: Vec<_> = inner
+            .tasks_waiting_by_service_name
+            .iter()
+            .flat_map(|(service_name, process_tasks)| {
+                process_tasks
+                    .iter()
+                    .filter(|entry| Arc::ptr_eq(&entry.process, process))
+                    .map(move |entry| (service_name.clone(), entry.task_id))
+            })
+            .collect();
+        tasks.sort_unstable();
+        tasks
+    }
+
+    /// Stops routing new requests to `process`. Requests it has already been given are unaffected.
+    pub fn unregister_servers(&self, process: &Arc<Process>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut departures = Vec::new();
+        inner
+            .servers_by_service_name
+            .retain(|service_name, servers| {
+                let count = servers.len();
+                servers.retain(|server| !server.is_in(process));
+                for instances in (servers.len()..count).rev() {
+                    departures.push((service_name.clone(), instances));
+                }
+                // Requests to a service with no servers are refused. Only clients waiting to be
+                // healthy wait for another server to register.
+                !servers.is_empty()
+            });
+        departures.sort_by(|a, b| a.0.cmp(&b.0));
+        for (service_name, instances) in departures {
+            inner.publish(&service_name, RegistryChange::Left, instances);
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Returns every service with servers or waiting clients, ordered by name.
+    pub fn dump(&self) -> Vec<RegistryEntry> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries = BTreeMap::<&str, RegistryEntry>::new();
+        for (service_name, servers) in &inner.servers_by_service_name {
+            entries.entry(service_name).or_default().servers = servers
+                .iter()
+                .map(|server| match server {
+                    RpcServerRef::Guest {
+                        process,
+                        rpc_server,
+                    } => Some((process.pid(), *rpc_server)),
+                    RpcServerRef::Native(_) => None,
+                })
+                .collect();
+        }
+        for (service_name, process_tasks) in &inner.tasks_waiting_by_service_name {
+            entries.entry(service_name).or_default().waiting_pids = process_tasks
+                .iter()
+                .map(|entry| entry.process.pid())
+                .collect();
+        }
+        entries
+            .into_iter()
+            .map(|(service_name, mut entry)| {
+                entry.service_name = service_name.to_owned();
+                entry.servers.sort_unstable();
+                entry.waiting_pids.sort_unstable();
+                entry.waiting_pids.dedup();
+                entry
+            })
+            .collect()
+    }
+
+    /// Forgets a process that has exited.
+    pub fn remove_process(&self, process: &Arc<Process>) {
+        self.unregister_servers(process);
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .tasks_waiting_by_service_name
+            .retain(|_, process_tasks| {
+                process_tasks.retain(|entry| !Arc::ptr_eq(&entry.process, process));
+                !process_tasks.is_empty()
+            });
     }
 }
 
+fn list(inner: &InnerServiceRegistry) -> Vec<(String, usize)> {
+    let mut services: Vec<_> = inner
+        .servers_by_service_name
+        .iter()
+        .map(|(service_name, servers)| (service_name.clone(), servers.len()))
+        .collect();
+    services.sort_unstable();
+    services
+}
+
+/// The registry's view of one service, as returned by [`ServiceRegistry::dump`].
+#[derive(Clone, Debug, Default)]
+pub struct RegistryEntry {
+    pub service_name: String,
+    /// The pid and RPC server handle of each guest server, or None for a native service.
+    pub servers: Vec<Option<(usize, u32)>>,
+    pub waiting_pids: Vec<usize>,
+}
+
 #[derive(Hash, PartialEq, Eq)]
 struct ProcessTask {
     process: PointerIdentityArc<Process>,
@@ -91,11 +345,269 @@ struct ProcessTask {
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
-pub struct RpcServerRef {
-    pub process: PointerIdentityArc<Process>,
-    pub rpc_server: u32,
+pub enum RpcServerRef {
+    /// An RPC server created by a guest process.
+    Guest {
+        process: PointerIdentityArc<Process>,
+        rpc_server: u32,
+    },
+    /// A service implemented by the host itself.
+    Native(PointerIdentityArc<dyn NativeService>),
+}
+
+impl RpcServerRef {
+    fn is_in(&self, process: &Arc<Process>) -> bool {
+        match self {
+            RpcServerRef::Guest {
+                process: server_process,
+                ..
+            } => Arc::ptr_eq(server_process, process),
+            RpcServerRef::Native(_) => false,
+        }
+    }
 }
 
 lazy_static! {
     pub static ref SERVICE_REGISTRY: Arc<ServiceRegistry> = Default::default();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::Poll;
+
+    use futures::FutureExt;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::{RegistryChange, WatchEvent, SERVICE_REGISTRY, WATCH_QUEUE_CAPACITY};
+    use crate::interop::rpc::{RpcServerMethodParams, RpcServerParams};
+    use crate::process::process::Process;
+    use crate::{TaskId, WakeParams};
+
+    const SERVICE_NAME: &str = "UpgradeTestService";
+
+    fn process(pid: usize) -> (Arc<Process>, UnboundedReceiver<WakeParams>) {
+        let (process, wake_queue_receiver) = Process::new(pid, "test".to_owned(), None);
+        (Arc::new(process), wake_queue_receiver)
+    }
+
+    fn serve(process: &Arc<Process>) -> u32 {
+        Process::rpc_server_create(
+            process,
+            &RpcServerParams {
+                service_name: SERVICE_NAME.to_owned(),
+                methods: vec![RpcServerMethodParams {
+                    method_name: "get".to_owned(),
+                }],
+            },
+        )
+    }
+
+    #[test]
+    fn hands_service_over_between_processes() {
+        let (client, _client_wakes) = process(0);
+        let rpc_client = client.rpc_client_create(SERVICE_NAME.to_owned());
+        let (old, _old_wakes) = process(1);
+        let old_server = serve(&old);
+        Process::rpc_client_request(&client, rpc_client, "get").unwrap();
+        assert!(old.has_pending_requests());
+
+        let (new, _new_wakes) = process(2);
+        let changed = SERVICE_REGISTRY.changed();
+        serve(&new);
+        assert!(changed.now_or_never().is_some());
+        assert!(SERVICE_REGISTRY
+            .services_served_by(&new)
+            .is_superset(&SERVICE_REGISTRY.services_served_by(&old)));
+
+        SERVICE_REGISTRY.unregister_servers(&old);
+        assert!(SERVICE_REGISTRY.services_served_by(&old).is_empty());
+        Process::rpc_client_request(&client, rpc_client, "get").unwrap();
+        assert!(new.has_pending_requests());
+
+        // The old process still finishes the request it was given.
+        let request = match old.rpc_server_get_request(TaskId(0), old_server).unwrap() {
+            Poll::Ready(request) => request,
+            Poll::Pending => panic!("request wasn't queued"),
+        };
+        let drained = old.drained();
+        old.io_close(request.response_io).unwrap();
+        assert!(!old.has_pending_requests());
+        assert!(drained.now_or_never().is_some());
+
+        SERVICE_REGISTRY.remove_process(&new);
+        assert!(SERVICE_REGISTRY.services_served_by(&new).is_empty());
+    }
+
+    #[test]
+    fn counts_requests_picked_before_unregistering() {
+        const PICK_SERVICE_NAME: &str = "PickTestService";
+        let (old, _old_wakes) = process(4);
+        Process::rpc_server_create(
+            &old,
+            &RpcServerParams {
+                service_name: PICK_SERVICE_NAME.to_owned(),
+                methods: Vec::new(),
+            },
+        );
+        let (_, in_flight) = SERVICE_REGISTRY.pick_server(PICK_SERVICE_NAME).unwrap();
+        SERVICE_REGISTRY.unregister_servers(&old);
+        // The request hasn't reached the old process's queue, but draining must still wait for it.
+        assert!(old.has_pending_requests());
+        let drained = old.drained();
+        drop(in_flight);
+        assert!(!old.has_pending_requests());
+        assert!(drained.now_or_never().is_some());
+    }
+
+    #[test]
+    fn refuses_requests_once_the_last_server_exits() {
+        const GONE_SERVICE_NAME: &str = "GoneTestService";
+        let (client, _client_wakes) = process(6);
+        let rpc_client = client.rpc_client_create(GONE_SERVICE_NAME.to_owned());
+        let (server, _server_wakes) = process(7);
+        Process::rpc_server_create(
+            &server,
+            &RpcServerParams {
+                service_name: GONE_SERVICE_NAME.to_owned(),
+                methods: vec![],
+            },
+        );
+        SERVICE_REGISTRY.remove_process(&server);
+
+        // The client still gets its ends of the request, which no server holds the other ends of.
+        Process::rpc_client_request(&client, rpc_client, "get").unwrap();
+        assert_eq!(client.handles().io_objects, 2);
+        assert!(!server.has_pending_requests());
+        // The registry is still usable.
+        assert!(!SERVICE_REGISTRY
+            .list()
+            .iter()
+            .any(|(service_name, _)| service_name == GONE_SERVICE_NAME));
+    }
+
+    #[test]
+    fn dumps_servers_and_waiting_clients() {
+        const DUMP_SERVICE_NAME: &str = "DumpTestService";
+        let (client, _client_wakes) = process(3);
+        assert_eq!(
+            SERVICE_REGISTRY.wait_for_server(&client, TaskId(7), DUMP_SERVICE_NAME.into()),
+            Poll::Pending
+        );
+        let entry = SERVICE_REGISTRY
+            .dump()
+            .into_iter()
+            .find(|entry| entry.service_name == DUMP_SERVICE_NAME)
+            .unwrap();
+        assert!(entry.servers.is_empty());
+        assert_eq!(entry.waiting_pids, [3]);
+
+        let (server, _server_wakes) = process(4);
+        let rpc_server = Process::rpc_server_create(
+            &server,
+            &RpcServerParams {
+                service_name: DUMP_SERVICE_NAME.to_owned(),
+                methods: vec![],
+            },
+        );
+        let entry = SERVICE_REGISTRY
+            .dump()
+            .into_iter()
+            .find(|entry| entry.service_name == DUMP_SERVICE_NAME)
+            .unwrap();
+        assert_eq!(entry.servers, [Some((4, rpc_server))]);
+        assert!(entry.waiting_pids.is_empty());
+
+        SERVICE_REGISTRY.remove_process(&server);
+        SERVICE_REGISTRY.remove_process(&client);
+    }
+
+    #[test]
+    fn watchers_see_servers_join_and_leave() {
+        const WATCH_SERVICE_NAME: &str = "WatchTestService";
+        let (server, _server_wakes) = process(5);
+        let create_server = || {
+            Process::rpc_server_create(
+                &server,
+                &RpcServerParams {
+                    service_name: WATCH_SERVICE_NAME.to_owned(),
+                    methods: vec![],
+                },
+            )
+        };
+        create_server();
+        let (services, mut events) = SERVICE_REGISTRY.watch();
+        assert!(services.contains(&(WATCH_SERVICE_NAME.to_owned(), 1)));
+
+        create_server();
+        SERVICE_REGISTRY.remove_process(&server);
+        let mut seen = Vec::new();
+        while let Some(event) = events.recv().now_or_never() {
+            match event {
+                WatchEvent::Changed(event) if event.service_name == WATCH_SERVICE_NAME => {
+                    seen.push((event.change, event.instances));
+                }
+                WatchEvent::Changed(_) => {}
+                WatchEvent::Resync(_) => panic!("watcher fell behind"),
+            }
+        }
+        assert_eq!(
+            seen,
+            [
+                (RegistryChange::Joined, 2),
+                (RegistryChange::Left, 1),
+                (RegistryChange::Left, 0),
+            ]
+        );
+        assert!(!SERVICE_REGISTRY
+            .list()
+            .iter()
+            .any(|(service_name, _)| service_name == WATCH_SERVICE_NAME));
+    }
+
+    #[test]
+    fn watchers_that_fall_behind_resync() {
+        const LAG_SERVICE_NAME: &str = "LagTestService";
+        let (server, _server_wakes) = process(6);
+        let (_, mut events) = SERVICE_REGISTRY.watch();
+        for _ in 0..WATCH_QUEUE_CAPACITY + 10 {
+            Process::rpc_server_create(
+                &server,
+                &RpcServerParams {
+                    service_name: LAG_SERVICE_NAME.to_owned(),
+                    methods: vec![],
+                },
+            );
+        }
+
+        // Other tests' changes may fill some of the queue too.
+        let mut last_instances = 0;
+        let services = loop {
+            match events.recv().now_or_never().unwrap() {
+                WatchEvent::Changed(event) => {
+                    if event.service_name == LAG_SERVICE_NAME {
+                        assert_eq!(event.instances, last_instances + 1);
+                        last_instances = event.instances;
+                    }
+                }
+                WatchEvent::Resync(services) => break services,
+            }
+        };
+        assert!(last_instances <= WATCH_QUEUE_CAPACITY);
+        assert!(services.contains(&(LAG_SERVICE_NAME.to_owned(), WATCH_QUEUE_CAPACITY + 10)));
+
+        // Changes after the resync arrive as usual.
+        SERVICE_REGISTRY.remove_process(&server);
+        loop {
+            match events.recv().now_or_never().unwrap() {
+                WatchEvent::Changed(event) if event.service_name == LAG_SERVICE_NAME => {
+                    assert_eq!(event.change, RegistryChange::Left);
+                    assert_eq!(event.instances, WATCH_QUEUE_CAPACITY + 9);
+                    break;
+                }
+                WatchEvent::Changed(_) => {}
+                WatchEvent::Resync(_) => panic!("resynced again"),
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Gives guests access to an `ignition-blob` content-addressed store.
+//!
+//! Blobs are named by IDs in the text form `blake3:<64 hex digits>`. Responses begin with a
+//! [`Status`] byte.
+//!
+//! - `put`: the request body is the blob's content. After an `Ok` status, the response is the blob's
+//!   ID.
+//! - `get`: the request body is a blob ID. After an `Ok` status, the response is the blob's length as
+//!   a little-endian `u64`, followed by its content. Content that ends early means the store failed
+//!   part way through.
+//!
+//! Content is streamed between the guest's pipes and the store in chunks, so neither end holds a
+//! whole blob in memory.
+
+use std::fmt::Write;
+
+use async_trait::async_trait;
+use ignition_blob_proto::blob_pb::blob_service_client::BlobServiceClient;
+use ignition_blob_proto::blob_pb::{BlobId, GetRequest, HashAlgorithm, PutRequest};
+use log::{error, warn};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::codegen::{Body, HttpBody, StdError};
+use tonic::Code;
+
+use crate::service::{NativeRequest, NativeService};
+
+/// How much content is moved at a time in either direction.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The longest request a `get` accepts, which is more than any valid ID.
+const MAX_ID_LENGTH: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    InvalidRequest = 2,
+    /// The blob store couldn't be reached or failed.
+    Unavailable = 3,
+}
+
+/// Parses a blob ID from its text form.
+pub fn parse_blob_id(s: &str) -> Option<BlobId> {
+    let hex = s.strip_prefix("blake3:")?;
+    // from_str_radix alone would let a sign through.
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hash = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(BlobId {
+        algorithm: HashAlgorithm::Blake3 as i32,
+        hash,
+    })
+}
+
+/// Formats a blob ID in the text form [`parse_blob_id`] accepts.
+pub fn format_blob_id(id: &BlobId) -> String {
+    let mut s = String::from("blake3:");
+    for b in &id.hash {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+/// Serves guest requests from a blob store reached through `client`, which is usually a network
+/// connection to an `ignition-blob` server.
+pub struct BlobService<T> {
+    client: BlobServiceClient<T>,
+}
+
+impl<T> BlobService<T> {
+    pub const SERVICE_NAME: &'static str = "BlobService";
+
+    pub fn new(client: BlobServiceClient<T>) -> Self {
+        Self { client }
+    }
+}
+
+impl<T> BlobService<T>
+where
+    T: GrpcService<BoxBody> + Clone + Send + Sync + 'static,
+    T::Future: Send,
+    T::ResponseBody: Body + HttpBody + Send + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as HttpBody>::Error: Into<StdError> + Send,
+{
+    async fn put(&self, request: &mut NativeRequest) -> Result<Vec<u8>, Status> {
+        let (sender, receiver) = mpsc::channel(1);
+        let reader = &mut request.request;
+        let send_content = async move {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                let n = reader.read(&mut chunk).await;
+                if n == 0 {
+                    break;
+                }
+                let data = chunk[..n].to_vec();
+                if sender.send(PutRequest { data }).await.is_err() {
+                    // The store gave up on the request.
+                    break;
+                }
+            }
+        };
+        let mut client = self.client.clone();
+        let ((), result) = tokio::join!(send_content, client.put(ReceiverStream::new(receiver)));
+
+        let id = result
+            .map_err(|status| self.log_error(request, &status))?
+            .into_inner()
+            .id
+            .ok_or(Status::Unavailable)?;
+        Ok(format_blob_id(&id).into_bytes())
+    }
+
+    async fn get(&self, request: &mut NativeRequest) -> Result<(), Status> {
+        let mut id = Vec::new();
+        let mut chunk = [0; MAX_ID_LENGTH];
+        loop {
+            let n = request.request.read(&mut chunk).await;
+            if n == 0 {
+                break;
+            }
+            id.extend_from_slice(&chunk[..n]);
+            if id.len() > MAX_ID_LENGTH {
+                return Err(Status::InvalidRequest);
+            }
+        }
+        let id = std::str::from_utf8(&id)
+            .ok()
+            .and_then(parse_blob_id)
+            .ok_or(Status::InvalidRequest)?;
+
+        let mut client = self.client.clone();
+        let mut stream = client
+            .get(GetRequest {
+                id: Some(id),
+                max_chunk_size: CHUNK_SIZE as u64,
+            })
+            .await
+            .map_err(|status| match status.code() {
+                Code::NotFound => Status::NotFound,
+                _ => self.log_error(request, &status),
+            })?
+            .into_inner();
+
+        let mut header_written = false;
+        loop {
+            let message = match stream.message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(()),
+                Err(status) => {
+                    let status = self.log_error(request, &status);
+                    return if header_written { Ok(()) } else { Err(status) };
+                }
+            };
+            let response = &mut request.response;
+            let written = if header_written {
+                response.write_all(&message.data).await
+            } else {
+                // The first message carries only the length.
+                header_written = true;
+                let mut header = vec![Status::Ok as u8];
+                header.extend_from_slice(&message.total_byte_length.to_le_bytes());
+                header.extend_from_slice(&message.data);
+                response.write_all(&header).await
+            };
+            if !written {
+                // The guest stopped reading.
+                return Ok(());
+            }
+        }
+    }
+
+    fn log_error(&self, request: &NativeRequest, status: &tonic::Status) -> Status {
+        error!(
+            "pid {}: {} {} failed: {}",
+            request.caller_pid,
+            Self::SERVICE_NAME,
+            request.method_name,
+            status
+        );
+        Status::Unavailable
+    }
+}
+
+#[async_trait]
+impl<T> NativeService for BlobService<T>
+where
+    T: GrpcService<BoxBody> + Clone + Send + Sync + 'static,
+    T::Future: Send,
+    T::ResponseBody: Body + HttpBody + Send + 'static,
+    T::Error: Into<StdError>,
+    <T::ResponseBody as HttpBody>::Error: Into<StdError> + Send,
+{
+    async fn handle(&self, mut request: NativeRequest) {
+        let response = match &*request.method_name {
+            "put" => self.put(&mut request).await.map(|id| {
+                let mut response = vec![Status::Ok as u8];
+                response.extend_from_slice(&id);
+                response
+            }),
+            // A successful `get` streams its own response.
+            "get" => self.get(&mut request).await.map(|()| Vec::new()),
+            method_name => {
+                warn!(
+                    "pid {}: {} has no method {:?}",
+                    request.caller_pid,
+                    Self::SERVICE_NAME,
+                    method_name
+                );
+                Err(Status::InvalidRequest)
+            }
+        };
+        let response = response.unwrap_or_else(|status| vec![status as u8]);
+        request.response.write_all(&response).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ignition_blob::BlobServiceImpl;
+    use ignition_blob_proto::blob_pb::blob_service_client::BlobServiceClient;
+    use ignition_blob_proto::blob_pb::blob_service_server::BlobServiceServer;
+    use testable_file_system::InMemoryFileSystem;
+
+    use super::{parse_blob_id, BlobService, Status, CHUNK_SIZE};
+    use crate::service::tests::call;
+
+    #[tokio::test]
+    async fn puts_and_gets_blobs() {
+        let service = BlobService::new(BlobServiceClient::new(BlobServiceServer::new(
+            BlobServiceImpl::new(InMemoryFileSystem::new()),
+        )));
+
+        // Big enough to take several chunks each way.
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let response = call(&service, "test", "put", &content).await;
+        assert_eq!(response[0], Status::Ok as u8);
+        let id = &response[1..];
+        assert!(parse_blob_id(std::str::from_utf8(id).unwrap()).is_some());
+
+        let response = call(&service, "test", "get", id).await;
+        assert_eq!(response[0], Status::Ok as u8);
+        assert_eq!(response[1..9], (content.len() as u64).to_le_bytes());
+        assert_eq!(response[9..], content[..]);
+
+        let missing = format!("blake3:{}", "00".repeat(32));
+        assert_eq!(
+            call(&service, "test", "get", missing.as_bytes()).await,
+            [Status::NotFound as u8]
+        );
+        assert_eq!(
+            call(&service, "test", "get", b"sha1:1234").await,
+            [Status::InvalidRequest as u8]
+        );
+        assert_eq!(
+            call(&service, "test", "delete", id).await,
+            [Status::InvalidRequest as u8]
+        );
+    }
+
+    #[test]
+    fn parses_only_exact_ids() {
+        let id = format!("blake3:{}", "0f".repeat(32));
+        assert_eq!(parse_blob_id(&id).unwrap().hash, vec![0x0f; 32]);
+        assert!(parse_blob_id(&format!("blake3:{}", "0F".repeat(32))).is_some());
+        assert!(parse_blob_id(&format!("blake3:+f{}", "00".repeat(31))).is_none());
+        assert!(parse_blob_id(&format!("blake3:{}", "0".repeat(63))).is_none());
+        assert!(parse_blob_id(&format!("blake3:{}g", "0".repeat(63))).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::warn;
+
+use crate::service::{NativeRequest, NativeService};
+
+/// Serves read-only configuration given to the host on its command line.
+///
+/// Methods:
+/// - `get`: the request body is a key. The response is its value, or empty if it isn't set.
+/// - `list`: the response is a `KEY=VALUE` line for every entry, sorted by key.
+pub struct ConfigService {
+    entries: BTreeMap<String, String>,
+}
+
+impl ConfigService {
+    pub const SERVICE_NAME: &'static str = "ConfigService";
+
+    pub fn new(entries: BTreeMap<String, String>) -> Self {
+        Self { entries }
+    }
+}
+
+#[async_trait]
+impl NativeService for ConfigService {
+    async fn handle(&self, mut request: NativeRequest) {
+        let response = match &*request.method_name {
+            "get" => {
+                let key = request.request.read_to_end().await;
+                String::from_utf8(key)
+                    .ok()
+                    .and_then(|key| self.entries.get(&key))
+                    .cloned()
+                    .unwrap_or_default()
+            }
+            "list" => self
+                .entries
+                .iter()
+                .map(|(key, value)| format!("{}={}\n", key, value))
+                .collect(),
+            method_name => {
+                warn!(
+                    "pid {}: {} has no method {:?}",
+                    request.caller_pid,
+                    Self::SERVICE_NAME,
+                    method_name
+                );
+                return;
+            }
+        };
+        request.response.write_all(response.as_bytes()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::ConfigService;
+    use crate::service::tests::call;
+
+    #[tokio::test]
+    async fn serves_entries() {
+        let service = ConfigService::new(BTreeMap::from([
+            ("region".to_owned(), "north".to_owned()),
+            ("debug".to_owned(), "1".to_owned()),
+        ]));
+        assert_eq!(call(&service, "test", "get", b"region").await, b"north");
+        assert_eq!(call(&service, "test", "get", b"missing").await, b"");
+        assert_eq!(
+            call(&service, "test", "list", b"").await,
+            b"debug=1\nregion=north\n"
+        );
+        assert_eq!(call(&service, "test", "set", b"region=south").await, b"");
+    }
+}
@@ -0,0 +1,201 @@
+//! Lets guests see which services have servers, and watch servers come and go.
+//!
+//! Requests are empty. Responses begin with a [`Status`] byte, and are otherwise made of fields as
+//! described in [`wire`](crate::service::wire) and `u32` instance counts, where an instance is one
+//! RPC server.
+//!
+//! - `list`: the response is the name and instance count of each service that has servers, in
+//!   name order.
+//! - `watch`: the response is a stream of events, each an [`Event`] byte, a service name, and the
+//!   service's instance count after the event. It begins with a `Listed` event for each service
+//!   that has servers, in name order, then has a `Joined` or `Left` event for each server that
+//!   registers or goes away. The stream lasts until the guest closes it, which the service notices
+//!   at the next event.
+//!
+//!   A guest that falls behind misses events. It then gets a `Reset` event, with an empty service
+//!   name and no instances, followed by a `Listed` event for each service that has servers then,
+//!   and events carry on from there.
+
+use std::convert::TryInto;
+
+use async_trait::async_trait;
+use log::warn;
+
+use crate::process::service_registry::{RegistryChange, WatchEvent, SERVICE_REGISTRY};
+use crate::service::wire::{end, put_field, read_request};
+use crate::service::{NativeRequest, NativeService};
+
+/// Requests are empty, so anything more is refused early.
+const MAX_REQUEST_SIZE: usize = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    InvalidRequest = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Event {
+    /// The service had servers when the watch began.
+    Listed = 0,
+    Joined = 1,
+    Left = 2,
+    /// Events were missed, and `Listed` events for the current services follow.
+    Reset = 3,
+}
+
+impl From<RegistryChange> for Event {
+    fn from(change: RegistryChange) -> Self {
+        match change {
+            RegistryChange::Joined => Event::Joined,
+            RegistryChange::Left => Event::Left,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct DiscoveryService;
+
+impl DiscoveryService {
+    pub const SERVICE_NAME: &'static str = "DiscoveryService";
+
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Streams registry events until the guest closes its response.
+    async fn watch(request: &mut NativeRequest) {
+        let (services, mut events) = SERVICE_REGISTRY.watch();
+        let mut response = vec![Status::Ok as u8];
+        put_listed(&mut response, services);
+        while request.response.write_all(&response).await {
+            response.clear();
+            match events.recv().await {
+                WatchEvent::Changed(event) => put_event(
+                    &mut response,
+                    event.change.into(),
+                    &event.service_name,
+                    event.instances,
+                ),
+                WatchEvent::Resync(services) => {
+                    put_event(&mut response, Event::Reset, "", 0);
+                    put_listed(&mut response, services);
+                }
+            }
+        }
+    }
+}
+
+fn put_listed(dst: &mut Vec<u8>, services: Vec<(String, usize)>) {
+    for (service_name, instances) in services {
+        put_event(dst, Event::Listed, &service_name, instances);
+    }
+}
+
+fn put_event(dst: &mut Vec<u8>, event: Event, service_name: &str, instances: usize) {
+    dst.push(event as u8);
+    put_service(dst, service_name, instances);
+}
+
+fn put_service(dst: &mut Vec<u8>, service_name: &str, instances: usize) {
+    put_field(dst, service_name.as_bytes());
+    let instances: u32 = instances.try_into().unwrap();
+    dst.extend_from_slice(&instances.to_le_bytes());
+}
+
+#[async_trait]
+impl NativeService for DiscoveryService {
+    async fn handle(&self, mut request: NativeRequest) {
+        let body = read_request(&mut request.request, MAX_REQUEST_SIZE).await;
+        let is_valid = body.as_deref().and_then(end).is_some();
+        let response = match &*request.method_name {
+            "list" if is_valid => {
+                let mut response = vec![Status::Ok as u8];
+                for (service_name, instances) in SERVICE_REGISTRY.list() {
+                    put_service(&mut response, &service_name, instances);
+                }
+                response
+            }
+            "watch" if is_valid => return Self::watch(&mut request).await,
+            method_name => {
+                warn!(
+                    "pid {}: Invalid {} request for method {:?}",
+                    request.caller_pid,
+                    Self::SERVICE_NAME,
+                    method_name
+                );
+                vec![Status::InvalidRequest as u8]
+            }
+        };
+        request.response.write_all(&response).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use super::{put_service, DiscoveryService, Event, Status};
+    use crate::interop::rpc::RpcServerParams;
+    use crate::process::host_pipe::HostPipeReader;
+    use crate::process::process::Process;
+    use crate::process::service_registry::SERVICE_REGISTRY;
+    use crate::service::register_native_service;
+    use crate::service::tests::{call, read_exact, start};
+
+    const SERVICE_NAME: &str = "DiscoveryTestService";
+
+    async fn read_u32(response: &mut HostPipeReader) -> u32 {
+        u32::from_le_bytes(read_exact(response, 4).await.try_into().unwrap())
+    }
+
+    /// Reads events until one for [`SERVICE_NAME`], skipping those of services other tests use.
+    async fn next_event(response: &mut HostPipeReader) -> (u8, u32) {
+        loop {
+            let event = read_exact(response, 1).await[0];
+            let len = read_u32(response).await as usize;
+            let service_name = read_exact(response, len).await;
+            let instances = read_u32(response).await;
+            if service_name == SERVICE_NAME.as_bytes() {
+                return (event, instances);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_and_watches_services() {
+        let service = Arc::new(DiscoveryService::new());
+        register_native_service(SERVICE_NAME, service.clone());
+        let mut listed = Vec::new();
+        put_service(&mut listed, SERVICE_NAME, 1);
+        let response = call(&*service, "test", "list", b"").await;
+        assert_eq!(response[0], Status::Ok as u8);
+        assert!(response
+            .windows(listed.len())
+            .any(|window| window == listed));
+        assert_eq!(
+            call(&*service, "test", "list", b"junk").await,
+            [Status::InvalidRequest as u8]
+        );
+
+        // A watch starts with what's listed, then follows changes.
+        let (mut response, _watch) = start(service.clone(), "test", "watch", b"").await;
+        assert_eq!(read_exact(&mut response, 1).await, [Status::Ok as u8]);
+        assert_eq!(next_event(&mut response).await, (Event::Listed as u8, 1));
+        let (server, _server_wakes) = Process::new(0, "test".to_owned(), None);
+        let server = Arc::new(server);
+        Process::rpc_server_create(
+            &server,
+            &RpcServerParams {
+                service_name: SERVICE_NAME.to_owned(),
+                methods: vec![],
+            },
+        );
+        SERVICE_REGISTRY.remove_process(&server);
+        assert_eq!(next_event(&mut response).await, (Event::Joined as u8, 2));
+        assert_eq!(next_event(&mut response).await, (Event::Left as u8, 1));
+    }
+}
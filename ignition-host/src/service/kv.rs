@@ -0,0 +1,442 @@
+//! A durable key-value store for guests.
+//!
+//! Each principal has a namespace of its own, so guests never see each other's keys. Keys and
+//! values are arbitrary bytes.
+//!
+//! Requests and responses are made of fields as described in [`wire`](crate::service::wire).
+//! Responses begin with a [`Status`] byte.
+//!
+//! | Method             | Request                  | Response after an `Ok` status      |
+//! |--------------------|--------------------------|------------------------------------|
+//! | `get`              | key                      | value                              |
+//! | `put`              | key, value               |                                    |
+//! | `delete`           | key                      |                                    |
+//! | `compare_and_swap` | key, optional expected, optional new | (after `Conflict`: optional current) |
+//! | `scan`             | prefix                   | key, value pairs in key order      |
+//!
+//! `get` and `delete` report `NotFound` for a missing key. `compare_and_swap` replaces the value, or
+//! removes the key if `new` is absent, only if the current value equals `expected`, where absent
+//! means the key must not exist.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{error, warn};
+use testable_file_system::FileSystem;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::service::wire::{
+    end, put_field, put_optional_field, read_request, take_field, take_optional_field,
+};
+use crate::service::{NativeRequest, NativeService};
+
+/// Requests larger than this are refused without being read completely.
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Identifies the format of a namespace's file.
+const FILE_FORMAT_VERSION: u8 = 1;
+
+const ENTRIES_FILE_NAME: &str = "entries";
+const TEMPORARY_FILE_NAME: &str = "entries.tmp";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    Conflict = 2,
+    InvalidRequest = 3,
+    StorageError = 4,
+}
+
+type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Stores each namespace as a single file that is replaced whenever the namespace changes.
+///
+/// The [`FileSystem`] trait can't delete or list files, so per-key files couldn't support deletes
+/// or scans. Replacing a whole file on every write suits the small amounts of state guests keep.
+/// Namespaces are loaded on first use and then cached.
+pub struct KvStore<F: FileSystem> {
+    file_system: F,
+    root: PathBuf,
+    // None until the namespace has been loaded, and again after a failed write, so that the next
+    // access reloads whatever actually reached storage.
+    namespaces: Mutex<HashMap<String, Arc<AsyncMutex<Option<Entries>>>>>,
+}
+
+impl<F: FileSystem> KvStore<F> {
+    pub fn new(file_system: F, root: PathBuf) -> Self {
+        Self {
+            file_system,
+            root,
+            namespaces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, principal: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut namespace = self.lock(principal).await?;
+        Ok(entries(&mut namespace).get(key).cloned())
+    }
+
+    pub async fn put(&self, principal: &str, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        let mut namespace = self.lock(principal).await?;
+        entries(&mut namespace).insert(key, value);
+        self.save(principal, &mut namespace).await
+    }
+
+    /// Returns false if the key didn't exist.
+    pub async fn delete(&self, principal: &str, key: &[u8]) -> io::Result<bool> {
+        let mut namespace = self.lock(principal).await?;
+        if entries(&mut namespace).remove(key).is_none() {
+            return Ok(false);
+        }
+        self.save(principal, &mut namespace).await?;
+        Ok(true)
+    }
+
+    /// Sets the key to `new`, or removes it if `new` is None, as long as its current value is
+    /// `expected`. Otherwise, returns the current value.
+    pub async fn compare_and_swap(
+        &self,
+        principal: &str,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> io::Result<Result<(), Option<Vec<u8>>>> {
+        let mut namespace = self.lock(principal).await?;
+        let entries = entries(&mut namespace);
+        let current = entries.get(&key);
+        if current.map(Vec::as_slice) != expected {
+            return Ok(Err(current.cloned()));
+        }
+        match new {
+            Some(value) => entries.insert(key, value),
+            None => entries.remove(&key),
+        };
+        self.save(principal, &mut namespace).await?;
+        Ok(Ok(()))
+    }
+
+    /// Returns every entry whose key starts with `prefix`, in key order.
+    pub async fn scan(
+        &self,
+        principal: &str,
+        prefix: &[u8],
+    ) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut namespace = self.lock(principal).await?;
+        Ok(entries(&mut namespace)
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn lock(&self, principal: &str) -> io::Result<OwnedMutexGuard<Option<Entries>>> {
+        let namespace = Arc::clone(
+            self.namespaces
+                .lock()
+                .unwrap()
+                .entry(principal.to_owned())
+                .or_default(),
+        );
+        let mut namespace = namespace.lock_owned().await;
+        if namespace.is_none() {
+            *namespace = Some(self.load(principal).await?);
+        }
+        Ok(namespace)
+    }
+
+    fn dir(&self, principal: &str) -> PathBuf {
+        // Hex keeps any principal a single, safe path component.
+        let name: String = principal.bytes().map(|b| format!("{:02x}", b)).collect();
+        self.root.join(name)
+    }
+
+    async fn load(&self, principal: &str) -> io::Result<Entries> {
+        let path = self.dir(principal).join(ENTRIES_FILE_NAME);
+        let mut file = match self.file_system.open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Entries::new()),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await?;
+        decode_entries(&data).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupt", path.display()),
+            )
+        })
+    }
+
+    /// Writes a namespace's entries to a temporary file, then moves it into place, so that a
+    /// failure part way through leaves the previous version intact.
+    async fn save(&self, principal: &str, namespace: &mut Option<Entries>) -> io::Result<()> {
+        let result = async {
+            let dir = self.dir(principal);
+            self.file_system.create_dir_all(&dir).await?;
+            let temporary_path = dir.join(TEMPORARY_FILE_NAME);
+            let mut file = self.file_system.create(&temporary_path).await?;
+            file.write_all(&encode_entries(namespace.as_ref().unwrap()))
+                .await?;
+            file.flush().await?;
+            drop(file);
+            self.file_system
+                .rename(&temporary_path, &dir.join(ENTRIES_FILE_NAME))
+                .await
+        }
+        .await;
+        if result.is_err() {
+            *namespace = None;
+        }
+        result
+    }
+}
+
+fn entries(namespace: &mut Option<Entries>) -> &mut Entries {
+    namespace.as_mut().expect("namespace not loaded")
+}
+
+fn encode_entries(entries: &Entries) -> Vec<u8> {
+    let mut data = vec![FILE_FORMAT_VERSION];
+    for (key, value) in entries {
+        put_field(&mut data, key);
+        put_field(&mut data, value);
+    }
+    data
+}
+
+fn decode_entries(data: &[u8]) -> Option<Entries> {
+    let (&version, mut data) = data.split_first()?;
+    if version != FILE_FORMAT_VERSION {
+        return None;
+    }
+    let mut entries = Entries::new();
+    while !data.is_empty() {
+        let key = take_field(&mut data)?;
+        let value = take_field(&mut data)?;
+        entries.insert(key.to_vec(), value.to_vec());
+    }
+    Some(entries)
+}
+
+pub struct KvService<F: FileSystem> {
+    store: KvStore<F>,
+}
+
+impl<F: FileSystem> KvService<F> {
+    pub const SERVICE_NAME: &'static str = "KvService";
+
+    pub fn new(store: KvStore<F>) -> Self {
+        Self { store }
+    }
+
+    /// Carries out a request, returning the response body. None means the request was malformed.
+    async fn respond(
+        &self,
+        principal: &str,
+        method_name: &str,
+        mut body: &[u8],
+    ) -> Option<io::Result<Vec<u8>>> {
+        let store = &self.store;
+        let mut response = vec![Status::Ok as u8];
+        let result = match method_name {
+            "get" => {
+                let key = take_field(&mut body)?;
+                end(body)?;
+                store.get(principal, key).await.map(|value| match value {
+                    Some(value) => put_field(&mut response, &value),
+                    None => response[0] = Status::NotFound as u8,
+                })
+            }
+            "put" => {
+                let key = take_field(&mut body)?;
+                let value = take_field(&mut body)?;
+                end(body)?;
+                store.put(principal, key.to_vec(), value.to_vec()).await
+            }
+            "delete" => {
+                let key = take_field(&mut body)?;
+                end(body)?;
+                store.delete(principal, key).await.map(|deleted| {
+                    if !deleted {
+                        response[0] = Status::NotFound as u8;
+                    }
+                })
+            }
+            "compare_and_swap" => {
+                let key = take_field(&mut body)?;
+                let expected = take_optional_field(&mut body)?;
+                let new = take_optional_field(&mut body)?;
+                end(body)?;
+                store
+                    .compare_and_swap(principal, key.to_vec(), expected, new.map(<[u8]>::to_vec))
+                    .await
+                    .map(|result| {
+                        if let Err(current) = result {
+                            response[0] = Status::Conflict as u8;
+                            put_optional_field(&mut response, current.as_deref());
+                        }
+                    })
+            }
+            "scan" => {
+                let prefix = take_field(&mut body)?;
+                end(body)?;
+                store.scan(principal, prefix).await.map(|entries| {
+                    for (key, value) in entries {
+                        put_field(&mut response, &key);
+                        put_field(&mut response, &value);
+                    }
+                })
+            }
+            _ => return None,
+        };
+        Some(result.map(|()| response))
+    }
+}
+
+#[async_trait]
+impl<F: FileSystem + 'static> NativeService for KvService<F> {
+    async fn handle(&self, mut request: NativeRequest) {
+        let body = read_request(&mut request.request, MAX_REQUEST_SIZE).await;
+        let result = match &body {
+            Some(body) => {
+                self.respond(&request.caller_principal, &request.method_name, body)
+                    .await
+            }
+            None => None,
+        };
+        let response = match result {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => {
+                error!(
+                    "pid {}: {} storage error: {}",
+                    request.caller_pid,
+                    Self::SERVICE_NAME,
+                    e
+                );
+                vec![Status::StorageError as u8]
+            }
+            None => {
+                warn!(
+                    "pid {}: Invalid {} request for method {:?}",
+                    request.caller_pid,
+                    Self::SERVICE_NAME,
+                    request.method_name
+                );
+                vec![Status::InvalidRequest as u8]
+            }
+        };
+        request.response.write_all(&response).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use testable_file_system::InMemoryFileSystem;
+
+    use super::{KvService, KvStore, Status};
+    use crate::service::tests::call;
+    use crate::service::wire::{put_field, put_optional_field};
+
+    fn store(file_system: &InMemoryFileSystem) -> KvStore<InMemoryFileSystem> {
+        KvStore::new(file_system.clone(), PathBuf::from("kv"))
+    }
+
+    #[tokio::test]
+    async fn operations_persist_per_principal() {
+        let file_system = InMemoryFileSystem::new();
+        let kv = store(&file_system);
+        kv.put("a", b"color".to_vec(), b"red".to_vec())
+            .await
+            .unwrap();
+        kv.put("a", b"count".to_vec(), b"1".to_vec()).await.unwrap();
+        kv.put("a", b"size".to_vec(), b"9".to_vec()).await.unwrap();
+        kv.put("b", b"color".to_vec(), b"blue".to_vec())
+            .await
+            .unwrap();
+        assert!(kv.delete("a", b"size").await.unwrap());
+        assert!(!kv.delete("a", b"size").await.unwrap());
+
+        assert_eq!(
+            kv.compare_and_swap("a", b"count".to_vec(), Some(b"0"), Some(b"2".to_vec()))
+                .await
+                .unwrap(),
+            Err(Some(b"1".to_vec()))
+        );
+        kv.compare_and_swap("a", b"count".to_vec(), Some(b"1"), Some(b"2".to_vec()))
+            .await
+            .unwrap()
+            .unwrap();
+        kv.compare_and_swap("a", b"new".to_vec(), None, Some(b"x".to_vec()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A fresh store sees only what was saved.
+        let kv = store(&file_system);
+        assert_eq!(
+            kv.scan("a", b"co").await.unwrap(),
+            vec![
+                (b"color".to_vec(), b"red".to_vec()),
+                (b"count".to_vec(), b"2".to_vec()),
+            ]
+        );
+        assert_eq!(kv.get("a", b"new").await.unwrap(), Some(b"x".to_vec()));
+        assert_eq!(kv.get("a", b"size").await.unwrap(), None);
+        assert_eq!(kv.get("b", b"color").await.unwrap(), Some(b"blue".to_vec()));
+        assert_eq!(kv.scan("c", b"").await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn service_speaks_the_wire_format() {
+        let service = KvService::new(store(&InMemoryFileSystem::new()));
+        let field = |data: &[u8]| {
+            let mut encoded = Vec::new();
+            put_field(&mut encoded, data);
+            encoded
+        };
+        let ok = [Status::Ok as u8];
+
+        let put = [field(b"k"), field(b"v")].concat();
+        assert_eq!(call(&service, "p", "put", &put).await, ok);
+        assert_eq!(
+            call(&service, "p", "get", &field(b"k")).await,
+            [&ok[..], &field(b"v")].concat()
+        );
+        assert_eq!(
+            call(&service, "other", "get", &field(b"k")).await,
+            [Status::NotFound as u8]
+        );
+
+        let mut cas = field(b"k");
+        put_optional_field(&mut cas, None);
+        put_optional_field(&mut cas, Some(b"w"));
+        let mut conflict = vec![Status::Conflict as u8];
+        put_optional_field(&mut conflict, Some(b"v"));
+        assert_eq!(
+            call(&service, "p", "compare_and_swap", &cas).await,
+            conflict
+        );
+
+        assert_eq!(
+            call(&service, "p", "scan", &field(b"")).await,
+            [&ok[..], &put].concat()
+        );
+        assert_eq!(
+            call(&service, "p", "get", b"junk").await,
+            [Status::InvalidRequest as u8]
+        );
+        assert_eq!(
+            call(&service, "p", "bogus", &field(b"k")).await,
+            [Status::InvalidRequest as u8]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! RPC services implemented natively by the host rather than by a guest process.
+//!
+//! A native service is registered in the [`ServiceRegistry`](crate::process::service_registry)
+//! under a service name like any guest server, so guests reach it through the usual
+//! `rpc_client_request` import. Each request is handled by a host task that reads the request body
+//! and writes the response body through the other ends of the guest's pipes.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::process::host_pipe::{HostPipeReader, HostPipeWriter};
+use crate::process::service_registry::{RpcServerRef, SERVICE_REGISTRY};
+use crate::util::pointer_identity_arc::PointerIdentityArc;
+
+mod blob;
+mod config;
+mod discovery;
+mod kv;
+mod topic;
+mod wire;
+
+pub use self::blob::{format_blob_id, parse_blob_id, BlobService};
+pub use self::config::ConfigService;
+pub use self::discovery::DiscoveryService;
+pub use self::kv::{KvService, KvStore};
+pub use self::topic::TopicService;
+
+#[async_trait]
+pub trait NativeService: Send + Sync {
+    /// Handles one request. The response is complete once `request.response` is dropped.
+    ///
+    /// Requests for methods the service doesn't know should be logged. A service whose responses
+    /// begin with a status byte answers them with its `InvalidRequest` status; others drop them,
+    /// which the caller sees as an empty response.
+    async fn handle(&self, request: NativeRequest);
+}
+
+pub struct NativeRequest {
+    /// The process that made the request.
+    pub caller_pid: usize,
+    /// See [`Process::principal`](crate::process::process::Process::principal).
+    pub caller_principal: String,
+    pub method_name: String,
+    pub request: HostPipeReader,
+    pub response: HostPipeWriter,
+}
+
+/// Makes `service` available to guests as `service_name`.
+pub fn register_native_service(service_name: &str, service: Arc<dyn NativeService>) {
+    SERVICE_REGISTRY.register(
+        service_name.to_owned(),
+        RpcServerRef::Native(PointerIdentityArc::new(service)),
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use tokio::task::JoinHandle;
+
+    use super::{NativeRequest, NativeService};
+    use crate::process::host_pipe::{HostPipeReader, HostPipeWriter};
+    use crate::process::pipe::pipe;
+
+    /// Makes a request of `service` as `principal` and returns the response.
+    pub async fn call(
+        service: &dyn NativeService,
+        principal: &str,
+        method_name: &str,
+        body: &[u8],
+    ) -> Vec<u8> {
+        let (request_reader, request_writer) = pipe();
+        let (response_reader, response_writer) = pipe();
+        let mut request_writer = HostPipeWriter::new(request_writer);
+        let mut response_reader = HostPipeReader::new(response_reader);
+        let request = NativeRequest {
+            caller_pid: 0,
+            caller_principal: principal.to_owned(),
+            method_name: method_name.to_owned(),
+            request: HostPipeReader::new(request_reader),
+            response: HostPipeWriter::new(response_writer),
+        };
+
+        let send = async move {
+            request_writer.write_all(body).await;
+        };
+        let ((), (), response) =
+            tokio::join!(send, service.handle(request), response_reader.read_to_end());
+        response
+    }
+
+    /// Starts a request of `service` whose response is read as it's written, for methods that
+    /// stream. Returns the response and the task handling the request.
+    pub async fn start(
+        service: Arc<dyn NativeService>,
+        principal: &str,
+        method_name: &str,
+        body: &[u8],
+    ) -> (HostPipeReader, JoinHandle<()>) {
+        let (request_reader, request_writer) = pipe();
+        let (response_reader, response_writer) = pipe();
+        let request = NativeRequest {
+            caller_pid: 0,
+            caller_principal: principal.to_owned(),
+            method_name: method_name.to_owned(),
+            request: HostPipeReader::new(request_reader),
+            response: HostPipeWriter::new(response_writer),
+        };
+        let task = tokio::spawn(async move { service.handle(request).await });
+        HostPipeWriter::new(request_writer).write_all(body).await;
+        (HostPipeReader::new(response_reader), task)
+    }
+
+    /// Reads exactly `len` bytes of a response.
+    pub async fn read_exact(response: &mut HostPipeReader, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        let mut filled = 0;
+        while filled < len {
+            let n = response.read(&mut data[filled..]).await;
+            assert!(n > 0, "response ended early");
+            filled += n;
+        }
+        data
+    }
+}
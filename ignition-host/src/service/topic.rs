@@ -0,0 +1,387 @@
+//! Broadcasts messages to every guest subscribed to a topic.
+//!
+//! Topics are named by strings, are shared by all principals, and exist while they have
+//! subscribers. Each subscriber has a bounded queue of its own, so a slow subscriber never holds up
+//! the others unless it asks to, and what happens when its queue is full is up to its
+//! [`Policy`].
+//!
+//! Requests and responses are made of fields as described in [`wire`](crate::service::wire).
+//! Responses begin with a [`Status`] byte.
+//!
+//! | Method      | Request                                      | Response after an `Ok` status     |
+//! |-------------|----------------------------------------------|-----------------------------------|
+//! | `publish`   | topic, message                               | subscribers queued for, as a `u32` |
+//! | `subscribe` | topic, capacity as a `u32`, [`Policy`] byte | a delivery per message            |
+//!
+//! A subscription lasts as long as its response, which the guest reads deliveries from as they
+//! arrive. Each delivery is the number of messages the subscriber lost just before this one, as a
+//! `u32`, followed by the message as a field. The queue holds the messages after the one being
+//! delivered. Closing the response ends the subscription, which the service notices at the next
+//! delivery.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::service::wire::{end, put_field, read_request, take_field, take_u32, take_u8};
+use crate::service::{NativeRequest, NativeService};
+
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Room for a topic name and the framing around the message.
+const MAX_REQUEST_SIZE: usize = MAX_MESSAGE_SIZE + 4096;
+
+/// The largest queue a subscriber can ask for, in messages.
+const MAX_QUEUE_CAPACITY: u32 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    InvalidRequest = 1,
+}
+
+/// What happens to a message published while a subscriber's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    /// The publisher waits until the subscriber has room, or unsubscribes.
+    Block = 0,
+    /// The oldest queued message makes way for it.
+    DropOldest = 1,
+    /// The new message is dropped.
+    DropNewest = 2,
+}
+
+impl TryFrom<u8> for Policy {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Policy::Block),
+            1 => Ok(Policy::DropOldest),
+            2 => Ok(Policy::DropNewest),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Subscriber {
+    capacity: usize,
+    policy: Policy,
+    queue: Mutex<Queue>,
+    /// Notified whenever the queue changes.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Each message with the number lost just before it.
+    messages: VecDeque<(u32, Arc<[u8]>)>,
+    /// Messages lost since the last one queued.
+    dropped: u32,
+    /// Set once the subscription has ended, after which nothing more is queued.
+    is_closed: bool,
+}
+
+impl Subscriber {
+    fn new(capacity: usize, policy: Policy) -> Self {
+        Self {
+            capacity,
+            policy,
+            queue: Mutex::new(Queue::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Queues `message`, waiting for room if the policy says to. Returns false if the message was
+    /// dropped or the subscription ended.
+    async fn push(&self, message: Arc<[u8]>) -> bool {
+        loop {
+            let changed = {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.is_closed {
+                    return false;
+                }
+                if queue.messages.len() == self.capacity {
+                    match self.policy {
+                        Policy::Block => {}
+                        Policy::DropOldest => {
+                            let (dropped, _) = queue.messages.pop_front().unwrap();
+                            let lost = dropped.saturating_add(1);
+                            match queue.messages.front_mut() {
+                                Some((next_dropped, _)) => {
+                                    *next_dropped = next_dropped.saturating_add(lost)
+                                }
+                                None => queue.dropped = queue.dropped.saturating_add(lost),
+                            }
+                        }
+                        Policy::DropNewest => {
+                            queue.dropped = queue.dropped.saturating_add(1);
+                            return false;
+                        }
+                    }
+                }
+                if queue.messages.len() < self.capacity {
+                    let dropped = std::mem::take(&mut queue.dropped);
+                    queue.messages.push_back((dropped, message));
+                    self.changed.notify_waiters();
+                    return true;
+                }
+                // Created before the lock is released, so it can't miss the change it waits for.
+                self.changed.notified()
+            };
+            changed.await;
+        }
+    }
+
+    /// Waits for the next message, and returns it with the number of messages lost before it.
+    async fn pop(&self) -> (u32, Arc<[u8]>) {
+        loop {
+            let changed = {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(delivery) = queue.messages.pop_front() {
+                    self.changed.notify_waiters();
+                    return delivery;
+                }
+                self.changed.notified()
+            };
+            changed.await;
+        }
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.is_closed = true;
+        queue.messages.clear();
+        self.changed.notify_waiters();
+    }
+}
+
+#[derive(Default)]
+pub struct TopicService {
+    topics: Mutex<HashMap<String, Vec<Arc<Subscriber>>>>,
+}
+
+impl TopicService {
+    pub const SERVICE_NAME: &'static str = "TopicService";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `message` for every subscriber to `topic`, and returns how many took it.
+    async fn publish(&self, topic: &str, message: &[u8]) -> u32 {
+        let subscribers = match self.topics.lock().unwrap().get(topic) {
+            Some(subscribers) => subscribers.clone(),
+            None => return 0,
+        };
+        let message: Arc<[u8]> = message.into();
+        let mut count = 0;
+        for subscriber in subscribers {
+            if subscriber.push(Arc::clone(&message)).await {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn subscribe(&self, topic: &str, capacity: usize, policy: Policy) -> Arc<Subscriber> {
+        let subscriber = Arc::new(Subscriber::new(capacity, policy));
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(topic.to_owned())
+            .or_default()
+            .push(Arc::clone(&subscriber));
+        subscriber
+    }
+
+    fn unsubscribe(&self, topic: &str, subscriber: &Arc<Subscriber>) {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.retain(|other| !Arc::ptr_eq(other, subscriber));
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+        drop(topics);
+        // Releases any publisher waiting for room.
+        subscriber.close();
+    }
+
+    /// Delivers messages from a new subscription until the guest closes its response.
+    async fn run_subscription(
+        &self,
+        request: &mut NativeRequest,
+        topic: &str,
+        capacity: usize,
+        policy: Policy,
+    ) {
+        let subscriber = self.subscribe(topic, capacity, policy);
+        if request.response.write_all(&[Status::Ok as u8]).await {
+            loop {
+                let (dropped, message) = subscriber.pop().await;
+                let mut delivery = dropped.to_le_bytes().to_vec();
+                put_field(&mut delivery, &message);
+                if !request.response.write_all(&delivery).await {
+                    break;
+                }
+            }
+        }
+        self.unsubscribe(topic, &subscriber);
+    }
+}
+
+/// Parses a `publish` request into its topic and message.
+fn parse_publish(mut body: &[u8]) -> Option<(&str, &[u8])> {
+    let topic = std::str::from_utf8(take_field(&mut body)?).ok()?;
+    let message = take_field(&mut body)?;
+    end(body)?;
+    if message.len() > MAX_MESSAGE_SIZE {
+        return None;
+    }
+    Some((topic, message))
+}
+
+/// Parses a `subscribe` request into its topic, queue capacity and policy.
+fn parse_subscribe(mut body: &[u8]) -> Option<(&str, usize, Policy)> {
+    let topic = std::str::from_utf8(take_field(&mut body)?).ok()?;
+    let capacity = take_u32(&mut body)?;
+    let policy = Policy::try_from(take_u8(&mut body)?).ok()?;
+    end(body)?;
+    if capacity == 0 || capacity > MAX_QUEUE_CAPACITY {
+        return None;
+    }
+    Some((topic, capacity as usize, policy))
+}
+
+#[async_trait]
+impl NativeService for TopicService {
+    async fn handle(&self, mut request: NativeRequest) {
+        let body = read_request(&mut request.request, MAX_REQUEST_SIZE)
+            .await
+            .unwrap_or_default();
+        let response = match &*request.method_name {
+            "publish" => match parse_publish(&body) {
+                Some((topic, message)) => {
+                    let count = self.publish(topic, message).await;
+                    Some([&[Status::Ok as u8][..], &count.to_le_bytes()].concat())
+                }
+                None => None,
+            },
+            "subscribe" => match parse_subscribe(&body) {
+                Some((topic, capacity, policy)) => {
+                    self.run_subscription(&mut request, topic, capacity, policy)
+                        .await;
+                    return;
+                }
+                None => None,
+            },
+            _ => None,
+        };
+        let response = response.unwrap_or_else(|| {
+            warn!(
+                "pid {}: Invalid {} request for method {:?}",
+                request.caller_pid,
+                Self::SERVICE_NAME,
+                request.method_name
+            );
+            vec![Status::InvalidRequest as u8]
+        });
+        request.response.write_all(&response).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+
+    use super::{Policy, Status, TopicService};
+    use crate::service::tests::{call, read_exact, start};
+    use crate::service::wire::put_field;
+
+    #[tokio::test]
+    async fn full_queues_follow_their_policy() {
+        let service = TopicService::new();
+        let oldest = service.subscribe("t", 2, Policy::DropOldest);
+        let newest = service.subscribe("t", 2, Policy::DropNewest);
+        assert_eq!(service.publish("t", b"1").await, 2);
+        assert_eq!(service.publish("t", b"2").await, 2);
+        assert_eq!(service.publish("t", b"3").await, 1);
+        assert_eq!(service.publish("other", b"x").await, 0);
+
+        assert_eq!(oldest.pop().await, (1, b"2"[..].into()));
+        assert_eq!(oldest.pop().await, (0, b"3"[..].into()));
+        assert_eq!(newest.pop().await, (0, b"1"[..].into()));
+        assert_eq!(newest.pop().await, (0, b"2"[..].into()));
+        assert_eq!(service.publish("t", b"4").await, 2);
+        assert_eq!(newest.pop().await, (1, b"4"[..].into()));
+    }
+
+    #[tokio::test]
+    async fn blocking_subscribers_hold_up_publishers() {
+        let service = Arc::new(TopicService::new());
+        let subscriber = service.subscribe("t", 1, Policy::Block);
+        assert_eq!(service.publish("t", b"1").await, 1);
+
+        let publisher = Arc::clone(&service);
+        let mut second = tokio::spawn(async move { publisher.publish("t", b"2").await });
+        let () = tokio::task::yield_now().await;
+        assert!((&mut second).now_or_never().is_none());
+        assert_eq!(subscriber.pop().await, (0, b"1"[..].into()));
+        assert_eq!(second.await.unwrap(), 1);
+
+        // Unsubscribing releases a waiting publisher without the message.
+        let publisher = Arc::clone(&service);
+        let third = tokio::spawn(async move { publisher.publish("t", b"3").await });
+        let () = tokio::task::yield_now().await;
+        service.unsubscribe("t", &subscriber);
+        assert_eq!(third.await.unwrap(), 0);
+        assert!(service.topics.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscriptions_stream_deliveries() {
+        let service = Arc::new(TopicService::new());
+        let field = |data: &[u8]| {
+            let mut encoded = Vec::new();
+            put_field(&mut encoded, data);
+            encoded
+        };
+
+        let mut body = field(b"t");
+        body.extend_from_slice(&4u32.to_le_bytes());
+        body.push(Policy::Block as u8);
+        let (mut response, subscription) = start(service.clone(), "sub", "subscribe", &body).await;
+        assert_eq!(read_exact(&mut response, 1).await, [Status::Ok as u8]);
+
+        let publish = [field(b"t"), field(b"hello")].concat();
+        assert_eq!(
+            call(&*service, "pub", "publish", &publish).await,
+            [Status::Ok as u8, 1, 0, 0, 0]
+        );
+        let expected = [&0u32.to_le_bytes()[..], &field(b"hello")].concat();
+        assert_eq!(read_exact(&mut response, expected.len()).await, expected);
+
+        // Closing the response ends the subscription at the next delivery.
+        drop(response);
+        call(&*service, "pub", "publish", &publish).await;
+        subscription.await.unwrap();
+        assert!(service.topics.lock().unwrap().is_empty());
+
+        let mut zero_capacity = field(b"t");
+        zero_capacity.extend_from_slice(&0u32.to_le_bytes());
+        zero_capacity.push(Policy::Block as u8);
+        assert_eq!(
+            call(&*service, "sub", "subscribe", &zero_capacity).await,
+            [Status::InvalidRequest as u8]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! The request and response encoding shared by native services.
+//!
+//! A field is a little-endian `u32` length followed by that many bytes. An optional field is a `0`
+//! byte if absent, or a `1` byte followed by the field.
+
+use std::convert::TryInto;
+
+use crate::process::host_pipe::HostPipeReader;
+
+pub fn put_field(dst: &mut Vec<u8>, field: &[u8]) {
+    let len: u32 = field.len().try_into().unwrap();
+    dst.extend_from_slice(&len.to_le_bytes());
+    dst.extend_from_slice(field);
+}
+
+pub fn put_optional_field(dst: &mut Vec<u8>, field: Option<&[u8]>) {
+    match field {
+        Some(field) => {
+            dst.push(1);
+            put_field(dst, field);
+        }
+        None => dst.push(0),
+    }
+}
+
+pub fn take_field<'a>(src: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(src)? as usize;
+    if src.len() < len {
+        return None;
+    }
+    let (field, rest) = src.split_at(len);
+    *src = rest;
+    Some(field)
+}
+
+pub fn take_optional_field<'a>(src: &mut &'a [u8]) -> Option<Option<&'a [u8]>> {
+    match take_u8(src)? {
+        0 => Some(None),
+        1 => take_field(src).map(Some),
+        _ => None,
+    }
+}
+
+pub fn take_u8(src: &mut &[u8]) -> Option<u8> {
+    let (&value, rest) = src.split_first()?;
+    *src = rest;
+    Some(value)
+}
+
+pub fn take_u32(src: &mut &[u8]) -> Option<u32> {
+    if src.len() < 4 {
+        return None;
+    }
+    let (value, rest) = src.split_at(4);
+    *src = rest;
+    Some(u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+/// Checks that a request has no trailing data.
+pub fn end(body: &[u8]) -> Option<()> {
+    if body.is_empty() {
+        Some(())
+    } else {
+        None
+    }
+}
+
+/// Reads a whole request, or returns None if it's longer than `max_len`.
+pub async fn read_request(reader: &mut HostPipeReader, max_len: usize) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut chunk = vec![0; 8192];
+    loop {
+        let n = reader.read(&mut chunk).await;
+        if n == 0 {
+            return Some(data);
+        }
+        if data.len() + n > max_len {
+            return None;
+        }
+        data.extend_from_slice(&chunk[..n]);
+    }
+}
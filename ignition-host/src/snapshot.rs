@@ -0,0 +1,470 @@
+//! Saves an idle process to a file and brings it back later, possibly in another host.
+//!
+//! A snapshot holds the guest's linear memory, its exported mutable globals, the host's state for
+//! the process (see [`SavedProcess`]), and the wakes queued for it. It's taken between wakes, when
+//! any shadow stack pointer a compiler keeps in an unexported global is back where it started, so
+//! a fresh instance of the same module has the right value already. Other unexported mutable
+//! globals aren't saved.
+//!
+//! The module is recorded by reference, and restoring loads it again, so a snapshot is only
+//! portable between hosts that resolve the reference to the same module. Blob IDs always do. The
+//! module's hash is recorded too, and a snapshot is never applied to a different module, such as a
+//! file that has since been rebuilt.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tokio::sync::mpsc::UnboundedReceiver;
+use wasmtime::{Instance, Mutability, Store, Val};
+
+use crate::process::process::{Process, SavedProcess, SavedRpcServer};
+use crate::util::temporary_path;
+use crate::wake::try_recv;
+use crate::{TaskId, WakeParams};
+
+const MAGIC: &[u8; 8] = b"IGNSNAP\0";
+const FILE_FORMAT_VERSION: u8 = 2;
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// A path or blob ID, as accepted by [`ModuleLoader::load`](crate::module_loader::ModuleLoader).
+    pub module_ref: String,
+    /// The BLAKE3 hash of the module's bytes, which restoring checks against the module it loads.
+    pub module_hash: [u8; 32],
+    pub principal: String,
+    /// The guest's monotonic time when the snapshot was taken.
+    pub monotonic_time: Duration,
+    pub memory: Vec<u8>,
+    pub globals: Vec<(String, Val)>,
+    pub process: SavedProcess,
+    pub wakes: Vec<WakeParams>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the process in `store`, which must be between wakes. Wakes already
+    /// queued are recorded and left in the queue.
+    pub fn capture(
+        store: &mut Store<Arc<Process>>,
+        instance: &Instance,
+        wake_queue_receiver: &mut UnboundedReceiver<WakeParams>,
+        module_ref: &str,
+        module_hash: [u8; 32],
+    ) -> Result<Self> {
+        let process = Arc::clone(store.data());
+        let saved = Process::save(&process)?;
+
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| anyhow!("pid {} exports no memory", process.pid()))?
+            .data(&*store)
+            .to_vec();
+
+        let exported_globals: Vec<_> = instance
+            .exports(&mut *store)
+            .filter_map(|export| {
+                let name = export.name().to_owned();
+                export.into_global().map(|global| (name, global))
+            })
+            .collect();
+        let mut globals = Vec::new();
+        for (name, global) in exported_globals {
+            if global.ty(&*store).mutability() == Mutability::Const {
+                continue;
+            }
+            let value = global.get(&mut *store);
+            match value {
+                Val::I32(_) | Val::I64(_) | Val::F32(_) | Val::F64(_) => {}
+                _ => bail!("global {:?} has a type that can't be saved", name),
+            }
+            globals.push((name, value));
+        }
+
+        let mut wakes = Vec::new();
+        while let Some(params) = try_recv(wake_queue_receiver) {
+            wakes.push(params);
+        }
+        for &params in &wakes {
+            process.wake_queue_sender().send(params).unwrap();
+        }
+
+        Ok(Self {
+            module_ref: module_ref.to_owned(),
+            module_hash,
+            principal: process.principal().to_owned(),
+            monotonic_time: process.start_time().elapsed(),
+            memory,
+            globals,
+            process: saved,
+            wakes,
+        })
+    }
+
+    /// Applies the snapshot to a new instance of its module. The process should have been created
+    /// with the snapshot's principal and monotonic time, and not sent the initial wake.
+    pub fn apply(&self, store: &mut Store<Arc<Process>>, instance: &Instance) -> Result<()> {
+        let process = Arc::clone(store.data());
+
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| anyhow!("the module exports no memory"))?;
+        let pages = self.memory.len().div_ceil(WASM_PAGE_SIZE) as u64;
+        let current_pages = memory.size(&*store);
+        if pages > current_pages {
+            memory.grow(&mut *store, pages - current_pages)?;
+        }
+        memory.data_mut(&mut *store)[..self.memory.len()].copy_from_slice(&self.memory);
+
+        for (name, value) in &self.globals {
+            instance
+                .get_global(&mut *store, name)
+                .ok_or_else(|| anyhow!("the module exports no global {:?}", name))?
+                .set(&mut *store, value.clone())
+                .with_context(|| format!("Failed to restore global {:?}", name))?;
+        }
+
+        Process::restore(&process, &self.process);
+        for &params in &self.wakes {
+            process.wake_queue_sender().send(params).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Writes the snapshot to `path`, through a temporary file so that a failure never leaves a
+    /// truncated snapshot behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = temporary_path(path);
+        std::fs::write(&tmp_path, self.encode())
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(FILE_FORMAT_VERSION);
+        self.write(&mut data).unwrap();
+        data
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self> {
+        let mut magic = [0; 8];
+        data.read_exact(&mut magic)
+            .ok()
+            .filter(|_| magic == *MAGIC)
+            .ok_or_else(|| anyhow!("not a snapshot"))?;
+        let version = data.read_u8()?;
+        if version != FILE_FORMAT_VERSION {
+            bail!("unsupported snapshot version {}", version);
+        }
+        let snapshot = Self::read(&mut data).context("snapshot is corrupt")?;
+        if !data.is_empty() {
+            bail!("snapshot is corrupt: trailing data");
+        }
+        Ok(snapshot)
+    }
+
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        write_string(w, &self.module_ref)?;
+        w.write_all(&self.module_hash)?;
+        write_string(w, &self.principal)?;
+        w.write_u64::<LittleEndian>(self.monotonic_time.as_micros().try_into().unwrap())?;
+        write_bytes(w, &self.memory)?;
+
+        write_len(w, self.globals.len())?;
+        for (name, value) in &self.globals {
+            write_string(w, name)?;
+            let (tag, bits) = match *value {
+                Val::I32(x) => (0, x as u32 as u64),
+                Val::I64(x) => (1, x as u64),
+                Val::F32(x) => (2, u64::from(x)),
+                Val::F64(x) => (3, x),
+                _ => unreachable!("unsupported globals aren't captured"),
+            };
+            w.write_u8(tag)?;
+            w.write_u64::<LittleEndian>(bits)?;
+        }
+
+        let process = &self.process;
+        write_len(w, process.rpc_clients.len())?;
+        for (handle, service_name) in &process.rpc_clients {
+            w.write_u32::<LittleEndian>(*handle)?;
+            write_string(w, service_name)?;
+        }
+        write_len(w, process.rpc_servers.len())?;
+        for server in &process.rpc_servers {
+            w.write_u32::<LittleEndian>(server.handle)?;
+            write_string(w, &server.service_name)?;
+            write_len(w, server.method_names.len())?;
+            for method_name in &server.method_names {
+                write_string(w, method_name)?;
+            }
+            write_task_ids(w, &server.waiting_task_ids)?;
+        }
+        write_len(w, process.tasks_waiting_for_servers.len())?;
+        for (service_name, task_id) in &process.tasks_waiting_for_servers {
+            write_string(w, service_name)?;
+            w.write_u32::<LittleEndian>(task_id.0)?;
+        }
+        write_task_ids(w, &process.host_shutdown_tasks)?;
+        write_len(w, process.timers.len())?;
+        for (task_id, remaining) in &process.timers {
+            w.write_u32::<LittleEndian>(task_id.0)?;
+            w.write_u64::<LittleEndian>(remaining.as_micros().try_into().unwrap())?;
+        }
+
+        write_len(w, self.wakes.len())?;
+        for wake in &self.wakes {
+            w.write_u32::<LittleEndian>(wake.task_id.0)?;
+            w.write_u32::<LittleEndian>(wake.param)?;
+        }
+        Ok(())
+    }
+
+    fn read(r: &mut impl Read) -> io::Result<Self> {
+        let module_ref = read_string(r)?;
+        let mut module_hash = [0; 32];
+        r.read_exact(&mut module_hash)?;
+        let principal = read_string(r)?;
+        let monotonic_time = Duration::from_micros(r.read_u64::<LittleEndian>()?);
+        let memory = read_bytes(r)?;
+
+        let mut globals = Vec::new();
+        for _ in 0..read_len(r)? {
+            let name = read_string(r)?;
+            let tag = r.read_u8()?;
+            let bits = r.read_u64::<LittleEndian>()?;
+            let value = match tag {
+                0 => Val::I32(bits as u32 as i32),
+                1 => Val::I64(bits as i64),
+                2 => Val::F32(bits as u32),
+                3 => Val::F64(bits),
+                _ => return Err(invalid_data("unknown global type")),
+            };
+            globals.push((name, value));
+        }
+
+        let mut process = SavedProcess::default();
+        for _ in 0..read_len(r)? {
+            let handle = r.read_u32::<LittleEndian>()?;
+            process.rpc_clients.push((handle, read_string(r)?));
+        }
+        for _ in 0..read_len(r)? {
+            let handle = r.read_u32::<LittleEndian>()?;
+            let service_name = read_string(r)?;
+            let method_names = (0..read_len(r)?)
+                .map(|_| read_string(r))
+                .collect::<io::Result<_>>()?;
+            let waiting_task_ids = read_task_ids(r)?;
+            process.rpc_servers.push(SavedRpcServer {
+                handle,
+                service_name,
+                method_names,
+                waiting_task_ids,
+            });
+        }
+        for _ in 0..read_len(r)? {
+            let service_name = read_string(r)?;
+            let task_id = TaskId(r.read_u32::<LittleEndian>()?);
+            process
+                .tasks_waiting_for_servers
+                .push((service_name, task_id));
+        }
+        process.host_shutdown_tasks = read_task_ids(r)?;
+        for _ in 0..read_len(r)? {
+            let task_id = TaskId(r.read_u32::<LittleEndian>()?);
+            let remaining = Duration::from_micros(r.read_u64::<LittleEndian>()?);
+            process.timers.push((task_id, remaining));
+        }
+
+        let mut wakes = Vec::new();
+        for _ in 0..read_len(r)? {
+            let task_id = TaskId(r.read_u32::<LittleEndian>()?);
+            let param = r.read_u32::<LittleEndian>()?;
+            wakes.push(WakeParams { task_id, param });
+        }
+
+        Ok(Self {
+            module_ref,
+            module_hash,
+            principal,
+            monotonic_time,
+            memory,
+            globals,
+            process,
+            wakes,
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_len(w: &mut impl Write, len: usize) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(len.try_into().unwrap())
+}
+
+fn read_len(r: &mut impl Read) -> io::Result<usize> {
+    Ok(r.read_u32::<LittleEndian>()? as usize)
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = r.read_u64::<LittleEndian>()?;
+    let mut bytes = Vec::new();
+    // Reading through `take` keeps a corrupt length from allocating more than the file holds.
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| invalid_data("string is not UTF-8"))
+}
+
+fn write_task_ids(w: &mut impl Write, task_ids: &[TaskId]) -> io::Result<()> {
+    write_len(w, task_ids.len())?;
+    for task_id in task_ids {
+        w.write_u32::<LittleEndian>(task_id.0)?;
+    }
+    Ok(())
+}
+
+fn read_task_ids(r: &mut impl Read) -> io::Result<Vec<TaskId>> {
+    (0..read_len(r)?)
+        .map(|_| Ok(TaskId(r.read_u32::<LittleEndian>()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use wasmtime::{Engine, Instance, Module, Store, Val};
+
+    use super::Snapshot;
+    use crate::process::process::Process;
+    use crate::{TaskId, WakeParams};
+
+    const MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global (export "counter") (mut i64) (i64.const 0))
+            (global (export "limit") i32 (i32.const 10))
+            (func (export "wake") (param i32 i32)))
+    "#;
+
+    #[tokio::test]
+    async fn restores_memory_globals_handles_and_wakes() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, MODULE).unwrap();
+
+        let (process, mut wake_queue_receiver) = Process::new(1, "snapshotted".to_owned(), None);
+        let process = Arc::new(process);
+        let mut store = Store::new(&engine, Arc::clone(&process));
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        memory.grow(&mut store, 1).unwrap();
+        memory.data_mut(&mut store)[70_000..70_005].copy_from_slice(b"hello");
+        instance
+            .get_global(&mut store, "counter")
+            .unwrap()
+            .set(&mut store, Val::I64(-42))
+            .unwrap();
+        process.rpc_client_create("first".to_owned());
+        let second = process.rpc_client_create("second".to_owned());
+        Process::sleep(&process, TaskId(5), Duration::from_secs(60));
+        let wake = WakeParams {
+            task_id: TaskId(9),
+            param: 3,
+        };
+        process.wake_queue_sender().send(wake).unwrap();
+
+        let snapshot = Snapshot::capture(
+            &mut store,
+            &instance,
+            &mut wake_queue_receiver,
+            "snapshotted.wasm",
+            [7; 32],
+        )
+        .unwrap();
+        // The queued wake is left for the process.
+        assert_eq!(wake_queue_receiver.recv().await.unwrap().task_id, TaskId(9));
+        let snapshot = Snapshot::decode(&snapshot.encode()).unwrap();
+        assert_eq!(snapshot.module_ref, "snapshotted.wasm");
+        assert_eq!(snapshot.module_hash, [7; 32]);
+
+        let (mut state, mut wake_queue_receiver) =
+            Process::new(2, snapshot.principal.clone(), None);
+        state.set_monotonic_time(snapshot.monotonic_time);
+        let restored = Arc::new(state);
+        let mut store = Store::new(&engine, Arc::clone(&restored));
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        snapshot.apply(&mut store, &instance).unwrap();
+
+        assert_eq!(restored.principal(), "snapshotted");
+        assert!(restored.start_time().elapsed() >= snapshot.monotonic_time);
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        assert_eq!(memory.size(&store), 2);
+        assert_eq!(&memory.data(&store)[70_000..70_005], b"hello");
+        let counter = instance.get_global(&mut store, "counter").unwrap();
+        assert_eq!(counter.get(&mut store).unwrap_i64(), -42);
+        assert_eq!(
+            restored.handles().rpc_clients,
+            vec!["first".to_owned(), "second".to_owned()]
+        );
+        // Handles keep their numbers, since the guest holds on to them.
+        assert_eq!(Process::save(&restored).unwrap().rpc_clients[1].0, second);
+        assert_eq!(Process::save(&restored).unwrap().timers[0].0, TaskId(5));
+        let restored_wake = wake_queue_receiver.recv().await.unwrap();
+        assert_eq!(restored_wake.task_id, wake.task_id);
+        assert_eq!(restored_wake.param, wake.param);
+    }
+
+    #[test]
+    fn saves_beside_files_with_other_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("app.tmp");
+        std::fs::write(&other, "other").unwrap();
+        let snapshot = Snapshot {
+            module_ref: "app.wasm".to_owned(),
+            module_hash: [0; 32],
+            principal: "app".to_owned(),
+            monotonic_time: Duration::ZERO,
+            memory: Vec::new(),
+            globals: Vec::new(),
+            process: Default::default(),
+            wakes: Vec::new(),
+        };
+        let path = dir.path().join("app.snapshot");
+        snapshot.save(&path).unwrap();
+        assert_eq!(std::fs::read(&other).unwrap(), b"other");
+        let saved = Snapshot::decode(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.module_ref, "app.wasm");
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        match Snapshot::decode(b"\0asm\x01\0\0\0") {
+            Ok(_) => panic!("decoded a module as a snapshot"),
+            Err(e) => assert_eq!(e.to_string(), "not a snapshot"),
+        }
+    }
+}
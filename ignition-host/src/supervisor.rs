@@ -0,0 +1,811 @@
+//! Keeps track of running processes, and starts, stops and upgrades them.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info, warn};
+use testable_file_system::RealFileSystem;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::{sleep, timeout_at};
+use tonic::transport::Channel;
+use wasmtime::{Engine, Instance, InstancePre, Linker, Store, Trap};
+
+use crate::api;
+use crate::crash::CrashReport;
+use crate::module_loader::ModuleLoader;
+use crate::namespace::Namespace;
+use crate::process::process::Process;
+use crate::process::service_registry::SERVICE_REGISTRY;
+use crate::service::parse_blob_id;
+use crate::snapshot::Snapshot;
+use crate::wake::Waker;
+use crate::WakeParams;
+
+/// How long a new version of a module has to register the services of the version it replaces.
+const UPGRADE_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the old version of a module has to finish the requests it was given before the upgrade.
+const UPGRADE_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a shutdown checks whether every process has exited.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
+pub struct ProcessEntry {
+    /// A path or blob ID, as accepted by [`ModuleLoader::load`].
+    pub module_ref: String,
+    pub loaded_at: SystemTime,
+    pub process: Arc<Process>,
+    /// Set once an upgrade has moved the process's services to its replacement.
+    pub draining: bool,
+    /// How long the process took to load, link and instantiate, up to its first wake.
+    pub spawn_time: Duration,
+    snapshot_sender: UnboundedSender<SnapshotRequest>,
+}
+
+/// Asks a process's run task to save a snapshot, which it takes between wakes.
+struct SnapshotRequest {
+    path: PathBuf,
+    /// Exit once the snapshot is saved, so that no more work is done that it would miss.
+    stop: bool,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+/// A module linked against the host API, ready to be instantiated by any number of processes.
+struct Prepared {
+    /// For a module loaded from a file, when the file was last modified. A newer file is prepared
+    /// again.
+    modified: Option<SystemTime>,
+    instance_pre: Arc<InstancePre<Arc<Process>>>,
+    /// The BLAKE3 hash of the module's bytes.
+    hash: [u8; 32],
+}
+
+pub struct Supervisor {
+    loader: ModuleLoader<Channel, RealFileSystem>,
+    /// Shared by every process that needs no optional features. Modules that do are linked against
+    /// their own, which offers those features' imports too.
+    linker: Linker<Arc<Process>>,
+    /// Keyed by module reference. Like compiled modules, each is prepared only once, even when
+    /// several processes start it at the same time.
+    prepared: Mutex<BTreeMap<String, Arc<AsyncMutex<Option<Prepared>>>>>,
+    namespace: Option<Arc<dyn Namespace>>,
+    next_pid: AtomicUsize,
+    processes: Mutex<BTreeMap<usize, ProcessEntry>>,
+    /// Set under the `processes` lock, so that every process either sees it or is told about it.
+    is_shutting_down: AtomicBool,
+    exit_sender: UnboundedSender<Result<()>>,
+}
+
+impl Supervisor {
+    /// Also returns a receiver for the outcome of every process, which is a [`CrashReport`] error if
+    /// the process trapped.
+    pub fn new(
+        loader: ModuleLoader<Channel, RealFileSystem>,
+        namespace: Option<Arc<dyn Namespace>>,
+    ) -> Result<(Arc<Self>, UnboundedReceiver<Result<()>>)> {
+        let (exit_sender, exit_receiver) = unbounded_channel();
+        let linker = new_linker(loader.engine(), &BTreeSet::new())?;
+        let supervisor = Arc::new(Self {
+            loader,
+            linker,
+            prepared: Mutex::new(BTreeMap::new()),
+            namespace,
+            next_pid: AtomicUsize::new(0),
+            processes: Mutex::new(BTreeMap::new()),
+            is_shutting_down: AtomicBool::new(false),
+            exit_sender,
+        });
+        Ok((supervisor, exit_receiver))
+    }
+
+    /// Returns true once every process has exited.
+    pub fn is_empty(&self) -> bool {
+        self.processes.lock().unwrap().is_empty()
+    }
+
+    /// Returns the running processes by pid.
+    pub fn processes(&self) -> BTreeMap<usize, ProcessEntry> {
+        self.processes.lock().unwrap().clone()
+    }
+
+    /// Reserves a pid for [`start_as`](Self::start_as).
+    pub fn new_pid(&self) -> usize {
+        self.next_pid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Starts a process running `module_ref` and returns its pid. Its exit is reported separately.
+    ///
+    /// The process acts as `principal`, which host services like KvService keep its durable state
+    /// under. Without one, it acts as its module, named by canonical path or blob ID, so a new
+    /// version of the module won't see the old one's state unless both are given the same
+    /// principal.
+    pub async fn start(
+        self: &Arc<Self>,
+        module_ref: &str,
+        principal: Option<&str>,
+    ) -> Result<usize> {
+        let pid = self.new_pid();
+        self.start_as(pid, module_ref, principal).await?;
+        Ok(pid)
+    }
+
+    /// Like [`start`](Self::start) with a pid from [`new_pid`](Self::new_pid). Returns how long
+    /// the process took to spawn.
+    pub async fn start_as(
+        self: &Arc<Self>,
+        pid: usize,
+        module_ref: &str,
+        principal: Option<&str>,
+    ) -> Result<Duration> {
+        let principal = match principal {
+            Some(principal) => principal.to_owned(),
+            None => module_principal(module_ref).await,
+        };
+        self.launch(pid, module_ref, principal, None).await
+    }
+
+    /// Starts a process from a snapshot taken by [`snapshot`](Self::snapshot), here or in another
+    /// host, and returns its pid.
+    pub async fn restore(self: &Arc<Self>, snapshot: &Snapshot) -> Result<usize> {
+        let pid = self.new_pid();
+        self.launch(
+            pid,
+            &snapshot.module_ref,
+            snapshot.principal.clone(),
+            Some(snapshot),
+        )
+        .await?;
+        Ok(pid)
+    }
+
+    /// Starts process `pid` as `principal`, either afresh or from `snapshot`, and returns how long
+    /// that took.
+    async fn launch(
+        self: &Arc<Self>,
+        pid: usize,
+        module_ref: &str,
+        principal: String,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Duration> {
+        info!("pid {}: Loading {} as {}", pid, module_ref, principal);
+        let start_time = Instant::now();
+
+        let (mut state, wake_queue_receiver) = Process::new(pid, principal, self.namespace.clone());
+        if let Some(snapshot) = snapshot {
+            state.set_monotonic_time(snapshot.monotonic_time);
+        }
+        let process = Arc::new(state);
+        let mut store = Store::new(self.loader.engine(), Arc::clone(&process));
+        process.set_interrupt_handle(store.interrupt_handle()?);
+
+        let (instance_pre, module_hash) = self
+            .prepare(module_ref, &mut store)
+            .await
+            .with_context(|| format!("pid {}: Failed to load {}", pid, module_ref))?;
+        if let Some(snapshot) = snapshot {
+            if snapshot.module_hash != module_hash {
+                bail!(
+                    "pid {}: {} isn't the module the snapshot was taken of",
+                    pid,
+                    module_ref
+                );
+            }
+        }
+        let instance =
+            instance_pre
+                .instantiate(&mut store)
+                .map_err(|e| match e.downcast::<Trap>() {
+                    Ok(trap) => crash_report(&process, module_ref, &trap).into(),
+                    Err(e) => {
+                        e.context(format!("pid {}: Failed to instantiate {}", pid, module_ref))
+                    }
+                })?;
+        // Restoring a snapshot registers the process's servers, which mustn't outlive a failed
+        // launch.
+        let registered = RegisteredServers(Some(Arc::clone(&process)));
+        match snapshot {
+            Some(snapshot) => snapshot
+                .apply(&mut store, &instance)
+                .with_context(|| format!("pid {}: Failed to restore snapshot", pid))?,
+            None => process.wake_queue_sender().send(WakeParams::INIT).unwrap(),
+        }
+        // After any snapshot is applied, so that a wake buffer is allocated in the restored memory.
+        let waker = Waker::new(&mut store, &instance)
+            .with_context(|| format!("pid {}: Can't wake {}", pid, module_ref))?;
+
+        let spawn_time = start_time.elapsed();
+        debug!("pid {}: Spawned in {:?}", pid, spawn_time);
+
+        let (snapshot_sender, snapshot_receiver) = unbounded_channel();
+        {
+            let mut processes = self.processes.lock().unwrap();
+            if self.is_shutting_down.load(Ordering::SeqCst) {
+                bail!("pid {}: The host is shutting down", pid);
+            }
+            processes.insert(
+                pid,
+                ProcessEntry {
+                    module_ref: module_ref.to_owned(),
+                    loaded_at: SystemTime::now(),
+                    process: Arc::clone(&process),
+                    draining: false,
+                    spawn_time,
+                    snapshot_sender,
+                },
+            );
+        }
+        // From here on, the run task removes the process from the registry when it exits.
+        registered.keep();
+        let supervisor = Arc::clone(self);
+        let module_ref = module_ref.to_owned();
+        tokio::spawn(async move {
+            let result = run(
+                &mut store,
+                &instance,
+                waker,
+                wake_queue_receiver,
+                snapshot_receiver,
+                &module_ref,
+                module_hash,
+            )
+            .await;
+            SERVICE_REGISTRY.remove_process(&process);
+            let upgraded = {
+                // Report the exit before anyone can see that the process is gone.
+                let mut processes = supervisor.processes.lock().unwrap();
+                let entry = processes.remove(&pid);
+                // The receiver only goes away when the host is exiting anyway.
+                let _ = supervisor.exit_sender.send(result);
+                entry.is_some_and(|entry| entry.draining)
+            };
+            supervisor.evict_prepared(&module_ref, upgraded).await;
+        });
+        Ok(spawn_time)
+    }
+
+    /// Loads `module_ref`, checks that it was built for an ABI this host provides, and links it, or
+    /// returns it as already prepared for an earlier process. Also returns the module's hash.
+    /// `store` is only used to check the imports.
+    async fn prepare(
+        &self,
+        module_ref: &str,
+        store: &mut Store<Arc<Process>>,
+    ) -> Result<(Arc<InstancePre<Arc<Process>>>, [u8; 32])> {
+        let slot = Arc::clone(
+            self.prepared
+                .lock()
+                .unwrap()
+                .entry(module_ref.to_owned())
+                .or_default(),
+        );
+        let mut slot = slot.lock().await;
+        let modified = modified(module_ref).await;
+        if let Some(prepared) = &*slot {
+            if prepared.modified == modified {
+                return Ok((Arc::clone(&prepared.instance_pre), prepared.hash));
+            }
+        }
+
+        let loaded = match self.loader.load(module_ref).await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // Nothing is kept for a module that doesn't load, e.g. a mistyped path.
+                if slot.is_none() {
+                    self.prepared.lock().unwrap().remove(module_ref);
+                }
+                return Err(e);
+            }
+        };
+        loaded.abi.check(&loaded.module)?;
+        let linker = if loaded.abi.features.is_empty() {
+            Cow::Borrowed(&self.linker)
+        } else {
+            Cow::Owned(new_linker(self.loader.engine(), &loaded.abi.features)?)
+        };
+        let instance_pre = Arc::new(linker.instantiate_pre(store, &loaded.module)?);
+        *slot = Some(Prepared {
+            modified,
+            instance_pre: Arc::clone(&instance_pre),
+            hash: loaded.hash,
+        });
+        Ok((instance_pre, loaded.hash))
+    }
+
+    /// Forgets the prepared form of `module_ref` once no process runs it, if it was `upgraded` away
+    /// from or its file has changed or gone. Otherwise it's kept for the next process, however
+    /// short-lived its processes are.
+    async fn evict_prepared(&self, module_ref: &str, upgraded: bool) {
+        let slot = match self.prepared.lock().unwrap().get(module_ref) {
+            Some(slot) => Arc::clone(slot),
+            None => return,
+        };
+        let is_current = match &*slot.lock().await {
+            Some(prepared) => prepared.modified == modified(module_ref).await,
+            None => false,
+        };
+        if is_current && !upgraded {
+            return;
+        }
+        // Checked last, and under the same lock that processes are added with, though a process
+        // starting now has its module already and would only make the next one prepare it again.
+        let processes = self.processes.lock().unwrap();
+        if processes
+            .values()
+            .all(|entry| entry.module_ref != module_ref)
+        {
+            self.prepared.lock().unwrap().remove(module_ref);
+        }
+    }
+
+    /// Saves a snapshot of process `pid` to `path` once it's between wakes, and then, if `stop` is
+    /// set, has it exit. Fails unless the process is idle; see [`Process::save`].
+    pub async fn snapshot(&self, pid: usize, path: &Path, stop: bool) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        match self.processes.lock().unwrap().get(&pid) {
+            Some(entry) => {
+                // The process may exit before it gets the request.
+                let _ = entry.snapshot_sender.send(SnapshotRequest {
+                    path: path.to_owned(),
+                    stop,
+                    reply,
+                });
+            }
+            None => bail!("no process with pid {}", pid),
+        }
+        response
+            .await
+            .map_err(|_| anyhow!("pid {} exited before the snapshot", pid))?
+    }
+
+    /// Kills process `pid`. Its exit is reported like any other.
+    pub fn kill(&self, pid: usize) -> Result<()> {
+        match self.processes.lock().unwrap().get(&pid) {
+            Some(entry) => entry.process.kill(),
+            None => bail!("no process with pid {}", pid),
+        }
+        Ok(())
+    }
+
+    /// Replaces process `pid` with a new one running `module_ref` as the same principal, and
+    /// returns the new pid.
+    ///
+    /// Once the new process serves every service the old one did, new requests go only to the new
+    /// process. The old one is killed after responding to every request it was already given.
+    /// If the new process doesn't come up, it's killed instead and the old one carries on.
+    pub async fn upgrade(self: &Arc<Self>, pid: usize, module_ref: &str) -> Result<usize> {
+        let old = match self.processes.lock().unwrap().get(&pid) {
+            Some(entry) => Arc::clone(&entry.process),
+            None => bail!("no process with pid {}", pid),
+        };
+        let services = SERVICE_REGISTRY.services_served_by(&old);
+
+        // The new version carries on as the same principal, with the same durable state.
+        let new_pid = self.start(module_ref, Some(old.principal())).await?;
+        let new = match self.processes.lock().unwrap().get(&new_pid) {
+            Some(entry) => Arc::clone(&entry.process),
+            None => bail!("pid {} exited during upgrade", new_pid),
+        };
+        let deadline = tokio::time::Instant::now() + UPGRADE_START_TIMEOUT;
+        loop {
+            // Before checking, so that a server registering in between isn't missed.
+            let changed = SERVICE_REGISTRY.changed();
+            if SERVICE_REGISTRY
+                .services_served_by(&new)
+                .is_superset(&services)
+            {
+                break;
+            }
+            if new.is_shutdown() {
+                bail!("pid {} exited during upgrade", new_pid);
+            }
+            if timeout_at(deadline, changed).await.is_err() {
+                new.kill();
+                bail!(
+                    "pid {} didn't register all of {:?} in time",
+                    new_pid,
+                    services
+                );
+            }
+        }
+
+        info!("pid {}: Upgrading to pid {}", pid, new_pid);
+        SERVICE_REGISTRY.unregister_servers(&old);
+        if let Some(entry) = self.processes.lock().unwrap().get_mut(&pid) {
+            entry.draining = true;
+        }
+        // Requests that picked the old process just before it was unregistered count as pending
+        // until they reach its queue.
+        let deadline = tokio::time::Instant::now() + UPGRADE_DRAIN_TIMEOUT;
+        loop {
+            let drained = old.drained();
+            if !old.has_pending_requests() || old.is_shutdown() {
+                break;
+            }
+            if timeout_at(deadline, drained).await.is_err() {
+                warn!("pid {}: Requests still pending after upgrade", pid);
+                break;
+            }
+        }
+        old.kill();
+        Ok(new_pid)
+    }
+
+    /// Stops starting processes and routing requests to guests, and tells every guest that asked
+    /// that the host is shutting down. Processes still running after `grace_period` are killed.
+    /// Completes once every process has exited.
+    pub async fn shutdown(self: &Arc<Self>, grace_period: Duration) {
+        {
+            let processes = self.processes.lock().unwrap();
+            self.is_shutting_down.store(true, Ordering::SeqCst);
+            for entry in processes.values() {
+                entry.process.request_host_shutdown();
+            }
+        }
+
+        let _grace_period = GracePeriod::start(Arc::clone(self), grace_period);
+        while !self.is_empty() {
+            sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Upgrades every process whose module is a file that has changed since it was loaded. Blob
+    /// IDs always refer to the same module, so processes loaded from blobs are left alone.
+    pub async fn reload(self: &Arc<Self>) {
+        for (pid, entry) in self.processes() {
+            let modified = match modified(&entry.module_ref).await {
+                Some(modified) => modified,
+                // Not a file, or not one that can be reloaded.
+                None => continue,
+            };
+            if modified <= entry.loaded_at {
+                continue;
+            }
+            if let Err(e) = self.upgrade(pid, &entry.module_ref).await {
+                error!("pid {}: Upgrade failed: {:#}", pid, e);
+            }
+        }
+    }
+}
+
+/// When the file `module_ref` names was last modified, or None for a blob ID, which always refers to
+/// the same module.
+async fn modified(module_ref: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(module_ref)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// The principal of a process started without one: its module's canonical path, or blob ID.
+async fn module_principal(module_ref: &str) -> String {
+    if parse_blob_id(module_ref).is_some() {
+        return module_ref.to_owned();
+    }
+    match tokio::fs::canonicalize(module_ref).await {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        // Loading the module will fail and say why.
+        Err(_) => module_ref.to_owned(),
+    }
+}
+
+fn new_linker(engine: &Engine, features: &BTreeSet<String>) -> Result<Linker<Arc<Process>>> {
+    let mut linker = Linker::new(engine);
+    api::add_to_linker(&mut linker, features)?;
+    Ok(linker)
+}
+
+/// Kills every process still running once a shutdown's grace period is up, unless it's dropped
+/// first.
+struct GracePeriod(Arc<(Mutex<bool>, Condvar)>);
+
+impl GracePeriod {
+    fn start(supervisor: Arc<Supervisor>, grace_period: Duration) -> Self {
+        let cancelled = Arc::new((Mutex::new(false), Condvar::new()));
+        let grace = GracePeriod(Arc::clone(&cancelled));
+        // A guest stuck in a wake holds on to its runtime thread, which may be the only one, so
+        // the deadline is kept by a thread of its own.
+        std::thread::spawn(move || {
+            let (cancelled, condvar) = &*cancelled;
+            let (cancelled, _) = condvar
+                .wait_timeout_while(cancelled.lock().unwrap(), grace_period, |cancelled| {
+                    !*cancelled
+                })
+                .unwrap();
+            if *cancelled {
+                return;
+            }
+            for (pid, entry) in supervisor.processes() {
+                warn!("pid {}: Still running after the grace period", pid);
+                entry.process.kill();
+            }
+        });
+        grace
+    }
+}
+
+impl Drop for GracePeriod {
+    fn drop(&mut self) {
+        let (cancelled, condvar) = &*self.0;
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+}
+
+/// Removes a process from the service registry when dropped, unless [`keep`](Self::keep) is called
+/// first.
+struct RegisteredServers(Option<Arc<Process>>);
+
+impl RegisteredServers {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for RegisteredServers {
+    fn drop(&mut self) {
+        if let Some(process) = self.0.take() {
+            SERVICE_REGISTRY.remove_process(&process);
+        }
+    }
+}
+
+/// Dispatches wake events until the process shuts down or is killed, taking snapshots between
+/// them as they're requested.
+async fn run(
+    store: &mut Store<Arc<Process>>,
+    instance: &Instance,
+    waker: Waker,
+    mut wake_queue_receiver: UnboundedReceiver<WakeParams>,
+    mut snapshot_receiver: UnboundedReceiver<SnapshotRequest>,
+    module_ref: &str,
+    module_hash: [u8; 32],
+) -> Result<()> {
+    let process = Arc::clone(store.data());
+    let pid = process.pid();
+    while !process.is_shutdown() {
+        let params = tokio::select! {
+            params = wake_queue_receiver.recv() => params.unwrap(),
+            Some(request) = snapshot_receiver.recv() => {
+                // The snapshot is saved before the process can exit, since the host may exit with it.
+                let result = Snapshot::capture(
+                    store,
+                    instance,
+                    &mut wake_queue_receiver,
+                    module_ref,
+                    module_hash,
+                )
+                .and_then(|snapshot| snapshot.save(&request.path));
+                if let Err(e) = &result {
+                    warn!("pid {}: Failed to snapshot: {:#}", pid, e);
+                }
+                let stop = request.stop && result.is_ok();
+                let _ = request.reply.send(result);
+                if stop {
+                    break;
+                }
+                continue;
+            }
+            () = process.killed() => break,
+        };
+        match waker.run_turn(store, params, &mut wake_queue_receiver) {
+            Ok(false) => {}
+            // Let other processes have a turn before delivering the rest.
+            Ok(true) => tokio::task::yield_now().await,
+            // Being killed mid-wake traps the guest, but that's no crash.
+            Err(_) if process.is_killed() => break,
+            Err(trap) => return Err(crash_report(&process, module_ref, &trap).into()),
+        }
+    }
+
+    match process.heap_stats() {
+        Some(stats) => info!("pid {}: Quit ({})", pid, stats),
+        None => info!("pid {}: Quit", pid),
+    }
+    Ok(())
+}
+
+fn crash_report(process: &Process, module_ref: &str, trap: &Trap) -> CrashReport {
+    CrashReport::new(process.pid(), module_ref, process.take_guest_panic(), trap)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use testable_file_system::RealFileSystem;
+    use tokio::time::timeout;
+    use tonic::transport::Channel;
+
+    use super::Supervisor;
+    use crate::engine::EngineOptions;
+    use crate::module_loader::ModuleLoader;
+    use crate::process::process::{SavedProcess, SavedRpcServer};
+    use crate::process::service_registry::SERVICE_REGISTRY;
+    use crate::snapshot::Snapshot;
+
+    /// Waits for the host to shut down, then exits.
+    const COOPERATIVE_MODULE: &str = r#"
+        (module
+            (import "ignition" "host_shutdown_requested" (func $host_shutdown_requested (param i32)))
+            (import "ignition" "shutdown" (func $shutdown))
+            (memory (export "memory") 1)
+            (func (export "wake") (param $task_id i32) (param $param i32)
+                (if (i32.eq (local.get $task_id) (i32.const -1))
+                    (then (call $host_shutdown_requested (i32.const 0)))
+                    (else (call $shutdown)))))
+    "#;
+
+    /// Never exits by itself.
+    const STUBBORN_MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "wake") (param i32 i32)))
+    "#;
+
+    fn supervisor() -> Arc<Supervisor> {
+        let engine = EngineOptions::default().build().unwrap();
+        let loader = ModuleLoader::<Channel, RealFileSystem>::new(engine, None, None);
+        Supervisor::new(loader, None).unwrap().0
+    }
+
+    fn write_module(dir: &Path, name: &str, wat: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn guests_exit_when_the_host_shuts_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = write_module(dir.path(), "cooperative.wasm", COOPERATIVE_MODULE);
+        let supervisor = supervisor();
+        supervisor
+            .start(module.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert!(!supervisor.is_empty());
+
+        // With an hour's grace, only the guest exiting by itself ends the shutdown.
+        let grace = Duration::from_secs(3600);
+        timeout(Duration::from_secs(10), supervisor.shutdown(grace))
+            .await
+            .expect("the guest didn't exit");
+        assert!(supervisor.is_empty());
+        // Nor does the grace period's thread hold on to the supervisor for the rest of the hour.
+        timeout(Duration::from_secs(10), async {
+            while Arc::strong_count(&supervisor) > 1 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the grace period wasn't cancelled");
+    }
+
+    #[tokio::test]
+    async fn guests_still_running_after_the_grace_period_are_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = write_module(dir.path(), "stubborn.wasm", STUBBORN_MODULE);
+        let supervisor = supervisor();
+        supervisor
+            .start(module.to_str().unwrap(), None)
+            .await
+            .unwrap();
+
+        timeout(
+            Duration::from_secs(10),
+            supervisor.shutdown(Duration::from_millis(10)),
+        )
+        .await
+        .expect("the guest wasn't killed");
+        assert!(supervisor.is_empty());
+        // No more processes start once the host is shutting down.
+        assert!(supervisor
+            .start(module.to_str().unwrap(), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_snapshots_only_into_the_same_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = write_module(dir.path(), "idle.wasm", STUBBORN_MODULE);
+        let host = supervisor();
+        let pid = host.start(module.to_str().unwrap(), None).await.unwrap();
+        let path = dir.path().join("idle.snapshot");
+        host.snapshot(pid, &path, true).await.unwrap();
+        let snapshot = Snapshot::decode(&std::fs::read(&path).unwrap()).unwrap();
+        host.restore(&snapshot).await.unwrap();
+
+        // A host that finds a different module at the same path refuses it.
+        write_module(dir.path(), "idle.wasm", COOPERATIVE_MODULE);
+        let other_host = supervisor();
+        let error = other_host.restore(&snapshot).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("isn't the module the snapshot was taken of"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_restores_leave_no_servers_behind() {
+        const SERVICE_NAME: &str = "FailedRestoreTestService";
+        let dir = tempfile::tempdir().unwrap();
+        // Restores, but can't be woken.
+        let module = write_module(
+            dir.path(),
+            "unwakeable.wasm",
+            "(module (memory (export \"memory\") 1))",
+        );
+        let snapshot = Snapshot {
+            module_ref: module.to_str().unwrap().to_owned(),
+            module_hash: *blake3::hash(&std::fs::read(&module).unwrap()).as_bytes(),
+            principal: "test".to_owned(),
+            monotonic_time: Duration::ZERO,
+            memory: Vec::new(),
+            globals: Vec::new(),
+            process: SavedProcess {
+                rpc_servers: vec![SavedRpcServer {
+                    handle: 0,
+                    service_name: SERVICE_NAME.to_owned(),
+                    method_names: Vec::new(),
+                    waiting_task_ids: Vec::new(),
+                }],
+                ..Default::default()
+            },
+            wakes: Vec::new(),
+        };
+        let error = supervisor().restore(&snapshot).await.unwrap_err();
+        assert!(error.to_string().contains("Can't wake"), "{:#}", error);
+        assert!(!SERVICE_REGISTRY
+            .list()
+            .iter()
+            .any(|(service_name, _)| service_name == SERVICE_NAME));
+    }
+
+    #[tokio::test]
+    async fn forgets_prepared_modules_that_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = write_module(dir.path(), "idle.wasm", STUBBORN_MODULE);
+        let module = module.to_str().unwrap();
+        let supervisor = supervisor();
+        let missing = dir.path().join("missing.wasm");
+        assert!(supervisor
+            .start(missing.to_str().unwrap(), None)
+            .await
+            .is_err());
+        assert!(supervisor.prepared.lock().unwrap().is_empty());
+
+        // An unchanged module stays prepared for the next process.
+        let pid = supervisor.start(module, None).await.unwrap();
+        supervisor.kill(pid).unwrap();
+        while !supervisor.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(supervisor.prepared.lock().unwrap().contains_key(module));
+
+        // One whose file is gone is forgotten once its last process exits.
+        let pid = supervisor.start(module, None).await.unwrap();
+        std::fs::remove_file(module).unwrap();
+        supervisor.kill(pid).unwrap();
+        timeout(Duration::from_secs(10), async {
+            while supervisor.prepared.lock().unwrap().contains_key(module) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the module is still prepared");
+    }
+}
@@ -1,9 +1,18 @@
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 
 use wasmtime::{AsContext, Caller, Extern, Memory, StoreContext, StoreContextMut, Trap};
 
 pub mod pointer_identity_arc;
 
+/// Where to write `path` before renaming it into place: beside it, with ".tmp" added to its whole
+/// file name, so that it can't be mistaken for a file that only differs in its extension.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
 pub fn get_memory<T>(caller: &mut Caller<T>) -> Result<Memory, Trap> {
     match caller.get_export("memory") {
         Some(Extern::Memory(memory)) => Ok(memory),
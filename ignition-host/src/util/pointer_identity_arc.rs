@@ -2,21 +2,21 @@ use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::sync::Arc;
 
-pub struct PointerIdentityArc<T>(Arc<T>);
+pub struct PointerIdentityArc<T: ?Sized>(Arc<T>);
 
-impl<T> PointerIdentityArc<T> {
+impl<T: ?Sized> PointerIdentityArc<T> {
     pub fn new(arc: Arc<T>) -> Self {
         Self(arc)
     }
 }
 
-impl<T> Clone for PointerIdentityArc<T> {
+impl<T: ?Sized> Clone for PointerIdentityArc<T> {
     fn clone(&self) -> Self {
         Self(Arc::clone(&self.0))
     }
 }
 
-impl<T> Deref for PointerIdentityArc<T> {
+impl<T: ?Sized> Deref for PointerIdentityArc<T> {
     type Target = Arc<T>;
 
     fn deref(&self) -> &Self::Target {
@@ -24,16 +24,17 @@ impl<T> Deref for PointerIdentityArc<T> {
     }
 }
 
-impl<T> PartialEq for PointerIdentityArc<T> {
+impl<T: ?Sized> PartialEq for PointerIdentityArc<T> {
     fn eq(&self, other: &Self) -> bool {
         Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-impl<T> Eq for PointerIdentityArc<T> {}
+impl<T: ?Sized> Eq for PointerIdentityArc<T> {}
 
-impl<T> Hash for PointerIdentityArc<T> {
+impl<T: ?Sized> Hash for PointerIdentityArc<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        Arc::as_ptr(&self.0).hash(state);
+        // Only the address, to agree with `Arc::ptr_eq` for unsized types.
+        Arc::as_ptr(&self.0).cast::<()>().hash(state);
     }
 }
@@ -0,0 +1,247 @@
+//! Delivers the wakes in a process's queue to its instance.
+//!
+//! Every call into a guest costs a transition, so when the guest exports `wake_many`, the host
+//! writes queued wakes to a buffer in guest memory and delivers them a batch per call. The guest
+//! provides the buffer through its `wake_buffer` export, which takes the number of entries the host
+//! wants room for and returns the buffer's address. Each entry is a task ID and a param, both
+//! little-endian `u32`s. Guests without those exports get a `wake` call per wake.
+//!
+//! A process delivers at most [`TURN_BUDGET`] wakes before its run task yields, so that one busy
+//! process can't keep others on the same runtime thread waiting.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use futures::FutureExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use wasmtime::{AsContextMut, Instance, Memory, Store, Trap, TypedFunc};
+
+use crate::process::process::Process;
+use crate::util::get_slice_mut;
+use crate::WakeParams;
+
+/// How many wakes fit in a guest's wake buffer.
+const BATCH_SIZE: u32 = 256;
+
+/// The size of one wake buffer entry.
+const ENTRY_SIZE: u32 = 8;
+
+/// How many wakes a process is given before others get a turn.
+pub const TURN_BUDGET: usize = 1024;
+
+pub enum Waker {
+    Batched {
+        wake_many: TypedFunc<u32, ()>,
+        memory: Memory,
+        buffer: u32,
+    },
+    Single(TypedFunc<(u32, u32), ()>),
+}
+
+impl Waker {
+    /// Finds how to wake `instance`, setting up its wake buffer if it takes batches.
+    pub fn new(store: &mut Store<Arc<Process>>, instance: &Instance) -> Result<Self> {
+        let wake_many = instance.get_typed_func::<u32, (), _>(&mut *store, "wake_many");
+        let wake_buffer = instance.get_typed_func::<u32, u32, _>(&mut *store, "wake_buffer");
+        if let (Ok(wake_many), Ok(wake_buffer)) = (wake_many, wake_buffer) {
+            let memory = instance
+                .get_memory(&mut *store, "memory")
+                .ok_or_else(|| anyhow!("the module exports wake_many but no memory"))?;
+            let buffer = wake_buffer
+                .call(&mut *store, BATCH_SIZE)
+                .context("wake_buffer failed")?;
+            // Check the whole buffer now, rather than on every batch.
+            get_slice_mut(
+                store.as_context_mut(),
+                memory,
+                buffer,
+                BATCH_SIZE * ENTRY_SIZE,
+            )
+            .context("wake_buffer returned a buffer out of bounds")?;
+            return Ok(Waker::Batched {
+                wake_many,
+                memory,
+                buffer,
+            });
+        }
+        let wake = instance
+            .get_typed_func::<(u32, u32), (), _>(&mut *store, "wake")
+            .context("the module has no usable wake export")?;
+        Ok(Waker::Single(wake))
+    }
+
+    /// Delivers `first`, then whatever else is queued, for up to [`TURN_BUDGET`] wakes. Returns
+    /// whether the budget ran out, in which case there may be more to deliver.
+    pub fn run_turn(
+        &self,
+        store: &mut Store<Arc<Process>>,
+        first: WakeParams,
+        wake_queue_receiver: &mut UnboundedReceiver<WakeParams>,
+    ) -> Result<bool, Trap> {
+        let process = Arc::clone(store.data());
+        let mut next = Some(first);
+        let mut delivered = 0;
+        while let Some(params) = next {
+            match self {
+                Waker::Batched {
+                    wake_many,
+                    memory,
+                    buffer,
+                } => {
+                    let entries = get_slice_mut(
+                        store.as_context_mut(),
+                        *memory,
+                        *buffer,
+                        BATCH_SIZE * ENTRY_SIZE,
+                    )?;
+                    let mut count = 0;
+                    let mut next_params = Some(params);
+                    while let Some(params) = next_params {
+                        let entry =
+                            &mut entries[count * ENTRY_SIZE as usize..][..ENTRY_SIZE as usize];
+                        entry[..4].copy_from_slice(&params.task_id.0.to_le_bytes());
+                        entry[4..].copy_from_slice(&params.param.to_le_bytes());
+                        count += 1;
+                        if count == BATCH_SIZE as usize || delivered + count == TURN_BUDGET {
+                            break;
+                        }
+                        next_params = try_recv(wake_queue_receiver);
+                    }
+                    wake_many.call(&mut *store, count as u32)?;
+                    delivered += count;
+                }
+                Waker::Single(wake) => {
+                    wake.call(&mut *store, params.into())?;
+                    delivered += 1;
+                }
+            }
+            if delivered == TURN_BUDGET {
+                return Ok(true);
+            }
+            if process.is_shutdown() {
+                break;
+            }
+            next = try_recv(wake_queue_receiver);
+        }
+        Ok(false)
+    }
+}
+
+/// Takes the next queued wake, if there is one.
+pub fn try_recv(wake_queue_receiver: &mut UnboundedReceiver<WakeParams>) -> Option<WakeParams> {
+    // Unconstrained, or the task's cooperative scheduling budget would make a long queue look
+    // empty part way through.
+    tokio::task::unconstrained(wake_queue_receiver.recv())
+        .now_or_never()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use wasmtime::{Engine, Instance, Module, Store};
+
+    use super::{Waker, TURN_BUDGET};
+    use crate::process::process::Process;
+    use crate::{TaskId, WakeParams};
+
+    /// Counts the calls and wakes it gets, and sums the wakes' params.
+    const BATCHED_MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $calls (export "calls") (mut i32) (i32.const 0))
+            (global $wakes (export "wakes") (mut i32) (i32.const 0))
+            (global $sum (export "sum") (mut i32) (i32.const 0))
+            (func (export "wake") (param i32 i32) unreachable)
+            (func (export "wake_buffer") (param i32) (result i32) (i32.const 1024))
+            (func (export "wake_many") (param $count i32)
+                (local $entry i32)
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                (global.set $wakes (i32.add (global.get $wakes) (local.get $count)))
+                (local.set $entry (i32.const 1024))
+                (block $done
+                    (loop $next
+                        (br_if $done (i32.eqz (local.get $count)))
+                        (global.set $sum
+                            (i32.add
+                                (global.get $sum)
+                                (i32.load (i32.add (local.get $entry) (i32.const 4)))))
+                        (local.set $entry (i32.add (local.get $entry) (i32.const 8)))
+                        (local.set $count (i32.sub (local.get $count) (i32.const 1)))
+                        (br $next)))))
+    "#;
+
+    const SINGLE_MODULE: &str = r#"
+        (module
+            (global $calls (export "calls") (mut i32) (i32.const 0))
+            (func (export "wake") (param i32 i32)
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))))
+    "#;
+
+    fn instantiate(wat: &str) -> (Store<Arc<Process>>, Instance) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let (process, _wake_queue_receiver) = Process::new(0, "test".to_owned(), None);
+        let mut store = Store::new(&engine, Arc::new(process));
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        (store, instance)
+    }
+
+    fn global(store: &mut Store<Arc<Process>>, instance: &Instance, name: &str) -> i32 {
+        instance
+            .get_global(&mut *store, name)
+            .unwrap()
+            .get(&mut *store)
+            .unwrap_i32()
+    }
+
+    fn wake(param: u32) -> WakeParams {
+        WakeParams {
+            task_id: TaskId(param),
+            param,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_wakes_in_batches_within_a_budget() {
+        let (mut store, instance) = instantiate(BATCHED_MODULE);
+        let waker = Waker::new(&mut store, &instance).unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        for param in 1..300 {
+            sender.send(wake(param)).unwrap();
+        }
+        assert!(!waker.run_turn(&mut store, wake(0), &mut receiver).unwrap());
+        assert_eq!(global(&mut store, &instance, "calls"), 2);
+        assert_eq!(global(&mut store, &instance, "wakes"), 300);
+        assert_eq!(global(&mut store, &instance, "sum"), (0..300).sum::<i32>());
+
+        for param in 1..TURN_BUDGET as u32 + 10 {
+            sender.send(wake(param)).unwrap();
+        }
+        assert!(waker.run_turn(&mut store, wake(0), &mut receiver).unwrap());
+        assert_eq!(
+            global(&mut store, &instance, "wakes"),
+            300 + TURN_BUDGET as i32
+        );
+        // The rest wait for the next turn.
+        let first = receiver.recv().await.unwrap();
+        assert!(!waker.run_turn(&mut store, first, &mut receiver).unwrap());
+        assert_eq!(
+            global(&mut store, &instance, "wakes"),
+            300 + TURN_BUDGET as i32 + 10
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_a_call_per_wake() {
+        let (mut store, instance) = instantiate(SINGLE_MODULE);
+        let waker = Waker::new(&mut store, &instance).unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        for param in 1..5 {
+            sender.send(wake(param)).unwrap();
+        }
+        assert!(!waker.run_turn(&mut store, wake(0), &mut receiver).unwrap());
+        assert_eq!(global(&mut store, &instance, "calls"), 5);
+    }
+}
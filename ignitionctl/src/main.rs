@@ -0,0 +1,281 @@
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{clap_app, Arg, ArgMatches, SubCommand};
+use ignition_control_proto::control_pb::control_service_client::ControlServiceClient;
+use ignition_control_proto::control_pb::{
+    self, get_heap_stats_request, rpc_server, set_log_level_request, LogLevel, ProcessState,
+};
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+
+const SOCKET_VAR: &str = "IGNITION_CONTROL_SOCKET";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = clap_app!(ignitionctl =>
+        (about: "Inspects and manages a running ignition-host")
+        (@setting SubcommandRequiredElseHelp)
+        (@arg socket: --socket [PATH]
+            "The host's control socket (default: $IGNITION_CONTROL_SOCKET)")
+        (@subcommand ps =>
+            (about: "Lists processes with their state, services, handles and heap usage"))
+        (@subcommand start =>
+            (about: "Starts a process")
+            (@arg module: <MODULE> "A path or a blob ID like blake3:<hex>")
+            (@arg principal: --principal [NAME]
+                "Who the process acts as, keeping its durable state (default: the module)"))
+        (@subcommand kill =>
+            (about: "Kills a process")
+            (@arg pid: <PID>))
+        (@subcommand upgrade =>
+            (about: "Replaces a process with one running a new module, draining the old one")
+            (@arg pid: <PID>)
+            (@arg module: <MODULE>))
+        (@subcommand snapshot =>
+            (about: "Saves an idle process to a file on the host")
+            (@arg pid: <PID>)
+            (@arg file: <FILE>)
+            (@arg stop: --stop "Stops the process once it's saved"))
+        (@subcommand restore =>
+            (about: "Starts a process from a snapshot file on the host")
+            (@arg file: <FILE>))
+        (@subcommand registry =>
+            (about: "Shows the service registry"))
+        (@subcommand heap =>
+            (about: "Shows the heap usage that guests built with the allocator report")
+            (@arg pid: --pid [PID] "Only shows one process"))
+    )
+    // The macro only takes subcommand names that are identifiers.
+    .subcommand(
+        SubCommand::with_name("log-level")
+            .about("Changes the host's log level, or that of one process's guest messages")
+            .arg(
+                Arg::with_name("level")
+                    .required(true)
+                    .possible_values(&["off", "error", "warn", "info", "debug", "trace"]),
+            )
+            .arg(Arg::with_name("pid").long("pid").takes_value(true)),
+    )
+    .get_matches();
+
+    let socket = match matches.value_of_os("socket") {
+        Some(path) => PathBuf::from(path),
+        None => match std::env::var_os(SOCKET_VAR) {
+            Some(path) => PathBuf::from(path),
+            None => bail!("no control socket given with --socket or ${}", SOCKET_VAR),
+        },
+    };
+    let mut client = connect(socket).await?;
+
+    match matches.subcommand() {
+        ("ps", _) => ps(&mut client).await?,
+        ("start", Some(matches)) => {
+            let response = client
+                .start_process(control_pb::StartProcessRequest {
+                    module_ref: matches.value_of("module").unwrap().to_owned(),
+                    principal: matches.value_of("principal").unwrap_or_default().to_owned(),
+                })
+                .await?;
+            println!("{}", response.get_ref().pid);
+        }
+        ("kill", Some(matches)) => {
+            client
+                .kill_process(control_pb::KillProcessRequest {
+                    pid: parse_pid(matches)?.unwrap(),
+                })
+                .await?;
+        }
+        ("upgrade", Some(matches)) => {
+            let response = client
+                .upgrade_process(control_pb::UpgradeProcessRequest {
+                    pid: parse_pid(matches)?.unwrap(),
+                    module_ref: matches.value_of("module").unwrap().to_owned(),
+                })
+                .await?;
+            println!("{}", response.get_ref().new_pid);
+        }
+        ("snapshot", Some(matches)) => {
+            client
+                .snapshot_process(control_pb::SnapshotProcessRequest {
+                    pid: parse_pid(matches)?.unwrap(),
+                    path: absolute_path(matches.value_of("file").unwrap())?,
+                    stop: matches.is_present("stop"),
+                })
+                .await?;
+        }
+        ("restore", Some(matches)) => {
+            let response = client
+                .restore_process(control_pb::RestoreProcessRequest {
+                    path: absolute_path(matches.value_of("file").unwrap())?,
+                })
+                .await?;
+            println!("{}", response.get_ref().pid);
+        }
+        ("registry", _) => registry(&mut client).await?,
+        ("heap", Some(matches)) => heap(&mut client, parse_pid(matches)?).await?,
+        ("log-level", Some(matches)) => {
+            let level = match matches.value_of("level").unwrap() {
+                "off" => LogLevel::Off,
+                "error" => LogLevel::Error,
+                "warn" => LogLevel::Warn,
+                "info" => LogLevel::Info,
+                "debug" => LogLevel::Debug,
+                _ => LogLevel::Trace,
+            };
+            client
+                .set_log_level(control_pb::SetLogLevelRequest {
+                    level: level as i32,
+                    target: parse_pid(matches)?.map(set_log_level_request::Target::Pid),
+                })
+                .await?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+async fn connect(socket: PathBuf) -> Result<ControlServiceClient<Channel>> {
+    // The URI is required but unused, since every connection goes to the socket.
+    let channel = Endpoint::try_from("http://[::]:0")?
+        .connect_with_connector(service_fn(move |_: Uri| {
+            UnixStream::connect(socket.clone())
+        }))
+        .await?;
+    Ok(ControlServiceClient::new(channel))
+}
+
+fn parse_pid(matches: &ArgMatches) -> Result<Option<u64>> {
+    matches
+        .value_of("pid")
+        .map(|pid| pid.parse().map_err(|_| anyhow!("invalid pid {:?}", pid)))
+        .transpose()
+}
+
+/// Resolves `path` against our working directory, since the host's may differ.
+fn absolute_path(path: &str) -> Result<String> {
+    let path = std::env::current_dir()?.join(path);
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| anyhow!("{:?} isn't valid UTF-8", path))
+}
+
+async fn ps(client: &mut ControlServiceClient<Channel>) -> Result<()> {
+    let response = client
+        .list_processes(control_pb::ListProcessesRequest {})
+        .await?;
+    println!(
+        "{:>5}  {:<8}  {:>9}  {:>9}  {:<5}  {:>7}  {:>7}  {:>4}  {:>7}  {:>10}  {:>10}  {:<}",
+        "PID",
+        "STATE",
+        "UPTIME",
+        "SPAWN",
+        "LOG",
+        "CLIENTS",
+        "SERVERS",
+        "IO",
+        "PENDING",
+        "HEAP",
+        "PEAK",
+        "MODULE",
+    );
+    for process in &response.get_ref().processes {
+        let state = match ProcessState::from_i32(process.state) {
+            Some(ProcessState::Running) => "running",
+            Some(ProcessState::Draining) => "draining",
+            Some(ProcessState::Stopping) => "stopping",
+            _ => "unknown",
+        };
+        let handles = process.handles.clone().unwrap_or_default();
+        let (heap, peak) = match &process.heap_stats {
+            Some(stats) => (stats.live_bytes.to_string(), stats.peak_bytes.to_string()),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        println!(
+            "{:>5}  {:<8}  {:>8.1}s  {:>7.2}ms  {:<5}  {:>7}  {:>7}  {:>4}  {:>7}  {:>10}  {:>10}  {}",
+            process.pid,
+            state,
+            process.uptime_ms as f64 / 1000.0,
+            process.spawn_time_us as f64 / 1000.0,
+            level_name(process.log_level),
+            handles.rpc_clients.len(),
+            handles.rpc_servers,
+            handles.io_objects,
+            handles.pending_requests,
+            heap,
+            peak,
+            process.module_ref,
+        );
+        if !process.services.is_empty() {
+            println!("       serves: {}", process.services.join(", "));
+        }
+        if !handles.rpc_clients.is_empty() {
+            println!("       uses: {}", handles.rpc_clients.join(", "));
+        }
+    }
+    Ok(())
+}
+
+async fn registry(client: &mut ControlServiceClient<Channel>) -> Result<()> {
+    let response = client
+        .dump_registry(control_pb::DumpRegistryRequest {})
+        .await?;
+    for entry in &response.get_ref().entries {
+        println!("{}", entry.service_name);
+        for server in &entry.servers {
+            match &server.server {
+                Some(rpc_server::Server::Guest(guest)) => {
+                    println!(
+                        "  served by pid {} (server {})",
+                        guest.pid, guest.rpc_server
+                    )
+                }
+                Some(rpc_server::Server::Native(_)) => println!("  served by the host"),
+                None => {}
+            }
+        }
+        for pid in &entry.waiting_pids {
+            println!("  awaited by pid {}", pid);
+        }
+    }
+    Ok(())
+}
+
+async fn heap(client: &mut ControlServiceClient<Channel>, pid: Option<u64>) -> Result<()> {
+    let response = client
+        .get_heap_stats(control_pb::GetHeapStatsRequest {
+            target: pid.map(get_heap_stats_request::Target::Pid),
+        })
+        .await?;
+    println!(
+        "{:>5}  {:>10}  {:>10}  {:>12}  {:>12}  {:<}",
+        "PID", "LIVE", "PEAK", "ALLOCS", "DEALLOCS", "MODULE",
+    );
+    for process in &response.get_ref().processes {
+        let stats = process.heap_stats.clone().unwrap_or_default();
+        println!(
+            "{:>5}  {:>10}  {:>10}  {:>12}  {:>12}  {}",
+            process.pid,
+            stats.live_bytes,
+            stats.peak_bytes,
+            stats.allocations,
+            stats.deallocations,
+            process.module_ref,
+        );
+    }
+    Ok(())
+}
+
+fn level_name(level: i32) -> &'static str {
+    match LogLevel::from_i32(level) {
+        Some(LogLevel::Off) => "off",
+        Some(LogLevel::Error) => "error",
+        Some(LogLevel::Warn) => "warn",
+        Some(LogLevel::Info) => "info",
+        Some(LogLevel::Debug) => "debug",
+        Some(LogLevel::Trace) => "trace",
+        None => "?",
+    }
+}
@@ -4,16 +4,19 @@ shopt -s extglob
 
 function usage {
     echo "USAGE:" >&2
-    echo "    $0 ( <multiplier> | <crate_name> )..." >&2
+    echo "    $0 ( <multiplier> | <crate_name> )... [-- <host_flag>...]" >&2
     echo >&2
     echo "    <multiplier> An integer that sets the repeat count for the next <crate_name>" >&2
     echo "    <crate_name> The name of a crate in the wasm Cargo workspace" >&2
+    echo "    <host_flag>  A flag passed on to ignition-host, like --pooling" >&2
     echo >&2
     echo "    At least one crate must be specified after multipliers are considered."
     echo >&2
     echo "EXAMPLES:" >&2
     echo "    $0 ignition-impulse-bench" >&2
     echo "    $0 20 ignition-impulse-bench" >&2
+    echo "    $0 --release 1000 ignition-spawn-bench" >&2
+    echo "    $0 --release 1000 ignition-spawn-bench -- --pooling" >&2
     echo "    $0 ignition-rpc-echo-server 20 ignition-rpc-echo-client" >&2
     exit 1
 }
@@ -21,8 +24,14 @@ function usage {
 profile=debug
 multiplier=1
 declare -a crates
+declare -a host_flags
 while [ $# -gt 0 ]; do
     case $1 in
+        --)
+            shift
+            host_flags=("$@")
+            break
+            ;;
         --debug)
             profile=debug
             shift
@@ -93,4 +102,4 @@ for crate_name in "${crates[@]}"; do
 done
 
 # Run the host and pass it the path to the optimized wasm.
-cargo run -p ignition-host --release -- "${wasm_paths[@]}"
+cargo run -p ignition-host --release -- "${host_flags[@]}" "${wasm_paths[@]}"
@@ -1,35 +1,27 @@
 use std::str::from_utf8;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use ignition_guest::api::{log, shutdown};
+use ignition_guest::api::log;
 use ignition_guest::rpc_client::RpcClient;
 use ignition_guest::runtime::spawn;
-use ignition_guest::{emit_wake, Instant};
+use ignition_guest::Instant;
 
 const MESSAGES: &[&str] = &["abc123", "def456", "ghi789", "hello, world", "asdfjkl;"];
 
-struct SharedState {
-    counter: AtomicUsize,
-    client: RpcClient,
-}
-
-emit_wake!(init);
-
-fn init() {
-    spawn(async {
-        let shared_state = Arc::new(SharedState {
-            counter: AtomicUsize::new(MESSAGES.len()),
-            client: RpcClient::new("EchoService"),
-        });
-        shared_state.client.wait_healthy().await;
+#[ignition_guest::main]
+async fn main() {
+    let client = Arc::new(RpcClient::new("EchoService"));
+    client.wait_healthy().await;
 
-        for message in MESSAGES.iter().copied() {
-            let shared_state = Arc::clone(&shared_state);
+    let requests: Vec<_> = MESSAGES
+        .iter()
+        .copied()
+        .map(|message| {
+            let client = Arc::clone(&client);
             spawn(async move {
                 let start_time = Instant::now();
 
-                let request = shared_state.client.request("echo");
+                let request = client.request("echo");
                 request.write_all(message.as_bytes()).await;
                 let response = request.into_response().read_to_end().await;
                 let elapsed_seconds = (Instant::now() - start_time).as_secs_f64();
@@ -40,11 +32,38 @@ fn init() {
                     from_utf8(&response).unwrap(),
                     (elapsed_seconds * 1e6).ceil(),
                 ));
+            })
+        })
+        .collect();
+    for request in requests {
+        request.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ignition_guest::rpc_server::RpcServerBuilder;
+    use ignition_guest::runtime::spawn_local;
+    use ignition_guest::testing::run;
 
-                if shared_state.counter.fetch_sub(1, Ordering::SeqCst) == 1 {
-                    shutdown();
-                }
-            });
-        }
-    })
+    use super::main;
+
+    #[test]
+    fn sends_every_message() {
+        run(async {
+            // The client starts first and has to wait for the service to come up.
+            let client = spawn_local(main());
+            RpcServerBuilder::new("EchoService")
+                .add_handler(
+                    "echo",
+                    Box::new(|request, response| {
+                        Box::pin(async move {
+                            response.write_all(&request.read_to_end().await).await;
+                        })
+                    }),
+                )
+                .build();
+            client.await.unwrap();
+        });
+    }
 }
@@ -2,31 +2,70 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use ignition_guest::api::{shutdown, sleep};
-use ignition_guest::emit_wake;
+use ignition_guest::api::{host_shutdown, sleep};
 use ignition_guest::rpc_server::RpcServerBuilder;
-use ignition_guest::runtime::spawn;
-
-emit_wake!(init);
-
-fn init() {
-    spawn(async {
-        sleep(Duration::from_secs(1)).await;
-
-        let counter = Arc::new(AtomicUsize::new(5));
-        RpcServerBuilder::new("EchoService")
-            .add_handler(
-                "echo",
-                Box::new(move |request, response| {
-                    let counter = Arc::clone(&counter);
-                    Box::pin(async move {
-                        response.write_all(&request.read_to_end().await).await;
-                        if counter.fetch_sub(1, Ordering::SeqCst) == 1 {
-                            shutdown();
-                        }
-                    })
-                }),
-            )
-            .build();
+use ignition_guest::runtime::spawn_local;
+use ignition_guest::sync::Notify;
+
+#[ignition_guest::main]
+async fn main() {
+    sleep(Duration::from_secs(1)).await;
+
+    let counter = Arc::new(AtomicUsize::new(5));
+    let done = Arc::new(Notify::new());
+    // Stop early if the host is shutting down, since it routes no new requests here.
+    let shutdown_done = Arc::clone(&done);
+    spawn_local(async move {
+        host_shutdown().await;
+        shutdown_done.notify_one();
     });
+    let handler_done = Arc::clone(&done);
+    RpcServerBuilder::new("EchoService")
+        .add_handler(
+            "echo",
+            Box::new(move |request, response| {
+                let counter = Arc::clone(&counter);
+                let done = Arc::clone(&handler_done);
+                Box::pin(async move {
+                    response.write_all(&request.read_to_end().await).await;
+                    if counter.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        done.notify_one();
+                    }
+                })
+            }),
+        )
+        .build();
+    done.notified().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ignition_guest::rpc_client::RpcClient;
+    use ignition_guest::runtime::spawn_local;
+    use ignition_guest::testing::run;
+    use ignition_guest::Instant;
+
+    use super::main;
+
+    #[test]
+    fn echoes_five_requests_then_exits() {
+        run(async {
+            let start_time = Instant::now();
+            let server = spawn_local(main());
+
+            let client = RpcClient::new("EchoService");
+            client.wait_healthy().await;
+            assert_eq!(Instant::now() - start_time, Duration::from_secs(1));
+
+            for message in ["one", "two", "three", "four", "five"] {
+                let request = client.request("echo");
+                request.write_all(message.as_bytes()).await;
+                let response = request.into_response().read_to_end().await;
+                assert_eq!(response, message.as_bytes());
+            }
+            server.await.unwrap();
+        });
+    }
 }
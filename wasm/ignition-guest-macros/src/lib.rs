@@ -0,0 +1,101 @@
+//! This crate provides attribute macros for use with the `ignition-guest` crate.
+//!
+//! See `ignition_guest::main` for usage.
+
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, ItemFn, ReturnType};
+
+/// Marks an async function as the entry point of a guest.
+///
+/// The function must take no arguments and return `()`. The generated `wake` export starts it as a
+/// task when the host initializes the instance, and calls `shutdown()` once it returns. The
+/// `wake_buffer` and `wake_many` exports let the host deliver wakes in batches. A panic anywhere in
+/// the guest is logged and then aborts the instance. The guest also gets the `ignition_abi` custom
+/// section, which tells the host the ABI version and features it was built for.
+///
+/// ```ignore
+/// #[ignition_guest::main]
+/// async fn main() {
+///     ignition_guest::api::log("hello");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(
+    args: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let args = TokenStream::from(args);
+    let item = parse_macro_input!(item as ItemFn);
+
+    let errors = check_signature(&args, &item);
+    if !errors.is_empty() {
+        return quote! {
+            #(#errors)*
+            #item
+        }
+        .into();
+    }
+
+    let name = &item.sig.ident;
+    let expanded = quote! {
+        #item
+
+        fn __ignition_guest_init() {
+            ::ignition_guest::start_main(#name());
+        }
+
+        #[no_mangle]
+        pub extern "C" fn wake(task_id: u32, param: usize) {
+            ::ignition_guest::wake_internal(task_id, param, __ignition_guest_init);
+        }
+
+        #[no_mangle]
+        pub extern "C" fn wake_buffer(capacity: u32) -> *mut [u32; 2] {
+            ::ignition_guest::wake_buffer_internal(capacity)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn wake_many(count: u32) {
+            ::ignition_guest::wake_many_internal(count, __ignition_guest_init);
+        }
+
+        // Emitted here rather than in ignition_guest, where nothing would keep it from being
+        // dropped at link time.
+        #[cfg(target_arch = "wasm32")]
+        #[link_section = "ignition_abi"]
+        #[used]
+        static __IGNITION_ABI: [u8; ::ignition_guest::ABI_SECTION_LEN] =
+            ::ignition_guest::ABI_SECTION;
+    };
+    expanded.into()
+}
+
+fn check_signature(args: &TokenStream, item: &ItemFn) -> Vec<TokenStream> {
+    let mut errors = Vec::new();
+    let mut error = |span, message: &str| {
+        errors.push(quote_spanned! {span=> compile_error!(#message);});
+    };
+
+    if !args.is_empty() {
+        error(args.span(), "#[ignition_guest::main] takes no arguments");
+    }
+    let sig = &item.sig;
+    if sig.asyncness.is_none() {
+        error(sig.fn_token.span(), "the main function must be async");
+    }
+    if !sig.inputs.is_empty() {
+        error(
+            sig.inputs.span(),
+            "the main function must take no arguments",
+        );
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        error(sig.generics.span(), "the main function must not be generic");
+    }
+    if let ReturnType::Type(_, ty) = &sig.output {
+        error(ty.span(), "the main function must return ()");
+    }
+    errors
+}
@@ -0,0 +1,600 @@
+//! A compact heap allocator for guests, enabled by the `allocator` feature.
+//!
+//! Small requests are rounded up to a power-of-two size class and served from blocks carved out of
+//! Wasm pages, with a free list per class. Requests larger than the biggest class take whole pages,
+//! which are reused first-fit once freed. Nothing is ever handed back to the host, since Wasm
+//! memories cannot shrink.
+//!
+//! The allocator also keeps usage statistics. The guest reports them to the host once every
+//! [`REPORT_INTERVAL`] wakes if they've changed, and again when it shuts down.
+//!
+//! Pages come from a [`PageSource`], which is the Wasm memory in a guest. Tests run the same code
+//! natively against a fixed region of pages.
+
+use std::alloc::{GlobalAlloc, Layout};
+#[cfg(target_arch = "wasm32")]
+use std::arch::wasm32;
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_arch = "wasm32")]
+use crate::api::sys;
+
+const PAGE_SIZE: usize = 65536;
+
+/// Size classes run from 16 bytes to 4 KiB.
+const MIN_CLASS_SHIFT: u32 = 4;
+const MAX_CLASS_SHIFT: u32 = 12;
+const CLASS_COUNT: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+
+/// How many wakes pass between reports of the statistics. Almost every wake allocates, so
+/// reporting after each one would cost a host call per wake.
+const REPORT_INTERVAL: u32 = 64;
+
+#[cfg(target_arch = "wasm32")]
+#[global_allocator]
+static ALLOCATOR: Allocator<WasmMemory> = Allocator::new(WasmMemory);
+
+/// Where the allocator gets fresh pages from.
+trait PageSource {
+    /// Returns the start of `pages` new, `PAGE_SIZE` aligned pages, or null if there are no more.
+    fn grow(&mut self, pages: usize) -> *mut u8;
+}
+
+#[cfg(target_arch = "wasm32")]
+struct WasmMemory;
+
+#[cfg(target_arch = "wasm32")]
+impl PageSource for WasmMemory {
+    fn grow(&mut self, pages: usize) -> *mut u8 {
+        match wasm32::memory_grow(0, pages) {
+            usize::MAX => null_mut(),
+            old_pages => (old_pages * PAGE_SIZE) as *mut u8,
+        }
+    }
+}
+
+/// A snapshot of heap usage. Byte counts are the sizes callers asked for, not including rounding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl HeapStats {
+    const ZERO: HeapStats = HeapStats {
+        live_bytes: 0,
+        peak_bytes: 0,
+        allocations: 0,
+        deallocations: 0,
+    };
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats
+}
+
+/// Called after every wake. Sends the current statistics to the host if this is the last wake of an
+/// interval, or if `flush` is set, unless they haven't changed since the last report.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn report_heap_stats(flush: bool) {
+    let stats = match ALLOCATOR.lock().unreported_stats(flush) {
+        Some(stats) => stats,
+        None => return,
+    };
+
+    // SAFETY: No special considerations.
+    unsafe {
+        sys::heap_stats(
+            stats.live_bytes,
+            stats.peak_bytes,
+            stats.allocations,
+            stats.deallocations,
+        )
+    }
+}
+
+struct Allocator<P> {
+    locked: AtomicBool,
+    state: UnsafeCell<State<P>>,
+}
+
+// SAFETY: `state` is only accessed while `locked` is held.
+unsafe impl<P: Send> Sync for Allocator<P> {}
+
+struct State<P> {
+    pages: P,
+    free_blocks: [*mut FreeBlock; CLASS_COUNT],
+    /// Runs of whole pages that have been freed.
+    free_runs: *mut FreeRun,
+    /// The part of the most recently claimed page not yet carved into blocks.
+    carve_start: usize,
+    carve_end: usize,
+    stats: HeapStats,
+    reported_stats: HeapStats,
+    wakes_since_report: u32,
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct FreeRun {
+    pages: usize,
+    next: *mut FreeRun,
+}
+
+enum SizeClass {
+    Small(usize),
+    /// A number of pages.
+    Large(usize),
+}
+
+impl SizeClass {
+    fn of(layout: Layout) -> Option<Self> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(1 << MIN_CLASS_SHIFT)
+            .checked_next_power_of_two()?;
+        if size <= 1 << MAX_CLASS_SHIFT {
+            Some(SizeClass::Small(
+                (size.trailing_zeros() - MIN_CLASS_SHIFT) as usize,
+            ))
+        } else if layout.align() <= PAGE_SIZE {
+            Some(SizeClass::Large(
+                layout.size().checked_add(PAGE_SIZE - 1)? / PAGE_SIZE,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (class as u32 + MIN_CLASS_SHIFT)
+}
+
+impl<P> Allocator<P> {
+    const fn new(pages: P) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            state: UnsafeCell::new(State {
+                pages,
+                free_blocks: [null_mut(); CLASS_COUNT],
+                free_runs: null_mut(),
+                carve_start: 0,
+                carve_end: 0,
+                stats: HeapStats::ZERO,
+                reported_stats: HeapStats::ZERO,
+                wakes_since_report: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> StateGuard<'_, P> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        StateGuard(self)
+    }
+}
+
+struct StateGuard<'a, P>(&'a Allocator<P>);
+
+impl<P> std::ops::Deref for StateGuard<'_, P> {
+    type Target = State<P>;
+
+    fn deref(&self) -> &State<P> {
+        // SAFETY: The lock is held.
+        unsafe { &*self.0.state.get() }
+    }
+}
+
+impl<P> std::ops::DerefMut for StateGuard<'_, P> {
+    fn deref_mut(&mut self) -> &mut State<P> {
+        // SAFETY: The lock is held.
+        unsafe { &mut *self.0.state.get() }
+    }
+}
+
+impl<P> Drop for StateGuard<'_, P> {
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<P: PageSource> State<P> {
+    unsafe fn alloc_block(&mut self, class: usize) -> *mut u8 {
+        let head = self.free_blocks[class];
+        if !head.is_null() {
+            self.free_blocks[class] = unsafe { (*head).next };
+            return head as *mut u8;
+        }
+
+        let size = class_size(class);
+        let mut start = align_up(self.carve_start, size);
+        if start + size > self.carve_end {
+            let page = unsafe { self.alloc_pages(1) };
+            if page.is_null() {
+                return null_mut();
+            }
+            // Keep what's left of the old page rather than stranding it.
+            unsafe { self.free_range(self.carve_start, self.carve_end) };
+            self.carve_start = page as usize;
+            self.carve_end = page as usize + PAGE_SIZE;
+            start = self.carve_start;
+        }
+        unsafe { self.free_range(self.carve_start, start) };
+        self.carve_start = start + size;
+        start as *mut u8
+    }
+
+    unsafe fn free_block(&mut self, ptr: *mut u8, class: usize) {
+        let block = ptr as *mut FreeBlock;
+        unsafe {
+            block.write(FreeBlock {
+                next: self.free_blocks[class],
+            })
+        };
+        self.free_blocks[class] = block;
+    }
+
+    /// Splits a range with 16-byte aligned ends into the largest aligned blocks that fit and frees
+    /// them.
+    unsafe fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut class = CLASS_COUNT - 1;
+            while !start.is_multiple_of(class_size(class)) || start + class_size(class) > end {
+                class -= 1;
+            }
+            unsafe { self.free_block(start as *mut u8, class) };
+            start += class_size(class);
+        }
+    }
+
+    unsafe fn alloc_pages(&mut self, pages: usize) -> *mut u8 {
+        let mut link: *mut *mut FreeRun = &mut self.free_runs;
+        unsafe {
+            while !(*link).is_null() {
+                let run = *link;
+                if (*run).pages == pages {
+                    *link = (*run).next;
+                    return run as *mut u8;
+                }
+                if (*run).pages > pages {
+                    // Hand out the front and keep the rest in place of the original run.
+                    let rest = (run as *mut u8).add(pages * PAGE_SIZE) as *mut FreeRun;
+                    rest.write(FreeRun {
+                        pages: (*run).pages - pages,
+                        next: (*run).next,
+                    });
+                    *link = rest;
+                    return run as *mut u8;
+                }
+                link = &mut (*run).next;
+            }
+        }
+
+        self.pages.grow(pages)
+    }
+
+    unsafe fn free_pages(&mut self, ptr: *mut u8, pages: usize) {
+        let run = ptr as *mut FreeRun;
+        unsafe {
+            run.write(FreeRun {
+                pages,
+                next: self.free_runs,
+            })
+        };
+        self.free_runs = run;
+    }
+
+    /// Counts a wake. Returns the statistics if the interval is up or `flush` is set, and they've
+    /// changed since this last returned them.
+    fn unreported_stats(&mut self, flush: bool) -> Option<HeapStats> {
+        self.wakes_since_report += 1;
+        if !flush && self.wakes_since_report < REPORT_INTERVAL {
+            return None;
+        }
+        self.wakes_since_report = 0;
+        if self.stats == self.reported_stats {
+            return None;
+        }
+        self.reported_stats = self.stats;
+        Some(self.stats)
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.stats.allocations += 1;
+        self.grow_live(size);
+    }
+
+    fn grow_live(&mut self, size: usize) {
+        self.stats.live_bytes += size;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.live_bytes);
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe impl<P: PageSource + Send> GlobalAlloc for Allocator<P> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut state = self.lock();
+        let ptr = match SizeClass::of(layout) {
+            Some(SizeClass::Small(class)) => unsafe { state.alloc_block(class) },
+            Some(SizeClass::Large(pages)) => unsafe { state.alloc_pages(pages) },
+            None => null_mut(),
+        };
+        if !ptr.is_null() {
+            state.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.lock();
+        match SizeClass::of(layout) {
+            Some(SizeClass::Small(class)) => unsafe { state.free_block(ptr, class) },
+            Some(SizeClass::Large(pages)) => unsafe { state.free_pages(ptr, pages) },
+            None => unreachable!(),
+        }
+        state.stats.deallocations += 1;
+        state.stats.live_bytes -= layout.size();
+    }
+
+    /// Resizes in place when the new size needs the same class or number of pages. Either way, a
+    /// reallocation counts as neither an allocation nor a deallocation.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // SAFETY: The caller guarantees that `new_size` is valid for `layout.align()`.
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let in_place = match (SizeClass::of(layout), SizeClass::of(new_layout)) {
+            (Some(SizeClass::Small(old)), Some(SizeClass::Small(new))) => old == new,
+            (Some(SizeClass::Large(old)), Some(SizeClass::Large(new))) => old == new,
+            _ => false,
+        };
+        if in_place {
+            let mut state = self.lock();
+            state.stats.live_bytes -= layout.size();
+            state.grow_live(new_size);
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            let mut state = self.lock();
+            state.stats.allocations -= 1;
+            state.stats.deallocations -= 1;
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::ptr::null_mut;
+
+    use super::{Allocator, HeapStats, PageSource, PAGE_SIZE, REPORT_INTERVAL};
+
+    /// A fixed number of pages, handed out in order.
+    struct FakePages {
+        start: *mut u8,
+        used: usize,
+        capacity: usize,
+    }
+
+    // SAFETY: Only the allocator that owns it touches the region.
+    unsafe impl Send for FakePages {}
+
+    impl FakePages {
+        fn new(capacity: usize) -> Self {
+            // SAFETY: The layout has a non-zero size.
+            let start = unsafe { System.alloc(Self::layout(capacity)) };
+            assert!(!start.is_null());
+            Self {
+                start,
+                used: 0,
+                capacity,
+            }
+        }
+
+        fn layout(capacity: usize) -> Layout {
+            Layout::from_size_align(capacity * PAGE_SIZE, PAGE_SIZE).unwrap()
+        }
+
+        fn page(&self, index: usize) -> *mut u8 {
+            self.start.wrapping_add(index * PAGE_SIZE)
+        }
+    }
+
+    impl PageSource for FakePages {
+        fn grow(&mut self, pages: usize) -> *mut u8 {
+            if self.used + pages > self.capacity {
+                return null_mut();
+            }
+            let start = self.page(self.used);
+            self.used += pages;
+            start
+        }
+    }
+
+    impl Drop for FakePages {
+        fn drop(&mut self) {
+            // SAFETY: `start` came from `System.alloc()` with the same layout.
+            unsafe { System.dealloc(self.start, Self::layout(self.capacity)) };
+        }
+    }
+
+    fn allocator(pages: usize) -> Allocator<FakePages> {
+        Allocator::new(FakePages::new(pages))
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn reuses_freed_blocks_of_the_same_class() {
+        let allocator = allocator(1);
+        let page = allocator.lock().pages.page(0);
+        unsafe {
+            let a = allocator.alloc(layout(24, 8));
+            assert_eq!(a, page);
+            let b = allocator.alloc(layout(32, 8));
+            assert_eq!(b, page.add(32));
+
+            allocator.dealloc(a, layout(24, 8));
+            // 17 to 32 bytes share a class, so the freed block is handed out again.
+            assert_eq!(allocator.alloc(layout(17, 1)), a);
+            // A smaller class doesn't take it.
+            assert_eq!(allocator.alloc(layout(16, 1)), page.add(64));
+        }
+    }
+
+    #[test]
+    fn frees_what_alignment_skips_over() {
+        let allocator = allocator(1);
+        let page = allocator.lock().pages.page(0);
+        unsafe {
+            assert_eq!(allocator.alloc(layout(16, 16)), page);
+            // A 4 KiB block has to start 4 KiB in. The gap is split into blocks of every smaller
+            // class, which later requests are served from.
+            assert_eq!(allocator.alloc(layout(4096, 4096)), page.add(4096));
+            assert_eq!(allocator.alloc(layout(2048, 1)), page.add(2048));
+            assert_eq!(allocator.alloc(layout(16, 1)), page.add(16));
+            assert_eq!(allocator.alloc(layout(100, 1)), page.add(128));
+        }
+    }
+
+    #[test]
+    fn keeps_the_rest_of_a_page_when_taking_another() {
+        let allocator = allocator(2);
+        let (first, second) = {
+            let state = allocator.lock();
+            (state.pages.page(0), state.pages.page(1))
+        };
+        unsafe {
+            for i in 0..15 {
+                assert_eq!(allocator.alloc(layout(4096, 1)), first.add(i * 4096));
+            }
+            assert_eq!(allocator.alloc(layout(2048, 1)), first.add(15 * 4096));
+            // The last 2 KiB of the first page can't fit this, so it moves on and frees them.
+            assert_eq!(allocator.alloc(layout(4096, 1)), second);
+            assert_eq!(
+                allocator.alloc(layout(2048, 1)),
+                first.add(15 * 4096 + 2048)
+            );
+        }
+    }
+
+    #[test]
+    fn reuses_freed_page_runs_first_fit() {
+        let allocator = allocator(4);
+        let page = allocator.lock().pages.page(0);
+        unsafe {
+            let run = allocator.alloc(layout(3 * PAGE_SIZE, 8));
+            assert_eq!(run, page);
+            allocator.dealloc(run, layout(3 * PAGE_SIZE, 8));
+
+            // Split from the front of the freed run.
+            assert_eq!(allocator.alloc(layout(PAGE_SIZE, 8)), page);
+            assert_eq!(
+                allocator.alloc(layout(PAGE_SIZE + 1, 8)),
+                page.add(PAGE_SIZE)
+            );
+            // Only one page is left in the source.
+            assert_eq!(
+                allocator.alloc(layout(PAGE_SIZE, 8)),
+                page.add(3 * PAGE_SIZE)
+            );
+            assert!(allocator.alloc(layout(PAGE_SIZE, 8)).is_null());
+            assert!(allocator.alloc(layout(16, 8)).is_null());
+        }
+    }
+
+    #[test]
+    fn refuses_alignments_beyond_a_page() {
+        let allocator = allocator(2);
+        unsafe {
+            assert!(allocator.alloc(layout(16, 2 * PAGE_SIZE)).is_null());
+        }
+        assert_eq!(allocator.lock().stats, HeapStats::default());
+    }
+
+    #[test]
+    fn reallocates_in_place_within_a_class() {
+        let allocator = allocator(1);
+        unsafe {
+            let ptr = allocator.alloc(layout(20, 4));
+            ptr.write_bytes(7, 20);
+            assert_eq!(allocator.realloc(ptr, layout(20, 4), 30), ptr);
+
+            let moved = allocator.realloc(ptr, layout(30, 4), 40);
+            assert_ne!(moved, ptr);
+            assert_eq!(std::slice::from_raw_parts(moved, 20), [7; 20]);
+            // The old block is free again.
+            assert_eq!(allocator.alloc(layout(32, 4)), ptr);
+        }
+    }
+
+    #[test]
+    fn tracks_usage() {
+        let allocator = allocator(1);
+        unsafe {
+            let a = allocator.alloc(layout(100, 1));
+            let b = allocator.alloc(layout(50, 1));
+            allocator.dealloc(a, layout(100, 1));
+            let b = allocator.realloc(b, layout(50, 1), 500);
+            assert_eq!(
+                allocator.lock().stats,
+                HeapStats {
+                    live_bytes: 500,
+                    peak_bytes: 550,
+                    allocations: 2,
+                    deallocations: 1,
+                }
+            );
+            allocator.dealloc(b, layout(500, 1));
+        }
+        let mut state = allocator.lock();
+        let stats = state.unreported_stats(true).unwrap();
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.deallocations, 2);
+        assert_eq!(state.unreported_stats(true), None);
+    }
+
+    #[test]
+    fn reports_changes_once_per_interval() {
+        let allocator = allocator(1);
+        let mut reports = 0;
+        for _ in 0..2 * REPORT_INTERVAL {
+            unsafe {
+                let ptr = allocator.alloc(layout(16, 1));
+                allocator.dealloc(ptr, layout(16, 1));
+            }
+            reports += allocator.lock().unreported_stats(false).is_some() as u32;
+        }
+        assert_eq!(reports, 2);
+
+        // Nothing has changed since the last report.
+        for _ in 0..REPORT_INTERVAL {
+            assert_eq!(allocator.lock().unreported_stats(false), None);
+        }
+    }
+}
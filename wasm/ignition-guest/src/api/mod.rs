@@ -1,5 +1,6 @@
 use std::convert::TryInto;
 use std::ffi::c_void;
+use std::panic::Location;
 use std::time::Duration;
 
 use crate::runtime::reactor;
@@ -10,15 +11,49 @@ pub(crate) mod sys;
 pub(crate) mod wait;
 
 pub fn shutdown() {
+    // The host keeps the last report it got, so send anything newer before exiting.
+    #[cfg(all(feature = "allocator", target_arch = "wasm32"))]
+    crate::allocator::report_heap_stats(true);
+
     // SAFETY: No special considerations.
     unsafe { sys::shutdown() }
 }
 
+/// Completes once the host has been asked to shut down. The process should finish what it's doing
+/// and call [`shutdown`] before the host's grace period runs out, or it will be killed.
+pub async fn host_shutdown() {
+    let task_id = reactor::new_task();
+
+    // SAFETY: No special considerations.
+    unsafe { sys::host_shutdown_requested(task_id) };
+
+    wait(task_id).await;
+}
+
 pub fn abort() -> ! {
     // SAFETY: No special considerations.
     unsafe { sys::abort() }
 }
 
+/// Tells the host about a panic so that it appears in the crash report. Callers should abort next.
+pub fn report_panic(message: &str, location: Option<&Location>) {
+    let (file, line, column) = location.map_or(("", 0, 0), |location| {
+        (location.file(), location.line(), location.column())
+    });
+
+    // SAFETY: `message` and `file` refer to UTF-8 strings.
+    unsafe {
+        sys::panic(
+            message.as_ptr(),
+            message.len(),
+            file.as_ptr(),
+            file.len(),
+            line,
+            column,
+        )
+    }
+}
+
 pub fn log(message: &str) {
     // SAFETY: `message` and `len` refer to a UTF-8 string.
     unsafe { sys::log(message.as_bytes().as_ptr() as *const c_void, message.len()) }
@@ -0,0 +1,306 @@
+//! Content-addressed blob storage through the host's `BlobService`.
+//!
+//! Blobs are immutable and named by the hash of their content, so putting the same content twice
+//! yields the same [`BlobId`]. Content streams through IO handles in both directions, so blobs
+//! needn't fit in memory.
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+use crate::io::{read_full, ReadHandle, WriteHandle};
+use crate::rpc_client::{Request, RpcClient};
+
+const SERVICE_NAME: &str = "BlobService";
+
+thread_local! {
+    static CLIENT: RpcClient = RpcClient::new(SERVICE_NAME);
+}
+
+/// Names a blob by the BLAKE3 hash of its content. Its text form is `blake3:<64 hex digits>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlobId {
+    hash: [u8; 32],
+}
+
+impl BlobId {
+    pub fn from_blake3(hash: [u8; 32]) -> Self {
+        Self { hash }
+    }
+
+    pub fn blake3(&self) -> &[u8; 32] {
+        &self.hash
+    }
+}
+
+impl Display for BlobId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "blake3:")?;
+        for b in &self.hash {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BlobId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let hex = s.strip_prefix("blake3:").ok_or(Error::InvalidId)?;
+        // from_str_radix alone would let a sign through.
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::InvalidId);
+        }
+        let mut hash = [0; 32];
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| Error::InvalidId)?;
+        }
+        Ok(Self { hash })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    InvalidId,
+    /// The host has no blob store, or it failed.
+    Unavailable,
+    /// The blob's content ended before its stated length.
+    Truncated,
+    /// A status code this version of the library doesn't know about.
+    Unknown(u8),
+}
+
+impl Error {
+    fn from_status(status: u8) -> Result<(), Self> {
+        match status {
+            0 => Ok(()),
+            1 => Err(Error::NotFound),
+            2 => Err(Error::InvalidId),
+            3 => Err(Error::Unavailable),
+            x => Err(Error::Unknown(x)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "blob not found"),
+            Error::InvalidId => write!(f, "invalid blob ID"),
+            Error::Unavailable => write!(f, "blob store unavailable"),
+            Error::Truncated => write!(f, "blob content was truncated"),
+            Error::Unknown(status) => write!(f, "unknown blob store error {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The content of a new blob, written through [`Deref`] to a [`WriteHandle`].
+pub struct BlobWriter {
+    request: Request,
+}
+
+impl BlobWriter {
+    /// Completes the blob and returns its ID.
+    pub async fn finish(self) -> Result<BlobId, Error> {
+        let response = self.request.into_response().read_to_end().await;
+        let (&status, id) = response.split_first().ok_or(Error::Unavailable)?;
+        Error::from_status(status)?;
+        std::str::from_utf8(id)
+            .map_err(|_| Error::Unavailable)?
+            .parse()
+            .map_err(|_| Error::Unavailable)
+    }
+}
+
+impl Deref for BlobWriter {
+    type Target = WriteHandle;
+
+    fn deref(&self) -> &WriteHandle {
+        &self.request
+    }
+}
+
+impl DerefMut for BlobWriter {
+    fn deref_mut(&mut self) -> &mut WriteHandle {
+        &mut self.request
+    }
+}
+
+/// The content of an existing blob, read through [`Deref`] to a [`ReadHandle`].
+pub struct BlobReader {
+    response: ReadHandle,
+    len: u64,
+}
+
+impl BlobReader {
+    /// The length of the blob's content.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the rest of the content, checking that none is missing.
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, Error> {
+        let content = self.response.read_to_end().await;
+        if (content.len() as u64) < self.len {
+            return Err(Error::Truncated);
+        }
+        Ok(content)
+    }
+}
+
+impl Deref for BlobReader {
+    type Target = ReadHandle;
+
+    fn deref(&self) -> &ReadHandle {
+        &self.response
+    }
+}
+
+impl DerefMut for BlobReader {
+    fn deref_mut(&mut self) -> &mut ReadHandle {
+        &mut self.response
+    }
+}
+
+/// Starts a new blob. Write its content, then call [`BlobWriter::finish`].
+pub fn put() -> BlobWriter {
+    BlobWriter {
+        request: CLIENT.with(|client| client.request("put")),
+    }
+}
+
+/// Stores `content` as a blob.
+pub async fn put_bytes(content: &[u8]) -> Result<BlobId, Error> {
+    let writer = put();
+    writer.write_all(content).await;
+    writer.finish().await
+}
+
+/// Opens a blob for reading.
+pub async fn get(id: &BlobId) -> Result<BlobReader, Error> {
+    let request = CLIENT.with(|client| client.request("get"));
+    request.write_all(id.to_string().as_bytes()).await;
+    let response = request.into_response();
+
+    let mut status = [0];
+    if !read_full(&response, &mut status).await {
+        return Err(Error::Unavailable);
+    }
+    Error::from_status(status[0])?;
+    let mut len = [0; 8];
+    if !read_full(&response, &mut len).await {
+        return Err(Error::Truncated);
+    }
+    Ok(BlobReader {
+        response,
+        len: u64::from_le_bytes(len),
+    })
+}
+
+/// Reads the entire content of a blob.
+pub async fn get_bytes(id: &BlobId) -> Result<Vec<u8>, Error> {
+    get(id).await?.read_to_end().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{get, get_bytes, put_bytes, BlobId, Error, SERVICE_NAME};
+    use crate::testing::{run, serve_natively};
+
+    /// Serves blobs from memory, naming each by a counter rather than its hash.
+    fn serve_blobs() {
+        let mut blobs = HashMap::new();
+        serve_natively(SERVICE_NAME, move |method_name, body| match method_name {
+            "put" => {
+                let id = BlobId::from_blake3([blobs.len() as u8; 32]).to_string();
+                blobs.insert(id.clone(), body.to_vec());
+                [&[0][..], id.as_bytes()].concat()
+            }
+            "get" => match blobs.get(std::str::from_utf8(body).unwrap()) {
+                Some(content) => [
+                    &[0][..],
+                    &(content.len() as u64).to_le_bytes(),
+                    content.as_slice(),
+                ]
+                .concat(),
+                None => vec![1],
+            },
+            _ => vec![2],
+        });
+    }
+
+    #[test]
+    fn parses_only_exact_ids() {
+        let id = BlobId::from_blake3([0x0f; 32]);
+        assert_eq!(id.to_string(), format!("blake3:{}", "0f".repeat(32)));
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(format!("blake3:{}", "0F".repeat(32)).parse(), Ok(id));
+
+        for invalid in &[
+            format!("blake3:+f{}", "00".repeat(31)),
+            format!("blake3:{}", "0".repeat(63)),
+            format!("blake3:{}g", "0".repeat(63)),
+            format!("sha256:{}", "00".repeat(32)),
+        ] {
+            assert_eq!(
+                invalid.parse::<BlobId>(),
+                Err(Error::InvalidId),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn puts_and_gets_blobs() {
+        run(async {
+            serve_blobs();
+            let a = put_bytes(b"apple").await.unwrap();
+            let b = put_bytes(b"").await.unwrap();
+            assert_ne!(a, b);
+            assert_eq!(get_bytes(&a).await.unwrap(), b"apple");
+
+            let reader = get(&b).await.unwrap();
+            assert!(reader.is_empty());
+            assert_eq!(reader.read_to_end().await.unwrap(), b"");
+
+            let missing = BlobId::from_blake3([0xff; 32]);
+            assert_eq!(get_bytes(&missing).await, Err(Error::NotFound));
+        });
+    }
+
+    #[test]
+    fn reports_content_that_ends_early() {
+        run(async {
+            serve_natively(SERVICE_NAME, |_, _| {
+                [&[0][..], &10u64.to_le_bytes(), b"abc"].concat()
+            });
+            let reader = get(&BlobId::from_blake3([0; 32])).await.unwrap();
+            assert_eq!(reader.len(), 10);
+            assert_eq!(reader.read_to_end().await, Err(Error::Truncated));
+        });
+    }
+
+    #[test]
+    fn reports_the_stores_status() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, _| match method_name {
+                "put" => vec![3],
+                _ => vec![9],
+            });
+            assert_eq!(put_bytes(b"apple").await, Err(Error::Unavailable));
+            let id = BlobId::from_blake3([0; 32]);
+            assert_eq!(get_bytes(&id).await, Err(Error::Unknown(9)));
+        });
+    }
+}
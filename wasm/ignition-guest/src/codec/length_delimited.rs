@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::io;
+
+use crate::codec::{DecodeBuf, Decoder, Encoder};
+
+/// Frames prefixed with their length, configured like `tokio_util`'s codec of the same name.
+///
+/// By default the length is a 4-byte big-endian count of the bytes that follow it, and frames over
+/// 8 MiB are rejected. Decoded frames don't include the length field.
+///
+/// A frame's length field stays in the buffer until the whole frame has arrived, so a stream that
+/// ends partway through a frame, even right after its length field, is an
+/// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) error.
+#[derive(Clone, Debug)]
+pub struct LengthDelimitedCodec {
+    builder: LengthDelimitedCodecBuilder,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LengthDelimitedCodecBuilder {
+    length_field_length: usize,
+    little_endian: bool,
+    length_adjustment: isize,
+    max_frame_length: usize,
+}
+
+impl LengthDelimitedCodec {
+    pub fn new() -> Self {
+        Self::builder().new_codec()
+    }
+
+    pub fn builder() -> LengthDelimitedCodecBuilder {
+        LengthDelimitedCodecBuilder {
+            length_field_length: 4,
+            little_endian: false,
+            length_adjustment: 0,
+            max_frame_length: 8 * 1024 * 1024,
+        }
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.builder.max_frame_length
+    }
+
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.builder.max_frame_length = max_frame_length;
+    }
+
+    /// Reads the length of the frame at the front of `src` without consuming it, or returns `None`
+    /// if the length field hasn't all arrived.
+    fn frame_len(&self, src: &[u8]) -> io::Result<Option<usize>> {
+        let field_len = self.builder.length_field_length;
+        if src.len() < field_len {
+            return Ok(None);
+        }
+        let field = &src[..field_len];
+        let mut raw_len: u64 = 0;
+        for i in 0..field_len {
+            let byte = if self.builder.little_endian {
+                field[field_len - 1 - i]
+            } else {
+                field[i]
+            };
+            raw_len = raw_len << 8 | u64::from(byte);
+        }
+
+        let len = i128::from(raw_len) + self.builder.length_adjustment as i128;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.builder.max_frame_length)
+            .ok_or_else(|| invalid_data("frame length out of range"))?;
+        Ok(Some(len))
+    }
+}
+
+impl Default for LengthDelimitedCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for LengthDelimitedCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut DecodeBuf) -> io::Result<Option<Vec<u8>>> {
+        let len = match self.frame_len(src)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let field_len = self.builder.length_field_length;
+        if src.len() - field_len < len {
+            return Ok(None);
+        }
+        src.advance(field_len);
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl<T: AsRef<[u8]>> Encoder<T> for LengthDelimitedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: T, dst: &mut Vec<u8>) -> io::Result<()> {
+        let data = data.as_ref();
+        if data.len() > self.builder.max_frame_length {
+            return Err(invalid_data("frame too large"));
+        }
+        let field_len = self.builder.length_field_length;
+        let raw_len = data.len() as i128 - self.builder.length_adjustment as i128;
+        let raw_len = u64::try_from(raw_len)
+            .ok()
+            .filter(|&len| field_len == 8 || len >> (field_len * 8) == 0)
+            .ok_or_else(|| invalid_data("frame length doesn't fit in the length field"))?;
+
+        let bytes = raw_len.to_be_bytes();
+        let field = &bytes[bytes.len() - field_len..];
+        if self.builder.little_endian {
+            dst.extend(field.iter().rev());
+        } else {
+            dst.extend_from_slice(field);
+        }
+        dst.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+impl LengthDelimitedCodecBuilder {
+    /// Sets the size of the length field in bytes, from 1 to 8.
+    pub fn length_field_length(&mut self, length_field_length: usize) -> &mut Self {
+        assert!(
+            (1..=8).contains(&length_field_length),
+            "length field must be between 1 and 8 bytes"
+        );
+        self.length_field_length = length_field_length;
+        self
+    }
+
+    pub fn big_endian(&mut self) -> &mut Self {
+        self.little_endian = false;
+        self
+    }
+
+    pub fn little_endian(&mut self) -> &mut Self {
+        self.little_endian = true;
+        self
+    }
+
+    /// Sets a value added to the length field to find the length of the rest of the frame. For
+    /// example, -4 handles a 4-byte length field that counts itself.
+    pub fn length_adjustment(&mut self, length_adjustment: isize) -> &mut Self {
+        self.length_adjustment = length_adjustment;
+        self
+    }
+
+    pub fn max_frame_length(&mut self, max_frame_length: usize) -> &mut Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    pub fn new_codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec { builder: *self }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::LengthDelimitedCodec;
+    use crate::codec::{DecodeBuf, Decoder, Encoder, FramedRead};
+    use crate::testing::run;
+
+    fn decode_all(codec: &mut LengthDelimitedCodec, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut src = DecodeBuf::from(data.to_vec());
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode_eof(&mut src)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn waits_for_whole_frames() {
+        let mut codec = LengthDelimitedCodec::new();
+        let mut src = DecodeBuf::new();
+        for &byte in b"\0\0\0\x02hi" {
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+            src.extend_from_slice(&[byte]);
+        }
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(b"hi".to_vec()));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn rejects_frames_truncated_by_eof() {
+        run(async {
+            for data in [&b"\0\0"[..], b"\0\0\0\x05", b"\0\0\0\x05abc"] {
+                let mut reader = FramedRead::new(data, LengthDelimitedCodec::new());
+                let error = reader.next().await.unwrap().unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+                assert!(reader.next().await.is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn follows_the_builder_configuration() {
+        // A 2-byte little-endian length that counts itself.
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_length(2)
+            .little_endian()
+            .length_adjustment(-2)
+            .max_frame_length(3)
+            .new_codec();
+        let mut encoded = Vec::new();
+        codec.encode(b"abc", &mut encoded).unwrap();
+        codec.encode(b"", &mut encoded).unwrap();
+        assert_eq!(encoded, b"\x05\0abc\x02\0");
+        assert_eq!(
+            decode_all(&mut codec, &encoded).unwrap(),
+            [b"abc".to_vec(), Vec::new()]
+        );
+
+        let error = codec.encode(b"abcd", &mut encoded).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = decode_all(&mut codec, b"\x06\0abcd").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Shorter than the length field itself.
+        let error = decode_all(&mut codec, b"\x01\0").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut codec = LengthDelimitedCodec::builder()
+            .length_field_length(1)
+            .new_codec();
+        let error = codec.encode([0; 256], &mut encoded).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,150 @@
+use std::io;
+
+use crate::codec::{DecodeBuf, Decoder, Encoder};
+
+/// Newline-delimited UTF-8 text. Decoded lines don't include their `\n` or `\r\n` terminator, and
+/// the last line of a stream doesn't need one.
+#[derive(Clone, Debug, Default)]
+pub struct LinesCodec {
+    max_length: Option<usize>,
+    // Where to resume searching for a newline, since everything before it has already been checked.
+    next_index: usize,
+}
+
+impl LinesCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rejects lines longer than `max_length` bytes, not counting the terminator, rather than
+    /// buffering without limit.
+    pub fn new_with_max_length(max_length: usize) -> Self {
+        Self {
+            max_length: Some(max_length),
+            next_index: 0,
+        }
+    }
+
+    pub fn max_length(&self) -> Option<usize> {
+        self.max_length
+    }
+
+    fn take_line(&mut self, src: &mut DecodeBuf, len: usize) -> io::Result<String> {
+        self.next_index = 0;
+        let mut line = src.split_to(len);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        if self.max_length.is_some_and(|max| line.len() > max) {
+            return Err(line_too_long());
+        }
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut DecodeBuf) -> io::Result<Option<String>> {
+        match src[self.next_index..].iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let len = self.next_index + i + 1;
+                self.take_line(src, len).map(Some)
+            }
+            None => {
+                self.next_index = src.len();
+                // Allow for a `\r` that's about to be followed by `\n`.
+                if self.max_length.is_some_and(|max| src.len() > max + 1) {
+                    return Err(line_too_long());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut DecodeBuf) -> io::Result<Option<String>> {
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let len = src.len();
+                self.take_line(src, len).map(Some)
+            }
+        }
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for LinesCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, line: T, dst: &mut Vec<u8>) -> io::Result<()> {
+        dst.extend_from_slice(line.as_ref().as_bytes());
+        dst.push(b'\n');
+        Ok(())
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "line too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::LinesCodec;
+    use crate::codec::{FramedRead, FramedWrite};
+    use crate::testing::run;
+
+    async fn read_lines(data: &[u8], codec: LinesCodec) -> Vec<io::Result<String>> {
+        let mut reader = FramedRead::new(data, codec);
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next().await {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn splits_lines() {
+        run(async {
+            let lines = read_lines(b"first\r\nsecond\n\nlast", LinesCodec::new()).await;
+            let lines: Vec<_> = lines.into_iter().map(Result::unwrap).collect();
+            assert_eq!(lines, ["first", "second", "", "last"]);
+
+            let mut encoded = Vec::new();
+            let mut writer = FramedWrite::new(&mut encoded, LinesCodec::new());
+            writer.send("one").await.unwrap();
+            writer.send(String::from("two")).await.unwrap();
+            assert_eq!(encoded, b"one\ntwo\n");
+        });
+    }
+
+    #[test]
+    fn rejects_long_lines_and_invalid_utf8() {
+        run(async {
+            let lines = read_lines(b"1234\r\n12345\n", LinesCodec::new_with_max_length(4)).await;
+            assert_eq!(lines[0].as_ref().unwrap(), "1234");
+            assert_eq!(
+                lines[1].as_ref().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert_eq!(lines.len(), 2);
+
+            // Too long even before the newline arrives.
+            let lines = read_lines(&[b'x'; 100], LinesCodec::new_with_max_length(4)).await;
+            assert_eq!(lines.len(), 1);
+            assert!(lines[0].is_err());
+
+            let lines = read_lines(b"\xff\n", LinesCodec::new()).await;
+            assert_eq!(
+                lines[0].as_ref().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        });
+    }
+}
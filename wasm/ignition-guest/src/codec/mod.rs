@@ -0,0 +1,363 @@
+//! Framing for message streams over byte streams such as RPC bodies.
+//!
+//! A [`Decoder`] splits frames off the front of a [`DecodeBuf`] of received bytes and an [`Encoder`] appends
+//! frames to a buffer of bytes to send. [`FramedRead`] and [`FramedWrite`] drive them over any
+//! [`AsyncRead`] or [`AsyncWrite`], including [`ReadHandle`] and [`WriteHandle`]. The design follows
+//! `tokio_util::codec`, so a guest can talk to a host-side peer using the same codec configuration.
+
+use std::io;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::io::{ReadHandle, WriteHandle};
+
+mod length_delimited;
+mod lines;
+mod nine_p;
+
+pub use self::length_delimited::{LengthDelimitedCodec, LengthDelimitedCodecBuilder};
+pub use self::lines::LinesCodec;
+pub use self::nine_p::NinePCodec;
+
+/// How much more is read from the underlying stream whenever a decoder needs more data.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Once this much encoded data is waiting, [`FramedWrite::feed`] writes it out.
+const WRITE_HIGH_WATER_MARK: usize = 8192;
+
+pub trait Decoder {
+    type Item;
+    type Error: From<io::Error>;
+
+    /// Removes one frame from the front of `src` and returns it, or returns `None` without
+    /// consuming anything if `src` doesn't yet hold a whole frame.
+    fn decode(&mut self, src: &mut DecodeBuf) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Like [`decode`](Self::decode), but called once the stream has ended, so no more data is
+    /// coming. By default, leftover bytes that don't form a frame are an error.
+    fn decode_eof(&mut self, src: &mut DecodeBuf) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "bytes remaining on stream").into(),
+            ),
+        }
+    }
+}
+
+/// Bytes that have been read but not yet decoded.
+///
+/// Taking data off the front only moves a cursor. The space before it is reclaimed when more is
+/// read, once it's at least as large as what would have to be moved, so decoding many small frames
+/// out of one large read takes time in proportion to the data rather than to frames times buffer.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeBuf {
+    data: Vec<u8>,
+    // `data[..start]` has been consumed.
+    start: usize,
+}
+
+impl DecodeBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards the first `n` bytes.
+    pub fn advance(&mut self, n: usize) {
+        assert!(n <= self.len(), "advanced past the end of the buffer");
+        self.start += n;
+        if self.start == self.data.len() {
+            self.data.clear();
+            self.start = 0;
+        }
+    }
+
+    /// Removes the first `n` bytes and returns them.
+    pub fn split_to(&mut self, n: usize) -> Vec<u8> {
+        let front = self[..n].to_vec();
+        self.advance(n);
+        front
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.compact();
+        self.data.extend_from_slice(data);
+    }
+
+    /// Moves the unconsumed bytes to the front if that costs no more than the bytes consumed
+    /// since the last move.
+    fn compact(&mut self) {
+        if self.start > 0 && self.start >= self.len() {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+impl Deref for DecodeBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+}
+
+impl From<Vec<u8>> for DecodeBuf {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, start: 0 }
+    }
+}
+
+pub trait Encoder<Item> {
+    type Error: From<io::Error>;
+
+    /// Appends the encoding of `item` to `dst`.
+    fn encode(&mut self, item: Item, dst: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// Reads a stream of frames. Implements [`Stream`], and [`next`](Self::next) is the equivalent
+/// async method.
+///
+/// After the decoder fails, the stream ends.
+pub struct FramedRead<D, R = ReadHandle> {
+    inner: R,
+    decoder: D,
+    buf: DecodeBuf,
+    state: ReadState,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    /// `buf` may hold a whole frame.
+    Decoding,
+    /// The decoder needs more data than `buf` holds.
+    Reading,
+    /// The stream ended; remaining frames are drained with `decode_eof`.
+    Eof,
+    Done,
+}
+
+impl<D: Decoder, R: AsyncRead + Unpin> FramedRead<D, R> {
+    pub fn new(inner: R, decoder: D) -> Self {
+        Self {
+            inner,
+            decoder,
+            buf: DecodeBuf::new(),
+            state: ReadState::Decoding,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    /// Returns the data that has been read but not yet decoded.
+    pub fn read_buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Returns the underlying reader, discarding any data that hasn't been decoded.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the next frame, or `None` once the stream has ended.
+    pub async fn next(&mut self) -> Option<Result<D::Item, D::Error>> {
+        std::future::poll_fn(|cx| self.poll_next_frame(cx)).await
+    }
+
+    fn poll_next_frame(&mut self, cx: &mut Context) -> Poll<Option<Result<D::Item, D::Error>>> {
+        match ready!(self.poll_frame(cx)) {
+            Ok(frame) => Poll::Ready(frame.map(Ok)),
+            Err(e) => {
+                self.state = ReadState::Done;
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<Result<Option<D::Item>, D::Error>> {
+        loop {
+            match self.state {
+                ReadState::Decoding => match self.decoder.decode(&mut self.buf)? {
+                    Some(frame) => return Poll::Ready(Ok(Some(frame))),
+                    None => self.state = ReadState::Reading,
+                },
+                ReadState::Reading => {
+                    self.buf.compact();
+                    let buf = &mut self.buf.data;
+                    let len = buf.len();
+                    buf.resize(len + READ_CHUNK_SIZE, 0);
+                    let result = Pin::new(&mut self.inner).poll_read(cx, &mut buf[len..]);
+                    let n = match result {
+                        Poll::Ready(Ok(n)) => n,
+                        Poll::Ready(Err(e)) => {
+                            buf.truncate(len);
+                            return Poll::Ready(Err(e.into()));
+                        }
+                        Poll::Pending => {
+                            buf.truncate(len);
+                            return Poll::Pending;
+                        }
+                    };
+                    buf.truncate(len + n);
+                    self.state = if n == 0 {
+                        ReadState::Eof
+                    } else {
+                        ReadState::Decoding
+                    };
+                }
+                ReadState::Eof => {
+                    let frame = self.decoder.decode_eof(&mut self.buf)?;
+                    if frame.is_none() {
+                        self.state = ReadState::Done;
+                    }
+                    return Poll::Ready(Ok(frame));
+                }
+                ReadState::Done => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+}
+
+impl<D: Decoder + Unpin, R: AsyncRead + Unpin> Stream for FramedRead<D, R> {
+    type Item = Result<D::Item, D::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_frame(cx)
+    }
+}
+
+/// Writes a stream of frames.
+///
+/// Encoded frames are buffered until [`flush`](Self::flush), [`send`](Self::send), or
+/// [`close`](Self::close) writes them out, or enough accumulate. Anything still buffered when the
+/// writer is dropped is lost.
+pub struct FramedWrite<E, W = WriteHandle> {
+    inner: W,
+    encoder: E,
+    buf: Vec<u8>,
+}
+
+impl<E, W: AsyncWrite + Unpin> FramedWrite<E, W> {
+    pub fn new(inner: W, encoder: E) -> Self {
+        Self {
+            inner,
+            encoder,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn encoder(&self) -> &E {
+        &self.encoder
+    }
+
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// Returns the underlying writer, discarding any frames that haven't been written.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Encodes a frame without flushing it, although earlier frames may be written out to keep the
+    /// buffer small.
+    pub async fn feed<Item>(&mut self, item: Item) -> Result<(), E::Error>
+    where
+        E: Encoder<Item>,
+    {
+        self.encoder.encode(item, &mut self.buf)?;
+        if self.buf.len() >= WRITE_HIGH_WATER_MARK {
+            self.write_buf().await?;
+        }
+        Ok(())
+    }
+
+    /// Encodes a frame and flushes it along with any others that are buffered.
+    pub async fn send<Item>(&mut self, item: Item) -> Result<(), E::Error>
+    where
+        E: Encoder<Item>,
+    {
+        self.encoder.encode(item, &mut self.buf)?;
+        Ok(self.flush().await?)
+    }
+
+    /// Writes all buffered frames and flushes the underlying writer.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.write_buf().await?;
+        std::future::poll_fn(|cx| Pin::new(&mut self.inner).poll_flush(cx)).await
+    }
+
+    /// Flushes, then closes the underlying writer so that the reader sees the end of the stream.
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.write_buf().await?;
+        std::future::poll_fn(|cx| Pin::new(&mut self.inner).poll_close(cx)).await
+    }
+
+    async fn write_buf(&mut self) -> io::Result<()> {
+        let result = crate::io::write_all(&mut self.inner, &self.buf).await;
+        self.buf.clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeBuf, FramedRead, FramedWrite, LengthDelimitedCodec};
+    use crate::testing::run;
+
+    #[test]
+    fn reclaims_consumed_space_lazily() {
+        let mut buf = DecodeBuf::from(b"abcdef".to_vec());
+        assert_eq!(buf.split_to(2), b"ab");
+        buf.extend_from_slice(b"g");
+        // Moving "cdefg" would cost more than the two bytes it reclaims.
+        assert_eq!((buf.start, &buf[..]), (2, &b"cdefg"[..]));
+
+        buf.advance(3);
+        buf.extend_from_slice(b"h");
+        assert_eq!((buf.start, &buf[..]), (0, &b"fgh"[..]));
+
+        buf.advance(3);
+        assert!(buf.is_empty());
+        assert!(buf.data.is_empty());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        run(async {
+            let frames = [&b"one"[..], b"", b"three"];
+            let mut encoded = Vec::new();
+            let mut writer = FramedWrite::new(&mut encoded, LengthDelimitedCodec::new());
+            for frame in frames {
+                writer.feed(frame).await.unwrap();
+            }
+            writer.close().await.unwrap();
+
+            let mut reader = FramedRead::new(&encoded[..], LengthDelimitedCodec::new());
+            for frame in frames {
+                assert_eq!(reader.next().await.unwrap().unwrap(), frame);
+            }
+            assert!(reader.next().await.is_none());
+            assert!(reader.read_buffer().is_empty());
+        });
+    }
+}
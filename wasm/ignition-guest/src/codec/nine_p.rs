@@ -0,0 +1,152 @@
+use std::io;
+
+use ignition_9p::message::Message;
+use ignition_9p::wire::{ReadFrom, WriteTo};
+
+use crate::codec::{DecodeBuf, Decoder, Encoder, LengthDelimitedCodec};
+
+/// Default limit on the size of a message, including its size field, until a different msize is
+/// negotiated.
+const DEFAULT_MSIZE: usize = 64 * 1024;
+
+/// 9p2000 messages, each prefixed by its 4-byte little-endian size, which counts the size field
+/// itself. This is the framing `ignition-demo-9p-server` uses.
+#[derive(Clone, Debug)]
+pub struct NinePCodec {
+    frames: LengthDelimitedCodec,
+}
+
+impl NinePCodec {
+    pub fn new() -> Self {
+        Self::with_msize(DEFAULT_MSIZE)
+    }
+
+    /// Rejects messages larger than `msize` bytes, as negotiated by `Tversion`.
+    pub fn with_msize(msize: usize) -> Self {
+        let mut codec = Self {
+            frames: LengthDelimitedCodec::builder()
+                .little_endian()
+                .length_field_length(4)
+                .length_adjustment(-4)
+                .new_codec(),
+        };
+        codec.set_msize(msize);
+        codec
+    }
+
+    pub fn set_msize(&mut self, msize: usize) {
+        self.frames.set_max_frame_length(msize.saturating_sub(4));
+    }
+}
+
+impl Default for NinePCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for NinePCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut DecodeBuf) -> io::Result<Option<Message>> {
+        let frame = match self.frames.decode(src)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let mut data = &frame[..];
+        let message = Message::read_from(&mut data)?;
+        if !data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message shorter than its size field",
+            ));
+        }
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<&Message> for NinePCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: &Message, dst: &mut Vec<u8>) -> io::Result<()> {
+        let mut body = Vec::new();
+        message.write_to(&mut body)?;
+        self.frames.encode(body, dst)
+    }
+}
+
+impl Encoder<Message> for NinePCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut Vec<u8>) -> io::Result<()> {
+        self.encode(&message, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use ignition_9p::message::raw::MessageType;
+    use ignition_9p::message::{Message, MessageBody, RError};
+    use ignition_9p::Tag;
+
+    use super::NinePCodec;
+    use crate::codec::{FramedRead, FramedWrite};
+    use crate::testing::run;
+
+    #[test]
+    fn frames_messages_with_their_size() {
+        run(async {
+            let messages = [
+                Message {
+                    tag: Tag(1),
+                    body: MessageBody::RClunk,
+                },
+                Message {
+                    tag: Tag(2),
+                    body: MessageBody::RError(RError {
+                        ename: "no such file".to_owned(),
+                    }),
+                },
+            ];
+            let mut encoded = Vec::new();
+            let mut writer = FramedWrite::new(&mut encoded, NinePCodec::new());
+            for message in &messages {
+                writer.send(message).await.unwrap();
+            }
+            // An Rclunk is just its size, type, and tag.
+            assert_eq!(encoded[..7], [7, 0, 0, 0, MessageType::RCLUNK.0, 1, 0]);
+
+            let mut reader = FramedRead::new(&encoded[..], NinePCodec::new());
+            for message in &messages {
+                assert_eq!(&reader.next().await.unwrap().unwrap(), message);
+            }
+            assert!(reader.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        run(async {
+            // Larger than the negotiated msize.
+            let mut reader =
+                FramedRead::new(&[8, 0, 0, 0, 0, 0, 0, 0][..], NinePCodec::with_msize(7));
+            let error = reader.next().await.unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+            // An Rclunk with a byte after it that its size field covers.
+            let data = [8, 0, 0, 0, MessageType::RCLUNK.0, 1, 0, 0];
+            let mut reader = FramedRead::new(&data[..], NinePCodec::new());
+            let error = reader.next().await.unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+            // Cut off partway through.
+            let mut reader =
+                FramedRead::new(&[7, 0, 0, 0, MessageType::RCLUNK.0][..], NinePCodec::new());
+            let error = reader.next().await.unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+}
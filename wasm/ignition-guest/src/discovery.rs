@@ -0,0 +1,255 @@
+//! Finds out which services are available through the host's `DiscoveryService`.
+//!
+//! [`RpcClient::wait_healthy`](crate::rpc_client::RpcClient::wait_healthy) waits for one service to
+//! have a server. This module lists every service with the number of instances, meaning RPC
+//! servers, it has, and watches instances join and leave.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+
+use crate::io::{read_full, ReadHandle};
+use crate::rpc_client::RpcClient;
+
+const SERVICE_NAME: &str = "DiscoveryService";
+
+thread_local! {
+    static CLIENT: RpcClient = RpcClient::new(SERVICE_NAME);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The host has no discovery service, or it didn't respond.
+    Unavailable,
+    /// A status code this version of the library doesn't know about.
+    Unknown(u8),
+}
+
+impl Error {
+    fn from_status(status: u8) -> Result<(), Self> {
+        match status {
+            0 => Ok(()),
+            x => Err(Error::Unknown(x)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Unavailable => write!(f, "discovery service unavailable"),
+            Error::Unknown(status) => write!(f, "unknown discovery service error {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInfo {
+    pub service_name: String,
+    pub instances: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// The service had instances when the watch began, or when it was reset.
+    Listed,
+    Joined,
+    Left,
+    /// The watch fell behind and missed events. `Listed` events for every service that has
+    /// instances follow, and replace what earlier events said. The event's service name is empty.
+    Reset,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub service_name: String,
+    /// How many instances the service has after the event.
+    pub instances: u32,
+}
+
+/// Follows the services that have instances. Dropping it stops the watch.
+pub struct Watch {
+    response: ReadHandle,
+}
+
+impl Watch {
+    /// Waits for the next event. Returns None if the host ended the watch.
+    pub async fn next(&self) -> Option<Event> {
+        let mut kind = [0];
+        if !read_full(&self.response, &mut kind).await {
+            return None;
+        }
+        let kind = match kind[0] {
+            0 => EventKind::Listed,
+            1 => EventKind::Joined,
+            2 => EventKind::Left,
+            3 => EventKind::Reset,
+            _ => return None,
+        };
+        let ServiceInfo {
+            service_name,
+            instances,
+        } = read_service(&self.response).await?;
+        Some(Event {
+            kind,
+            service_name,
+            instances,
+        })
+    }
+}
+
+/// Returns every service that has instances, ordered by name.
+pub async fn list() -> Result<Vec<ServiceInfo>, Error> {
+    let response = request("list").await?.read_to_end().await;
+    let mut src = &response[..];
+    let mut services = Vec::new();
+    while !src.is_empty() {
+        services.push(take_service(&mut src).ok_or(Error::Unavailable)?);
+    }
+    Ok(services)
+}
+
+/// Starts watching services. The first events list the services that have instances, and later
+/// ones report each instance that joins or leaves. A watch that falls behind is
+/// [reset](EventKind::Reset) rather than holding on to every event.
+pub async fn watch() -> Result<Watch, Error> {
+    Ok(Watch {
+        response: request("watch").await?,
+    })
+}
+
+/// Makes an empty request and returns its response after the status.
+async fn request(method_name: &str) -> Result<ReadHandle, Error> {
+    let response = CLIENT.with(|client| client.request(method_name).into_response());
+    let mut status = [0];
+    if !read_full(&response, &mut status).await {
+        return Err(Error::Unavailable);
+    }
+    Error::from_status(status[0])?;
+    Ok(response)
+}
+
+async fn read_service(response: &ReadHandle) -> Option<ServiceInfo> {
+    let mut len = [0; 4];
+    if !read_full(response, &mut len).await {
+        return None;
+    }
+    let mut service_name = vec![0; u32::from_le_bytes(len) as usize];
+    let mut instances = [0; 4];
+    if !read_full(response, &mut service_name).await || !read_full(response, &mut instances).await {
+        return None;
+    }
+    Some(ServiceInfo {
+        service_name: String::from_utf8(service_name).ok()?,
+        instances: u32::from_le_bytes(instances),
+    })
+}
+
+/// Parses a service name field followed by an instance count.
+fn take_service(src: &mut &[u8]) -> Option<ServiceInfo> {
+    let len = u32::from_le_bytes(src.get(..4)?.try_into().unwrap()) as usize;
+    let service_name = src.get(4..4 + len)?;
+    let instances = u32::from_le_bytes(src.get(4 + len..8 + len)?.try_into().unwrap());
+    let service_name = String::from_utf8(service_name.to_vec()).ok()?;
+    *src = &src[8 + len..];
+    Some(ServiceInfo {
+        service_name,
+        instances,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list, watch, Error, Event, EventKind, ServiceInfo, SERVICE_NAME};
+    use crate::testing::{run, serve_natively};
+
+    fn service(service_name: &str, instances: u32) -> Vec<u8> {
+        [
+            &(service_name.len() as u32).to_le_bytes()[..],
+            service_name.as_bytes(),
+            &instances.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn info(service_name: &str, instances: u32) -> ServiceInfo {
+        ServiceInfo {
+            service_name: service_name.to_owned(),
+            instances,
+        }
+    }
+
+    fn event(kind: EventKind, service_name: &str, instances: u32) -> Event {
+        Event {
+            kind,
+            service_name: service_name.to_owned(),
+            instances,
+        }
+    }
+
+    #[test]
+    fn lists_services() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, body| {
+                assert_eq!(method_name, "list");
+                assert!(body.is_empty());
+                [&[0][..], &service("a", 1), &service("bee", 2)].concat()
+            });
+            assert_eq!(list().await, Ok(vec![info("a", 1), info("bee", 2)]));
+        });
+    }
+
+    #[test]
+    fn refuses_a_cut_off_list() {
+        run(async {
+            serve_natively(SERVICE_NAME, |_, _| {
+                let service = service("a", 1);
+                [&[0][..], &service[..service.len() - 1]].concat()
+            });
+            assert_eq!(list().await, Err(Error::Unavailable));
+        });
+    }
+
+    #[test]
+    fn watches_services_until_an_unknown_event() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, _| {
+                assert_eq!(method_name, "watch");
+                [
+                    &[0][..],
+                    &[0],
+                    &service("a", 1),
+                    &[1],
+                    &service("b", 1),
+                    &[2],
+                    &service("a", 0),
+                    &[3],
+                    &service("", 0),
+                    &[0],
+                    &service("b", 1),
+                    &[9],
+                    &service("c", 1),
+                ]
+                .concat()
+            });
+            let watch = watch().await.unwrap();
+            assert_eq!(watch.next().await, Some(event(EventKind::Listed, "a", 1)));
+            assert_eq!(watch.next().await, Some(event(EventKind::Joined, "b", 1)));
+            assert_eq!(watch.next().await, Some(event(EventKind::Left, "a", 0)));
+            assert_eq!(watch.next().await, Some(event(EventKind::Reset, "", 0)));
+            assert_eq!(watch.next().await, Some(event(EventKind::Listed, "b", 1)));
+            assert_eq!(watch.next().await, None);
+        });
+    }
+
+    #[test]
+    fn reports_unknown_statuses() {
+        run(async {
+            serve_natively(SERVICE_NAME, |_, _| vec![5]);
+            assert_eq!(list().await, Err(Error::Unknown(5)));
+            assert!(matches!(watch().await, Err(Error::Unknown(5))));
+        });
+    }
+}
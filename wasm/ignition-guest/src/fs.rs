@@ -0,0 +1,200 @@
+//! Filesystem access through the namespace the host mounts for guests.
+//!
+//! Paths are `/`-separated and relative to the root of the namespace. Entries are described with
+//! 9p2000 [`Stat`] structures, whichever store actually backs the namespace.
+
+use std::fmt::{self, Display, Formatter};
+use std::future::poll_fn;
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use ignition_9p::wire::ReadFrom;
+pub use ignition_9p::Stat;
+
+use crate::api::sys::{self, IoHandle, TaskId};
+use crate::api::wait::wait;
+use crate::io::{AsyncWrite, ReadHandle, WriteHandle};
+use crate::runtime::reactor::new_task;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    InvalidPath,
+    /// The host has no filesystem mounted.
+    Unavailable,
+    Io,
+    /// A status code this version of the library doesn't know about.
+    Unknown(usize),
+}
+
+impl Error {
+    fn from_status(status: usize) -> Result<(), Self> {
+        match status {
+            0 => Ok(()),
+            1 => Err(Error::NotFound),
+            2 => Err(Error::PermissionDenied),
+            3 => Err(Error::AlreadyExists),
+            4 => Err(Error::NotADirectory),
+            5 => Err(Error::IsADirectory),
+            6 => Err(Error::InvalidPath),
+            7 => Err(Error::Unavailable),
+            8 => Err(Error::Io),
+            x => Err(Error::Unknown(x)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::AlreadyExists => write!(f, "already exists"),
+            Error::NotADirectory => write!(f, "not a directory"),
+            Error::IsADirectory => write!(f, "is a directory"),
+            Error::InvalidPath => write!(f, "invalid path"),
+            Error::Unavailable => write!(f, "no filesystem is mounted"),
+            Error::Io => write!(f, "I/O error"),
+            Error::Unknown(status) => write!(f, "unknown filesystem error {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Opens a file for reading.
+pub async fn open(path: &str) -> Result<ReadHandle, Error> {
+    let (io, status) = call(sys::fs_open, path).await;
+    let handle = ReadHandle::from_raw(io);
+    Error::from_status(status)?;
+    Ok(handle)
+}
+
+/// Creates a file for writing, truncating it if it already exists.
+pub async fn create(path: &str) -> Result<FileWriter, Error> {
+    let (io, status) = call(sys::fs_create, path).await;
+    let handle = WriteHandle::from_raw(io);
+    Error::from_status(status)?;
+    Ok(FileWriter { handle })
+}
+
+/// Reads the entire content of a file.
+pub async fn read(path: &str) -> Result<Vec<u8>, Error> {
+    Ok(open(path).await?.read_to_end().await)
+}
+
+/// Replaces the content of a file, creating it if necessary.
+pub async fn write(path: &str, content: &[u8]) -> Result<(), Error> {
+    let file = create(path).await?;
+    file.write_all(content).await;
+    file.finish().await
+}
+
+/// A file being written, from [`create`].
+///
+/// Writes succeed as soon as the host has them, before they reach the file, so an error in storing
+/// them is only reported by [`finish`](Self::finish). Dropping the writer completes the file
+/// without waiting, as does closing it through [`AsyncWrite`], which only flushes.
+pub struct FileWriter {
+    handle: WriteHandle,
+}
+
+impl FileWriter {
+    pub async fn write(&self, buf: &[u8]) -> usize {
+        self.handle.write(buf).await
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) {
+        self.handle.write_all(buf).await
+    }
+
+    /// Completes the file, and returns whether everything written to it was stored.
+    pub async fn finish(mut self) -> Result<(), Error> {
+        poll_fn(|cx| Pin::new(&mut self.handle).poll_flush(cx))
+            .await
+            .map_err(|_| Error::Io)?;
+        let task_id = new_task();
+        let io = self.handle.into_raw();
+        // SAFETY: No special considerations.
+        unsafe { sys::fs_close(task_id, io) };
+        Error::from_status(wait(task_id).await)
+    }
+}
+
+impl AsyncWrite for FileWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().handle).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().handle).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Describes a file or directory.
+pub async fn stat(path: &str) -> Result<Stat, Error> {
+    let (io, status) = call(sys::fs_stat, path).await;
+    let handle = ReadHandle::from_raw(io);
+    Error::from_status(status)?;
+    decode_stats(&handle.read_to_end().await)?
+        .pop()
+        .ok_or(Error::Io)
+}
+
+/// Describes each entry in a directory.
+pub async fn read_dir(path: &str) -> Result<Vec<Stat>, Error> {
+    let (io, status) = call(sys::fs_readdir, path).await;
+    let handle = ReadHandle::from_raw(io);
+    Error::from_status(status)?;
+    decode_stats(&handle.read_to_end().await)
+}
+
+async fn call(
+    f: unsafe extern "C" fn(TaskId, *const u8, usize, *mut IoHandle),
+    path: &str,
+) -> (IoHandle, usize) {
+    let task_id = new_task();
+    let mut io: MaybeUninit<IoHandle> = MaybeUninit::uninit();
+
+    // SAFETY: `path` refers to a UTF-8 string and `io` points to an appropriately sized space.
+    unsafe { f(task_id, path.as_ptr(), path.len(), io.as_mut_ptr()) };
+
+    // SAFETY: The host always initializes the handle before returning.
+    let io = unsafe { io.assume_init() };
+    (io, wait(task_id).await)
+}
+
+fn decode_stats(mut data: &[u8]) -> Result<Vec<Stat>, Error> {
+    let mut stats = Vec::new();
+    while !data.is_empty() {
+        stats.push(Stat::read_from(&mut data).map_err(|_| Error::Io)?);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create, write, Error};
+    use crate::testing::run;
+
+    #[test]
+    fn reports_the_status_of_each_call() {
+        // The fake host mounts nothing, so every call fails the way the real one does then.
+        run(async {
+            assert_eq!(write("a", b"a").await, Err(Error::Unavailable));
+            assert!(matches!(create("a").await, Err(Error::Unavailable)));
+            assert_eq!(super::read("a").await, Err(Error::Unavailable));
+            assert_eq!(super::stat("a").await.unwrap_err(), Error::Unavailable);
+        });
+    }
+}
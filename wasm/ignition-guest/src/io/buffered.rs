@@ -0,0 +1,448 @@
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+use crate::io::{write_all, ReadHandle, WriteHandle};
+
+const DEFAULT_BUF_SIZE: usize = 8192;
+
+/// Adds buffering to a reader, which makes small reads cheap and allows reading lines.
+pub struct BufReader<R = ReadHandle> {
+    inner: R,
+    buf: Box<[u8]>,
+    // `buf[pos..filled]` has been read from `inner` but not yet consumed.
+    pos: usize,
+    filled: usize,
+}
+
+impl<R: AsyncRead + Unpin> BufReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns the underlying reader. Reading from it directly skips any buffered data.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader, discarding any buffered data.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the data that has been buffered but not yet consumed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_read(cx, buf)).await
+    }
+
+    /// Fills `buf` completely, failing with [`io::ErrorKind::UnexpectedEof`] if the stream ends
+    /// first.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the buffered data, reading more from the underlying reader only if there is none.
+    /// An empty result means end of stream.
+    pub async fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        poll_fn(|cx| self.poll_fill(cx)).await?;
+        Ok(self.buffer())
+    }
+
+    /// Marks `amt` bytes returned by [`fill_buf`](Self::fill_buf) as read.
+    pub fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+
+    /// Appends bytes to `buf` up to and including `delimiter`, or until end of stream. Returns the
+    /// number of bytes appended, which is zero only at end of stream.
+    pub async fn read_until(&mut self, delimiter: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut read = 0;
+        loop {
+            let available = self.fill_buf().await?;
+            if available.is_empty() {
+                return Ok(read);
+            }
+            let (used, done) = match available.iter().position(|&b| b == delimiter) {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            buf.extend_from_slice(&available[..used]);
+            self.consume(used);
+            read += used;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Appends a line, including its `\n` terminator if there is one, to `buf`. Returns the number
+    /// of bytes appended, which is zero only at end of stream. If the line isn't valid UTF-8, fails
+    /// with [`io::ErrorKind::InvalidData`] and leaves `buf` unchanged.
+    pub async fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        let mut line = Vec::new();
+        let n = self.read_until(b'\n', &mut line).await?;
+        let line = String::from_utf8(line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.utf8_error()))?;
+        buf.push_str(&line);
+        Ok(n)
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if self.pos == self.filled {
+            self.filled = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut self.buf))?;
+            self.pos = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BufReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Large reads bypass the buffer when it's empty; copying through it would gain nothing.
+        if this.pos == this.filled && buf.len() >= this.buf.len() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        ready!(this.poll_fill(cx))?;
+        let available = this.buffer();
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        this.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for BufReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill(cx))?;
+        Poll::Ready(Ok(this.buffer()))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        BufReader::consume(self.get_mut(), amt)
+    }
+}
+
+/// Adds buffering to a writer, which batches small writes into fewer host calls.
+///
+/// Buffered data is only written by [`flush`](Self::flush), [`close`](Self::close), or once the
+/// buffer fills. Anything still buffered when the writer is dropped is lost.
+pub struct BufWriter<W = WriteHandle> {
+    inner: W,
+    buf: Vec<u8>,
+    // `buf[..written]` has already been passed to `inner`.
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> BufWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns the underlying writer. Writing to it directly bypasses any buffered data.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the underlying writer, discarding any buffered data.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Returns the data that has been buffered but not yet written.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.written..]
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        write_all(self, buf).await
+    }
+
+    /// Writes all buffered data and flushes the underlying writer.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_flush(cx)).await
+    }
+
+    /// Flushes, then closes the underlying writer.
+    pub async fn close(&mut self) -> io::Result<()> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_close(cx)).await
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let result = Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.written..]);
+            match ready!(result) {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Ok(n) => self.written += n,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        self.buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for BufWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() + buf.len() > this.buf.capacity() {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        // Large writes go straight through once the buffer is empty.
+        if buf.len() >= this.buf.capacity() {
+            Pin::new(&mut this.inner).poll_write(cx, buf)
+        } else {
+            this.buf.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use super::{BufReader, BufWriter};
+    use crate::io::copy;
+    use crate::testing::run;
+
+    /// Reads from `data` at most `chunk` bytes at a time, counting the reads.
+    struct ChunkedReader {
+        data: &'static [u8],
+        chunk: usize,
+        reads: usize,
+    }
+
+    impl ChunkedReader {
+        fn new(data: &'static [u8], chunk: usize) -> Self {
+            Self {
+                data,
+                chunk,
+                reads: 0,
+            }
+        }
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = self.data.len().min(buf.len()).min(self.chunk);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.reads += 1;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// Accepts at most `chunk` bytes per write, recording the size of each.
+    #[derive(Default)]
+    struct RecordingWriter {
+        data: Vec<u8>,
+        chunk: usize,
+        writes: Vec<usize>,
+        flushes: usize,
+        closed: bool,
+    }
+
+    impl RecordingWriter {
+        fn new(chunk: usize) -> Self {
+            Self {
+                chunk,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(self.chunk);
+            self.data.extend_from_slice(&buf[..n]);
+            self.writes.push(n);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn reads_lines_across_refills() {
+        run(async {
+            let inner = ChunkedReader::new(b"first line\nsecond\r\nno newline", 3);
+            let mut reader = BufReader::with_capacity(4, inner);
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                lines.push(line);
+            }
+            assert_eq!(lines, ["first line\n", "second\r\n", "no newline"]);
+
+            let mut reader = BufReader::new(ChunkedReader::new(b"ok\n\xff\n", 64));
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let error = reader.read_line(&mut line).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(line, "ok\n");
+        });
+    }
+
+    #[test]
+    fn large_reads_bypass_an_empty_buffer() {
+        run(async {
+            let mut reader = BufReader::with_capacity(4, ChunkedReader::new(b"0123456789", 8));
+            let mut small = [0; 2];
+            reader.read_exact(&mut small).await.unwrap();
+            assert_eq!(&small, b"01");
+            assert_eq!(reader.buffer(), b"23");
+
+            // What's buffered comes first, then a read bigger than the buffer skips it.
+            let mut large = [0; 8];
+            assert_eq!(reader.read(&mut large).await.unwrap(), 2);
+            assert_eq!(reader.read(&mut large).await.unwrap(), 6);
+            assert_eq!(&large[..6], b"456789");
+            assert!(reader.buffer().is_empty());
+            assert_eq!(reader.get_ref().reads, 2);
+
+            let error = reader.read_exact(&mut small).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn batches_small_writes_until_flushed() {
+        run(async {
+            let mut writer = BufWriter::with_capacity(16, RecordingWriter::new(usize::MAX));
+            for line in ["first line\n", "second\n", "no newline"] {
+                writer.write_all(line.as_bytes()).await.unwrap();
+            }
+            // Whatever is buffered goes out once the next write wouldn't fit beside it.
+            assert_eq!(writer.get_ref().writes, [11, 7]);
+            assert_eq!(writer.buffer(), b"no newline");
+
+            writer.flush().await.unwrap();
+            assert_eq!(writer.get_ref().writes, [11, 7, 10]);
+            assert_eq!(writer.get_ref().flushes, 1);
+
+            // Writes at least as large as the buffer go straight through.
+            writer.write_all(&[b'x'; 16]).await.unwrap();
+            assert!(writer.buffer().is_empty());
+            writer.close().await.unwrap();
+            let inner = writer.into_inner();
+            assert_eq!(inner.writes, [11, 7, 10, 16]);
+            assert!(inner.closed);
+            assert_eq!(inner.data.len(), 44);
+        });
+    }
+
+    #[test]
+    fn retries_partial_writes_and_refuses_zero() {
+        run(async {
+            let mut writer = BufWriter::with_capacity(8, RecordingWriter::new(3));
+            writer.write_all(b"abcdefg").await.unwrap();
+            writer.flush().await.unwrap();
+            assert_eq!(writer.get_ref().writes, [3, 3, 1]);
+            assert_eq!(writer.get_ref().data, b"abcdefg");
+
+            let mut writer = BufWriter::with_capacity(8, RecordingWriter::new(0));
+            writer.write_all(b"a").await.unwrap();
+            let error = writer.flush().await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+        });
+    }
+
+    #[test]
+    fn copies_through_both_buffers() {
+        run(async {
+            // Large enough that every layer has to make several reads and writes.
+            let message: &'static [u8] = (0..100_000u32)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>()
+                .leak();
+            let mut reader = BufReader::with_capacity(1000, ChunkedReader::new(message, 777));
+            let mut writer = BufWriter::with_capacity(1000, RecordingWriter::new(999));
+            assert_eq!(copy(&mut reader, &mut writer).await.unwrap(), 100_000);
+            assert!(writer.buffer().is_empty());
+            assert_eq!(writer.get_ref().data, message);
+            assert_eq!(writer.get_ref().flushes, 1);
+        });
+    }
+}
@@ -0,0 +1,404 @@
+//! Byte streams to and from the host, such as RPC bodies and files.
+//!
+//! The handles have simple async methods that map directly onto the host's `io_read` and `io_write`
+//! calls. They also implement the `futures-io` [`AsyncRead`] and [`AsyncWrite`] traits, and
+//! [`BufReader`] and [`BufWriter`] layer buffering over either. Because the host copies directly
+//! into and out of guest memory, the trait implementations stage data in a buffer owned by the
+//! handle. Don't interleave calls to a handle's inherent methods with an unfinished trait operation
+//! on the same handle.
+
+use std::future::poll_fn;
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+pub use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+use crate::api::sys::{self, TaskId};
+use crate::api::wait::wait;
+use crate::runtime::reactor;
+
+mod buffered;
+
+pub use self::buffered::{BufReader, BufWriter};
+
+/// Size of the first read made by `read_to_end`. Each later read doubles the buffer.
+const INITIAL_READ_TO_END_SIZE: usize = 1024;
+
+/// Largest single read staged by the [`AsyncRead`] implementation.
+const MAX_STAGED_READ_SIZE: usize = 64 * 1024;
+
+/// Size of the buffer used by [`copy`].
+const COPY_BUFFER_SIZE: usize = 8192;
+
+pub struct ReadHandle {
+    io: sys::IoHandle,
+    staged: StagedRead,
+}
+
+/// A read made on behalf of [`AsyncRead`]. While it's pending, `buf` is its destination; after it
+/// completes, `buf[pos..]` has been read but not yet returned.
+#[derive(Default)]
+struct StagedRead {
+    buf: Vec<u8>,
+    pos: usize,
+    pending: Option<TaskId>,
+}
+
+impl ReadHandle {
+    pub(crate) fn from_raw(io: sys::IoHandle) -> Self {
+        Self {
+            io,
+            staged: StagedRead::default(),
+        }
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        let task_id = reactor::new_task();
+        // SAFETY: `buf` outlives the read, since the future below borrows it until the host wakes
+        // the task.
+        match unsafe { start_read(task_id, self.io, buf.as_mut_ptr(), buf.len()) } {
+            Poll::Ready(n) => n,
+            Poll::Pending => wait(task_id).await,
+        }
+    }
+
+    /// Fills `buf` completely. Panics if the stream ends first.
+    pub async fn read_exact(&self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let n = self.read(buf).await;
+            assert!(n > 0 && n <= buf.len(), "unexpected end of stream");
+            buf = &mut buf[n..];
+        }
+    }
+
+    pub async fn read_to_end(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut len = 0;
+        loop {
+            if len == buf.len() {
+                buf.resize((len * 2).max(INITIAL_READ_TO_END_SIZE), 0);
+            }
+            let n = self.read(&mut buf[len..]).await;
+            len += n;
+            if n == 0 {
+                buf.truncate(len);
+                return buf;
+            }
+        }
+    }
+}
+
+impl AsyncRead for ReadHandle {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let staged = &mut this.staged;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if staged.pending.is_some() || staged.pos == staged.buf.len() {
+            let n = match staged.pending {
+                Some(task_id) => match poll_task(task_id, cx) {
+                    Poll::Ready(n) => {
+                        staged.pending = None;
+                        n
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                None => {
+                    let task_id = reactor::new_task();
+                    staged.buf.resize(buf.len().min(MAX_STAGED_READ_SIZE), 0);
+                    // SAFETY: `staged.buf` lives in the handle, which closes the IO object, ending
+                    // the read, before the buffer is dropped. It isn't resized while pending.
+                    let result = unsafe {
+                        start_read(task_id, this.io, staged.buf.as_mut_ptr(), staged.buf.len())
+                    };
+                    match result {
+                        Poll::Ready(n) => n,
+                        Poll::Pending => {
+                            reactor::store_waker(task_id, cx.waker().clone());
+                            staged.pending = Some(task_id);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+            };
+            staged.buf.truncate(n);
+            staged.pos = 0;
+        }
+
+        let available = &staged.buf[staged.pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        staged.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Drop for ReadHandle {
+    fn drop(&mut self) {
+        // SAFETY: No special considerations.
+        unsafe { sys::io_close(self.io) }
+        if let Some(task_id) = self.staged.pending {
+            reactor::future_dropped(task_id);
+        }
+    }
+}
+
+pub struct WriteHandle {
+    io: sys::IoHandle,
+    staged: StagedWrite,
+}
+
+/// Writes accepted by [`AsyncWrite`]. `buf[pos..]` hasn't been written to the host yet.
+#[derive(Default)]
+struct StagedWrite {
+    buf: Vec<u8>,
+    pos: usize,
+    pending: Option<TaskId>,
+    closed: bool,
+}
+
+impl WriteHandle {
+    pub(crate) fn from_raw(io: sys::IoHandle) -> Self {
+        Self {
+            io,
+            staged: StagedWrite::default(),
+        }
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> usize {
+        // Once closed, the handle's number may already belong to another IO object.
+        assert!(!self.staged.closed, "write to a closed handle");
+        let task_id = reactor::new_task();
+        // SAFETY: `buf` outlives the write, since the future below borrows it until the host wakes
+        // the task.
+        match unsafe { start_write(task_id, self.io, buf.as_ptr(), buf.len()) } {
+            Poll::Ready(n) => n,
+            Poll::Pending => wait(task_id).await,
+        }
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let n = self.write(buf).await;
+            assert!(n > 0 && n <= buf.len());
+            buf = &buf[n..];
+        }
+    }
+
+    /// Gives up the handle without closing it, for a host call that closes it instead. Panics if
+    /// the handle is closed or has a write pending.
+    pub(crate) fn into_raw(mut self) -> sys::IoHandle {
+        assert!(!self.staged.closed, "handle already closed");
+        assert!(self.staged.pending.is_none(), "write still pending");
+        // Keeps the drop from closing it.
+        self.staged.closed = true;
+        self.io
+    }
+
+    /// Writes everything staged by [`AsyncWrite::poll_write`].
+    fn poll_drain(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let staged = &mut self.staged;
+        loop {
+            let n = match staged.pending {
+                Some(task_id) => match poll_task(task_id, cx) {
+                    Poll::Ready(n) => {
+                        staged.pending = None;
+                        n
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                None if staged.pos == staged.buf.len() => {
+                    staged.buf.clear();
+                    staged.pos = 0;
+                    return Poll::Ready(Ok(()));
+                }
+                None => {
+                    let task_id = reactor::new_task();
+                    let unwritten = &staged.buf[staged.pos..];
+                    // SAFETY: `staged.buf` lives in the handle, which closes the IO object, ending
+                    // the write, before the buffer is dropped. It isn't modified while pending.
+                    let result = unsafe {
+                        start_write(task_id, self.io, unwritten.as_ptr(), unwritten.len())
+                    };
+                    match result {
+                        Poll::Ready(n) => n,
+                        Poll::Pending => {
+                            reactor::store_waker(task_id, cx.waker().clone());
+                            staged.pending = Some(task_id);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+            };
+            if n == 0 {
+                // The reader has gone away, so nothing staged can ever be delivered.
+                staged.buf.clear();
+                staged.pos = 0;
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            staged.pos += n;
+        }
+    }
+}
+
+impl AsyncWrite for WriteHandle {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.staged.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if this.staged.pending.is_some() {
+            return Poll::Pending;
+        }
+        this.staged.buf.extend_from_slice(buf);
+        // Start sending right away. If that can't finish now, completion is reported by the next
+        // call.
+        match this.poll_drain(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_drain(cx)
+    }
+
+    /// Flushes, then closes the IO object so that the reader sees end of file without waiting for
+    /// the handle to be dropped.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.staged.closed {
+            return Poll::Ready(Ok(()));
+        }
+        let result = ready!(this.poll_drain(cx));
+        // SAFETY: No special considerations.
+        unsafe { sys::io_close(this.io) }
+        this.staged.closed = true;
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for WriteHandle {
+    fn drop(&mut self) {
+        if !self.staged.closed {
+            // SAFETY: No special considerations.
+            unsafe { sys::io_close(self.io) }
+        }
+        if let Some(task_id) = self.staged.pending {
+            reactor::future_dropped(task_id);
+        }
+    }
+}
+
+/// Copies everything from `reader` to `writer`, then flushes `writer`. Returns the number of bytes
+/// copied.
+pub async fn copy<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+        if n == 0 {
+            poll_fn(|cx| Pin::new(&mut *writer).poll_flush(cx)).await?;
+            return Ok(copied);
+        }
+        write_all(writer, &buf[..n]).await?;
+        copied += n as u64;
+    }
+}
+
+/// Fills `buf`, or returns false if the stream ends first.
+pub(crate) async fn read_full(handle: &ReadHandle, mut buf: &mut [u8]) -> bool {
+    while !buf.is_empty() {
+        let n = handle.read(buf).await;
+        if n == 0 {
+            return false;
+        }
+        buf = &mut buf[n..];
+    }
+    true
+}
+
+pub(crate) async fn write_all<W>(writer: &mut W, mut buf: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    while !buf.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, buf)).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+/// Starts a read into `ptr`. If it doesn't complete immediately, `task_id` is woken with the number
+/// of bytes read once it does.
+///
+/// # Safety
+///
+/// `ptr` must remain valid for `len` bytes until the read completes or `io` is closed.
+unsafe fn start_read(task_id: TaskId, io: sys::IoHandle, ptr: *mut u8, len: usize) -> Poll<usize> {
+    let mut n: MaybeUninit<usize> = MaybeUninit::uninit();
+    let result = unsafe { sys::io_read(task_id, io, ptr, len, n.as_mut_ptr()) };
+    if result == 0 {
+        // Completed synchronously.
+        reactor::drop_unused_task(task_id);
+        Poll::Ready(unsafe { n.assume_init() })
+    } else {
+        // Will complete asynchronously.
+        Poll::Pending
+    }
+}
+
+/// Starts a write from `ptr`, like [`start_read`].
+///
+/// # Safety
+///
+/// `ptr` must remain valid for `len` bytes until the write completes or `io` is closed.
+unsafe fn start_write(
+    task_id: TaskId,
+    io: sys::IoHandle,
+    ptr: *const u8,
+    len: usize,
+) -> Poll<usize> {
+    let mut n: MaybeUninit<usize> = MaybeUninit::uninit();
+    let result = unsafe { sys::io_write(task_id, io, ptr, len, n.as_mut_ptr()) };
+    if result == 0 {
+        // Completed synchronously.
+        reactor::drop_unused_task(task_id);
+        Poll::Ready(unsafe { n.assume_init() })
+    } else {
+        // Will complete asynchronously.
+        Poll::Pending
+    }
+}
+
+/// Polls a task started by [`start_read`] or [`start_write`], releasing it once it completes.
+fn poll_task(task_id: TaskId, cx: &mut Context) -> Poll<usize> {
+    match reactor::get_wake_param(task_id) {
+        Some(n) => {
+            reactor::future_dropped(task_id);
+            Poll::Ready(n)
+        }
+        None => {
+            reactor::store_waker(task_id, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
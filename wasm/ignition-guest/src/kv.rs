@@ -0,0 +1,316 @@
+//! A durable key-value store through the host's `KvService`.
+//!
+//! Each process sees the namespace of its principal, which survives restarts and upgrades. Keys and
+//! values are arbitrary bytes.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+
+use crate::rpc_client::RpcClient;
+
+const SERVICE_NAME: &str = "KvService";
+
+thread_local! {
+    static CLIENT: RpcClient = RpcClient::new(SERVICE_NAME);
+}
+
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_CONFLICT: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The request was too large, or the host couldn't make sense of it.
+    InvalidRequest,
+    /// The host failed to read or write its storage.
+    StorageError,
+    /// The host has no key-value store, or its response was cut off.
+    Unavailable,
+    /// A status code this version of the library doesn't know about.
+    Unknown(u8),
+}
+
+impl Error {
+    fn from_status(status: u8) -> Self {
+        match status {
+            3 => Error::InvalidRequest,
+            4 => Error::StorageError,
+            x => Error::Unknown(x),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidRequest => write!(f, "invalid key-value request"),
+            Error::StorageError => write!(f, "key-value storage failed"),
+            Error::Unavailable => write!(f, "key-value store unavailable"),
+            Error::Unknown(status) => write!(f, "unknown key-value store error {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns the value of `key`, or None if it isn't set.
+pub async fn get(key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let mut body = Vec::new();
+    put_field(&mut body, key);
+    match call("get", &body).await? {
+        (STATUS_OK, rest) => {
+            let value = take_field(&mut rest.as_slice()).ok_or(Error::Unavailable)?;
+            Ok(Some(value.to_vec()))
+        }
+        (STATUS_NOT_FOUND, _) => Ok(None),
+        (status, _) => Err(Error::from_status(status)),
+    }
+}
+
+pub async fn put(key: &[u8], value: &[u8]) -> Result<(), Error> {
+    let mut body = Vec::new();
+    put_field(&mut body, key);
+    put_field(&mut body, value);
+    match call("put", &body).await? {
+        (STATUS_OK, _) => Ok(()),
+        (status, _) => Err(Error::from_status(status)),
+    }
+}
+
+/// Removes `key`. Returns false if it wasn't set.
+pub async fn delete(key: &[u8]) -> Result<bool, Error> {
+    let mut body = Vec::new();
+    put_field(&mut body, key);
+    match call("delete", &body).await? {
+        (STATUS_OK, _) => Ok(true),
+        (STATUS_NOT_FOUND, _) => Ok(false),
+        (status, _) => Err(Error::from_status(status)),
+    }
+}
+
+/// Sets `key` to `new`, or removes it if `new` is None, as long as its current value is `expected`,
+/// where None means it must not be set. Otherwise, returns the current value as the inner error.
+pub async fn compare_and_swap(
+    key: &[u8],
+    expected: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Result<Result<(), Option<Vec<u8>>>, Error> {
+    let mut body = Vec::new();
+    put_field(&mut body, key);
+    put_optional_field(&mut body, expected);
+    put_optional_field(&mut body, new);
+    match call("compare_and_swap", &body).await? {
+        (STATUS_OK, _) => Ok(Ok(())),
+        (STATUS_CONFLICT, rest) => {
+            let current = take_optional_field(&mut rest.as_slice()).ok_or(Error::Unavailable)?;
+            Ok(Err(current.map(<[u8]>::to_vec)))
+        }
+        (status, _) => Err(Error::from_status(status)),
+    }
+}
+
+/// Returns every entry whose key starts with `prefix`, in key order.
+pub async fn scan(prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+    let mut body = Vec::new();
+    put_field(&mut body, prefix);
+    let response = match call("scan", &body).await? {
+        (STATUS_OK, response) => response,
+        (status, _) => return Err(Error::from_status(status)),
+    };
+    let mut rest = response.as_slice();
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let key = take_field(&mut rest).ok_or(Error::Unavailable)?;
+        let value = take_field(&mut rest).ok_or(Error::Unavailable)?;
+        entries.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(entries)
+}
+
+/// Makes a request and returns the response's status and the rest of its body.
+async fn call(method_name: &str, body: &[u8]) -> Result<(u8, Vec<u8>), Error> {
+    let request = CLIENT.with(|client| client.request(method_name));
+    request.write_all(body).await;
+    let mut response = request.into_response().read_to_end().await;
+    if response.is_empty() {
+        return Err(Error::Unavailable);
+    }
+    let rest = response.split_off(1);
+    Ok((response[0], rest))
+}
+
+fn put_field(dst: &mut Vec<u8>, field: &[u8]) {
+    let len: u32 = field.len().try_into().unwrap();
+    dst.extend_from_slice(&len.to_le_bytes());
+    dst.extend_from_slice(field);
+}
+
+fn put_optional_field(dst: &mut Vec<u8>, field: Option<&[u8]>) {
+    match field {
+        Some(field) => {
+            dst.push(1);
+            put_field(dst, field);
+        }
+        None => dst.push(0),
+    }
+}
+
+fn take_field<'a>(src: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if src.len() < 4 {
+        return None;
+    }
+    let (len, rest) = src.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (field, rest) = rest.split_at(len);
+    *src = rest;
+    Some(field)
+}
+
+fn take_optional_field<'a>(src: &mut &'a [u8]) -> Option<Option<&'a [u8]>> {
+    let (&present, rest) = src.split_first()?;
+    *src = rest;
+    match present {
+        0 => Some(None),
+        1 => take_field(src).map(Some),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{
+        compare_and_swap, delete, get, put, put_field, put_optional_field, scan, take_field,
+        take_optional_field, Error, SERVICE_NAME,
+    };
+    use crate::testing::{run, serve_natively};
+
+    /// Serves a namespace from memory, the way the host's `KvService` does.
+    fn serve_entries() {
+        let mut entries = BTreeMap::<Vec<u8>, Vec<u8>>::new();
+        serve_natively(SERVICE_NAME, move |method_name, mut body| {
+            let mut response = vec![0];
+            let key = take_field(&mut body).unwrap().to_vec();
+            match method_name {
+                "get" => match entries.get(&key) {
+                    Some(value) => put_field(&mut response, value),
+                    None => response[0] = 1,
+                },
+                "put" => {
+                    let value = take_field(&mut body).unwrap();
+                    entries.insert(key, value.to_vec());
+                }
+                "delete" => {
+                    if entries.remove(&key).is_none() {
+                        response[0] = 1;
+                    }
+                }
+                "compare_and_swap" => {
+                    let expected = take_optional_field(&mut body).unwrap();
+                    let new = take_optional_field(&mut body).unwrap();
+                    let current = entries.get(&key).map(Vec::as_slice);
+                    if current != expected {
+                        response[0] = 2;
+                        put_optional_field(&mut response, current);
+                    } else if let Some(new) = new {
+                        entries.insert(key, new.to_vec());
+                    } else {
+                        entries.remove(&key);
+                    }
+                }
+                "scan" => {
+                    for (k, v) in entries.range(key.clone()..) {
+                        if !k.starts_with(&key) {
+                            break;
+                        }
+                        put_field(&mut response, k);
+                        put_field(&mut response, v);
+                    }
+                }
+                _ => response[0] = 3,
+            }
+            assert!(body.is_empty());
+            response
+        });
+    }
+
+    #[test]
+    fn puts_gets_and_deletes() {
+        run(async {
+            serve_entries();
+            assert_eq!(get(b"a").await, Ok(None));
+            put(b"a", b"1").await.unwrap();
+            put(b"b", b"").await.unwrap();
+            assert_eq!(get(b"a").await, Ok(Some(b"1".to_vec())));
+            assert_eq!(get(b"b").await, Ok(Some(Vec::new())));
+            assert_eq!(delete(b"a").await, Ok(true));
+            assert_eq!(delete(b"a").await, Ok(false));
+            assert_eq!(get(b"a").await, Ok(None));
+        });
+    }
+
+    #[test]
+    fn compares_and_swaps() {
+        run(async {
+            serve_entries();
+            assert_eq!(compare_and_swap(b"k", None, Some(b"1")).await, Ok(Ok(())));
+            assert_eq!(
+                compare_and_swap(b"k", None, Some(b"2")).await,
+                Ok(Err(Some(b"1".to_vec())))
+            );
+            assert_eq!(
+                compare_and_swap(b"k", Some(b"1"), Some(b"2")).await,
+                Ok(Ok(()))
+            );
+            assert_eq!(compare_and_swap(b"k", Some(b"2"), None).await, Ok(Ok(())));
+            assert_eq!(
+                compare_and_swap(b"k", Some(b"2"), None).await,
+                Ok(Err(None))
+            );
+        });
+    }
+
+    #[test]
+    fn scans_a_prefix_in_key_order() {
+        run(async {
+            serve_entries();
+            for key in [&b"user/2"[..], b"user/1", b"other", b"user"] {
+                put(key, key).await.unwrap();
+            }
+            let entries = scan(b"user/").await.unwrap();
+            assert_eq!(
+                entries,
+                [
+                    (b"user/1".to_vec(), b"user/1".to_vec()),
+                    (b"user/2".to_vec(), b"user/2".to_vec()),
+                ]
+            );
+            assert_eq!(scan(b"none").await, Ok(Vec::new()));
+        });
+    }
+
+    #[test]
+    fn reports_errors_and_cut_off_responses() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, _| match method_name {
+                "put" => vec![4],
+                "delete" => vec![3],
+                "scan" => [&[0][..], &5u32.to_le_bytes(), b"key"].concat(),
+                "compare_and_swap" => vec![9],
+                _ => Vec::new(),
+            });
+            assert_eq!(put(b"k", b"v").await, Err(Error::StorageError));
+            assert_eq!(delete(b"k").await, Err(Error::InvalidRequest));
+            assert_eq!(scan(b"").await, Err(Error::Unavailable));
+            assert_eq!(
+                compare_and_swap(b"k", None, None).await,
+                Err(Error::Unknown(9))
+            );
+            assert_eq!(get(b"k").await, Err(Error::Unavailable));
+        });
+    }
+}
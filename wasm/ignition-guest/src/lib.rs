@@ -1,33 +1,116 @@
+use std::cell::RefCell;
+use std::future::Future;
+
 use crate::api::sys::TaskId;
 use crate::runtime::executor::run;
 use crate::runtime::reactor::dispatch_wake;
+use crate::runtime::spawn_local;
 
+// Native tests run the allocator against fake pages.
+#[cfg(any(
+    all(feature = "allocator", target_arch = "wasm32"),
+    all(test, not(target_arch = "wasm32"))
+))]
+pub mod allocator;
 pub mod api;
+pub mod blob;
+pub mod codec;
+pub mod discovery;
+pub mod fs;
 mod instant;
 pub mod io;
+pub mod kv;
 pub mod rpc_client;
 pub mod rpc_server;
 pub mod runtime;
+pub mod sync;
+#[cfg(feature = "native-test")]
+pub mod testing;
+pub mod topic;
 
 pub use crate::instant::Instant;
+pub use ignition_guest_macros::main;
+
+/// The optional parts of the ABI this build uses, which the host has to agree to.
+const ABI_FEATURES: &[&str] = if cfg!(all(feature = "allocator", target_arch = "wasm32")) {
+    &[ignition_abi::HEAP_STATS]
+} else {
+    &[]
+};
+
+#[doc(hidden)]
+pub const ABI_SECTION_LEN: usize = ignition_abi::section::section_len(ABI_FEATURES);
+
+/// The content of the `ignition_abi` section that `#[main]` gives the guest.
+#[doc(hidden)]
+pub const ABI_SECTION: [u8; ABI_SECTION_LEN] =
+    ignition_abi::section::section(ignition_abi::ABI_VERSION, ABI_FEATURES);
+
+thread_local! {
+    /// Where the host writes batches of wakes, as task ID and param pairs.
+    static WAKE_BUFFER: RefCell<Vec<[u32; 2]>> = const { RefCell::new(Vec::new()) };
+}
 
 #[doc(hidden)]
 pub fn wake_internal(task_id: u32, param: usize, init: fn()) {
+    dispatch(task_id, param, init);
+    finish_wake();
+}
+
+#[doc(hidden)]
+pub fn wake_buffer_internal(capacity: u32) -> *mut [u32; 2] {
+    WAKE_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.resize(capacity as usize, [0; 2]);
+        buffer.as_mut_ptr()
+    })
+}
+
+/// Dispatches the first `count` wakes in the wake buffer, then runs tasks once for all of them.
+#[doc(hidden)]
+pub fn wake_many_internal(count: u32, init: fn()) {
+    for i in 0..count as usize {
+        let [task_id, param] = WAKE_BUFFER.with(|buffer| buffer.borrow()[i]);
+        dispatch(task_id, param as usize, init);
+    }
+    finish_wake();
+}
+
+fn dispatch(task_id: u32, param: usize, init: fn()) {
     if task_id == u32::MAX {
         init();
     } else {
         dispatch_wake(TaskId(task_id), param);
     }
+}
+
+fn finish_wake() {
     run();
+
+    #[cfg(all(feature = "allocator", target_arch = "wasm32"))]
+    allocator::report_heap_stats(false);
+}
+
+#[doc(hidden)]
+pub fn start_main<F>(main: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    // The test harness reports panics itself.
+    #[cfg(not(feature = "native-test"))]
+    std::panic::set_hook(Box::new(report_panic));
+
+    spawn_local(async move {
+        main.await;
+        api::shutdown();
+    });
 }
 
-// TODO: Expand this into a fancy proc macro, something like #[ignition::init] to wrap init().
-#[macro_export]
-macro_rules! emit_wake {
-    ($init:ident) => {
-        #[no_mangle]
-        pub extern "C" fn wake(task_id: u32, param: usize) {
-            ::ignition_guest::wake_internal(task_id, param, $init);
-        }
-    };
+/// Reports a panic to the host before aborting, since the host otherwise only sees that the
+/// instance trapped.
+#[cfg(not(feature = "native-test"))]
+fn report_panic(info: &std::panic::PanicHookInfo) {
+    let message = info.payload_as_str().unwrap_or("Box<dyn Any>");
+    api::report_panic(message, info.location());
+    api::abort();
 }
@@ -1,5 +1,5 @@
 use std::mem::MaybeUninit;
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
 
 use crate::api::sys::{self, IoHandle, RpcClientHandle};
 use crate::api::wait::wait;
@@ -70,3 +70,9 @@ impl Deref for Request {
         &self.request
     }
 }
+
+impl DerefMut for Request {
+    fn deref_mut(&mut self) -> &mut WriteHandle {
+        &mut self.request
+    }
+}
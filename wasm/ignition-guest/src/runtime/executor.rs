@@ -7,6 +7,7 @@ use std::task::Context;
 use lazy_static::lazy_static;
 
 use crate::runtime::executor::task_waker::TaskWaker;
+use crate::runtime::join::{joinable, JoinHandle};
 use crate::runtime::task::Task;
 
 lazy_static! {
@@ -17,8 +18,26 @@ pub(crate) fn run() {
     executor_run(&EXECUTOR);
 }
 
-pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
-    EXECUTOR.lock().unwrap().spawn(Task::new(future));
+/// Starts running `future` as a new task.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (task, join_handle) = joinable(future);
+    EXECUTOR.lock().unwrap().spawn(Task::new(task));
+    join_handle
+}
+
+/// Starts running a future that is not [`Send`] as a new task. The task will only ever be polled
+/// by the instance that spawned it.
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    let (task, join_handle) = joinable(future);
+    EXECUTOR.lock().unwrap().spawn(Task::new_local(task));
+    join_handle
 }
 
 #[derive(Default)]
@@ -26,6 +45,16 @@ struct Executor {
     awake: VecDeque<Task>,
 }
 
+/// Drops every runnable task. Returns false if there were none.
+#[cfg(feature = "native-test")]
+pub(crate) fn clear() -> bool {
+    let tasks = std::mem::take(&mut EXECUTOR.lock().unwrap().awake);
+    let cleared = !tasks.is_empty();
+    // Dropping the tasks may wake others, so the lock must not be held here.
+    drop(tasks);
+    cleared
+}
+
 fn executor_run(arc: &Arc<Mutex<Executor>>) {
     loop {
         let mut inner = arc.lock().unwrap();
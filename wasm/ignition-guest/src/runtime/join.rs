@@ -0,0 +1,235 @@
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// An owned permission to await or abort a spawned task.
+///
+/// Dropping a `JoinHandle` detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted before it completed.
+    Aborted,
+}
+
+impl Display for JoinError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            JoinError::Aborted => write!(f, "task was aborted"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    is_finished: bool,
+    is_aborted: bool,
+    join_waker: Option<Waker>,
+    task_waker: Option<Waker>,
+}
+
+/// Wraps `future` so that its output is delivered to the returned [`JoinHandle`].
+pub(crate) fn joinable<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        is_finished: false,
+        is_aborted: false,
+        join_waker: None,
+        task_waker: None,
+    }));
+    let task = Joinable {
+        future: Some(Box::pin(future)),
+        state: Arc::clone(&state),
+    };
+    (task, JoinHandle { state })
+}
+
+impl<T> JoinHandle<T> {
+    /// Requests that the task stop. It is dropped the next time the executor would have polled it,
+    /// and awaiting this handle then yields [`JoinError::Aborted`].
+    ///
+    /// As with dropping any future, aborting a task with a read or write in flight leaves the host
+    /// free to complete that operation into buffers the task no longer owns.
+    pub fn abort(&self) {
+        let task_waker = {
+            let mut state = self.state.lock().unwrap();
+            if state.is_finished {
+                return;
+            }
+            state.is_aborted = true;
+            state.task_waker.take()
+        };
+        if let Some(task_waker) = task_waker {
+            task_waker.wake();
+        }
+    }
+
+    /// Returns true once the task has completed or been dropped after an abort.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().is_finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                assert!(!state.is_finished, "JoinHandle polled after completion");
+                state.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct Joinable<F: Future> {
+    // Taken as soon as the task completes or is aborted, so that anything it holds is released
+    // without waiting for the last waker to go away.
+    future: Option<Pin<Box<F>>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    fn finish(&mut self, output: Result<F::Output, JoinError>) {
+        self.future = None;
+        let join_waker = {
+            let mut state = self.state.lock().unwrap();
+            state.output = Some(output);
+            state.is_finished = true;
+            state.task_waker = None;
+            state.join_waker.take()
+        };
+        if let Some(join_waker) = join_waker {
+            join_waker.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let is_aborted = {
+            let mut state = self.state.lock().unwrap();
+            if state.is_finished {
+                return Poll::Ready(());
+            }
+            state.task_waker = Some(cx.waker().clone());
+            state.is_aborted
+        };
+        if is_aborted {
+            self.finish(Err(JoinError::Aborted));
+            return Poll::Ready(());
+        }
+
+        // The state lock is not held while polling, so the task may abort itself.
+        match self.future.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(output) => {
+                self.finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::{JoinError, JoinHandle};
+    use crate::api::sleep;
+    use crate::runtime::{spawn, spawn_local};
+    use crate::testing::{run, settle};
+
+    /// Sets its flag when dropped, to show that a task's future was released.
+    struct DropFlag(Rc<Cell<bool>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn join_handle_yields_the_output() {
+        run(async {
+            assert_eq!(spawn(async { 7 }).await, Ok(7));
+            let local = Rc::new(5);
+            assert_eq!(spawn_local(async move { *local }).await, Ok(5));
+        });
+    }
+
+    #[test]
+    fn abort_before_the_first_poll() {
+        run(async {
+            let ran = Rc::new(Cell::new(false));
+            let task_ran = Rc::clone(&ran);
+            let handle = spawn_local(async move { task_ran.set(true) });
+            handle.abort();
+            settle().await;
+            assert!(handle.is_finished());
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            assert!(!ran.get());
+        });
+    }
+
+    #[test]
+    fn abort_while_pending() {
+        run(async {
+            let dropped = Rc::new(Cell::new(false));
+            let flag = DropFlag(Rc::clone(&dropped));
+            let handle = spawn_local(async move {
+                let _flag = flag;
+                sleep(Duration::from_secs(1)).await;
+                unreachable!("the task ran after it was aborted");
+            });
+            settle().await;
+            assert!(!handle.is_finished());
+            handle.abort();
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            assert!(dropped.get());
+
+            // Aborting a finished task changes nothing.
+            let handle = spawn_local(async { 1 });
+            settle().await;
+            handle.abort();
+            assert_eq!(handle.await, Ok(1));
+        });
+    }
+
+    #[test]
+    fn task_aborts_itself() {
+        run(async {
+            let slot: Rc<RefCell<Option<JoinHandle<()>>>> = Rc::default();
+            let task_slot = Rc::clone(&slot);
+            let handle = spawn_local(async move {
+                task_slot.borrow().as_ref().unwrap().abort();
+                sleep(Duration::from_secs(1)).await;
+                unreachable!("the task ran after it was aborted");
+            });
+            *slot.borrow_mut() = Some(handle);
+            settle().await;
+
+            let handle = slot.borrow_mut().take().unwrap();
+            assert!(handle.is_finished());
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+        });
+    }
+}
@@ -1,5 +1,9 @@
 pub(crate) mod executor;
+mod join;
 pub(crate) mod reactor;
 mod task;
+mod task_local;
 
-pub use self::executor::spawn;
+pub use self::executor::{spawn, spawn_local};
+pub use self::join::{JoinError, JoinHandle};
+pub use self::task_local::{AccessError, LocalKey, TaskLocalFuture};
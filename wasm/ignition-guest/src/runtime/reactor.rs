@@ -1,10 +1,9 @@
-use std::convert::TryInto;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::Waker;
 
 use lazy_static::lazy_static;
-use slab::Slab;
 
 use crate::api::sys::TaskId;
 
@@ -12,16 +11,19 @@ lazy_static! {
     static ref REACTOR: Arc<Mutex<Reactor>> = Default::default();
 }
 
+/// Means "initialize" rather than naming a task when the host wakes the guest.
+const INIT_TASK_ID: u32 = u32::MAX;
+
 pub fn new_task() -> TaskId {
     REACTOR.lock().unwrap().new_task()
 }
 
 pub fn drop_unused_task(task_id: TaskId) {
-    REACTOR.lock().unwrap().drop_unused_task(task_id);
+    REACTOR.lock().unwrap().drop_task_state(task_id);
 }
 
 pub fn future_dropped(task_id: TaskId) {
-    REACTOR.lock().unwrap().future_dropped(task_id);
+    REACTOR.lock().unwrap().drop_task_state(task_id);
 }
 
 pub fn store_waker(task_id: TaskId, waker: Waker) {
@@ -36,16 +38,30 @@ pub fn get_wake_param(task_id: TaskId) -> Option<usize> {
     REACTOR.lock().unwrap().get_wake_param(task_id)
 }
 
+/// Forgets every pending task, dropping the wakers that were waiting on them. Returns false if
+/// there were none.
+#[cfg(feature = "native-test")]
+pub fn clear() -> bool {
+    let reactor = std::mem::take(&mut *REACTOR.lock().unwrap());
+    let cleared = !reactor.tasks.is_empty();
+    // Dropping a waker may drop its task, which calls back into the reactor.
+    drop(reactor);
+    cleared
+}
+
 #[derive(Default)]
 struct Reactor {
-    tasks: Slab<TaskState>,
+    tasks: HashMap<u32, TaskState>,
+    // A task's state is released as soon as its future is dropped, while the host may still wake
+    // it. IDs count through all 32 bits before one is handed out again, so a late wake finds
+    // nothing instead of a newer task that took the same ID.
+    next_id: u32,
 }
 
 struct TaskState {
     waker: Option<Waker>,
     // None before wakened and Some after.
     wake_param: Option<usize>,
-    future_is_dropped: bool,
 }
 
 impl TaskState {
@@ -53,55 +69,107 @@ impl TaskState {
         Self {
             waker: None,
             wake_param: None,
-            future_is_dropped: false,
         }
     }
-
-    fn ready_to_drop(&self) -> bool {
-        self.wake_param.is_some() && self.future_is_dropped
-    }
 }
 
 impl Reactor {
     fn new_task(&mut self) -> TaskId {
-        TaskId(self.tasks.insert(TaskState::new()).try_into().unwrap())
-    }
-
-    fn drop_unused_task(&mut self, task_id: TaskId) {
-        self.tasks.remove(task_id.0 as usize);
+        assert!(
+            self.tasks.len() < INIT_TASK_ID as usize,
+            "too many pending tasks"
+        );
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if id != INIT_TASK_ID && !self.tasks.contains_key(&id) {
+                self.tasks.insert(id, TaskState::new());
+                return TaskId(id);
+            }
+        }
     }
 
-    fn future_dropped(&mut self, task_id: TaskId) {
-        let task_state = self.tasks.get_mut(task_id.0 as usize).unwrap();
-        task_state.future_is_dropped = true;
-
-        if task_state.ready_to_drop() {
-            self.drop_task_state(task_id);
-        }
+    /// Returns the state for a task, or None if it has since been released.
+    fn task_state_mut(&mut self, task_id: TaskId) -> Option<&mut TaskState> {
+        self.tasks.get_mut(&task_id.0)
     }
 
     fn drop_task_state(&mut self, task_id: TaskId) {
-        self.tasks.remove(task_id.0 as usize);
+        self.tasks.remove(&task_id.0);
     }
 
     fn store_waker(&mut self, task_id: TaskId, waker: Waker) {
-        let task_state = self.tasks.get_mut(task_id.0 as usize).unwrap();
+        let task_state = self.task_state_mut(task_id).unwrap();
         task_state.waker = Some(waker);
     }
 
     fn dispatch_wake(&mut self, task_id: TaskId, param: usize) {
-        let task_state = self.tasks.get_mut(task_id.0 as usize).unwrap();
-        task_state.wake_param = Some(param);
-        if let Some(waker) = task_state.waker.take() {
-            waker.wake();
+        // The future may have been dropped (for example, by aborting its task) before the host
+        // delivered this wake. There is nobody left to tell.
+        if let Some(task_state) = self.task_state_mut(task_id) {
+            task_state.wake_param = Some(param);
+            if let Some(waker) = task_state.waker.take() {
+                waker.wake();
+            }
         }
+    }
 
-        if task_state.ready_to_drop() {
-            self.drop_task_state(task_id);
+    fn get_wake_param(&mut self, task_id: TaskId) -> Option<usize> {
+        self.task_state_mut(task_id).unwrap().wake_param
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    use super::Reactor;
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
         }
     }
 
-    fn get_wake_param(&self, task_id: TaskId) -> Option<usize> {
-        self.tasks.get(task_id.0 as usize).unwrap().wake_param
+    #[test]
+    fn ignores_wakes_for_aborted_tasks() {
+        let mut reactor = Reactor::default();
+        let aborted = reactor.new_task();
+        let aborted_flag = Arc::new(Flag::default());
+        reactor.store_waker(aborted, Waker::from(Arc::clone(&aborted_flag)));
+        reactor.drop_task_state(aborted);
+
+        // Enough reuses for an 8-bit generation to come back around.
+        for _ in 0..u8::MAX {
+            let task_id = reactor.new_task();
+            reactor.drop_task_state(task_id);
+        }
+
+        let task_id = reactor.new_task();
+        let flag = Arc::new(Flag::default());
+        reactor.store_waker(task_id, Waker::from(Arc::clone(&flag)));
+
+        reactor.dispatch_wake(aborted, 1);
+        assert!(!aborted_flag.0.load(Ordering::SeqCst));
+        assert!(!flag.0.load(Ordering::SeqCst));
+        assert_eq!(reactor.get_wake_param(task_id), None);
+
+        reactor.dispatch_wake(task_id, 2);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert_eq!(reactor.get_wake_param(task_id), Some(2));
+    }
+
+    #[test]
+    fn skips_the_init_id_and_ids_in_use() {
+        let mut reactor = Reactor::default();
+        let pending = reactor.new_task();
+        reactor.next_id = u32::MAX - 1;
+        assert_eq!(reactor.new_task().0, u32::MAX - 1);
+        assert_eq!(reactor.new_task().0, pending.0 + 1);
     }
 }
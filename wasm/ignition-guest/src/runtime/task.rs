@@ -13,7 +13,29 @@ impl Task {
         }
     }
 
+    /// Creates a task that must only ever be polled by the instance that spawned it.
+    pub fn new_local(future: impl Future<Output = ()> + 'static) -> Self {
+        Self {
+            future: Box::pin(AssertSend(future)),
+        }
+    }
+
     pub fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
+
+struct AssertSend<F>(F);
+
+// SAFETY: A process runs as a single instance, so every task is polled by the instance that
+// spawned it.
+unsafe impl<F> Send for AssertSend<F> {}
+
+impl<F: Future> Future for AssertSend<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // SAFETY: The inner future is never moved out of its pinned wrapper.
+        unsafe { self.map_unchecked_mut(|this| &mut this.0) }.poll(cx)
+    }
+}
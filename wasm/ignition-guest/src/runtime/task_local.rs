@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::mem::swap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Declares task-local keys of type [`LocalKey`].
+///
+/// A task-local value is set for the duration of a future with [`LocalKey::scope`], and is visible
+/// to everything that future runs, but not to other tasks interleaved with it.
+///
+/// ```ignore
+/// ignition_guest::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// REQUEST_ID
+///     .scope(42, async {
+///         REQUEST_ID.with(|id| log(&format!("handling request {}", id)));
+///     })
+///     .await;
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+        $crate::task_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::runtime::LocalKey<$t> = {
+            ::std::thread_local! {
+                static __KEY: ::std::cell::RefCell<::std::option::Option<$t>> =
+                    ::std::cell::RefCell::new(::std::option::Option::None);
+            }
+            $crate::runtime::LocalKey { inner: __KEY }
+        };
+    };
+}
+
+/// A key for task-local data. Declare keys with [`task_local!`](crate::task_local).
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub inner: std::thread::LocalKey<RefCell<Option<T>>>,
+}
+
+/// Returned when a task-local value is accessed outside of its scope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessError;
+
+impl Display for AccessError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "task-local value accessed outside of its scope")
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets the value of this key while `future` runs.
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key: self,
+            slot: Some(value),
+            future: Box::pin(future),
+        }
+    }
+
+    /// Calls `f` with the current value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a [`scope`](Self::scope) for this key.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f).unwrap()
+    }
+
+    /// Calls `f` with the current value, if there is one.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        self.inner.with(|cell| match cell.borrow().as_ref() {
+            Some(value) => Ok(f(value)),
+            None => Err(AccessError),
+        })
+    }
+
+    fn swap(&'static self, slot: &mut Option<T>) {
+        self.inner.with(|cell| swap(&mut *cell.borrow_mut(), slot));
+    }
+}
+
+impl<T: Clone + 'static> LocalKey<T> {
+    /// Returns a clone of the current value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a [`scope`](Self::scope) for this key.
+    pub fn get(&'static self) -> T {
+        self.with(Clone::clone)
+    }
+}
+
+/// A future that sets a task-local value each time it is polled. See [`LocalKey::scope`].
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+    // Holds the value between polls, and the enclosing scope's value (if any) during them.
+    slot: Option<T>,
+    future: Pin<Box<F>>,
+}
+
+// Neither the value nor the boxed future is ever pinned through this struct.
+impl<T: 'static, F> Unpin for TaskLocalFuture<T, F> {}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        let this = &mut *self;
+        this.key.swap(&mut this.slot);
+        let result = this.future.as_mut().poll(cx);
+        this.key.swap(&mut this.slot);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::AccessError;
+    use crate::api::sleep;
+    use crate::runtime::spawn_local;
+    use crate::testing::run;
+
+    crate::task_local! {
+        static VALUE: u32;
+    }
+
+    #[test]
+    fn scopes_nest() {
+        run(async {
+            assert_eq!(VALUE.try_with(|_| ()), Err(AccessError));
+            VALUE
+                .scope(1, async {
+                    assert_eq!(VALUE.get(), 1);
+                    VALUE.scope(2, async { assert_eq!(VALUE.get(), 2) }).await;
+                    assert_eq!(VALUE.get(), 1);
+                })
+                .await;
+            assert_eq!(VALUE.try_with(|_| ()), Err(AccessError));
+        });
+    }
+
+    #[test]
+    fn interleaved_tasks_see_their_own_values() {
+        run(async {
+            // The inner scope is suspended while the other task runs, and must hand the outer
+            // value back each time.
+            let nested = spawn_local(VALUE.scope(1, async {
+                VALUE
+                    .scope(2, async {
+                        for _ in 0..3 {
+                            sleep(Duration::from_secs(2)).await;
+                            assert_eq!(VALUE.get(), 2);
+                        }
+                    })
+                    .await;
+                assert_eq!(VALUE.get(), 1);
+            }));
+            let other = spawn_local(VALUE.scope(3, async {
+                for _ in 0..3 {
+                    sleep(Duration::from_secs(3)).await;
+                    assert_eq!(VALUE.get(), 3);
+                }
+            }));
+            sleep(Duration::from_secs(1)).await;
+            assert_eq!(VALUE.try_with(|_| ()), Err(AccessError));
+
+            nested.await.unwrap();
+            other.await.unwrap();
+            assert_eq!(VALUE.try_with(|_| ()), Err(AccessError));
+        });
+    }
+}
@@ -0,0 +1,267 @@
+//! A multi-producer, multi-consumer channel where every receiver sees every value.
+//!
+//! The channel keeps the last `capacity` values. A receiver that falls further behind than that
+//! gets [`RecvError::Lagged`] with the number of values it missed, then resumes from the oldest one
+//! still retained.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll, Waker};
+
+use slab::Slab;
+
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be positive");
+    let shared = Arc::new(StdMutex::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity,
+        senders: 1,
+        receivers: Slab::new(),
+    }));
+    let sender = Sender { shared };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+struct Shared<T> {
+    buffer: VecDeque<T>,
+    /// Position of `buffer[0]` in the sequence of all values ever sent.
+    head: u64,
+    capacity: usize,
+    senders: usize,
+    /// A waker slot per live receiver.
+    receivers: Slab<Option<Waker>>,
+}
+
+impl<T> Shared<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+
+    fn take_wakers(&mut self) -> Vec<Waker> {
+        self.receivers
+            .iter_mut()
+            .filter_map(|(_, waker)| waker.take())
+            .collect()
+    }
+}
+
+/// Returned when there are no receivers. Holds the value that could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> Display for SendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and this receiver has seen every value.
+    Closed,
+    /// This receiver skipped over the given number of values that were no longer retained.
+    Lagged(u64),
+}
+
+impl Display for RecvError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(n) => write!(f, "receiver lagged by {} values", n),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+pub struct Sender<T> {
+    shared: Arc<StdMutex<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` to every current receiver and returns how many there are.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (receivers, wakers) = {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.receivers.is_empty() {
+                return Err(SendError(value));
+            }
+            if shared.buffer.len() == shared.capacity {
+                shared.buffer.pop_front();
+                shared.head += 1;
+            }
+            shared.buffer.push_back(value);
+            (shared.receivers.len(), shared.take_wakers())
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        Ok(receivers)
+    }
+
+    /// Creates a receiver that will see values sent after this call.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut shared = self.shared.lock().unwrap();
+        let key = shared.receivers.insert(None);
+        Receiver {
+            shared: self.shared.clone(),
+            key,
+            next: shared.tail(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().unwrap().receivers.len()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.senders -= 1;
+            if shared.senders > 0 {
+                return;
+            }
+            shared.take_wakers()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<StdMutex<Shared<T>>>,
+    key: usize,
+    /// Position of the next value this receiver will return.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(n)) => Poll::Ready(Err(RecvError::Lagged(n))),
+            Err(TryRecvError::Empty) => {
+                let mut shared = self.shared.lock().unwrap();
+                // A value may have arrived since try_recv() released the lock.
+                if self.next < shared.tail() || shared.senders == 0 {
+                    cx.waker().wake_by_ref();
+                } else {
+                    shared.receivers[self.key] = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = self.shared.lock().unwrap();
+        if self.next < shared.head {
+            let missed = shared.head - self.next;
+            self.next = shared.head;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        if self.next < shared.tail() {
+            let value = shared.buffer[(self.next - shared.head) as usize].clone();
+            self.next += 1;
+            Ok(value)
+        } else if shared.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receivers.remove(self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, RecvError, SendError};
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn wakes_every_receiver() {
+        run(async {
+            let (tx, rx) = channel(4);
+            let receivers: Vec<_> = vec![rx, tx.subscribe()]
+                .into_iter()
+                .map(|mut rx| {
+                    spawn_local(async move {
+                        let mut values = Vec::new();
+                        while let Ok(value) = rx.recv().await {
+                            values.push(value);
+                        }
+                        values
+                    })
+                })
+                .collect();
+            settle().await;
+
+            assert_eq!(tx.send(0), Ok(2));
+            settle().await;
+            assert_eq!(tx.send(1), Ok(2));
+            drop(tx);
+            for receiver in receivers {
+                assert_eq!(receiver.await.unwrap(), [0, 1]);
+            }
+        });
+    }
+
+    #[test]
+    fn lagging_receivers_skip_ahead() {
+        run(async {
+            let (tx, mut rx) = channel(2);
+            for i in 0..5 {
+                tx.send(i).unwrap();
+            }
+            assert_eq!(rx.recv().await, Err(RecvError::Lagged(3)));
+            assert_eq!(rx.recv().await, Ok(3));
+            assert_eq!(rx.recv().await, Ok(4));
+
+            // A receiver aborted while waiting no longer counts.
+            let waiting = spawn_local(async move { rx.recv().await });
+            settle().await;
+            waiting.abort();
+            assert!(waiting.await.is_err());
+            assert_eq!(tx.receiver_count(), 0);
+            assert_eq!(tx.send(5), Err(SendError(5)));
+        });
+    }
+}
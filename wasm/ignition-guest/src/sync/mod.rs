@@ -0,0 +1,17 @@
+//! Synchronization primitives for tasks.
+//!
+//! These only rely on the standard [`Waker`](std::task::Waker) contract, so they work the same on
+//! the guest executor and under any other executor, such as one driving native tests.
+
+pub mod broadcast;
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+
+pub use self::mutex::{Mutex, MutexGuard, WouldBlock};
+pub use self::notify::{Notified, Notify};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
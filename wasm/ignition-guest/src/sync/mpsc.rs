@@ -0,0 +1,377 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! A bounded channel makes senders wait while it holds `capacity` undelivered values. An unbounded
+//! channel never makes senders wait. Either way the receiver sees `None` once every sender is gone
+//! and the buffered values have been drained.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::sync::semaphore::{Semaphore, TryAcquireError};
+
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be positive");
+    let chan = Chan::new(Some(Semaphore::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+struct Chan<T> {
+    state: StdMutex<ChanState<T>>,
+    /// Free slots in a bounded channel; None for an unbounded one.
+    capacity: Option<Semaphore>,
+}
+
+struct ChanState<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_closed: bool,
+    receiver_waker: Option<Waker>,
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<Semaphore>) -> Arc<Self> {
+        Arc::new(Self {
+            state: StdMutex::new(ChanState {
+                queue: VecDeque::new(),
+                senders: 1,
+                receiver_closed: false,
+                receiver_waker: None,
+            }),
+            capacity,
+        })
+    }
+
+    fn push(&self, value: T) -> Result<(), SendError<T>> {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            if state.receiver_closed {
+                return Err(SendError(value));
+            }
+            state.queue.push_back(value);
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().unwrap().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.senders -= 1;
+            if state.senders > 0 {
+                return;
+            }
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().receiver_closed
+    }
+}
+
+/// Returned when the receiver has closed the channel. Holds the value that could not be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> Display for SendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+impl<T> Display for TrySendError<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl<T> std::error::Error for TrySendError<T> {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for space if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let capacity = self.chan.capacity.as_ref().unwrap();
+        match capacity.acquire().await {
+            // The receiver returns the slot when it takes the value.
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        self.chan.push(value)
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let capacity = self.chan.capacity.as_ref().unwrap();
+        match capacity.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+        }
+        self.chan
+            .push(value)
+            .map_err(|SendError(value)| TrySendError::Closed(value))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Self {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.push(value)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Self {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, or None once all senders are gone and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.chan.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(value) => {
+                drop(state);
+                self.release_slot();
+                Poll::Ready(Some(value))
+            }
+            None if state.senders == 0 => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.chan.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(value) => {
+                drop(state);
+                self.release_slot();
+                Ok(value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Stops accepting new values. Values already sent can still be received.
+    pub fn close(&mut self) {
+        self.chan.state.lock().unwrap().receiver_closed = true;
+        if let Some(capacity) = &self.chan.capacity {
+            capacity.close();
+        }
+    }
+
+    fn release_slot(&self) {
+        if let Some(capacity) = &self.chan.capacity {
+            capacity.add_permits(1);
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // Drop undelivered values now rather than whenever the last sender goes away.
+        let queue = std::mem::take(&mut self.chan.state.lock().unwrap().queue);
+        drop(queue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{channel, unbounded_channel, SendError, TryRecvError, TrySendError};
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn full_channels_make_senders_wait_their_turn() {
+        run(async {
+            let (tx, mut rx) = channel(1);
+            let tx = Rc::new(tx);
+            tx.send(0).await.unwrap();
+            assert_eq!(tx.try_send(9), Err(TrySendError::Full(9)));
+
+            let senders: Vec<_> = (1..4)
+                .map(|i| {
+                    let tx = Rc::clone(&tx);
+                    spawn_local(async move { tx.send(i).await })
+                })
+                .collect();
+            settle().await;
+            assert!(senders.iter().all(|sender| !sender.is_finished()));
+
+            for expected in 0..4 {
+                assert_eq!(rx.recv().await, Some(expected));
+            }
+            for sender in senders {
+                sender.await.unwrap().unwrap();
+            }
+            drop(tx);
+            assert_eq!(rx.recv().await, None);
+        });
+    }
+
+    #[test]
+    fn aborted_senders_do_not_take_a_slot() {
+        run(async {
+            let (tx, mut rx) = channel(1);
+            let tx = Rc::new(tx);
+            tx.send(0).await.unwrap();
+            let blocked = {
+                let tx = Rc::clone(&tx);
+                spawn_local(async move { tx.send(1).await })
+            };
+            settle().await;
+
+            blocked.abort();
+            assert!(blocked.await.is_err());
+            assert_eq!(rx.recv().await, Some(0));
+            tx.try_send(2).unwrap();
+            assert_eq!(rx.try_recv(), Ok(2));
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        });
+    }
+
+    #[test]
+    fn dropping_the_receiver_fails_waiting_senders() {
+        run(async {
+            let (tx, rx) = channel(1);
+            let tx = Rc::new(tx);
+            tx.send(0).await.unwrap();
+            let blocked = {
+                let tx = Rc::clone(&tx);
+                spawn_local(async move { tx.send(1).await })
+            };
+            settle().await;
+
+            drop(rx);
+            assert_eq!(blocked.await.unwrap(), Err(SendError(1)));
+            assert!(tx.is_closed());
+        });
+    }
+
+    #[test]
+    fn receiving_waits_for_unbounded_senders() {
+        run(async {
+            let (tx, mut rx) = unbounded_channel();
+            let receiver = spawn_local(async move {
+                let mut values = Vec::new();
+                while let Some(value) = rx.recv().await {
+                    values.push(value);
+                }
+                values
+            });
+            settle().await;
+
+            let other = tx.clone();
+            tx.send(0).unwrap();
+            settle().await;
+            other.send(1).unwrap();
+            drop(tx);
+            drop(other);
+            assert_eq!(receiver.await.unwrap(), [0, 1]);
+        });
+    }
+}
@@ -0,0 +1,151 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+
+use crate::sync::semaphore::{Semaphore, SemaphorePermit};
+
+/// An async mutual exclusion lock whose guard may be held across `.await`.
+///
+/// Tasks acquire the lock in the order they started waiting for it.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: The semaphore ensures at most one guard exists at a time, so `T` is only ever accessed
+// from one task at a time.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// Returned by [`Mutex::try_lock`] when the lock is held or contended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WouldBlock;
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        // The semaphore is never closed.
+        let permit = self.semaphore.acquire().await.unwrap();
+        MutexGuard {
+            mutex: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, WouldBlock> {
+        let permit = self.semaphore.try_acquire().map_err(|_| WouldBlock)?;
+        Ok(MutexGuard {
+            mutex: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+// SAFETY: A guard only hands out references to `T`, so it is as shareable as `&T`.
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: This guard holds the only permit.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: This guard holds the only permit.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{Mutex, WouldBlock};
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn serves_waiters_in_order() {
+        run(async {
+            let mutex = Rc::new(Mutex::new(Vec::new()));
+            let guard = mutex.lock().await;
+            let tasks: Vec<_> = (0..3)
+                .map(|i| {
+                    let mutex = Rc::clone(&mutex);
+                    spawn_local(async move { mutex.lock().await.push(i) })
+                })
+                .collect();
+            settle().await;
+            assert!(mutex.try_lock().is_err());
+
+            drop(guard);
+            // The lock has been handed to the first waiter, even though it hasn't run yet.
+            assert!(matches!(mutex.try_lock(), Err(WouldBlock)));
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(*mutex.lock().await, [0, 1, 2]);
+        });
+    }
+
+    #[test]
+    fn aborted_waiters_give_up_their_turn() {
+        run(async {
+            let mutex = Rc::new(Mutex::new(0));
+            let guard = mutex.lock().await;
+            let spawn_waiter = |value| {
+                let mutex = Rc::clone(&mutex);
+                spawn_local(async move { *mutex.lock().await = value })
+            };
+            let first = spawn_waiter(1);
+            let second = spawn_waiter(2);
+            settle().await;
+
+            // Aborted while waiting.
+            first.abort();
+            settle().await;
+            drop(guard);
+            second.await.unwrap();
+            assert_eq!(*mutex.lock().await, 2);
+
+            // Aborted after the lock was handed over, but before it ran.
+            let guard = mutex.lock().await;
+            let third = spawn_waiter(3);
+            settle().await;
+            drop(guard);
+            third.abort();
+            assert!(third.await.is_err());
+            assert_eq!(*mutex.try_lock().unwrap(), 2);
+        });
+    }
+}
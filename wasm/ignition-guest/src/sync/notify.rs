@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex as StdMutex;
+use std::task::{Context, Poll, Waker};
+
+use slab::Slab;
+
+/// Wakes one or all tasks waiting for an event, without carrying any data.
+///
+/// A call to [`notify_one`](Notify::notify_one) with nobody waiting is remembered, so the next
+/// [`notified`](Notify::notified) completes immediately. At most one such notification is stored.
+pub struct Notify {
+    state: StdMutex<NotifyState>,
+}
+
+#[derive(Default)]
+struct NotifyState {
+    stored: bool,
+    waiters: Slab<NotifyWaiter>,
+    queue: VecDeque<usize>,
+}
+
+struct NotifyWaiter {
+    notification: Option<Notification>,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Self {
+            state: Default::default(),
+        }
+    }
+
+    /// Wakes the longest-waiting task, or stores a notification if there is none.
+    pub fn notify_one(&self) {
+        let waker = self.state.lock().unwrap().notify_one();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every task currently waiting. Nothing is stored for later callers.
+    pub fn notify_waiters(&self) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock().unwrap();
+            let NotifyState { waiters, queue, .. } = &mut *state;
+            queue
+                .drain(..)
+                .filter_map(|key| {
+                    let waiter = &mut waiters[key];
+                    waiter.notification = Some(Notification::All);
+                    waiter.waker.take()
+                })
+                .collect()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Waits for a notification. The waiter is registered when the future is first polled.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            key: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NotifyState {
+    fn notify_one(&mut self) -> Option<Waker> {
+        match self.queue.pop_front() {
+            Some(key) => {
+                let waiter = &mut self.waiters[key];
+                waiter.notification = Some(Notification::One);
+                waiter.waker.take()
+            }
+            None => {
+                self.stored = true;
+                None
+            }
+        }
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    key: Option<usize>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock().unwrap();
+        match self.key {
+            None => {
+                if state.stored {
+                    state.stored = false;
+                    return Poll::Ready(());
+                }
+                let key = state.waiters.insert(NotifyWaiter {
+                    notification: None,
+                    waker: Some(cx.waker().clone()),
+                });
+                state.queue.push_back(key);
+                drop(state);
+                self.key = Some(key);
+                Poll::Pending
+            }
+            Some(key) => {
+                if state.waiters[key].notification.is_some() {
+                    state.waiters.remove(key);
+                    drop(state);
+                    self.key = None;
+                    Poll::Ready(())
+                } else {
+                    state.waiters[key].waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let waker = {
+            let mut state = self.notify.state.lock().unwrap();
+            let waiter = state.waiters.remove(key);
+            match waiter.notification {
+                // A notify_one() aimed at this waiter must not be lost; pass it along.
+                Some(Notification::One) => state.notify_one(),
+                Some(Notification::All) => None,
+                None => {
+                    state.queue.retain(|&queued| queued != key);
+                    None
+                }
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Notify;
+    use crate::runtime::{spawn_local, JoinHandle};
+    use crate::testing::{run, settle};
+
+    fn spawn_waiter(notify: &Rc<Notify>, order: &Rc<RefCell<Vec<u32>>>, i: u32) -> JoinHandle<()> {
+        let notify = Rc::clone(notify);
+        let order = Rc::clone(order);
+        spawn_local(async move {
+            notify.notified().await;
+            order.borrow_mut().push(i);
+        })
+    }
+
+    #[test]
+    fn notify_one_wakes_the_longest_waiting() {
+        run(async {
+            let notify = Rc::new(Notify::new());
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let waiters: Vec<_> = (0..3).map(|i| spawn_waiter(&notify, &order, i)).collect();
+            settle().await;
+
+            for expected in [vec![0], vec![0, 1], vec![0, 1, 2]] {
+                notify.notify_one();
+                settle().await;
+                assert_eq!(*order.borrow(), expected);
+            }
+            for waiter in waiters {
+                waiter.await.unwrap();
+            }
+
+            // With nobody waiting, one notification is kept for the next waiter.
+            notify.notify_one();
+            notify.notify_one();
+            notify.notified().await;
+            let waiter = spawn_waiter(&notify, &order, 3);
+            settle().await;
+            assert!(!waiter.is_finished());
+        });
+    }
+
+    #[test]
+    fn notify_waiters_wakes_everyone_and_stores_nothing() {
+        run(async {
+            let notify = Rc::new(Notify::new());
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let waiters: Vec<_> = (0..2).map(|i| spawn_waiter(&notify, &order, i)).collect();
+            settle().await;
+
+            notify.notify_waiters();
+            for waiter in waiters {
+                waiter.await.unwrap();
+            }
+            assert_eq!(*order.borrow(), [0, 1]);
+
+            let late = spawn_waiter(&notify, &order, 2);
+            settle().await;
+            assert!(!late.is_finished());
+        });
+    }
+
+    #[test]
+    fn passes_on_a_notification_its_waiter_dropped() {
+        run(async {
+            let notify = Rc::new(Notify::new());
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let first = spawn_waiter(&notify, &order, 0);
+            let second = spawn_waiter(&notify, &order, 1);
+            settle().await;
+
+            // The first waiter is notified but aborted before it sees it.
+            notify.notify_one();
+            first.abort();
+            assert!(first.await.is_err());
+            second.await.unwrap();
+            assert_eq!(*order.borrow(), [1]);
+
+            // A waiter dropped before being notified leaves nothing behind.
+            let third = spawn_waiter(&notify, &order, 2);
+            settle().await;
+            third.abort();
+            assert!(third.await.is_err());
+            notify.notify_one();
+            notify.notified().await;
+        });
+    }
+}
@@ -0,0 +1,162 @@
+//! A channel for sending a single value between tasks.
+
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll, Waker};
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(StdMutex::new(Shared {
+        value: None,
+        sender_dropped: false,
+        receiver_dropped: false,
+        receiver_waker: None,
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    value: Option<T>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+    receiver_waker: Option<Waker>,
+}
+
+/// Returned by the receiver when the sender was dropped without sending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+impl Display for RecvError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "sender dropped")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+pub struct Sender<T> {
+    shared: Arc<StdMutex<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, or hands it back if the receiver has been dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.receiver_dropped {
+                return Err(value);
+            }
+            shared.value = Some(value);
+            shared.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().receiver_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.sender_dropped = true;
+            shared.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the sent value.
+pub struct Receiver<T> {
+    shared: Arc<StdMutex<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.value.take() {
+            Some(value) => Ok(value),
+            None if shared.sender_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(value) = shared.value.take() {
+            Poll::Ready(Ok(value))
+        } else if shared.sender_dropped {
+            Poll::Ready(Err(RecvError))
+        } else {
+            shared.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receiver_dropped = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, RecvError, TryRecvError};
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn wakes_the_receiver_on_send_or_drop() {
+        run(async {
+            let (tx, rx) = channel();
+            let receiver = spawn_local(rx);
+            settle().await;
+            tx.send(1).unwrap();
+            assert_eq!(receiver.await.unwrap(), Ok(1));
+
+            let (tx, mut rx) = channel::<u32>();
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+            let receiver = spawn_local(rx);
+            settle().await;
+            drop(tx);
+            assert_eq!(receiver.await.unwrap(), Err(RecvError));
+        });
+    }
+
+    #[test]
+    fn hands_back_values_nobody_will_receive() {
+        run(async {
+            let (tx, rx) = channel();
+            let receiver = spawn_local(rx);
+            settle().await;
+            receiver.abort();
+            assert!(receiver.await.is_err());
+            assert!(tx.is_closed());
+            assert_eq!(tx.send(1), Err(1));
+        });
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+
+use crate::sync::mutex::WouldBlock;
+use crate::sync::semaphore::{Semaphore, SemaphorePermit};
+
+/// The number of permits a writer takes, which is also the maximum number of concurrent readers.
+const MAX_READERS: usize = u32::MAX as usize >> 3;
+
+/// An async reader-writer lock whose guards may be held across `.await`.
+///
+/// Readers and writers are served in the order they started waiting, so a steady stream of readers
+/// cannot starve a writer.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: The semaphore ensures that either one writer or any number of readers hold guards.
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        // The semaphore is never closed.
+        let permit = self.semaphore.acquire().await.unwrap();
+        RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        // The semaphore is never closed.
+        let permit = self.semaphore.acquire_many(MAX_READERS).await.unwrap();
+        RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        }
+    }
+
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>, WouldBlock> {
+        let permit = self.semaphore.try_acquire().map_err(|_| WouldBlock)?;
+        Ok(RwLockReadGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, WouldBlock> {
+        let permit = self
+            .semaphore
+            .try_acquire_many(MAX_READERS)
+            .map_err(|_| WouldBlock)?;
+        Ok(RwLockWriteGuard {
+            lock: self,
+            _permit: permit,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+// SAFETY: A read guard only hands out shared references.
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: No writer can hold a guard while this one exists.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+// SAFETY: A write guard only hands out references to `T`, so it is as shareable as `&T`.
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: This guard holds every permit.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: This guard holds every permit.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::RwLock;
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn readers_queue_behind_a_waiting_writer() {
+        run(async {
+            let lock = Rc::new(RwLock::new(0));
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let reader = lock.read().await;
+            assert!(lock.try_read().is_ok());
+
+            let writer = {
+                let lock = Rc::clone(&lock);
+                let order = Rc::clone(&order);
+                spawn_local(async move {
+                    *lock.write().await += 1;
+                    order.borrow_mut().push("write");
+                })
+            };
+            settle().await;
+            let late_reader = {
+                let lock = Rc::clone(&lock);
+                let order = Rc::clone(&order);
+                spawn_local(async move {
+                    let value = *lock.read().await;
+                    order.borrow_mut().push("read");
+                    value
+                })
+            };
+            settle().await;
+            assert!(lock.try_read().is_err());
+            assert!(order.borrow().is_empty());
+
+            drop(reader);
+            writer.await.unwrap();
+            assert_eq!(late_reader.await.unwrap(), 1);
+            assert_eq!(*order.borrow(), ["write", "read"]);
+        });
+    }
+
+    #[test]
+    fn aborting_a_waiting_writer_admits_readers() {
+        run(async {
+            let lock = Rc::new(RwLock::new(0));
+            let reader = lock.read().await;
+            let writer = {
+                let lock = Rc::clone(&lock);
+                spawn_local(async move { *lock.write().await = 1 })
+            };
+            settle().await;
+            assert!(lock.try_read().is_err());
+
+            writer.abort();
+            assert!(writer.await.is_err());
+            assert_eq!(*lock.try_read().unwrap(), 0);
+            drop(reader);
+            assert!(lock.try_write().is_ok());
+        });
+    }
+}
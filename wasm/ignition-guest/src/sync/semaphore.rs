@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex as StdMutex;
+use std::task::{Context, Poll, Waker};
+
+use slab::Slab;
+
+/// An async counting semaphore.
+///
+/// Waiters are served in the order they first polled, and a waiter asking for many permits blocks
+/// those behind it until it is satisfied, so large requests are never starved.
+pub struct Semaphore {
+    state: StdMutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    closed: bool,
+    waiters: Slab<Waiter>,
+    /// Keys into `waiters` that have not yet been granted permits, in arrival order.
+    queue: VecDeque<usize>,
+}
+
+struct Waiter {
+    needed: usize,
+    granted: bool,
+    waker: Option<Waker>,
+}
+
+/// Returned when acquiring from a closed semaphore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcquireError;
+
+impl Display for AcquireError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "semaphore closed")
+    }
+}
+
+impl std::error::Error for AcquireError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+impl Display for TryAcquireError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TryAcquireError::Closed => write!(f, "semaphore closed"),
+            TryAcquireError::NoPermits => write!(f, "no permits available"),
+        }
+    }
+}
+
+impl std::error::Error for TryAcquireError {}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: StdMutex::new(SemaphoreState {
+                permits,
+                closed: false,
+                waiters: Slab::new(),
+                queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.permits += n;
+            state.grant_waiters()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Fails every pending and future acquisition. Permits already held are unaffected.
+    pub fn close(&self) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            state.queue.clear();
+            state
+                .waiters
+                .iter_mut()
+                .filter_map(|(_, waiter)| waiter.waker.take())
+                .collect()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            key: None,
+        }
+        .await?;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            Err(TryAcquireError::Closed)
+        } else if state.queue.is_empty() && state.permits >= n {
+            state.permits -= n;
+            Ok(SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            })
+        } else {
+            Err(TryAcquireError::NoPermits)
+        }
+    }
+}
+
+impl SemaphoreState {
+    /// Hands out permits to waiters at the front of the queue. Returns the wakers to call once the
+    /// lock is released.
+    fn grant_waiters(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while let Some(&key) = self.queue.front() {
+            let waiter = &mut self.waiters[key];
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.granted = true;
+            wakers.extend(waiter.waker.take());
+            self.queue.pop_front();
+        }
+        wakers
+    }
+}
+
+struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    key: Option<usize>,
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.semaphore.state.lock().unwrap();
+        match self.key {
+            None => {
+                if state.closed {
+                    return Poll::Ready(Err(AcquireError));
+                }
+                if state.queue.is_empty() && state.permits >= self.needed {
+                    state.permits -= self.needed;
+                    return Poll::Ready(Ok(()));
+                }
+                let key = state.waiters.insert(Waiter {
+                    needed: self.needed,
+                    granted: false,
+                    waker: Some(cx.waker().clone()),
+                });
+                state.queue.push_back(key);
+                drop(state);
+                self.key = Some(key);
+                Poll::Pending
+            }
+            Some(key) => {
+                if state.waiters[key].granted {
+                    state.waiters.remove(key);
+                    drop(state);
+                    self.key = None;
+                    Poll::Ready(Ok(()))
+                } else if state.closed {
+                    state.waiters.remove(key);
+                    drop(state);
+                    self.key = None;
+                    Poll::Ready(Err(AcquireError))
+                } else {
+                    state.waiters[key].waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let wakers = {
+            let mut state = self.semaphore.state.lock().unwrap();
+            let waiter = state.waiters.remove(key);
+            if waiter.granted {
+                // Permits were handed over but never observed; give them back.
+                state.permits += waiter.needed;
+            } else {
+                state.queue.retain(|&queued| queued != key);
+            }
+            // Either way, whoever is now at the front may be satisfiable.
+            state.grant_waiters()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Permits acquired from a [`Semaphore`], returned when dropped.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits out of the semaphore for good.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{AcquireError, Semaphore, TryAcquireError};
+    use crate::runtime::spawn_local;
+    use crate::testing::{run, settle};
+
+    #[test]
+    fn large_requests_hold_back_later_ones() {
+        run(async {
+            let semaphore = Rc::new(Semaphore::new(2));
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let held = semaphore.acquire().await.unwrap();
+            let spawn_waiter = |name, n| {
+                let semaphore = Rc::clone(&semaphore);
+                let order = Rc::clone(&order);
+                spawn_local(async move {
+                    let _permit = semaphore.acquire_many(n).await.unwrap();
+                    order.borrow_mut().push(name);
+                    settle().await;
+                })
+            };
+            let large = spawn_waiter("large", 2);
+            let small = spawn_waiter("small", 1);
+            settle().await;
+
+            // A permit is free, but the small request is queued behind the large one.
+            assert_eq!(semaphore.available_permits(), 1);
+            assert!(order.borrow().is_empty());
+            assert_eq!(
+                semaphore.try_acquire().err(),
+                Some(TryAcquireError::NoPermits)
+            );
+
+            drop(held);
+            large.await.unwrap();
+            small.await.unwrap();
+            assert_eq!(*order.borrow(), ["large", "small"]);
+            assert_eq!(semaphore.available_permits(), 2);
+        });
+    }
+
+    #[test]
+    fn aborting_the_front_waiter_lets_the_next_through() {
+        run(async {
+            let semaphore = Rc::new(Semaphore::new(2));
+            let held = semaphore.acquire().await.unwrap();
+            let large = {
+                let semaphore = Rc::clone(&semaphore);
+                spawn_local(async move { semaphore.acquire_many(2).await.map(drop) })
+            };
+            let small = {
+                let semaphore = Rc::clone(&semaphore);
+                spawn_local(async move { semaphore.acquire().await.map(|p| p.forget()) })
+            };
+            settle().await;
+            assert!(!small.is_finished());
+
+            large.abort();
+            assert!(large.await.is_err());
+            small.await.unwrap().unwrap();
+            drop(held);
+            assert_eq!(semaphore.available_permits(), 1);
+        });
+    }
+
+    #[test]
+    fn closing_fails_waiters() {
+        run(async {
+            let semaphore = Rc::new(Semaphore::new(0));
+            let waiter = {
+                let semaphore = Rc::clone(&semaphore);
+                spawn_local(async move { semaphore.acquire().await.map(drop) })
+            };
+            settle().await;
+
+            semaphore.close();
+            assert_eq!(waiter.await.unwrap(), Err(AcquireError));
+            assert_eq!(semaphore.try_acquire().err(), Some(TryAcquireError::Closed));
+            assert!(semaphore.acquire().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,735 @@
+//! The fake host behind the `native-test` feature. Each function here stands in for the import of
+//! the same name in [`crate::api::sys`] and follows the real host's semantics, except that there is
+//! only ever one process, time is virtual, no filesystem is mounted, and the only native services
+//! are those a test provides with [`serve_natively`].
+//!
+//! Bad handles and other misuse that would trap in the real host panic instead.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::ffi::c_void;
+use std::ptr::copy_nonoverlapping;
+use std::slice;
+use std::str;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::task::Poll;
+
+use lazy_static::lazy_static;
+use slab::Slab;
+
+use ignition_abi::{
+    IoHandle, RpcClientHandle, RpcMethodMetadata, RpcServerGetRequestResult, RpcServerHandle,
+    RpcServerParams, TaskId,
+};
+
+/// Status reported by filesystem calls when nothing is mounted, as in the real host.
+const FS_UNAVAILABLE: usize = 7;
+
+lazy_static! {
+    static ref HOST: Mutex<FakeHost> = Default::default();
+}
+
+fn host() -> MutexGuard<'static, FakeHost> {
+    // A panicking test may have poisoned the lock. The next test resets the state anyway.
+    HOST.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct FakeHost {
+    wake_queue: VecDeque<(TaskId, usize)>,
+    /// Microseconds since the start of the test.
+    now: u64,
+    /// Pending sleeps as (deadline, sequence number, raw task ID). The sequence number keeps timers
+    /// with equal deadlines in the order they were started.
+    timers: BinaryHeap<Reverse<(u64, u64, u32)>>,
+    next_timer_seq: u64,
+    pipes: Slab<Pipe>,
+    io_objects: Slab<IoObject>,
+    rpc_clients: Slab<String>,
+    rpc_servers: Slab<RpcServer>,
+    native_services: HashMap<String, NativeHandler>,
+    native_calls: Slab<NativeCall>,
+    servers_by_service_name: HashMap<String, Vec<usize>>,
+    tasks_waiting_by_service_name: HashMap<String, Vec<TaskId>>,
+    shutdown: bool,
+    tasks_waiting_for_shutdown: Vec<TaskId>,
+    host_shutdown_requested: bool,
+    tasks_waiting_for_host_shutdown: Vec<TaskId>,
+}
+
+struct SendPointer<T>(*const T);
+
+unsafe impl<T> Send for SendPointer<T> {}
+
+struct SendPointerMut<T>(*mut T);
+
+unsafe impl<T> Send for SendPointerMut<T> {}
+
+struct Pipe {
+    state: PipeState,
+    open_ends: u8,
+}
+
+enum PipeState {
+    Idle,
+    PendingRead {
+        task_id: TaskId,
+        dst: SendPointerMut<u8>,
+        len: usize,
+    },
+    PendingWrite {
+        task_id: TaskId,
+        src: SendPointer<u8>,
+        len: usize,
+    },
+    Closed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PipeEnd {
+    Reader,
+    Writer,
+}
+
+enum IoObject {
+    Pipe {
+        pipe: usize,
+        end: PipeEnd,
+    },
+    /// The guest's end of a request to a native service, which it writes.
+    NativeRequest(usize),
+    /// The guest's end of a native service's response, which it reads.
+    NativeResponse(usize),
+}
+
+/// Answers a request to a native service, given its method name and body, with the whole response.
+type NativeHandler = Box<dyn FnMut(&str, &[u8]) -> Vec<u8> + Send>;
+
+struct NativeCall {
+    service_name: String,
+    method_name: String,
+    request: Vec<u8>,
+    /// None until the guest closes the request and the handler has answered it.
+    response: Option<VecDeque<u8>>,
+    pending_read: Option<(TaskId, SendPointerMut<u8>, usize)>,
+    open_ends: u8,
+}
+
+struct RpcServer {
+    method_index_by_name: HashMap<String, usize>,
+    request_queue: VecDeque<RpcMethodMetadata>,
+    waiting_task_ids: Vec<TaskId>,
+}
+
+impl FakeHost {
+    fn wake(&mut self, task_id: TaskId, param: usize) {
+        self.wake_queue.push_back((task_id, param));
+    }
+
+    /// Creates a pipe and returns handles for its reading and writing ends.
+    fn pipe(&mut self) -> (IoHandle, IoHandle) {
+        let pipe = self.pipes.insert(Pipe {
+            state: PipeState::Idle,
+            open_ends: 2,
+        });
+        let reader = self.io_objects.insert(IoObject::Pipe {
+            pipe,
+            end: PipeEnd::Reader,
+        });
+        let writer = self.io_objects.insert(IoObject::Pipe {
+            pipe,
+            end: PipeEnd::Writer,
+        });
+        (io_handle(reader), io_handle(writer))
+    }
+
+    fn io_object(&self, io: IoHandle) -> &IoObject {
+        self.io_objects.get(io.0 as usize).expect("bad IO handle")
+    }
+
+    fn pipe_for(&mut self, io: IoHandle, end: PipeEnd) -> &mut Pipe {
+        match *self.io_object(io) {
+            IoObject::Pipe {
+                pipe,
+                end: pipe_end,
+            } if pipe_end == end => &mut self.pipes[pipe],
+            _ => panic!("wrong direction for IO handle"),
+        }
+    }
+
+    /// Starts a request to a native service, returning handles for the guest's ends of the request
+    /// and response.
+    fn native_call(&mut self, service_name: &str, method_name: &str) -> (IoHandle, IoHandle) {
+        let call = self.native_calls.insert(NativeCall {
+            service_name: service_name.to_owned(),
+            method_name: method_name.to_owned(),
+            request: Vec::new(),
+            response: None,
+            pending_read: None,
+            open_ends: 2,
+        });
+        let request = self.io_objects.insert(IoObject::NativeRequest(call));
+        let response = self.io_objects.insert(IoObject::NativeResponse(call));
+        (io_handle(request), io_handle(response))
+    }
+
+    unsafe fn read_native_response(
+        &mut self,
+        task_id: TaskId,
+        call: usize,
+        dst: *mut u8,
+        len: usize,
+    ) -> Poll<usize> {
+        let call = &mut self.native_calls[call];
+        if len == 0 {
+            return Poll::Ready(0);
+        }
+        match &mut call.response {
+            Some(response) => Poll::Ready(unsafe { drain_into(response, dst, len) }),
+            None => {
+                assert!(
+                    call.pending_read.is_none(),
+                    "read with a read already pending"
+                );
+                call.pending_read = Some((task_id, SendPointerMut(dst), len));
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Answers a native call whose request the guest has closed, completing any pending read.
+    fn answer_native_call(&mut self, call: usize) {
+        let call = &mut self.native_calls[call];
+        let handler = self
+            .native_services
+            .get_mut(&call.service_name)
+            .expect("native service removed");
+        let mut response = VecDeque::from(handler(&call.method_name, &call.request));
+        let woken = call.pending_read.take().map(|(task_id, dst, len)| {
+            let n = unsafe { drain_into(&mut response, dst.0, len) };
+            (task_id, n)
+        });
+        call.response = Some(response);
+        if let Some((task_id, n)) = woken {
+            self.wake(task_id, n);
+        }
+    }
+
+    fn close_native_end(&mut self, call: usize) {
+        let native_call = &mut self.native_calls[call];
+        native_call.open_ends -= 1;
+        if native_call.open_ends == 0 {
+            self.native_calls.remove(call);
+        }
+    }
+
+    unsafe fn read(
+        &mut self,
+        task_id: TaskId,
+        io: IoHandle,
+        dst: *mut u8,
+        len: usize,
+    ) -> Poll<usize> {
+        if let IoObject::NativeResponse(call) = *self.io_object(io) {
+            return unsafe { self.read_native_response(task_id, call, dst, len) };
+        }
+        let pipe = self.pipe_for(io, PipeEnd::Reader);
+        if len == 0 {
+            return Poll::Ready(0);
+        }
+        match std::mem::replace(&mut pipe.state, PipeState::Idle) {
+            PipeState::Idle => {
+                pipe.state = PipeState::PendingRead {
+                    task_id,
+                    dst: SendPointerMut(dst),
+                    len,
+                };
+                Poll::Pending
+            }
+            PipeState::PendingRead { .. } => panic!("read with a read already pending"),
+            PipeState::PendingWrite {
+                task_id: write_task_id,
+                src,
+                len: src_len,
+            } => {
+                let n = len.min(src_len);
+                unsafe { copy_nonoverlapping(src.0, dst, n) }
+                self.wake(write_task_id, n);
+                Poll::Ready(n)
+            }
+            PipeState::Closed => {
+                pipe.state = PipeState::Closed;
+                Poll::Ready(0)
+            }
+        }
+    }
+
+    unsafe fn write(
+        &mut self,
+        task_id: TaskId,
+        io: IoHandle,
+        src: *const u8,
+        len: usize,
+    ) -> Poll<usize> {
+        if let IoObject::NativeRequest(call) = *self.io_object(io) {
+            let request = &mut self.native_calls[call].request;
+            request.extend_from_slice(unsafe { slice::from_raw_parts(src, len) });
+            return Poll::Ready(len);
+        }
+        let pipe = self.pipe_for(io, PipeEnd::Writer);
+        if len == 0 {
+            return Poll::Ready(0);
+        }
+        match std::mem::replace(&mut pipe.state, PipeState::Idle) {
+            PipeState::Idle => {
+                pipe.state = PipeState::PendingWrite {
+                    task_id,
+                    src: SendPointer(src),
+                    len,
+                };
+                Poll::Pending
+            }
+            PipeState::PendingRead {
+                task_id: read_task_id,
+                dst,
+                len: dst_len,
+            } => {
+                let n = len.min(dst_len);
+                unsafe { copy_nonoverlapping(src, dst.0, n) }
+                self.wake(read_task_id, n);
+                Poll::Ready(n)
+            }
+            PipeState::PendingWrite { .. } => panic!("write with a write already pending"),
+            PipeState::Closed => {
+                pipe.state = PipeState::Closed;
+                Poll::Ready(0)
+            }
+        }
+    }
+
+    fn close(&mut self, io: IoHandle) {
+        let io_object = self
+            .io_objects
+            .try_remove(io.0 as usize)
+            .expect("bad IO handle");
+        let pipe = match io_object {
+            IoObject::Pipe { pipe, .. } => pipe,
+            IoObject::NativeRequest(call) => {
+                self.answer_native_call(call);
+                self.close_native_end(call);
+                return;
+            }
+            IoObject::NativeResponse(call) => {
+                self.close_native_end(call);
+                return;
+            }
+        };
+        let pipe_state = &mut self.pipes[pipe];
+        // Whichever side is waiting can never complete, so it sees end of file or a zero-length
+        // write.
+        let waiting = match std::mem::replace(&mut pipe_state.state, PipeState::Closed) {
+            PipeState::PendingRead { task_id, .. } | PipeState::PendingWrite { task_id, .. } => {
+                Some(task_id)
+            }
+            PipeState::Idle | PipeState::Closed => None,
+        };
+        pipe_state.open_ends -= 1;
+        if pipe_state.open_ends == 0 {
+            self.pipes.remove(pipe);
+        }
+        if let Some(task_id) = waiting {
+            self.wake(task_id, 0);
+        }
+    }
+
+    /// Allocates a handle for a filesystem call and fails the call, since nothing is mounted.
+    fn fs_unavailable(&mut self, task_id: TaskId, end: PipeEnd) -> IoHandle {
+        let (reader, writer) = self.pipe();
+        let (kept, closed) = match end {
+            PipeEnd::Reader => (reader, writer),
+            PipeEnd::Writer => (writer, reader),
+        };
+        self.close(closed);
+        self.wake(task_id, FS_UNAVAILABLE);
+        kept
+    }
+}
+
+fn io_handle(key: usize) -> IoHandle {
+    IoHandle(key as u32)
+}
+
+/// Moves up to `len` bytes from the front of `src` to `dst`, returning how many.
+unsafe fn drain_into(src: &mut VecDeque<u8>, dst: *mut u8, len: usize) -> usize {
+    let n = len.min(src.len());
+    for (i, b) in src.drain(..n).enumerate() {
+        unsafe { dst.add(i).write(b) };
+    }
+    n
+}
+
+/// Reads a string the guest passed by pointer and length.
+unsafe fn guest_str<'a>(ptr: *const u8, len: usize) -> &'a str {
+    str::from_utf8(unsafe { slice::from_raw_parts(ptr, len) }).expect("string is not UTF-8")
+}
+
+//
+// Harness interface
+//
+
+/// Discards all state, returning the host to how it was at the start of a test.
+pub(crate) fn reset() {
+    let old = std::mem::take(&mut *host());
+    drop(old);
+}
+
+/// Serves `service_name` from the host, as the real host serves services like `BlobService`.
+/// `handler` answers each request once the guest has closed it.
+pub(crate) fn serve_natively(service_name: &str, handler: NativeHandler) {
+    host()
+        .native_services
+        .insert(service_name.to_owned(), handler);
+}
+
+/// Returns the next wake to deliver. When none are queued, advances the clock to the earliest
+/// pending timer instead. Returns None only if nothing can ever wake the guest again.
+pub(crate) fn next_wake() -> Option<(TaskId, usize)> {
+    let mut host = host();
+    if let Some(wake) = host.wake_queue.pop_front() {
+        return Some(wake);
+    }
+    let Reverse((deadline, _, task_id)) = host.timers.pop()?;
+    host.now = host.now.max(deadline);
+    Some((TaskId(task_id), 0))
+}
+
+/// Returns true if the guest has called `shutdown()`. Otherwise wakes `task_id` once it does.
+pub(crate) fn wait_for_shutdown(task_id: TaskId) -> bool {
+    let mut host = host();
+    if !host.shutdown {
+        host.tasks_waiting_for_shutdown.push(task_id);
+    }
+    host.shutdown
+}
+
+/// Starts shutting the host down, waking every task waiting in `host_shutdown_requested()`.
+pub(crate) fn request_host_shutdown() {
+    let mut host = host();
+    host.host_shutdown_requested = true;
+    for task_id in std::mem::take(&mut host.tasks_waiting_for_host_shutdown) {
+        host.wake(task_id, 0);
+    }
+}
+
+//
+// Core Functions
+//
+
+pub unsafe fn shutdown() {
+    let mut host = host();
+    host.shutdown = true;
+    for task_id in std::mem::take(&mut host.tasks_waiting_for_shutdown) {
+        host.wake(task_id, 0);
+    }
+}
+
+pub unsafe fn host_shutdown_requested(task_id: TaskId) {
+    let mut host = host();
+    if host.host_shutdown_requested {
+        host.wake(task_id, 0);
+    } else {
+        host.tasks_waiting_for_host_shutdown.push(task_id);
+    }
+}
+
+pub unsafe fn abort() -> ! {
+    panic!("guest aborted");
+}
+
+pub unsafe fn panic(
+    message_ptr: *const u8,
+    message_len: usize,
+    file_ptr: *const u8,
+    file_len: usize,
+    line: u32,
+    column: u32,
+) {
+    let message = unsafe { guest_str(message_ptr, message_len) };
+    let file = unsafe { guest_str(file_ptr, file_len) };
+    println!(
+        "guest panicked at {}:{}:{}: {}",
+        file, line, column, message
+    );
+}
+
+//
+// Debug, test, and diagnostic functions.
+//
+
+pub unsafe fn log(ptr: *const c_void, len: usize) {
+    let message = unsafe { guest_str(ptr as *const u8, len) };
+    println!("[{} us] {}", host().now, message);
+}
+
+pub unsafe fn impulse(task_id: TaskId) {
+    host().wake(task_id, 0);
+}
+
+// The allocator that reports heap stats is only built for Wasm.
+#[allow(dead_code)]
+pub unsafe fn heap_stats(
+    _live_bytes: usize,
+    _peak_bytes: usize,
+    _allocations: u64,
+    _deallocations: u64,
+) {
+}
+
+//
+// Time Functions
+//
+
+pub unsafe fn sleep(task_id: TaskId, usec: u32) {
+    let mut host = host();
+    let deadline = host.now + u64::from(usec);
+    let seq = host.next_timer_seq;
+    host.next_timer_seq += 1;
+    host.timers.push(Reverse((deadline, seq, task_id.0)));
+}
+
+pub unsafe fn monotonic_time() -> u64 {
+    host().now
+}
+
+//
+// I/O Functions
+//
+
+pub unsafe fn io_read(
+    task_id: TaskId,
+    io: IoHandle,
+    ptr: *mut u8,
+    len: usize,
+    n_ptr: *mut usize,
+) -> u32 {
+    match unsafe { host().read(task_id, io, ptr, len) } {
+        Poll::Ready(n) => {
+            unsafe { n_ptr.write(n) };
+            0
+        }
+        Poll::Pending => 1,
+    }
+}
+
+pub unsafe fn io_write(
+    task_id: TaskId,
+    io: IoHandle,
+    ptr: *const u8,
+    len: usize,
+    n_ptr: *mut usize,
+) -> u32 {
+    match unsafe { host().write(task_id, io, ptr, len) } {
+        Poll::Ready(n) => {
+            unsafe { n_ptr.write(n) };
+            0
+        }
+        Poll::Pending => 1,
+    }
+}
+
+pub unsafe fn io_close(io: IoHandle) {
+    host().close(io);
+}
+
+//
+// Filesystem Functions
+//
+// These are extern "C" because the guest library passes them around as function pointers of that
+// type.
+
+pub unsafe extern "C" fn fs_open(
+    task_id: TaskId,
+    _path_ptr: *const u8,
+    _path_len: usize,
+    io_ptr: *mut IoHandle,
+) {
+    let io = host().fs_unavailable(task_id, PipeEnd::Reader);
+    unsafe { io_ptr.write(io) };
+}
+
+pub unsafe extern "C" fn fs_create(
+    task_id: TaskId,
+    _path_ptr: *const u8,
+    _path_len: usize,
+    io_ptr: *mut IoHandle,
+) {
+    let io = host().fs_unavailable(task_id, PipeEnd::Writer);
+    unsafe { io_ptr.write(io) };
+}
+
+pub unsafe extern "C" fn fs_stat(
+    task_id: TaskId,
+    _path_ptr: *const u8,
+    _path_len: usize,
+    io_ptr: *mut IoHandle,
+) {
+    let io = host().fs_unavailable(task_id, PipeEnd::Reader);
+    unsafe { io_ptr.write(io) };
+}
+
+pub unsafe extern "C" fn fs_readdir(
+    task_id: TaskId,
+    _path_ptr: *const u8,
+    _path_len: usize,
+    io_ptr: *mut IoHandle,
+) {
+    let io = host().fs_unavailable(task_id, PipeEnd::Reader);
+    unsafe { io_ptr.write(io) };
+}
+
+pub unsafe fn fs_close(task_id: TaskId, io: IoHandle) {
+    let mut host = host();
+    host.close(io);
+    // The file was never created.
+    host.wake(task_id, FS_UNAVAILABLE);
+}
+
+//
+// RPC Client Functions
+//
+
+pub unsafe fn rpc_client_create(
+    service_name_ptr: *const u8,
+    service_name_len: usize,
+) -> RpcClientHandle {
+    let service_name = unsafe { guest_str(service_name_ptr, service_name_len) }.to_owned();
+    RpcClientHandle(host().rpc_clients.insert(service_name) as u32)
+}
+
+pub unsafe fn rpc_client_wait_healthy(task_id: TaskId, rpc_client: RpcClientHandle) -> u32 {
+    let mut host = host();
+    let service_name = host
+        .rpc_clients
+        .get(rpc_client.0 as usize)
+        .expect("bad RPC client handle")
+        .clone();
+    if host.servers_by_service_name.contains_key(&service_name)
+        || host.native_services.contains_key(&service_name)
+    {
+        0
+    } else {
+        host.tasks_waiting_by_service_name
+            .entry(service_name)
+            .or_default()
+            .push(task_id);
+        1
+    }
+}
+
+pub unsafe fn rpc_client_request(
+    rpc_client: RpcClientHandle,
+    method_name_ptr: *const u8,
+    method_name_len: usize,
+    request_io_ptr: *mut IoHandle,
+    response_io_ptr: *mut IoHandle,
+) -> u32 {
+    let method_name = unsafe { guest_str(method_name_ptr, method_name_len) };
+    let mut host = host();
+    let service_name = host
+        .rpc_clients
+        .get(rpc_client.0 as usize)
+        .expect("bad RPC client handle")
+        .clone();
+    if host.native_services.contains_key(&service_name) {
+        let (client_request_io, client_response_io) = host.native_call(&service_name, method_name);
+        unsafe {
+            request_io_ptr.write(client_request_io);
+            response_io_ptr.write(client_response_io);
+        }
+        return 0;
+    }
+    let server = *host
+        .servers_by_service_name
+        .get(&service_name)
+        .and_then(|servers| servers.first())
+        .unwrap_or_else(|| panic!("RPC service {:?} has no servers", service_name));
+
+    let (server_request_io, client_request_io) = host.pipe();
+    let (client_response_io, server_response_io) = host.pipe();
+
+    let server = &mut host.rpc_servers[server];
+    server.request_queue.push_back(RpcMethodMetadata {
+        index: server.method_index_by_name[method_name],
+        request_io: server_request_io,
+        response_io: server_response_io,
+    });
+    for task_id in std::mem::take(&mut server.waiting_task_ids) {
+        host.wake(task_id, 0);
+    }
+
+    unsafe {
+        request_io_ptr.write(client_request_io);
+        response_io_ptr.write(client_response_io);
+    }
+    0
+}
+
+//
+// RPC Server Functions
+//
+
+pub unsafe fn rpc_server_create(params: *const RpcServerParams) -> RpcServerHandle {
+    let params = unsafe { &*params };
+    let service_name = unsafe { guest_str(params.service_name_ptr, params.service_name_len) };
+    let methods = unsafe { slice::from_raw_parts(params.methods_ptr, params.methods_len) };
+    let method_index_by_name = methods
+        .iter()
+        .enumerate()
+        .map(|(index, method)| {
+            let name = unsafe { guest_str(method.method_name_ptr, method.method_name_len) };
+            (name.to_owned(), index)
+        })
+        .collect();
+
+    let mut host = host();
+    let rpc_server = host.rpc_servers.insert(RpcServer {
+        method_index_by_name,
+        request_queue: VecDeque::new(),
+        waiting_task_ids: Vec::new(),
+    });
+    host.servers_by_service_name
+        .entry(service_name.to_owned())
+        .or_default()
+        .push(rpc_server);
+
+    // Wake any tasks that were waiting for this service to become available.
+    if let Some(task_ids) = host.tasks_waiting_by_service_name.remove(service_name) {
+        for task_id in task_ids {
+            host.wake(task_id, 0);
+        }
+    }
+
+    RpcServerHandle(rpc_server as u32)
+}
+
+pub unsafe fn rpc_server_get_request(
+    task_id: TaskId,
+    rpc_server: RpcServerHandle,
+    metadata: *mut RpcMethodMetadata,
+) -> RpcServerGetRequestResult {
+    let mut host = host();
+    let rpc_server = host
+        .rpc_servers
+        .get_mut(rpc_server.0 as usize)
+        .expect("bad RPC server handle");
+    match rpc_server.request_queue.pop_front() {
+        Some(request) => {
+            unsafe { metadata.write(request) };
+            RpcServerGetRequestResult(0)
+        }
+        None => {
+            rpc_server.waiting_task_ids.push(task_id);
+            RpcServerGetRequestResult(1)
+        }
+    }
+}
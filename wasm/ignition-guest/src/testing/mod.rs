@@ -0,0 +1,97 @@
+//! Runs guest code natively, against an in-process fake of the host.
+//!
+//! With the `native-test` feature, the host imports in `api::sys` are replaced by a fake host that
+//! lives in the test binary. Everything a test spawns runs as one process: RPC servers it builds
+//! are registered where its clients can find them, [`serve_natively`] stands in for the host's own
+//! services, IO handles are backed by pipes that behave like the host's, and time comes from a
+//! virtual clock that jumps straight to the next timer whenever every task is idle. No filesystem
+//! is mounted.
+//!
+//! Tests that use [`run`] take turns, since the executor and the fake host are global.
+
+pub(crate) mod host;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::api::sleep;
+use crate::api::wait::wait;
+use crate::runtime::{executor, reactor, spawn_local};
+
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `future` to completion as the main task of a fresh process and returns its output.
+///
+/// Tasks still running when `future` completes are dropped. Panics if `future` can never complete
+/// because every task is waiting on something that will not happen.
+pub fn run<F>(future: F) -> F::Output
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    // A previous test panicking while holding the lock leaves nothing that reset() can't clear.
+    let _guard = TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    reset();
+
+    let output = Rc::new(RefCell::new(None));
+    let task_output = Rc::clone(&output);
+    spawn_local(async move {
+        *task_output.borrow_mut() = Some(future.await);
+    });
+    executor::run();
+
+    loop {
+        if let Some(output) = output.borrow_mut().take() {
+            reset();
+            return output;
+        }
+        match host::next_wake() {
+            Some((task_id, param)) => {
+                reactor::dispatch_wake(task_id, param);
+                executor::run();
+            }
+            None => panic!("deadlock: every task is waiting and nothing is left to wake them"),
+        }
+    }
+}
+
+/// Lets every other task run until it is waiting on something. The virtual clock only moves once
+/// nothing is left to run, so this completes after everything woken before it has been polled.
+pub async fn settle() {
+    sleep(Duration::ZERO).await;
+}
+
+/// Has the fake host serve `service_name` itself, the way the real host serves `BlobService` and
+/// its other native services. Once the guest closes a request, `handler` gets its method name and
+/// body and returns the whole response.
+pub fn serve_natively<F>(service_name: &str, handler: F)
+where
+    F: FnMut(&str, &[u8]) -> Vec<u8> + Send + 'static,
+{
+    host::serve_natively(service_name, Box::new(handler));
+}
+
+/// Waits until some task calls [`crate::api::shutdown`].
+pub async fn shutdown_requested() {
+    let task_id = reactor::new_task();
+    if host::wait_for_shutdown(task_id) {
+        reactor::drop_unused_task(task_id);
+    } else {
+        wait(task_id).await;
+    }
+}
+
+/// Acts as though the host were asked to shut down, completing [`crate::api::host_shutdown`].
+pub fn request_host_shutdown() {
+    host::request_host_shutdown();
+}
+
+fn reset() {
+    // Dropping a task can wake others or reach back into the fake host, so keep going until
+    // nothing is left, then clear the host.
+    while executor::clear() | reactor::clear() {}
+    host::reset();
+}
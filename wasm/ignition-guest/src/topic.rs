@@ -0,0 +1,206 @@
+//! Publish/subscribe messaging through the host's `TopicService`.
+//!
+//! Any process can publish a message to a named topic, and every process subscribed to the topic
+//! gets a copy. Each subscription has a bounded queue, and its [`Policy`] decides what happens to
+//! messages published while the queue is full. Messages are delivered in the order each publisher
+//! sent them.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+
+use crate::io::{read_full, ReadHandle};
+use crate::rpc_client::RpcClient;
+
+const SERVICE_NAME: &str = "TopicService";
+
+thread_local! {
+    static CLIENT: RpcClient = RpcClient::new(SERVICE_NAME);
+}
+
+/// What happens to a message published while a subscription's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Publishers wait until there's room, so a slow subscriber slows them down.
+    Block = 0,
+    /// The oldest queued message is dropped to make room.
+    DropOldest = 1,
+    /// The new message is dropped.
+    DropNewest = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The topic name or message was too long, or the queue capacity was out of range.
+    InvalidRequest,
+    /// The host has no topic service, or it didn't respond.
+    Unavailable,
+    /// A status code this version of the library doesn't know about.
+    Unknown(u8),
+}
+
+impl Error {
+    fn from_status(status: u8) -> Result<(), Self> {
+        match status {
+            0 => Ok(()),
+            1 => Err(Error::InvalidRequest),
+            x => Err(Error::Unknown(x)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidRequest => write!(f, "invalid topic request"),
+            Error::Unavailable => write!(f, "topic service unavailable"),
+            Error::Unknown(status) => write!(f, "unknown topic service error {}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A message received through a [`Subscription`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    /// How many messages the subscription lost just before this one because its queue was full.
+    pub dropped: u32,
+    pub message: Vec<u8>,
+}
+
+/// Receives the messages published to a topic. Dropping it unsubscribes.
+pub struct Subscription {
+    response: ReadHandle,
+}
+
+impl Subscription {
+    /// Waits for the next message. Returns None if the host ended the subscription.
+    pub async fn next(&self) -> Option<Delivery> {
+        let mut header = [0; 8];
+        if !read_full(&self.response, &mut header).await {
+            return None;
+        }
+        let dropped = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let mut message = vec![0; len as usize];
+        if !read_full(&self.response, &mut message).await {
+            return None;
+        }
+        Some(Delivery { dropped, message })
+    }
+}
+
+/// Sends `message` to every subscriber to `topic`, waiting for any with a [`Policy::Block`] queue
+/// that's full. Returns how many subscribers queued it.
+pub async fn publish(topic: &str, message: &[u8]) -> Result<u32, Error> {
+    let mut body = Vec::with_capacity(8 + topic.len() + message.len());
+    put_field(&mut body, topic.as_bytes());
+    put_field(&mut body, message);
+    let request = CLIENT.with(|client| client.request("publish"));
+    request.write_all(&body).await;
+    let response = request.into_response().read_to_end().await;
+    let (&status, count) = response.split_first().ok_or(Error::Unavailable)?;
+    Error::from_status(status)?;
+    Ok(u32::from_le_bytes(
+        count.try_into().map_err(|_| Error::Unavailable)?,
+    ))
+}
+
+/// Subscribes to `topic` with a queue of up to `capacity` messages. Messages published once this
+/// returns are delivered, subject to `policy`.
+pub async fn subscribe(topic: &str, capacity: u32, policy: Policy) -> Result<Subscription, Error> {
+    let mut body = Vec::with_capacity(9 + topic.len());
+    put_field(&mut body, topic.as_bytes());
+    body.extend_from_slice(&capacity.to_le_bytes());
+    body.push(policy as u8);
+    let request = CLIENT.with(|client| client.request("subscribe"));
+    request.write_all(&body).await;
+    let response = request.into_response();
+
+    let mut status = [0];
+    if !read_full(&response, &mut status).await {
+        return Err(Error::Unavailable);
+    }
+    Error::from_status(status[0])?;
+    Ok(Subscription { response })
+}
+
+fn put_field(dst: &mut Vec<u8>, field: &[u8]) {
+    let len: u32 = field.len().try_into().unwrap();
+    dst.extend_from_slice(&len.to_le_bytes());
+    dst.extend_from_slice(field);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{publish, put_field, subscribe, Delivery, Error, Policy, SERVICE_NAME};
+    use crate::testing::{run, serve_natively};
+
+    fn delivery(dropped: u32, message: &[u8]) -> Vec<u8> {
+        let mut delivery = dropped.to_le_bytes().to_vec();
+        put_field(&mut delivery, message);
+        delivery
+    }
+
+    #[test]
+    fn publishes_messages() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, body| {
+                assert_eq!(method_name, "publish");
+                let mut expected = Vec::new();
+                put_field(&mut expected, b"news");
+                put_field(&mut expected, b"hello");
+                if body == expected.as_slice() {
+                    [&[0][..], &2u32.to_le_bytes()].concat()
+                } else {
+                    vec![1]
+                }
+            });
+            assert_eq!(publish("news", b"hello").await, Ok(2));
+            assert_eq!(publish("news", b"").await, Err(Error::InvalidRequest));
+        });
+    }
+
+    #[test]
+    fn reads_deliveries_until_the_host_ends_the_subscription() {
+        run(async {
+            serve_natively(SERVICE_NAME, |method_name, body| {
+                assert_eq!(method_name, "subscribe");
+                let mut expected = Vec::new();
+                put_field(&mut expected, b"news");
+                expected.extend_from_slice(&16u32.to_le_bytes());
+                expected.push(Policy::DropOldest as u8);
+                assert_eq!(body, expected.as_slice());
+                [&[0][..], &delivery(0, b"a"), &delivery(3, b"bc")].concat()
+            });
+            let subscription = subscribe("news", 16, Policy::DropOldest).await.unwrap();
+            assert_eq!(
+                subscription.next().await,
+                Some(Delivery {
+                    dropped: 0,
+                    message: b"a".to_vec(),
+                })
+            );
+            assert_eq!(
+                subscription.next().await,
+                Some(Delivery {
+                    dropped: 3,
+                    message: b"bc".to_vec(),
+                })
+            );
+            assert_eq!(subscription.next().await, None);
+        });
+    }
+
+    #[test]
+    fn ends_at_a_cut_off_delivery() {
+        run(async {
+            serve_natively(SERVICE_NAME, |_, _| {
+                let delivery = delivery(0, b"abc");
+                [&[0][..], &delivery[..delivery.len() - 1]].concat()
+            });
+            let subscription = subscribe("news", 1, Policy::Block).await.unwrap();
+            assert_eq!(subscription.next().await, None);
+        });
+    }
+}
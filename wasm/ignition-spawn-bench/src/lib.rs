@@ -0,0 +1,7 @@
+//! Exits as soon as it starts, so that running many copies measures how fast the host spawns
+//! processes: `./runwasm.sh --release 1000 ignition-spawn-bench`. The host logs the throughput and
+//! spawn times once every copy has started. Pass `--pooling` to the host to compare allocators:
+//! `./runwasm.sh --release 1000 ignition-spawn-bench -- --pooling`.
+
+#[ignition_guest::main]
+async fn main() {}